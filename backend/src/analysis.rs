@@ -0,0 +1,494 @@
+// Readability and tone metrics computed locally, without calling the model.
+// The formulas and heuristics are tuned for English text.
+
+use axum::extract::Query;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+
+use crate::error::AppError;
+use crate::text::split_sentences;
+
+// Average silent reading speed, in words per minute
+const READING_WORDS_PER_MINUTE: f64 = 238.0;
+
+// Sentences longer than this many words are flagged
+const LONG_SENTENCE_WORDS: usize = 25;
+
+const BE_FORMS: &[&str] = &["am", "is", "are", "was", "were", "be", "been", "being"];
+
+// Common irregular past participles, regular ones are recognized by their `-ed`
+const IRREGULAR_PARTICIPLES: &[&str] = &[
+    "begun",
+    "born",
+    "bought",
+    "broken",
+    "brought",
+    "built",
+    "caught",
+    "chosen",
+    "done",
+    "drawn",
+    "driven",
+    "eaten",
+    "forgotten",
+    "found",
+    "given",
+    "grown",
+    "held",
+    "hidden",
+    "kept",
+    "known",
+    "led",
+    "left",
+    "lost",
+    "made",
+    "meant",
+    "met",
+    "paid",
+    "said",
+    "seen",
+    "sent",
+    "shown",
+    "sold",
+    "spoken",
+    "stolen",
+    "taken",
+    "taught",
+    "thought",
+    "thrown",
+    "told",
+    "understood",
+    "won",
+    "worn",
+    "written",
+];
+
+const HEDGES: &[&str] = &[
+    "maybe",
+    "perhaps",
+    "possibly",
+    "probably",
+    "might",
+    "somewhat",
+    "fairly",
+    "quite",
+    "arguably",
+    "apparently",
+    "seemingly",
+    "likely",
+];
+
+const FIRST_PERSON: &[&str] = &["i", "me", "my", "mine", "we", "us", "our", "ours"];
+const SECOND_PERSON: &[&str] = &["you", "your", "yours"];
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AnalyzeRequest {
+    pub text: String,
+    // Earlier version of the text to compare the scores with
+    #[serde(default)]
+    pub original: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Counts {
+    pub characters: usize,
+    pub words: usize,
+    pub sentences: usize,
+    pub paragraphs: usize,
+    pub syllables: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Readability {
+    pub flesch_reading_ease: f64,
+    pub flesch_kincaid_grade: f64,
+    pub gunning_fog: f64,
+    pub coleman_liau_index: f64,
+    pub average_sentence_length: f64,
+    pub average_syllables_per_word: f64,
+}
+
+// A flagged part of the text. Offsets count characters, not bytes.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LongSentence {
+    #[serde(flatten)]
+    pub span: Span,
+    pub words: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ToneSignals {
+    pub exclamations: usize,
+    pub questions: usize,
+    pub contractions: usize,
+    pub hedges: usize,
+    pub first_person: usize,
+    pub second_person: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TextAnalysis {
+    pub counts: Counts,
+    pub reading_time_seconds: u64,
+    // Missing when the text has no words to score
+    pub readability: Option<Readability>,
+    pub long_sentences: Vec<LongSentence>,
+    pub passive_voice: Vec<Span>,
+    pub tone: ToneSignals,
+}
+
+// How the scores moved from the original text to the new one
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct AnalysisDelta {
+    pub words: i64,
+    pub sentences: i64,
+    pub reading_time_seconds: i64,
+    pub flesch_reading_ease: Option<f64>,
+    pub flesch_kincaid_grade: Option<f64>,
+    pub long_sentences: i64,
+    pub passive_voice: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AnalyzeResponse {
+    pub analysis: TextAnalysis,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original: Option<TextAnalysis>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delta: Option<AnalysisDelta>,
+}
+
+// A word and the byte offset it starts at
+struct Word<'a> {
+    offset: usize,
+    text: &'a str,
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '\'' || c == '’'
+}
+
+fn words(text: &str) -> Vec<Word<'_>> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (index, c) in text
+        .char_indices()
+        .chain(std::iter::once((text.len(), ' ')))
+    {
+        match (start, is_word_char(c)) {
+            (None, true) => start = Some(index),
+            (Some(begin), false) => {
+                let word = text[begin..index].trim_matches(|c| c == '\'' || c == '’');
+                if word.chars().any(char::is_alphanumeric) {
+                    let offset = begin + text[begin..].find(word).unwrap_or(0);
+                    words.push(Word { offset, text: word });
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    words
+}
+
+// Estimate syllables from groups of vowels, ignoring a silent final `e`
+fn syllables(word: &str) -> usize {
+    let word = word.to_lowercase();
+    if !word.chars().any(char::is_alphabetic) {
+        return 1;
+    }
+
+    let is_vowel = |c: char| "aeiouy".contains(c);
+    let mut count = 0;
+    let mut previous_vowel = false;
+    for c in word.chars() {
+        let vowel = is_vowel(c);
+        if vowel && !previous_vowel {
+            count += 1;
+        }
+        previous_vowel = vowel;
+    }
+
+    if word.ends_with('e') && !word.ends_with("le") && count > 1 {
+        count -= 1;
+    }
+    count.max(1)
+}
+
+fn is_participle(word: &str) -> bool {
+    (word.len() > 3 && word.ends_with("ed")) || IRREGULAR_PARTICIPLES.contains(&word)
+}
+
+// Span of the bytes `start..end` of `text`, `start` being at character `char_start`
+fn span(text: &str, start: usize, end: usize, char_start: usize) -> Span {
+    let text = &text[start..end];
+    Span {
+        start: char_start,
+        end: char_start + text.chars().count(),
+        text: text.to_string(),
+    }
+}
+
+// Find "to be" followed by a past participle, allowing one adverb in between
+fn passive_phrases(text: &str, words: &[Word<'_>]) -> Vec<Span> {
+    let lower: Vec<String> = words.iter().map(|w| w.text.to_lowercase()).collect();
+    let mut phrases = Vec::new();
+    // Byte and character offsets reached so far, phrases coming in order
+    let (mut byte, mut chars) = (0, 0);
+
+    let mut index = 0;
+    while index < words.len() {
+        if BE_FORMS.contains(&lower[index].as_str()) {
+            let mut next = index + 1;
+            if lower.get(next).is_some_and(|w| w.ends_with("ly")) {
+                next += 1;
+            }
+            if lower.get(next).is_some_and(|w| is_participle(w)) {
+                let start = words[index].offset;
+                let end = words[next].offset + words[next].text.len();
+                chars += text[byte..start].chars().count();
+                let phrase = span(text, start, end, chars);
+                (byte, chars) = (end, phrase.end);
+                phrases.push(phrase);
+                index = next + 1;
+                continue;
+            }
+        }
+        index += 1;
+    }
+    phrases
+}
+
+fn round(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+pub fn analyze(text: &str) -> TextAnalysis {
+    let all_words = words(text);
+    let syllable_counts: Vec<usize> = all_words.iter().map(|w| syllables(w.text)).collect();
+    let word_count = all_words.len();
+    let syllable_count: usize = syllable_counts.iter().sum();
+    let letters: usize = all_words
+        .iter()
+        .map(|w| w.text.chars().filter(|c| c.is_alphanumeric()).count())
+        .sum();
+
+    // Sentences with their byte ranges and the character each starts at
+    let mut sentences = Vec::new();
+    let (mut offset, mut chars) = (0, 0);
+    for sentence in split_sentences(text) {
+        if !sentence.text.is_empty() {
+            sentences.push((offset, offset + sentence.text.len(), chars));
+        }
+        offset += sentence.text.len() + sentence.trailing.len();
+        chars += sentence.text.chars().count() + sentence.trailing.chars().count();
+    }
+
+    let long_sentences = sentences
+        .iter()
+        .filter_map(|&(start, end, char_start)| {
+            let count = words(&text[start..end]).len();
+            (count > LONG_SENTENCE_WORDS).then(|| LongSentence {
+                span: span(text, start, end, char_start),
+                words: count,
+            })
+        })
+        .collect();
+
+    let paragraphs = text.split("\n\n").filter(|p| !p.trim().is_empty()).count();
+
+    let readability = (word_count > 0).then(|| {
+        let words = word_count as f64;
+        let sentences = sentences.len().max(1) as f64;
+        let words_per_sentence = words / sentences;
+        let syllables_per_word = syllable_count as f64 / words;
+        let complex = syllable_counts.iter().filter(|&&s| s >= 3).count() as f64;
+        let letters_per_100 = letters as f64 / words * 100.0;
+        let sentences_per_100 = sentences / words * 100.0;
+
+        Readability {
+            flesch_reading_ease: round(
+                206.835 - 1.015 * words_per_sentence - 84.6 * syllables_per_word,
+            ),
+            flesch_kincaid_grade: round(
+                0.39 * words_per_sentence + 11.8 * syllables_per_word - 15.59,
+            ),
+            gunning_fog: round(0.4 * (words_per_sentence + 100.0 * complex / words)),
+            coleman_liau_index: round(0.0588 * letters_per_100 - 0.296 * sentences_per_100 - 15.8),
+            average_sentence_length: round(words_per_sentence),
+            average_syllables_per_word: round(syllables_per_word),
+        }
+    });
+
+    let lower: Vec<String> = all_words.iter().map(|w| w.text.to_lowercase()).collect();
+    let count_in = |list: &[&str]| lower.iter().filter(|w| list.contains(&w.as_str())).count();
+    let tone = ToneSignals {
+        exclamations: text.matches('!').count(),
+        questions: text.matches('?').count(),
+        contractions: lower
+            .iter()
+            .filter(|w| w.contains('\'') || w.contains('’'))
+            .count(),
+        hedges: count_in(HEDGES),
+        first_person: count_in(FIRST_PERSON),
+        second_person: count_in(SECOND_PERSON),
+    };
+
+    TextAnalysis {
+        counts: Counts {
+            characters: text.chars().count(),
+            words: word_count,
+            sentences: sentences.len(),
+            paragraphs,
+            syllables: syllable_count,
+        },
+        reading_time_seconds: (word_count as f64 / READING_WORDS_PER_MINUTE * 60.0).ceil() as u64,
+        readability,
+        long_sentences,
+        passive_voice: passive_phrases(text, &all_words),
+        tone,
+    }
+}
+
+impl AnalysisDelta {
+    pub fn between(original: &TextAnalysis, new: &TextAnalysis) -> Self {
+        let difference = |old: usize, new: usize| new as i64 - old as i64;
+        let score = |pick: fn(&Readability) -> f64| match (&original.readability, &new.readability)
+        {
+            (Some(old), Some(new)) => Some(round(pick(new) - pick(old))),
+            _ => None,
+        };
+
+        AnalysisDelta {
+            words: difference(original.counts.words, new.counts.words),
+            sentences: difference(original.counts.sentences, new.counts.sentences),
+            reading_time_seconds: new.reading_time_seconds as i64
+                - original.reading_time_seconds as i64,
+            flesch_reading_ease: score(|r| r.flesch_reading_ease),
+            flesch_kincaid_grade: score(|r| r.flesch_kincaid_grade),
+            long_sentences: difference(original.long_sentences.len(), new.long_sentences.len()),
+            passive_voice: difference(original.passive_voice.len(), new.passive_voice.len()),
+        }
+    }
+}
+
+// Analyze text - support both GET and POST
+pub async fn analyze_text(
+    analyze_param: Option<Query<AnalyzeRequest>>,
+    analyze_json: Option<Json<AnalyzeRequest>>,
+) -> Result<Json<AnalyzeResponse>, AppError> {
+    // Extract the request from either query parameters or JSON body
+    let request = if let Some(Query(query)) = analyze_param {
+        query
+    } else if let Some(Json(json)) = analyze_json {
+        json
+    } else {
+        return Err(AppError::BadRequest("Text is required".to_string()));
+    };
+
+    let analysis = analyze(&request.text);
+    let original = request.original.as_deref().map(analyze);
+    let delta = original
+        .as_ref()
+        .map(|original| AnalysisDelta::between(original, &analysis));
+
+    Ok(Json(AnalyzeResponse {
+        analysis,
+        original,
+        delta,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_syllables() {
+        assert_eq!(syllables("cat"), 1);
+        assert_eq!(syllables("make"), 1);
+        assert_eq!(syllables("table"), 2);
+        assert_eq!(syllables("readability"), 5);
+    }
+
+    #[test]
+    fn test_analyze_counts_and_scores() {
+        let analysis = analyze("The cat sat on the mat. It was happy!\n\nThe end.");
+
+        assert_eq!(analysis.counts.words, 11);
+        assert_eq!(analysis.counts.sentences, 3);
+        assert_eq!(analysis.counts.paragraphs, 2);
+        assert_eq!(analysis.reading_time_seconds, 3);
+        assert_eq!(analysis.tone.exclamations, 1);
+
+        // Short words in short sentences read very easily
+        let readability = analysis.readability.unwrap();
+        assert!(readability.flesch_reading_ease > 90.0);
+        assert!(readability.flesch_kincaid_grade < 2.0);
+    }
+
+    #[test]
+    fn test_analyze_flags_passive_voice_and_long_sentences() {
+        let long = vec!["word"; LONG_SENTENCE_WORDS + 1].join(" ");
+        let text = format!("The report was quickly written by Ana. {}.", long);
+        let analysis = analyze(&text);
+
+        assert_eq!(analysis.passive_voice.len(), 1);
+        assert_eq!(analysis.passive_voice[0].text, "was quickly written");
+        assert_eq!(analysis.passive_voice[0].start, 11);
+        assert_eq!(analysis.long_sentences.len(), 1);
+        assert_eq!(analysis.long_sentences[0].words, LONG_SENTENCE_WORDS + 1);
+    }
+
+    #[test]
+    fn test_spans_count_characters_not_bytes() {
+        let long = vec!["naïve"; LONG_SENTENCE_WORDS + 1].join(" ");
+        let text = format!(
+            "Café crème was served. Señor Muñoz was amazed. {}. Zoë is “well” liked.",
+            long
+        );
+        let analysis = analyze(&text);
+        let char_start = |needle: &str| text[..text.find(needle).unwrap()].chars().count();
+
+        let phrases: Vec<_> = analysis
+            .passive_voice
+            .iter()
+            .map(|p| (p.start, p.end))
+            .collect();
+        assert_eq!(
+            phrases,
+            vec![
+                (char_start("was served"), char_start("was served") + 10),
+                (char_start("was amazed"), char_start("was amazed") + 10),
+            ]
+        );
+        let sentence = &analysis.long_sentences[0].span;
+        assert_eq!(sentence.start, char_start("naïve"));
+        assert_eq!(sentence.end - sentence.start, long.chars().count() + 1);
+    }
+
+    #[test]
+    fn test_empty_text_has_no_scores() {
+        let analysis = analyze("   ");
+        assert_eq!(analysis.counts.words, 0);
+        assert!(analysis.readability.is_none());
+    }
+
+    #[test]
+    fn test_delta_between_versions() {
+        let original = analyze("The report was written by the team. It was reviewed.");
+        let new = analyze("The team wrote the report.");
+        let delta = AnalysisDelta::between(&original, &new);
+
+        assert_eq!(delta.sentences, -1);
+        assert_eq!(delta.passive_voice, -2);
+        assert!(delta.flesch_reading_ease.is_some());
+    }
+}
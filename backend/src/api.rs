@@ -1,17 +1,44 @@
 use std::env;
-use std::sync::Arc;
 
+use axum::extract::DefaultBodyLimit;
 use axum::http::{HeaderName, Method};
 use axum::middleware;
-use axum::routing::{get, post};
+use axum::routing::{delete, get, post};
 use axum::Router;
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::trace::TraceLayer;
 use tracing::info;
 
+use crate::analysis::analyze_text;
 use crate::auth::{auth_middleware, login};
-use crate::config::Config;
+use crate::collab::{collaborate, document_presence};
+use crate::document_store::{
+    add_collaborator, create_document, delete_document, diff_versions, get_document, get_version,
+    list_collaborators, list_documents, list_versions, remove_collaborator, restore_version,
+    update_document,
+};
+use crate::documents::{extract_document, process_document};
+use crate::export::export_result;
+use crate::glossary::{
+    create_do_not_translate, create_entry, delete_do_not_translate, delete_entry,
+    list_do_not_translate, list_entries,
+};
+use crate::history::{
+    clear_history, delete_history_entry, get_history_entry, list_history, rerun_history_entry,
+};
+use crate::jobs::{cancel_job, create_job, delete_job, get_job, job_results, list_jobs};
+use crate::languages::list_languages;
 use crate::openai::{expand, paraphrase, summarize, translate};
+use crate::operations::{delete_operation, list_operations, run_operation, upsert_operation};
+use crate::pipeline::run_pipeline;
+use crate::proofread::proofread;
+use crate::ratelimit::rate_limit_headers;
+use crate::socket::operation_socket;
+use crate::state::AppState;
+use crate::streams::{resume_middleware, resume_stream};
+use crate::templates::list_templates;
+use crate::translation_memory::{clear_memory, memory_stats};
+use crate::webhooks::{create_webhook, delete_webhook, list_deliveries, list_webhooks};
 
 // Health check handler
 async fn health_check() -> &'static str {
@@ -19,7 +46,9 @@ async fn health_check() -> &'static str {
 }
 
 // Create the application router
-pub fn create_router(config: Arc<Config>) -> Router {
+pub fn create_router(state: AppState) -> Router {
+    let config = state.config.clone();
+
     // Define CORS configuration
     let cors = if let Ok(allow_origin) = env::var("CORS_ALLOW_ORIGIN") {
         info!("CORS_ALLOW_ORIGIN: {}", allow_origin);
@@ -45,7 +74,13 @@ pub fn create_router(config: Arc<Config>) -> Router {
             info!("Allowed CORS origins: {:?}", origins);
 
             CorsLayer::new()
-                .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+                .allow_methods([
+                    Method::GET,
+                    Method::POST,
+                    Method::PUT,
+                    Method::DELETE,
+                    Method::OPTIONS,
+                ])
                 .allow_headers([
                     HeaderName::from_static("authorization"),
                     HeaderName::from_static("content-type"),
@@ -53,6 +88,7 @@ pub fn create_router(config: Arc<Config>) -> Router {
                     HeaderName::from_static("accept"),
                     HeaderName::from_static("origin"),
                     HeaderName::from_static("cookie"),
+                    HeaderName::from_static("last-event-id"),
                 ])
                 .allow_origin(origins)
                 .allow_credentials(true)
@@ -60,7 +96,7 @@ pub fn create_router(config: Arc<Config>) -> Router {
     } else {
         // Default configuration (localhost only)
         CorsLayer::new()
-            .allow_methods([Method::GET, Method::POST])
+            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
             .allow_headers([
                 HeaderName::from_static("authorization"),
                 HeaderName::from_static("content-type"),
@@ -68,6 +104,7 @@ pub fn create_router(config: Arc<Config>) -> Router {
                 HeaderName::from_static("accept"),
                 HeaderName::from_static("origin"),
                 HeaderName::from_static("cookie"),
+                HeaderName::from_static("last-event-id"),
             ])
             .allow_origin([
                 "http://localhost:3000".parse().unwrap(),
@@ -89,15 +126,100 @@ pub fn create_router(config: Arc<Config>) -> Router {
         .route("/health", get(health_check))
         .route("/api/auth/login", post(login));
 
-    // Protected routes that require authentication
-    let protected_routes = Router::new()
+    // Text operations, whose streams can be resumed
+    let text_routes = Router::new()
         // Support both GET and POST for SSE/fetch compatibility
         .route("/api/text/paraphrase", get(paraphrase).post(paraphrase))
         .route("/api/text/expand", get(expand).post(expand))
         .route("/api/text/summarize", get(summarize).post(summarize))
         .route("/api/text/translate", get(translate).post(translate))
+        .route("/api/text/proofread", get(proofread).post(proofread))
+        .route("/api/text/analyze", get(analyze_text).post(analyze_text))
+        .route("/api/text/pipeline", post(run_pipeline))
+        .route("/api/text/ws", get(operation_socket))
+        .route("/api/text/ops", get(list_operations).post(upsert_operation))
+        .route(
+            "/api/text/ops/:name",
+            get(run_operation)
+                .post(run_operation)
+                .delete(delete_operation),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            resume_middleware,
+        ));
+
+    // Protected routes that require authentication
+    let protected_routes = Router::new()
+        .merge(text_routes)
+        // Leave room for the multipart framing around the file
+        .route(
+            "/api/documents/extract",
+            post(extract_document).layer(DefaultBodyLimit::max(config.uploads.max_bytes + 65536)),
+        )
+        .route(
+            "/api/documents/process",
+            post(process_document).layer(DefaultBodyLimit::max(config.uploads.max_bytes + 65536)),
+        )
+        .route("/api/documents", get(list_documents).post(create_document))
+        .route(
+            "/api/documents/:id",
+            get(get_document)
+                .put(update_document)
+                .delete(delete_document),
+        )
+        .route("/api/documents/:id/versions", get(list_versions))
+        .route("/api/documents/:id/versions/:version", get(get_version))
+        .route(
+            "/api/documents/:id/versions/:version/restore",
+            post(restore_version),
+        )
+        .route("/api/documents/:id/diff", get(diff_versions))
+        .route(
+            "/api/documents/:id/collaborators",
+            get(list_collaborators).post(add_collaborator),
+        )
+        .route(
+            "/api/documents/:id/collaborators/:username",
+            delete(remove_collaborator),
+        )
+        .route("/api/documents/:id/live", get(collaborate))
+        .route("/api/documents/:id/presence", get(document_presence))
+        .route("/api/export", post(export_result))
+        .route("/api/history", get(list_history).delete(clear_history))
+        .route(
+            "/api/history/:id",
+            get(get_history_entry).delete(delete_history_entry),
+        )
+        .route("/api/history/:id/rerun", post(rerun_history_entry))
+        .route("/api/templates", get(list_templates))
+        .route("/api/languages", get(list_languages))
+        .route("/api/glossary/terms", get(list_entries).post(create_entry))
+        .route("/api/glossary/terms/:id", delete(delete_entry))
+        .route(
+            "/api/glossary/dnt",
+            get(list_do_not_translate).post(create_do_not_translate),
+        )
+        .route("/api/glossary/dnt/:id", delete(delete_do_not_translate))
+        .route(
+            "/api/translation-memory",
+            get(memory_stats).delete(clear_memory),
+        )
+        .route("/api/jobs", get(list_jobs).post(create_job))
+        .route("/api/jobs/:id", get(get_job).delete(delete_job))
+        .route("/api/jobs/:id/results", get(job_results))
+        .route("/api/jobs/:id/cancel", post(cancel_job))
+        .route("/api/webhooks", get(list_webhooks).post(create_webhook))
+        .route("/api/webhooks/:id", delete(delete_webhook))
+        .route("/api/webhooks/:id/deliveries", get(list_deliveries))
+        .route("/api/streams/:id", get(resume_stream))
+        // Runs after authentication, which wraps it
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit_headers,
+        ))
         .layer(middleware::from_fn_with_state(
-            config.clone(),
+            state.clone(),
             auth_middleware,
         ));
 
@@ -107,7 +229,7 @@ pub fn create_router(config: Arc<Config>) -> Router {
         .merge(protected_routes)
         .layer(TraceLayer::new_for_http())
         .layer(cors)
-        .with_state(config);
+        .with_state(state);
 
     info!("Router configured successfully");
     app
@@ -118,12 +240,13 @@ mod tests {
     use super::*;
     use axum::body::Body;
     use axum::http::{Request, StatusCode};
+    use std::sync::Arc;
     use tower::ServiceExt;
 
     #[tokio::test]
     async fn test_health_check() {
         let config = Arc::new(crate::config::Config::default_test_config());
-        let app = create_router(config);
+        let app = create_router(AppState::new(config).unwrap());
 
         let response = app
             .oneshot(
@@ -12,7 +12,7 @@ use tracing::{debug, info};
 
 use crate::config::Config;
 use crate::error::AppError;
-use crate::models::{Claims, LoginRequest, LoginResponse};
+use crate::models::{Claims, LoginRequest, LoginResponse, UserRole};
 
 // Hardcoded user credentials as specified in the requirements
 const USERNAME: &str = "neo";
@@ -28,10 +28,17 @@ pub fn generate_token(
     let exp = expires_at.timestamp();
     let iat = now.timestamp();
 
+    let role = if config.auth.admin_users.iter().any(|u| u == username) {
+        UserRole::Admin
+    } else {
+        UserRole::User
+    };
+
     let claims = Claims {
         sub: username.to_string(),
         exp,
         iat,
+        role,
     };
 
     let token = encode(
@@ -120,7 +127,7 @@ pub async fn auth_middleware<B>(
     State(config): State<Arc<Config>>,
     auth_header: Option<TypedHeader<Authorization<Bearer>>>,
     cookies_header: Option<TypedHeader<Cookie>>,
-    req: Request<B>,
+    mut req: Request<B>,
     next: Next<B>,
 ) -> Result<Response, AppError> {
     // Debug headers
@@ -200,6 +207,9 @@ pub async fn auth_middleware<B>(
 
     debug!("Authenticated user: {}", claims.sub);
 
+    // Make the claims available to handlers
+    req.extensions_mut().insert(claims);
+
     // Continue with the request
     Ok(next.run(req).await)
 }
@@ -254,6 +264,17 @@ mod tests {
         let claims = validate_token(&token, &config).unwrap();
 
         assert_eq!(claims.sub, username);
+        assert_eq!(claims.role, UserRole::User);
+    }
+
+    #[test]
+    fn test_admin_role_from_config() {
+        let config = Config::default_test_config();
+
+        let (token, _) = generate_token("admin", &config).unwrap();
+        let claims = validate_token(&token, &config).unwrap();
+
+        assert!(claims.is_admin());
     }
 
     #[test]
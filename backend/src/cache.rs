@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::config::CacheConfig;
+use crate::error::AppError;
+use crate::models::{StreamEvent, TokenUsage};
+
+// Everything a generation sent, kept to answer the same request again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub output: String,
+    pub usage: TokenUsage,
+    // Every event before `usage`
+    pub events: Vec<StreamEvent>,
+    pub created_at: DateTime<Utc>,
+}
+
+struct Slot {
+    response: Arc<CachedResponse>,
+    // Tick of the last lookup, the lowest is evicted first
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Slots {
+    slots: HashMap<String, Slot>,
+    tick: u64,
+}
+
+// Responses by cache key, the most recently used in memory and, when a
+// directory is configured, all of them on disk
+pub struct ResponseCache {
+    ttl: Duration,
+    capacity: usize,
+    dir: Option<PathBuf>,
+    slots: Mutex<Slots>,
+    // When expired files were last removed from the directory
+    pruned_at: Mutex<DateTime<Utc>>,
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> Result<Self, AppError> {
+        let dir = config.dir.as_ref().map(PathBuf::from);
+        if let Some(dir) = &dir {
+            fs::create_dir_all(dir).map_err(|e| {
+                AppError::Internal(format!("Failed to create {}: {}", dir.display(), e))
+            })?;
+        }
+
+        let cache = ResponseCache {
+            ttl: Duration::seconds(config.ttl_secs as i64),
+            capacity: config.capacity.max(1),
+            dir,
+            slots: Mutex::new(Slots::default()),
+            pruned_at: Mutex::new(Utc::now()),
+        };
+        cache.prune();
+        Ok(cache)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.ttl > Duration::zero()
+    }
+
+    fn is_fresh(&self, response: &CachedResponse) -> bool {
+        Utc::now() - response.created_at < self.ttl
+    }
+
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.json", key)))
+    }
+
+    // The response stored under `key`, unless it has expired
+    pub fn get(&self, key: &str) -> Option<Arc<CachedResponse>> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        {
+            let mut slots = self.slots.lock().ok()?;
+            slots.tick += 1;
+            let tick = slots.tick;
+            match slots.slots.get_mut(key) {
+                Some(slot) if self.is_fresh(&slot.response) => {
+                    slot.last_used = tick;
+                    return Some(slot.response.clone());
+                }
+                Some(_) => {
+                    slots.slots.remove(key);
+                }
+                None => {}
+            }
+        }
+
+        // Fall back to the disk, which outlives restarts and evictions
+        let path = self.path(key)?;
+        if !path.exists() {
+            return None;
+        }
+        match load_response(&path) {
+            Ok(response) if self.is_fresh(&response) => {
+                let response = Arc::new(response);
+                self.keep(key, response.clone());
+                Some(response)
+            }
+            Ok(_) => {
+                let _ = fs::remove_file(&path);
+                None
+            }
+            Err(e) => {
+                warn!("Skipping cache file {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, key: &str, response: CachedResponse) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        if let Some(path) = self.path(key) {
+            // Through a temporary file so a crash never leaves half of it
+            let temporary = path.with_extension("json.tmp");
+            let written = serde_json::to_string(&response)
+                .map_err(|e| e.to_string())
+                .and_then(|contents| {
+                    fs::write(&temporary, contents)
+                        .and_then(|_| fs::rename(&temporary, &path))
+                        .map_err(|e| e.to_string())
+                });
+            if let Err(e) = written {
+                error!("Failed to save cached response {}: {}", key, e);
+            }
+        }
+        self.keep(key, Arc::new(response));
+
+        // Responses nobody asks for again are only found by going through the
+        // directory, done at most once per expiry period
+        let due = self.pruned_at.lock().is_ok_and(|mut pruned_at| {
+            let due = Utc::now() - *pruned_at >= self.ttl;
+            if due {
+                *pruned_at = Utc::now();
+            }
+            due
+        });
+        if due {
+            self.prune();
+        }
+    }
+
+    // Remove the files of expired responses, and any temporary file left by a
+    // crash. A file is as old as the response it holds, which is written once.
+    fn prune(&self) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to read {}: {}", dir.display(), e);
+                return;
+            }
+        };
+        let ttl = self.ttl.to_std().unwrap_or_default();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let expired = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .map(|modified| modified.elapsed().unwrap_or_default() >= ttl)
+                .unwrap_or(false);
+            if expired || path.extension().is_some_and(|extension| extension == "tmp") {
+                if let Err(e) = fs::remove_file(&path) {
+                    warn!("Failed to remove {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+
+    // Hold a response in memory, evicting the least recently used past capacity
+    fn keep(&self, key: &str, response: Arc<CachedResponse>) {
+        let Ok(mut slots) = self.slots.lock() else {
+            return;
+        };
+        slots.tick += 1;
+        let last_used = slots.tick;
+        slots.slots.insert(
+            key.to_string(),
+            Slot {
+                response,
+                last_used,
+            },
+        );
+
+        while slots.slots.len() > self.capacity {
+            let oldest = slots
+                .slots
+                .iter()
+                .min_by_key(|(_, slot)| slot.last_used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => slots.slots.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+}
+
+fn load_response(path: &Path) -> Result<CachedResponse, String> {
+    let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    serde_json::from_str(&contents).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::create_router;
+    use crate::test_utils::*;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    fn response(output: &str) -> CachedResponse {
+        CachedResponse {
+            output: output.to_string(),
+            usage: TokenUsage::new(3, 2),
+            events: vec![StreamEvent::Delta(output.to_string())],
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used_and_reloads_from_disk() {
+        let dir = std::env::temp_dir().join(format!("cache-{:x}", rand::random::<u64>()));
+        let config = CacheConfig {
+            ttl_secs: 60,
+            capacity: 2,
+            dir: Some(dir.to_string_lossy().to_string()),
+        };
+        let cache = ResponseCache::new(&config).unwrap();
+        cache.insert("a", response("A"));
+        cache.insert("b", response("B"));
+        assert!(cache.get("a").is_some());
+        cache.insert("c", response("C"));
+
+        let in_memory = |cache: &ResponseCache| {
+            let slots = cache.slots.lock().unwrap();
+            let mut keys: Vec<String> = slots.slots.keys().cloned().collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(in_memory(&cache), vec!["a", "c"]);
+
+        // A new cache over the same directory still has every response
+        let reopened = ResponseCache::new(&config).unwrap();
+        assert_eq!(reopened.get("b").unwrap().output, "B");
+        assert!(reopened.get("d").is_none());
+
+        let disabled = ResponseCache::new(&CacheConfig {
+            ttl_secs: 0,
+            ..config
+        })
+        .unwrap();
+        assert!(disabled.get("b").is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_expired_files_are_pruned_at_startup() {
+        let dir = std::env::temp_dir().join(format!("cache-{:x}", rand::random::<u64>()));
+        let config = CacheConfig {
+            ttl_secs: 60,
+            capacity: 2,
+            dir: Some(dir.to_string_lossy().to_string()),
+        };
+        let cache = ResponseCache::new(&config).unwrap();
+        cache.insert("old", response("Old"));
+        cache.insert("new", response("New"));
+        fs::write(dir.join("half.json.tmp"), "{").unwrap();
+
+        // Written two minutes ago, past the expiry
+        let written = std::time::SystemTime::now() - std::time::Duration::from_secs(120);
+        fs::File::options()
+            .write(true)
+            .open(dir.join("old.json"))
+            .unwrap()
+            .set_modified(written)
+            .unwrap();
+
+        ResponseCache::new(&config).unwrap();
+        let mut files: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["new.json"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_repeated_request_is_served_from_cache() {
+        let mock = spawn_mock_openai(|_| vec!["Cached output.".to_string()]).await;
+        let mut config = test_config(&mock.base_url);
+        config.cache.ttl_secs = 60;
+        let state = test_state(config.clone());
+
+        let mut bodies = Vec::new();
+        for (user, text) in [("alice", "Hello%20%20world"), ("bob", "Hello%20world%20")] {
+            let request = authed_request(&config, user)
+                .uri(format!("/api/text/paraphrase?text={}", text))
+                .body(Body::empty())
+                .unwrap();
+            let response = create_router(state.clone()).oneshot(request).await.unwrap();
+            bodies.push((
+                response.headers()["x-cache"].to_str().unwrap().to_string(),
+                sse_content(&sse_events(response).await),
+            ));
+        }
+
+        assert_eq!(
+            bodies[0],
+            ("MISS".to_string(), "Cached output.".to_string())
+        );
+        assert_eq!(bodies[1], ("HIT".to_string(), "Cached output.".to_string()));
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cached_response_is_compared_with_each_request() {
+        let mock = spawn_mock_openai(|_| vec!["Cached output.".to_string()]).await;
+        let mut config = test_config(&mock.base_url);
+        config.cache.ttl_secs = 60;
+        let state = test_state(config.clone());
+
+        let mut diffs = Vec::new();
+        for (user, query) in [
+            ("alice", "text=Hello%20%20world&diff=word"),
+            ("bob", "text=Hello%20world%20&diff=sentence"),
+        ] {
+            let request = authed_request(&config, user)
+                .uri(format!("/api/text/paraphrase?{}", query))
+                .body(Body::empty())
+                .unwrap();
+            let response = create_router(state.clone()).oneshot(request).await.unwrap();
+            let events = sse_events(response).await;
+            diffs.push(sse_json(&events, "diff").unwrap());
+        }
+        assert_eq!(mock.requests().len(), 1);
+
+        assert_eq!(diffs[0]["granularity"], "word");
+        assert_eq!(diffs[1]["granularity"], "sentence");
+        // Bob's own input is what the output replaced
+        assert_eq!(diffs[1]["changes"][0]["op"], "delete");
+        assert_eq!(diffs[1]["changes"][0]["text"], "Hello world ");
+
+        // The replay cost nothing upstream
+        let page = state.history.list("bob", &Default::default()).unwrap();
+        assert_eq!(page.entries[0].usage, Some(TokenUsage::new(0, 0)));
+    }
+
+    #[tokio::test]
+    async fn test_translations_are_not_replayed_to_other_users() {
+        let mock = spawn_mock_openai(|_| vec!["Hola.".to_string()]).await;
+        let mut config = test_config(&mock.base_url);
+        config.cache.ttl_secs = 60;
+        let state = test_state(config.clone());
+
+        for user in ["alice", "bob"] {
+            let request = authed_request(&config, user)
+                .method("POST")
+                .uri("/api/text/translate")
+                .header("content-type", "application/json")
+                .body(json_body(serde_json::json!({
+                    "text": "Hello there.",
+                    "source_language": "en",
+                    "target_language": "es",
+                })))
+                .unwrap();
+            let response = create_router(state.clone()).oneshot(request).await.unwrap();
+            assert_eq!(response.headers()["x-cache"], "MISS");
+            let events = sse_events(response).await;
+            assert_eq!(sse_content(&events), "Hola.");
+        }
+
+        // Each user's translation memory learned the sentence
+        assert_eq!(mock.requests().len(), 2);
+        for user in ["alice", "bob"] {
+            let stats = state.translation_memory.stats(user).unwrap();
+            assert_eq!(stats[0].segments, 1);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::pin::pin;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use futures_util::StreamExt;
+use tokio::sync::mpsc;
+use tokio::task::{AbortHandle, JoinHandle};
+
+use crate::diff::DiffRequest;
+use crate::error::AppError;
+use crate::history::PendingEntry;
+use crate::models::{StreamEvent, TokenUsage};
+use crate::state::AppState;
+use crate::streams::StreamBuffer;
+
+// How a shared generation ended
+pub type Outcome = Result<(String, TokenUsage), Arc<AppError>>;
+
+// One upstream generation, streamed to every identical request that arrives
+// while it runs
+pub struct SharedGeneration {
+    key: String,
+    events: Arc<StreamBuffer>,
+    // Set before the final events are sent
+    outcome: OnceLock<Outcome>,
+    subscribers: Mutex<usize>,
+    task: Mutex<Option<AbortHandle>>,
+}
+
+impl SharedGeneration {
+    // The task producing the events, cancelled once nobody follows it anymore
+    pub fn set_task(&self, task: AbortHandle) {
+        if let Ok(mut slot) = self.task.lock() {
+            *slot = Some(task);
+        }
+    }
+
+    pub fn set_outcome(&self, outcome: Outcome) {
+        let _ = self.outcome.set(outcome);
+    }
+
+    // Send every event to a new receiver, from the first one on, adding the
+    // request's own diff before `usage` and finishing its history entry just
+    // before `done`
+    pub fn subscribe(
+        self: Arc<Self>,
+        state: AppState,
+        entry: PendingEntry,
+        diff: Option<DiffRequest>,
+    ) -> (mpsc::Receiver<StreamEvent>, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(100);
+        // Made here so that it is dropped even if the task never runs
+        let subscription = Subscription(self);
+        let task = tokio::spawn(async move {
+            let mut entry = Some(entry);
+            let mut diff = diff;
+            let mut events = pin!(subscription.0.events.clone().events_from(0));
+            while let Some((_, event)) = events.next().await {
+                if let StreamEvent::Usage(_) = event {
+                    if let (Some(diff), Some(Ok((output, _)))) =
+                        (diff.take(), subscription.0.outcome.get())
+                    {
+                        let _ = tx.send(StreamEvent::Diff(diff.report(output))).await;
+                    }
+                }
+                if event == StreamEvent::Done {
+                    if let (Some(entry), Some(outcome)) =
+                        (entry.take(), subscription.0.outcome.get())
+                    {
+                        let result = match outcome {
+                            Ok((output, usage)) => Ok((output.as_str(), *usage)),
+                            Err(e) => Err(e.as_ref()),
+                        };
+                        entry.finish(&state, result);
+                    }
+                }
+                let _ = tx.send(event).await;
+            }
+        });
+        (rx, task)
+    }
+}
+
+// Counts a follower of a shared generation for as long as it is kept
+struct Subscription(Arc<SharedGeneration>);
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let Ok(mut subscribers) = self.0.subscribers.lock() else {
+            return;
+        };
+        *subscribers -= 1;
+        if *subscribers == 0 && self.0.outcome.get().is_none() {
+            if let Some(task) = self.0.task.lock().ok().and_then(|task| task.clone()) {
+                task.abort();
+            }
+        }
+    }
+}
+
+// Generations running upstream, by the cache key of their requests
+#[derive(Default)]
+pub struct InFlight {
+    generations: Mutex<HashMap<String, Arc<SharedGeneration>>>,
+}
+
+impl InFlight {
+    // Join the generation running for `key`, or register a new one. The sender
+    // is returned to the caller that should run it.
+    pub fn join_or_start(
+        &self,
+        key: &str,
+    ) -> (Arc<SharedGeneration>, Option<mpsc::Sender<StreamEvent>>) {
+        let mut generations = self.generations.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(shared) = generations.get(key) {
+            let mut subscribers = shared.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+            // Without followers it is being cancelled
+            if *subscribers > 0 {
+                *subscribers += 1;
+                return (shared.clone(), None);
+            }
+        }
+
+        let (tx, rx) = mpsc::channel(100);
+        let shared = Arc::new(SharedGeneration {
+            key: key.to_string(),
+            events: StreamBuffer::follow("", rx),
+            outcome: OnceLock::new(),
+            subscribers: Mutex::new(1),
+            task: Mutex::new(None),
+        });
+        generations.insert(key.to_string(), shared.clone());
+        (shared, Some(tx))
+    }
+
+    // Stop attaching requests to a generation that is ending
+    pub fn remove(&self, shared: &Arc<SharedGeneration>) {
+        if let Ok(mut generations) = self.generations.lock() {
+            if generations
+                .get(&shared.key)
+                .is_some_and(|current| Arc::ptr_eq(current, shared))
+            {
+                generations.remove(&shared.key);
+            }
+        }
+    }
+}
+
+// Removes a generation from those in flight when its task ends or is aborted
+pub struct Registration {
+    pub state: AppState,
+    pub shared: Arc<SharedGeneration>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.state.inflight.remove(&self.shared);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::create_router;
+    use crate::test_utils::*;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_identical_requests_share_one_generation() {
+        let mock = spawn_mock_openai(|_| vec!["Shared output.".to_string()]).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+
+        let send = |user: &str| {
+            let request = authed_request(&config, user)
+                .uri("/api/text/paraphrase?text=Hello")
+                .body(Body::empty())
+                .unwrap();
+            create_router(state.clone()).oneshot(request)
+        };
+        let (first, second) = tokio::join!(send("alice"), send("bob"));
+
+        for response in [first.unwrap(), second.unwrap()] {
+            let events = sse_events(response).await;
+            assert_eq!(sse_content(&events), "Shared output.");
+            assert!(events.iter().any(|(event, _)| event == "usage"));
+        }
+        assert_eq!(mock.requests().len(), 1);
+        for user in ["alice", "bob"] {
+            let page = state.history.list(user, &Default::default()).unwrap();
+            assert_eq!(page.total, 1);
+        }
+
+        // Once it has ended, the same request runs again
+        let response = send("alice").await.unwrap();
+        assert_eq!(sse_content(&sse_events(response).await), "Shared output.");
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_shared_generation_is_compared_with_each_request() {
+        let mock = spawn_mock_openai(|_| vec!["Shared output.".to_string()]).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+
+        let send = |user: &str, query: &str| {
+            let request = authed_request(&config, user)
+                .uri(format!("/api/text/paraphrase?{}", query))
+                .body(Body::empty())
+                .unwrap();
+            create_router(state.clone()).oneshot(request)
+        };
+        let (first, second) = tokio::join!(
+            send("alice", "text=Hello%20%20there&diff=word"),
+            send("bob", "text=Hello%20there%20&diff=sentence"),
+        );
+
+        let first = sse_json(&sse_events(first.unwrap()).await, "diff").unwrap();
+        let second = sse_json(&sse_events(second.unwrap()).await, "diff").unwrap();
+        assert_eq!(mock.requests().len(), 1);
+        assert_eq!(first["granularity"], "word");
+        assert_eq!(second["granularity"], "sentence");
+        assert_eq!(second["changes"][0]["text"], "Hello there ");
+    }
+
+    #[tokio::test]
+    async fn test_translations_of_different_users_are_not_shared() {
+        let mock = spawn_mock_openai(|_| vec!["Hola.".to_string()]).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+
+        let send = |user: &str| {
+            let request = authed_request(&config, user)
+                .method("POST")
+                .uri("/api/text/translate")
+                .header("content-type", "application/json")
+                .body(json_body(serde_json::json!({
+                    "text": "Hello there.",
+                    "source_language": "en",
+                    "target_language": "es",
+                })))
+                .unwrap();
+            create_router(state.clone()).oneshot(request)
+        };
+        let (first, second) = tokio::join!(send("alice"), send("bob"));
+
+        for response in [first.unwrap(), second.unwrap()] {
+            let events = sse_events(response).await;
+            assert_eq!(sse_content(&events), "Hola.");
+            assert!(sse_json(&events, "segments").is_some());
+        }
+        // Each runs its own, so each user's translation memory learns the sentence
+        assert_eq!(mock.requests().len(), 2);
+        for user in ["alice", "bob"] {
+            assert_eq!(state.translation_memory.stats(user).unwrap()[0].segments, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generation_is_cancelled_with_its_last_follower() {
+        let config = test_config("http://127.0.0.1:9");
+        let state = test_state(config);
+        let (shared, leader) = state.inflight.join_or_start("key");
+        assert!(leader.is_some());
+        let task = tokio::spawn(std::future::pending::<()>());
+        shared.set_task(task.abort_handle());
+
+        let (joined, leader) = state.inflight.join_or_start("key");
+        assert!(leader.is_none());
+        assert!(Arc::ptr_eq(&shared, &joined));
+
+        drop(Subscription(shared));
+        assert!(!task.is_finished());
+        drop(Subscription(joined));
+        assert!(task.await.unwrap_err().is_cancelled());
+    }
+}
@@ -0,0 +1,614 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::response::Response;
+use axum::{Extension, Json};
+use futures_util::{SinkExt, StreamExt};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, error};
+use yrs::encoding::read::Cursor;
+use yrs::sync::{Awareness, DefaultProtocol, Message, MessageReader, Protocol, SyncMessage};
+use yrs::updates::decoder::{Decode, DecoderV1};
+use yrs::updates::encoder::{Encode, Encoder, EncoderV1};
+use yrs::{ClientID, Doc, GetString, ReadTxn, StateVector, Text, Transact, Update};
+
+use crate::document_store::{DocumentStore, DocumentUpdate};
+use crate::error::AppError;
+use crate::models::Claims;
+use crate::state::AppState;
+
+// Name of the shared text holding the document's content
+const TEXT_NAME: &str = "content";
+// Edits are saved this long after the first unsaved one
+const SAVE_DELAY: Duration = Duration::from_secs(2);
+// Sender of the changes made by the room itself, relayed to every connection
+const ROOM_CONNECTION: u64 = u64::MAX;
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>, AppError> {
+    mutex
+        .lock()
+        .map_err(|_| AppError::Internal("Collaboration room poisoned".to_string()))
+}
+
+// A client connected to a room
+struct Peer {
+    user: String,
+    // Awareness clients announced over this connection, removed when it closes
+    clients: HashSet<ClientID>,
+}
+
+// A document being edited live, shared by everyone connected to it
+pub struct Room {
+    document_id: String,
+    owner: String,
+    awareness: Mutex<Awareness>,
+    // Stored version the live state was loaded from or last saved as
+    version: Mutex<i64>,
+    // Messages to relay, with the connection they came from
+    relay: broadcast::Sender<(u64, Arc<Vec<u8>>)>,
+    peers: Mutex<HashMap<u64, Peer>>,
+    // Author of the edits not saved yet
+    unsaved: Mutex<Option<String>>,
+    save_scheduled: AtomicBool,
+    // Held while saving, so saves do not race each other
+    saving: Mutex<()>,
+}
+
+impl Room {
+    // Load the document, resuming the saved live state when nothing changed
+    // it since. Otherwise live editing starts over from the latest version.
+    fn load(documents: &DocumentStore, owner: &str, id: &str) -> Result<Self, AppError> {
+        let document = documents.get(owner, id)?;
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text(TEXT_NAME);
+
+        let saved = documents
+            .collab_state(id)?
+            .filter(|(version, _)| *version == document.summary.version)
+            .and_then(|(_, state)| Update::decode_v1(&state).ok());
+        match saved {
+            Some(update) => doc
+                .transact_mut()
+                .apply_update(update)
+                .map_err(|e| AppError::Internal(format!("Invalid live state: {}", e)))?,
+            None => {
+                let state = {
+                    let mut txn = doc.transact_mut();
+                    text.insert(&mut txn, 0, &document.content);
+                    txn.encode_state_as_update_v1(&StateVector::default())
+                };
+                // Clients that reconnect later must find the same state
+                documents.store_collab_state(id, document.summary.version, &state)?;
+            }
+        }
+
+        let (relay, _) = broadcast::channel(256);
+        Ok(Room {
+            document_id: id.to_string(),
+            owner: owner.to_string(),
+            awareness: Mutex::new(Awareness::new(doc)),
+            version: Mutex::new(document.summary.version),
+            relay,
+            peers: Mutex::new(HashMap::new()),
+            unsaved: Mutex::new(None),
+            save_scheduled: AtomicBool::new(false),
+            saving: Mutex::new(()),
+        })
+    }
+
+    // Messages opening a connection: the room's state vector, so the client
+    // sends what the room is missing, and everyone's presence
+    fn start(&self) -> Result<Vec<u8>, AppError> {
+        let awareness = lock(&self.awareness)?;
+        let mut encoder = EncoderV1::new();
+        DefaultProtocol
+            .start(&awareness, &mut encoder)
+            .map_err(|e| AppError::Internal(format!("Failed to start sync: {}", e)))?;
+        Ok(encoder.to_vec())
+    }
+
+    // Apply the y-sync messages a connection sent, relaying document and
+    // awareness updates to the others and noting who made unsaved edits.
+    // Returns the replies for the sender and whether the document was edited.
+    fn handle(&self, connection: u64, data: &[u8]) -> Result<(Option<Vec<u8>>, bool), AppError> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            AppError::BadRequest(format!("Invalid sync message: {}", e))
+        };
+
+        let mut decoder = DecoderV1::new(Cursor::new(data));
+        let mut replies = EncoderV1::new();
+        let mut replied = false;
+        let mut edited = false;
+        let mut awareness = lock(&self.awareness)?;
+        for message in MessageReader::new(&mut decoder) {
+            let message = message.map_err(|e| invalid(&e))?;
+            let relayed = match &message {
+                Message::Sync(SyncMessage::SyncStep2(update))
+                | Message::Sync(SyncMessage::Update(update)) => {
+                    edited = true;
+                    Some(Message::Sync(SyncMessage::Update(update.clone())).encode_v1())
+                }
+                Message::Awareness(update) => {
+                    let mut peers = lock(&self.peers)?;
+                    if let Some(peer) = peers.get_mut(&connection) {
+                        for (client, entry) in &update.clients {
+                            if &*entry.json == "null" {
+                                peer.clients.remove(client);
+                            } else {
+                                peer.clients.insert(*client);
+                            }
+                        }
+                    }
+                    Some(message.encode_v1())
+                }
+                _ => None,
+            };
+
+            if let Some(reply) = DefaultProtocol
+                .handle_message(&mut awareness, message)
+                .map_err(|e| invalid(&e))?
+            {
+                reply.encode(&mut replies);
+                replied = true;
+            }
+            if let Some(relayed) = relayed {
+                let _ = self.relay.send((connection, Arc::new(relayed)));
+            }
+        }
+
+        if edited {
+            if let Some(peer) = lock(&self.peers)?.get(&connection) {
+                *lock(&self.unsaved)? = Some(peer.user.clone());
+            }
+        }
+        Ok((replied.then(|| replies.to_vec()), edited))
+    }
+
+    // Store the document as a new version if it was edited since the last save
+    fn save(&self, documents: &DocumentStore) -> Result<(), AppError> {
+        let _saving = lock(&self.saving)?;
+        let Some(author) = lock(&self.unsaved)?.take() else {
+            return Ok(());
+        };
+
+        let (content, state) = {
+            let awareness = lock(&self.awareness)?;
+            let text = awareness.doc().get_or_insert_text(TEXT_NAME);
+            let txn = awareness.doc().transact();
+            (
+                text.get_string(&txn),
+                txn.encode_state_as_update_v1(&StateVector::default()),
+            )
+        };
+        let update = DocumentUpdate {
+            content: Some(content),
+            base_version: Some(*lock(&self.version)?),
+            ..DocumentUpdate::default()
+        };
+        let document = match documents.update(&self.owner, &self.document_id, &author, update) {
+            // Saved or restored outside the room meanwhile: that change wins
+            // and the room starts over from it
+            Err(AppError::Conflict(_)) => return self.reload(documents),
+            result => result?,
+        };
+        *lock(&self.version)? = document.summary.version;
+        documents.store_collab_state(&self.document_id, document.summary.version, &state)
+    }
+
+    // Replace the live content with the stored document when it has a newer
+    // version, dropping unsaved edits, and send the change to every connection
+    fn reload(&self, documents: &DocumentStore) -> Result<(), AppError> {
+        let document = documents.get(&self.owner, &self.document_id)?;
+        let mut version = lock(&self.version)?;
+        if *version == document.summary.version {
+            return Ok(());
+        }
+
+        let (update, state) = {
+            let awareness = lock(&self.awareness)?;
+            let text = awareness.doc().get_or_insert_text(TEXT_NAME);
+            let mut txn = awareness.doc().transact_mut();
+            let before = txn.state_vector();
+            let length = text.len(&txn);
+            text.remove_range(&mut txn, 0, length);
+            text.insert(&mut txn, 0, &document.content);
+            (
+                txn.encode_state_as_update_v1(&before),
+                txn.encode_state_as_update_v1(&StateVector::default()),
+            )
+        };
+        *version = document.summary.version;
+        *lock(&self.unsaved)? = None;
+
+        let message = Message::Sync(SyncMessage::Update(update)).encode_v1();
+        let _ = self.relay.send((ROOM_CONNECTION, Arc::new(message)));
+        documents.store_collab_state(&self.document_id, document.summary.version, &state)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Presence {
+    pub username: String,
+    pub connections: usize,
+}
+
+// The rooms of documents with at least one connection
+#[derive(Default)]
+pub struct CollabRooms {
+    rooms: Mutex<HashMap<String, Arc<Room>>>,
+    next_connection: AtomicU64,
+}
+
+impl CollabRooms {
+    fn join(
+        &self,
+        documents: &DocumentStore,
+        owner: &str,
+        id: &str,
+        user: &str,
+    ) -> Result<(Arc<Room>, u64), AppError> {
+        let mut rooms = lock(&self.rooms)?;
+        let room = match rooms.get(id) {
+            Some(room) => room.clone(),
+            None => {
+                let room = Arc::new(Room::load(documents, owner, id)?);
+                rooms.insert(id.to_string(), room.clone());
+                room
+            }
+        };
+
+        let connection = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        lock(&room.peers)?.insert(
+            connection,
+            Peer {
+                user: user.to_string(),
+                clients: HashSet::new(),
+            },
+        );
+        Ok((room, connection))
+    }
+
+    // Forget a connection and its presence. The last one to leave closes the
+    // room, saving any pending edit.
+    fn leave(
+        &self,
+        documents: &DocumentStore,
+        room: &Arc<Room>,
+        connection: u64,
+    ) -> Result<(), AppError> {
+        let peer = lock(&room.peers)?.remove(&connection);
+        if let Some(peer) = peer.filter(|peer| !peer.clients.is_empty()) {
+            let mut awareness = lock(&room.awareness)?;
+            for client in &peer.clients {
+                awareness.remove_state(*client);
+            }
+            if let Ok(update) = awareness.update_with_clients(peer.clients) {
+                let message = Message::Awareness(update).encode_v1();
+                let _ = room.relay.send((connection, Arc::new(message)));
+            }
+        }
+
+        let closed = {
+            let mut rooms = lock(&self.rooms)?;
+            let empty = lock(&room.peers)?.is_empty();
+            if empty {
+                rooms.remove(&room.document_id);
+            }
+            empty
+        };
+        if closed {
+            room.save(documents)?;
+        }
+        Ok(())
+    }
+
+    // Bring the open room of a document up to date with a change saved outside it
+    pub fn refresh(&self, documents: &DocumentStore, id: &str) -> Result<(), AppError> {
+        let Some(room) = lock(&self.rooms)?.get(id).cloned() else {
+            return Ok(());
+        };
+        let _saving = lock(&room.saving)?;
+        room.reload(documents)
+    }
+
+    // Who is connected to a document, when anyone is
+    pub fn presence(&self, id: &str) -> Result<Vec<Presence>, AppError> {
+        let Some(room) = lock(&self.rooms)?.get(id).cloned() else {
+            return Ok(Vec::new());
+        };
+        let mut users: BTreeMap<String, usize> = BTreeMap::new();
+        for peer in lock(&room.peers)?.values() {
+            *users.entry(peer.user.clone()).or_default() += 1;
+        }
+        Ok(users
+            .into_iter()
+            .map(|(username, connections)| Presence {
+                username,
+                connections,
+            })
+            .collect())
+    }
+}
+
+// Save a room's edits shortly after they are made, once per delay
+fn schedule_save(state: &AppState, room: &Arc<Room>) {
+    if room.save_scheduled.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    let state = state.clone();
+    let room = room.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(SAVE_DELAY).await;
+        room.save_scheduled.store(false, Ordering::Release);
+        if let Err(e) = room.save(&state.documents) {
+            error!("Failed to save document {}: {}", room.document_id, e);
+        }
+    });
+}
+
+// Edit a stored document live with its owner and collaborators, speaking the
+// y-sync protocol over a WebSocket
+pub async fn collaborate(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, AppError> {
+    let owner = state.documents.shared_owner(&claims.sub, &id)?;
+    Ok(upgrade.on_upgrade(move |socket| async move {
+        if let Err(e) = serve(&state, socket, &owner, &id, &claims.sub).await {
+            error!("Collaboration on document {} failed: {}", id, e);
+        }
+    }))
+}
+
+async fn serve(
+    state: &AppState,
+    socket: WebSocket,
+    owner: &str,
+    id: &str,
+    user: &str,
+) -> Result<(), AppError> {
+    let (room, connection) = state.collab.join(&state.documents, owner, id, user)?;
+    debug!("{} joined document {}", user, id);
+    let result = sync(state, socket, &room, connection).await;
+    debug!("{} left document {}", user, id);
+    state.collab.leave(&state.documents, &room, connection)?;
+    result
+}
+
+// Exchange messages with a client until either side closes
+async fn sync(
+    state: &AppState,
+    socket: WebSocket,
+    room: &Arc<Room>,
+    connection: u64,
+) -> Result<(), AppError> {
+    // Subscribe first so nothing sent after the initial sync is missed
+    let mut relayed = room.relay.subscribe();
+    let (mut sink, mut stream) = socket.split();
+
+    let mut open = sink.send(WsMessage::Binary(room.start()?)).await.is_ok();
+    while open {
+        tokio::select! {
+            incoming = stream.next() => match incoming {
+                Some(Ok(WsMessage::Binary(data))) => {
+                    let (reply, edited) = room.handle(connection, &data)?;
+                    if edited {
+                        schedule_save(state, room);
+                    }
+                    if let Some(reply) = reply {
+                        open = sink.send(WsMessage::Binary(reply)).await.is_ok();
+                    }
+                }
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => open = false,
+                Some(Ok(_)) => {}
+            },
+            message = relayed.recv() => match message {
+                Ok((from, message)) if from != connection => {
+                    open = sink.send(WsMessage::Binary(message.to_vec())).await.is_ok();
+                }
+                Ok(_) => {}
+                // The client missed updates; it syncs again when it reconnects
+                Err(RecvError::Lagged(_)) | Err(RecvError::Closed) => open = false,
+            },
+        }
+    }
+    Ok(())
+}
+
+pub async fn document_presence(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<Presence>>, AppError> {
+    state.documents.shared_owner(&claims.sub, &id)?;
+    Ok(Json(state.collab.presence(&id)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::db::Database;
+    use crate::test_utils::{connect_websocket, spawn_app, test_state};
+    use tokio_tungstenite::tungstenite;
+
+    // A client's whole document, as a y-sync update message
+    fn update_message(doc: &Doc) -> Vec<u8> {
+        let update = doc
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+        Message::Sync(SyncMessage::Update(update)).encode_v1()
+    }
+
+    // A client document holding what the room sends to a new connection
+    fn synced_client(room: &Room, connection: u64) -> Doc {
+        let request = Message::Sync(SyncMessage::SyncStep1(StateVector::default())).encode_v1();
+        let (reply, edited) = room.handle(connection, &request).unwrap();
+        assert!(!edited);
+
+        let doc = Doc::new();
+        let reply = reply.unwrap();
+        let mut decoder = DecoderV1::new(Cursor::new(&reply));
+        for message in MessageReader::new(&mut decoder) {
+            if let Message::Sync(SyncMessage::SyncStep2(update)) = message.unwrap() {
+                let update = Update::decode_v1(&update).unwrap();
+                doc.transact_mut().apply_update(update).unwrap();
+            }
+        }
+        doc
+    }
+
+    fn text(doc: &Doc) -> String {
+        let text = doc.get_or_insert_text(TEXT_NAME);
+        let txn = doc.transact();
+        text.get_string(&txn)
+    }
+
+    #[test]
+    fn test_edits_are_relayed_and_saved() {
+        let config = Config::default_test_config();
+        let documents = DocumentStore::new(Arc::new(Database::open(&config.database).unwrap()));
+        let id = documents
+            .create("alice", "Draft", "Hello")
+            .unwrap()
+            .summary
+            .id;
+        documents.add_collaborator("alice", &id, "bob").unwrap();
+        let rooms = CollabRooms::default();
+
+        let (room, alice) = rooms.join(&documents, "alice", &id, "alice").unwrap();
+        let (same_room, bob) = rooms.join(&documents, "alice", &id, "bob").unwrap();
+        assert!(Arc::ptr_eq(&room, &same_room));
+        let mut relayed = room.relay.subscribe();
+
+        let client = synced_client(&room, bob);
+        assert_eq!(text(&client), "Hello");
+        let edit = client.get_or_insert_text(TEXT_NAME);
+        edit.insert(&mut client.transact_mut(), 5, " world");
+        let (_, edited) = room.handle(bob, &update_message(&client)).unwrap();
+        assert!(edited);
+        let (from, _) = relayed.try_recv().unwrap();
+        assert_eq!(from, bob);
+
+        let presence = rooms.presence(&id).unwrap();
+        assert_eq!(presence.len(), 2);
+        rooms.leave(&documents, &room, alice).unwrap();
+        rooms.leave(&documents, &room, bob).unwrap();
+        assert!(rooms.presence(&id).unwrap().is_empty());
+
+        let document = documents.get("alice", &id).unwrap();
+        assert_eq!(document.content, "Hello world");
+        assert_eq!(document.summary.version, 2);
+        assert_eq!(documents.versions("alice", &id).unwrap()[0].author, "bob");
+
+        // A client reconnecting with its own copy does not duplicate the text
+        let (room, alice) = rooms.join(&documents, "alice", &id, "alice").unwrap();
+        room.handle(alice, &update_message(&client)).unwrap();
+        assert_eq!(text(&synced_client(&room, alice)), "Hello world");
+    }
+
+    #[test]
+    fn test_saves_made_outside_the_room_are_kept() {
+        let config = Config::default_test_config();
+        let documents = DocumentStore::new(Arc::new(Database::open(&config.database).unwrap()));
+        let id = documents
+            .create("alice", "Draft", "Hello")
+            .unwrap()
+            .summary
+            .id;
+        let rooms = CollabRooms::default();
+        let (room, alice) = rooms.join(&documents, "alice", &id, "alice").unwrap();
+        let mut relayed = room.relay.subscribe();
+
+        // An edit made live, then a save through the API before the room saves
+        let client = synced_client(&room, alice);
+        let edit = client.get_or_insert_text(TEXT_NAME);
+        edit.insert(&mut client.transact_mut(), 5, " world");
+        room.handle(alice, &update_message(&client)).unwrap();
+        relayed.try_recv().unwrap();
+        let update = DocumentUpdate {
+            content: Some("Saved elsewhere".to_string()),
+            ..DocumentUpdate::default()
+        };
+        documents.update("alice", &id, "alice", update).unwrap();
+
+        // The room's save does not overwrite it, and the room takes it up
+        room.save(&documents).unwrap();
+        let document = documents.get("alice", &id).unwrap();
+        assert_eq!(document.content, "Saved elsewhere");
+        assert_eq!(document.summary.version, 2);
+        assert_eq!(text(&synced_client(&room, alice)), "Saved elsewhere");
+        let (from, _) = relayed.try_recv().unwrap();
+        assert_eq!(from, ROOM_CONNECTION);
+
+        // Changes saved outside reach an open room straight away
+        let update = DocumentUpdate {
+            content: Some("Saved again".to_string()),
+            ..DocumentUpdate::default()
+        };
+        documents.update("alice", &id, "alice", update).unwrap();
+        rooms.refresh(&documents, &id).unwrap();
+        assert_eq!(text(&synced_client(&room, alice)), "Saved again");
+
+        // Later live edits are saved on top of them
+        let client = synced_client(&room, alice);
+        let edit = client.get_or_insert_text(TEXT_NAME);
+        edit.insert(&mut client.transact_mut(), 0, "> ");
+        room.handle(alice, &update_message(&client)).unwrap();
+        rooms.leave(&documents, &room, alice).unwrap();
+        let document = documents.get("alice", &id).unwrap();
+        assert_eq!(document.content, "> Saved again");
+        assert_eq!(document.summary.version, 4);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_syncs_collaborators() {
+        let config = Config::default_test_config();
+        let state = test_state(config.clone());
+        let id = state
+            .documents
+            .create("alice", "Draft", "Hello")
+            .unwrap()
+            .summary
+            .id;
+        state
+            .documents
+            .add_collaborator("alice", &id, "bob")
+            .unwrap();
+
+        let address = spawn_app(state.clone()).await;
+        let path = format!("/api/documents/{}/live", id);
+        let connect = |username: &'static str| connect_websocket(&config, address, &path, username);
+
+        assert!(connect("mallory").await.is_err());
+        let (mut alice, _) = connect("alice").await.unwrap();
+        let (mut bob, _) = connect("bob").await.unwrap();
+        // Both get the room's state vector and presence first
+        alice.next().await.unwrap().unwrap();
+        bob.next().await.unwrap().unwrap();
+
+        let client = Doc::new();
+        let edit = client.get_or_insert_text(TEXT_NAME);
+        edit.insert(&mut client.transact_mut(), 0, "Hi. ");
+        alice
+            .send(tungstenite::Message::Binary(update_message(&client)))
+            .await
+            .unwrap();
+
+        let relayed = bob.next().await.unwrap().unwrap().into_data();
+        let mut decoder = DecoderV1::new(Cursor::new(&relayed));
+        let message = MessageReader::new(&mut decoder).next().unwrap().unwrap();
+        assert!(matches!(message, Message::Sync(SyncMessage::Update(_))));
+
+        let presence = state.collab.presence(&id).unwrap();
+        assert_eq!(presence.len(), 2);
+    }
+}
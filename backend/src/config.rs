@@ -5,6 +5,13 @@ use std::path::Path;
 use dotenv::dotenv;
 use thiserror::Error;
 
+use crate::languages::LanguageTag;
+use crate::models::UserRole;
+
+const DEFAULT_SUPPORTED_LANGUAGES: &str = "en,es,ja,de,pt,fr,it,zh,ko";
+const DEFAULT_USER_OVERRIDES: &str = "temperature,max_tokens,n,length,tone,audience";
+const DEFAULT_ADMIN_OVERRIDES: &str = "temperature,top_p,max_tokens,n,length,tone,audience";
+
 #[derive(Debug, Error)]
 pub enum ConfigError {
     #[error("Failed to load .env file: {0}")]
@@ -28,6 +35,8 @@ pub struct OpenAIConfig {
     pub api_key: String,
     pub base_url: String,
     pub model: String,
+    pub context_limit: Option<usize>, // overrides the model's known context size
+    pub completion_reserve: usize,    // tokens kept free for the completion
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -36,6 +45,11 @@ pub struct JWTConfig {
     pub expiration: i64, // in seconds
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthConfig {
+    pub admin_users: Vec<String>, // usernames granted the admin role
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CookieConfig {
     pub secure: bool,
@@ -43,12 +57,148 @@ pub struct CookieConfig {
     pub same_site: String, // "Strict", "Lax", or "None"
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplatesConfig {
+    pub dir: String, // directory with prompt template overrides
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LanguagesConfig {
+    pub supported: Vec<LanguageTag>, // target languages accepted by translate
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenerationLimits {
+    pub max_temperature: f32,
+    pub max_tokens: u16,
+    pub overridable: Vec<String>, // generation parameters requests may set
+    pub models: Vec<String>,      // models operations may pin besides the default, `*` for any
+}
+
+impl GenerationLimits {
+    pub fn allows_model(&self, model: &str) -> bool {
+        self.models.iter().any(|m| m == "*" || m == model)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenerationConfig {
+    pub user: GenerationLimits,
+    pub admin: GenerationLimits,
+}
+
+impl GenerationConfig {
+    pub fn limits_for(&self, role: UserRole) -> &GenerationLimits {
+        match role {
+            UserRole::User => &self.user,
+            UserRole::Admin => &self.admin,
+        }
+    }
+}
+
+// Read the generation limits of a role from GENERATION_<ROLE>_* variables
+fn generation_limits(
+    role: &str,
+    max_temperature: f32,
+    max_tokens: u16,
+    overridable: &str,
+    models: &str,
+) -> Result<GenerationLimits, ConfigError> {
+    let var = format!("GENERATION_{}_MAX_TEMPERATURE", role);
+    let max_temperature = match env::var(&var) {
+        Ok(value) => value
+            .parse::<f32>()
+            .map_err(|e| ConfigError::EnvVarInvalid(var, e.to_string()))?,
+        Err(_) => max_temperature,
+    };
+
+    let var = format!("GENERATION_{}_MAX_TOKENS", role);
+    let max_tokens = match env::var(&var) {
+        Ok(value) => value
+            .parse::<u16>()
+            .map_err(|e| ConfigError::EnvVarInvalid(var, e.to_string()))?,
+        Err(_) => max_tokens,
+    };
+
+    let overridable = env::var(format!("GENERATION_{}_OVERRIDES", role))
+        .unwrap_or_else(|_| overridable.to_string())
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    let models = env::var(format!("GENERATION_{}_MODELS", role))
+        .unwrap_or_else(|_| models.to_string())
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    Ok(GenerationLimits {
+        max_temperature,
+        max_tokens,
+        overridable,
+        models,
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatabaseConfig {
+    pub url: String, // `sqlite://<path>`, or `sqlite::memory:` for a throwaway database
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobsConfig {
+    pub workers: usize,   // texts processed at once across all jobs
+    pub max_texts: usize, // texts accepted in a single job
+    pub max_queued_texts: usize, // texts a user may have waiting across their jobs
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadsConfig {
+    pub max_bytes: usize, // largest document accepted
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheConfig {
+    pub ttl_secs: u64,       // how long responses are reused, 0 to disable the cache
+    pub capacity: usize,     // responses kept in memory
+    pub dir: Option<String>, // where responses are also stored on disk, none to skip it
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    pub user_per_minute: u32, // text requests a user may start per minute, 0 for no limit
+    pub user_burst: u32,      // text requests a user may start at once
+    pub ip_per_minute: u32,   // same per client address, 0 for no limit
+    pub ip_burst: u32,        // text requests an address may start at once
+    pub max_concurrent_streams: usize, // streams a user may have open, 0 for no limit
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhooksConfig {
+    pub max_attempts: u32,         // deliveries tried before giving up
+    pub retry_delay_ms: u64,       // wait before the first retry, doubled after each one
+    pub timeout_secs: u64,         // per delivery attempt
+    pub allow_private_hosts: bool, // deliver to loopback, private and link-local addresses
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
     pub openai: OpenAIConfig,
     pub jwt: JWTConfig,
+    pub auth: AuthConfig,
     pub cookie: CookieConfig,
+    pub templates: TemplatesConfig,
+    pub languages: LanguagesConfig,
+    pub generation: GenerationConfig,
+    pub jobs: JobsConfig,
+    pub webhooks: WebhooksConfig,
+    pub uploads: UploadsConfig,
+    pub database: DatabaseConfig,
+    pub cache: CacheConfig,
+    pub rate_limit: RateLimitConfig,
 }
 
 impl Config {
@@ -75,6 +225,20 @@ impl Config {
 
         let model = env::var("OPENAI_MODEL").unwrap_or_else(|_| "gpt-3.5-turbo".to_string());
 
+        let context_limit = match env::var("OPENAI_CONTEXT_LIMIT") {
+            Ok(value) => Some(value.parse::<usize>().map_err(|e| {
+                ConfigError::EnvVarInvalid("OPENAI_CONTEXT_LIMIT".to_string(), e.to_string())
+            })?),
+            Err(_) => None,
+        };
+
+        let completion_reserve = env::var("OPENAI_COMPLETION_RESERVE")
+            .unwrap_or_else(|_| "1024".to_string())
+            .parse::<usize>()
+            .map_err(|e| {
+                ConfigError::EnvVarInvalid("OPENAI_COMPLETION_RESERVE".to_string(), e.to_string())
+            })?;
+
         // JWT configuration
         let secret = env::var("JWT_SECRET")
             .map_err(|_| ConfigError::EnvVarMissing("JWT_SECRET".to_string()))?;
@@ -84,6 +248,14 @@ impl Config {
             .parse::<i64>()
             .map_err(|e| ConfigError::EnvVarInvalid("JWT_EXPIRATION".to_string(), e.to_string()))?;
 
+        // Auth configuration
+        let admin_users = env::var("ADMIN_USERS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+
         // Cookie configuration
         let secure = env::var("COOKIE_SECURE")
             .unwrap_or_else(|_| "false".to_string())
@@ -101,19 +273,178 @@ impl Config {
             ));
         }
 
+        // Prompt template configuration
+        let templates_dir =
+            env::var("PROMPT_TEMPLATES_DIR").unwrap_or_else(|_| "templates".to_string());
+
+        // Language configuration
+        let supported = env::var("SUPPORTED_LANGUAGES")
+            .unwrap_or_else(|_| DEFAULT_SUPPORTED_LANGUAGES.to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                LanguageTag::parse(s).map_err(|e| {
+                    ConfigError::EnvVarInvalid("SUPPORTED_LANGUAGES".to_string(), e.to_string())
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Generation parameter limits per role
+        let generation = GenerationConfig {
+            user: generation_limits("USER", 1.0, 1024, DEFAULT_USER_OVERRIDES, "")?,
+            admin: generation_limits("ADMIN", 2.0, 4096, DEFAULT_ADMIN_OVERRIDES, "*")?,
+        };
+
+        // Background job configuration
+        let workers = env::var("JOBS_WORKERS")
+            .unwrap_or_else(|_| "4".to_string())
+            .parse::<usize>()
+            .ok()
+            .filter(|workers| *workers > 0)
+            .ok_or_else(|| {
+                ConfigError::EnvVarInvalid(
+                    "JOBS_WORKERS".to_string(),
+                    "Must be a positive integer".to_string(),
+                )
+            })?;
+
+        let max_texts = env::var("JOBS_MAX_TEXTS")
+            .unwrap_or_else(|_| "10000".to_string())
+            .parse::<usize>()
+            .map_err(|e| ConfigError::EnvVarInvalid("JOBS_MAX_TEXTS".to_string(), e.to_string()))?;
+
+        let max_queued_texts = env::var("JOBS_MAX_QUEUED_TEXTS")
+            .unwrap_or_else(|_| "20000".to_string())
+            .parse::<usize>()
+            .map_err(|e| {
+                ConfigError::EnvVarInvalid("JOBS_MAX_QUEUED_TEXTS".to_string(), e.to_string())
+            })?;
+
+        // Webhook delivery configuration
+        let max_attempts = env::var("WEBHOOK_MAX_ATTEMPTS")
+            .unwrap_or_else(|_| "5".to_string())
+            .parse::<u32>()
+            .ok()
+            .filter(|attempts| *attempts > 0)
+            .ok_or_else(|| {
+                ConfigError::EnvVarInvalid(
+                    "WEBHOOK_MAX_ATTEMPTS".to_string(),
+                    "Must be a positive integer".to_string(),
+                )
+            })?;
+
+        let retry_delay_ms = env::var("WEBHOOK_RETRY_DELAY_MS")
+            .unwrap_or_else(|_| "1000".to_string())
+            .parse::<u64>()
+            .map_err(|e| {
+                ConfigError::EnvVarInvalid("WEBHOOK_RETRY_DELAY_MS".to_string(), e.to_string())
+            })?;
+
+        let timeout_secs = env::var("WEBHOOK_TIMEOUT_SECS")
+            .unwrap_or_else(|_| "10".to_string())
+            .parse::<u64>()
+            .map_err(|e| {
+                ConfigError::EnvVarInvalid("WEBHOOK_TIMEOUT_SECS".to_string(), e.to_string())
+            })?;
+
+        // Off by default, so webhooks cannot reach services inside the network
+        let allow_private_hosts = env::var("WEBHOOK_ALLOW_PRIVATE_HOSTS")
+            .unwrap_or_else(|_| "false".to_string())
+            .parse::<bool>()
+            .map_err(|e| {
+                ConfigError::EnvVarInvalid("WEBHOOK_ALLOW_PRIVATE_HOSTS".to_string(), e.to_string())
+            })?;
+
+        // Document upload configuration
+        let max_bytes = env::var("UPLOAD_MAX_BYTES")
+            .unwrap_or_else(|_| "5242880".to_string()) // Default to 5 MiB
+            .parse::<usize>()
+            .map_err(|e| {
+                ConfigError::EnvVarInvalid("UPLOAD_MAX_BYTES".to_string(), e.to_string())
+            })?;
+
+        // Database configuration, SQLite being the only backend for now
+        let database_url =
+            env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite://data/app.db".to_string());
+        if !database_url.starts_with("sqlite:") {
+            return Err(ConfigError::EnvVarInvalid(
+                "DATABASE_URL".to_string(),
+                "Only sqlite: URLs are supported".to_string(),
+            ));
+        }
+
+        // Response cache configuration
+        let cache_ttl_secs = env::var("CACHE_TTL_SECS")
+            .unwrap_or_else(|_| "3600".to_string())
+            .parse::<u64>()
+            .map_err(|e| ConfigError::EnvVarInvalid("CACHE_TTL_SECS".to_string(), e.to_string()))?;
+
+        let cache_capacity = env::var("CACHE_CAPACITY")
+            .unwrap_or_else(|_| "1000".to_string())
+            .parse::<usize>()
+            .map_err(|e| ConfigError::EnvVarInvalid("CACHE_CAPACITY".to_string(), e.to_string()))?;
+
+        // No CACHE_DIR keeps cached responses in memory only
+        let cache_dir = env::var("CACHE_DIR").ok().filter(|dir| !dir.is_empty());
+
+        // Rate limit configuration
+        let rate = |var: &str, default: &str| {
+            env::var(var)
+                .unwrap_or_else(|_| default.to_string())
+                .parse::<u32>()
+                .map_err(|e| ConfigError::EnvVarInvalid(var.to_string(), e.to_string()))
+        };
+        let user_per_minute = rate("RATE_LIMIT_USER_PER_MINUTE", "30")?;
+        let user_burst = rate("RATE_LIMIT_USER_BURST", "10")?;
+        let ip_per_minute = rate("RATE_LIMIT_IP_PER_MINUTE", "60")?;
+        let ip_burst = rate("RATE_LIMIT_IP_BURST", "20")?;
+        let max_concurrent_streams = rate("MAX_CONCURRENT_STREAMS", "4")? as usize;
+
         Ok(Config {
             server: ServerConfig { port, host },
             openai: OpenAIConfig {
                 api_key,
                 base_url,
                 model,
+                context_limit,
+                completion_reserve,
             },
             jwt: JWTConfig { secret, expiration },
+            auth: AuthConfig { admin_users },
             cookie: CookieConfig {
                 secure,
                 domain,
                 same_site,
             },
+            templates: TemplatesConfig { dir: templates_dir },
+            languages: LanguagesConfig { supported },
+            generation,
+            jobs: JobsConfig {
+                workers,
+                max_texts,
+                max_queued_texts,
+            },
+            webhooks: WebhooksConfig {
+                max_attempts,
+                retry_delay_ms,
+                timeout_secs,
+                allow_private_hosts,
+            },
+            uploads: UploadsConfig { max_bytes },
+            database: DatabaseConfig { url: database_url },
+            cache: CacheConfig {
+                ttl_secs: cache_ttl_secs,
+                capacity: cache_capacity,
+                dir: cache_dir,
+            },
+            rate_limit: RateLimitConfig {
+                user_per_minute,
+                user_burst,
+                ip_per_minute,
+                ip_burst,
+                max_concurrent_streams,
+            },
         })
     }
 
@@ -129,16 +460,80 @@ impl Config {
                 api_key: "test_api_key".to_string(),
                 base_url: "https://api.openai.com/v1".to_string(),
                 model: "gpt-3.5-turbo".to_string(),
+                context_limit: None,
+                completion_reserve: 1024,
             },
             jwt: JWTConfig {
                 secret: "test_secret_key_for_testing_purposes_only".to_string(),
                 expiration: 86400,
             },
+            auth: AuthConfig {
+                admin_users: vec!["admin".to_string()],
+            },
             cookie: CookieConfig {
                 secure: false,
                 domain: None,
                 same_site: "None".to_string(),
             },
+            templates: TemplatesConfig {
+                dir: "templates".to_string(),
+            },
+            languages: LanguagesConfig {
+                supported: DEFAULT_SUPPORTED_LANGUAGES
+                    .split(',')
+                    .map(|s| LanguageTag::parse(s).unwrap())
+                    .collect(),
+            },
+            generation: GenerationConfig {
+                user: GenerationLimits {
+                    max_temperature: 1.0,
+                    max_tokens: 1024,
+                    overridable: DEFAULT_USER_OVERRIDES
+                        .split(',')
+                        .map(str::to_string)
+                        .collect(),
+                    models: Vec::new(),
+                },
+                admin: GenerationLimits {
+                    max_temperature: 2.0,
+                    max_tokens: 4096,
+                    overridable: DEFAULT_ADMIN_OVERRIDES
+                        .split(',')
+                        .map(str::to_string)
+                        .collect(),
+                    models: vec!["*".to_string()],
+                },
+            },
+            jobs: JobsConfig {
+                workers: 2,
+                max_texts: 100,
+                max_queued_texts: 200,
+            },
+            webhooks: WebhooksConfig {
+                max_attempts: 3,
+                retry_delay_ms: 10,
+                timeout_secs: 2,
+                // Receivers in tests listen on loopback
+                allow_private_hosts: true,
+            },
+            uploads: UploadsConfig { max_bytes: 65536 },
+            database: DatabaseConfig {
+                url: "sqlite::memory:".to_string(),
+            },
+            // Off, so tests repeating a request reach the mock every time
+            cache: CacheConfig {
+                ttl_secs: 0,
+                capacity: 100,
+                dir: None,
+            },
+            // No limits, tests run many requests as the same user
+            rate_limit: RateLimitConfig {
+                user_per_minute: 0,
+                user_burst: 10,
+                ip_per_minute: 0,
+                ip_burst: 20,
+                max_concurrent_streams: 0,
+            },
         }
     }
 }
@@ -0,0 +1,278 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::info;
+
+use crate::config::DatabaseConfig;
+use crate::error::AppError;
+
+// Schema changes, applied in order. The number applied so far is kept in the
+// database's `user_version`, so released migrations must never change.
+const MIGRATIONS: &[&str] = &[
+    r#"
+    CREATE TABLE documents (
+        id TEXT PRIMARY KEY,
+        owner TEXT NOT NULL,
+        title TEXT NOT NULL,
+        content TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        created_at TEXT NOT NULL,
+        updated_at TEXT NOT NULL
+    );
+    CREATE INDEX documents_by_owner ON documents (owner, updated_at);
+
+    CREATE TABLE document_versions (
+        document_id TEXT NOT NULL REFERENCES documents (id) ON DELETE CASCADE,
+        version INTEGER NOT NULL,
+        title TEXT NOT NULL,
+        content TEXT NOT NULL,
+        author TEXT NOT NULL,
+        source TEXT NOT NULL,
+        operation TEXT,
+        restored_from INTEGER,
+        created_at TEXT NOT NULL,
+        PRIMARY KEY (document_id, version)
+    );
+    CREATE TRIGGER document_versions_immutable BEFORE UPDATE ON document_versions
+    BEGIN
+        SELECT RAISE(ABORT, 'document versions are immutable');
+    END;
+"#,
+    r#"
+    CREATE TABLE operation_history (
+        id TEXT PRIMARY KEY,
+        owner TEXT NOT NULL,
+        operation TEXT NOT NULL,
+        source TEXT NOT NULL,
+        parameters TEXT NOT NULL,
+        input TEXT NOT NULL,
+        input_hash TEXT NOT NULL,
+        input_preview TEXT NOT NULL,
+        output TEXT,
+        model TEXT NOT NULL,
+        prompt_tokens INTEGER,
+        completion_tokens INTEGER,
+        latency_ms INTEGER NOT NULL,
+        status TEXT NOT NULL,
+        error TEXT,
+        created_at TEXT NOT NULL
+    );
+    CREATE INDEX operation_history_by_owner ON operation_history (owner, created_at);
+"#,
+    r#"
+    CREATE TABLE document_collaborators (
+        document_id TEXT NOT NULL REFERENCES documents (id) ON DELETE CASCADE,
+        username TEXT NOT NULL,
+        added_at TEXT NOT NULL,
+        PRIMARY KEY (document_id, username)
+    );
+
+    CREATE TABLE document_collab_state (
+        document_id TEXT PRIMARY KEY REFERENCES documents (id) ON DELETE CASCADE,
+        version INTEGER NOT NULL,
+        state BLOB NOT NULL
+    );
+"#,
+    r#"
+    CREATE TABLE webhooks (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        owner TEXT NOT NULL,
+        url TEXT NOT NULL,
+        events TEXT NOT NULL,
+        secret TEXT NOT NULL,
+        created_at TEXT NOT NULL
+    );
+    CREATE INDEX webhooks_by_owner ON webhooks (owner);
+
+    CREATE TABLE webhook_deliveries (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        webhook_id INTEGER NOT NULL REFERENCES webhooks (id) ON DELETE CASCADE,
+        event TEXT NOT NULL,
+        status TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        attempts TEXT NOT NULL,
+        created_at TEXT NOT NULL
+    );
+    CREATE INDEX webhook_deliveries_by_webhook ON webhook_deliveries (webhook_id, id);
+"#,
+    r#"
+    CREATE TABLE custom_operations (
+        owner TEXT NOT NULL,
+        name TEXT NOT NULL,
+        author TEXT NOT NULL,
+        definition TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        created_at TEXT NOT NULL,
+        updated_at TEXT NOT NULL,
+        PRIMARY KEY (owner, name)
+    );
+"#,
+    r#"
+    CREATE TABLE glossary_entries (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        owner TEXT NOT NULL,
+        source_term TEXT NOT NULL,
+        target_term TEXT NOT NULL,
+        source_language TEXT NOT NULL,
+        target_language TEXT NOT NULL
+    );
+    CREATE INDEX glossary_entries_by_owner ON glossary_entries (owner, id);
+
+    CREATE TABLE do_not_translate_terms (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        owner TEXT NOT NULL,
+        term TEXT NOT NULL
+    );
+    CREATE INDEX do_not_translate_terms_by_owner ON do_not_translate_terms (owner, id);
+"#,
+    r#"
+    CREATE TABLE translation_memory (
+        owner TEXT NOT NULL,
+        source_language TEXT NOT NULL,
+        target_language TEXT NOT NULL,
+        source TEXT NOT NULL,
+        translation TEXT NOT NULL,
+        updated_at TEXT NOT NULL,
+        PRIMARY KEY (owner, source_language, target_language, source)
+    );
+    CREATE INDEX translation_memory_by_age
+        ON translation_memory (owner, source_language, target_language, updated_at);
+"#,
+    r#"
+    CREATE TABLE jobs (
+        id TEXT PRIMARY KEY,
+        owner TEXT NOT NULL,
+        role TEXT NOT NULL,
+        op TEXT NOT NULL,
+        step TEXT NOT NULL,
+        status TEXT NOT NULL,
+        total INTEGER NOT NULL,
+        completed INTEGER NOT NULL,
+        failed INTEGER NOT NULL,
+        prompt_tokens INTEGER NOT NULL,
+        completion_tokens INTEGER NOT NULL,
+        created_at TEXT NOT NULL,
+        started_at TEXT,
+        finished_at TEXT
+    );
+    CREATE INDEX jobs_by_owner ON jobs (owner, created_at);
+    CREATE INDEX jobs_by_status ON jobs (status, created_at);
+
+    CREATE TABLE job_items (
+        job_id TEXT NOT NULL REFERENCES jobs (id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        status TEXT NOT NULL,
+        input TEXT NOT NULL,
+        output TEXT,
+        edits TEXT,
+        prompt_tokens INTEGER,
+        completion_tokens INTEGER,
+        error TEXT,
+        code INTEGER,
+        PRIMARY KEY (job_id, position)
+    );
+"#,
+];
+
+// Names stored in the database for enums serialized as strings
+pub fn as_column<T: Serialize>(value: T) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+pub fn from_column<T: for<'de> Deserialize<'de>>(column: String) -> rusqlite::Result<T> {
+    serde_json::from_value(Value::String(column)).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(e: rusqlite::Error) -> Self {
+        AppError::Internal(format!("Database error: {}", e))
+    }
+}
+
+// The application's database, a single SQLite connection shared by the stores
+pub struct Database {
+    connection: Mutex<Connection>,
+}
+
+impl Database {
+    pub fn open(config: &DatabaseConfig) -> Result<Self, AppError> {
+        let mut connection = match config.url.as_str() {
+            "sqlite::memory:" => Connection::open_in_memory()?,
+            url => {
+                let path = url.strip_prefix("sqlite://").unwrap_or(url);
+                if let Some(dir) = Path::new(path)
+                    .parent()
+                    .filter(|d| !d.as_os_str().is_empty())
+                {
+                    fs::create_dir_all(dir).map_err(|e| {
+                        AppError::Internal(format!("Failed to create {}: {}", dir.display(), e))
+                    })?;
+                }
+                let connection = Connection::open(path)?;
+                connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+                connection
+            }
+        };
+        connection.pragma_update(None, "foreign_keys", "ON")?;
+        migrate(&mut connection)?;
+
+        Ok(Database {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    // Run `query` with the connection, holding it for the duration
+    pub fn with<T>(
+        &self,
+        query: impl FnOnce(&mut Connection) -> Result<T, AppError>,
+    ) -> Result<T, AppError> {
+        let mut connection = self
+            .connection
+            .lock()
+            .map_err(|_| AppError::Internal("Database connection poisoned".to_string()))?;
+        query(&mut connection)
+    }
+}
+
+fn migrate(connection: &mut Connection) -> Result<(), AppError> {
+    let applied: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+        info!("Applied database migration {}", index + 1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrations_are_applied_once() {
+        let dir = std::env::temp_dir().join(format!("db-test-{}", rand::random::<u64>()));
+        let config = DatabaseConfig {
+            url: format!("sqlite://{}", dir.join("app.db").display()),
+        };
+
+        Database::open(&config).unwrap();
+        let database = Database::open(&config).unwrap();
+        let applied: usize = database
+            .with(|c| Ok(c.query_row("PRAGMA user_version", [], |row| row.get(0))?))
+            .unwrap();
+        assert_eq!(applied, MIGRATIONS.len());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+use serde::{Deserialize, Serialize};
+use similar::{Algorithm, ChangeTag, TextDiff};
+
+use crate::text::split_sentences;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffGranularity {
+    #[default]
+    Word,
+    Sentence,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffOp {
+    Equal,
+    Insert,
+    Delete,
+}
+
+// A run of text that is unchanged, added or removed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffChange {
+    pub op: DiffOp,
+    pub text: String,
+}
+
+// Difference between the input and the output of an operation. Applying the
+// equal and insert changes in order gives the output, equal and delete the input.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffReport {
+    pub granularity: DiffGranularity,
+    pub changes: Vec<DiffChange>,
+    // Number of words added and removed
+    pub insertions: usize,
+    pub deletions: usize,
+}
+
+// Sentences with their trailing whitespace, so the tokens cover the whole text
+fn sentence_tokens(text: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut offset = 0;
+    for sentence in split_sentences(text) {
+        let length = sentence.text.len() + sentence.trailing.len();
+        tokens.push(&text[offset..offset + length]);
+        offset += length;
+    }
+    tokens
+}
+
+pub fn diff_texts(old: &str, new: &str, granularity: DiffGranularity) -> DiffReport {
+    let mut config = TextDiff::configure();
+    config.algorithm(Algorithm::Patience);
+
+    let old_sentences;
+    let new_sentences;
+    let diff = match granularity {
+        DiffGranularity::Word => config.diff_words(old, new),
+        DiffGranularity::Sentence => {
+            old_sentences = sentence_tokens(old);
+            new_sentences = sentence_tokens(new);
+            config.diff_slices(&old_sentences, &new_sentences)
+        }
+    };
+
+    let mut changes: Vec<DiffChange> = Vec::new();
+    let mut insertions = 0;
+    let mut deletions = 0;
+    for change in diff.iter_all_changes() {
+        let op = match change.tag() {
+            ChangeTag::Equal => DiffOp::Equal,
+            ChangeTag::Insert => DiffOp::Insert,
+            ChangeTag::Delete => DiffOp::Delete,
+        };
+        let text = change.value();
+        match op {
+            DiffOp::Insert => insertions += text.split_whitespace().count(),
+            DiffOp::Delete => deletions += text.split_whitespace().count(),
+            DiffOp::Equal => {}
+        }
+
+        // Merge runs of the same kind so clients get whole phrases
+        match changes.last_mut() {
+            Some(last) if last.op == op => last.text.push_str(text),
+            _ => changes.push(DiffChange {
+                op,
+                text: text.to_string(),
+            }),
+        }
+    }
+
+    DiffReport {
+        granularity,
+        changes,
+        insertions,
+        deletions,
+    }
+}
+
+// The input a request asked to compare the output with. It belongs to the
+// request, not to the generation, so a shared or cached output is compared
+// once per request.
+#[derive(Debug, Clone)]
+pub struct DiffRequest {
+    pub input: String,
+    pub granularity: DiffGranularity,
+}
+
+impl DiffRequest {
+    pub fn report(&self, output: &str) -> DiffReport {
+        diff_texts(&self.input, output, self.granularity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rebuild(report: &DiffReport, skip: DiffOp) -> String {
+        report
+            .changes
+            .iter()
+            .filter(|c| c.op != skip)
+            .map(|c| c.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_word_diff_round_trips() {
+        let old = "The quick brown fox jumps over the lazy dog.";
+        let new = "The quick red fox leaps over the lazy dog.";
+        let report = diff_texts(old, new, DiffGranularity::Word);
+
+        assert_eq!(rebuild(&report, DiffOp::Insert), old);
+        assert_eq!(rebuild(&report, DiffOp::Delete), new);
+        assert_eq!((report.insertions, report.deletions), (2, 2));
+        assert!(report.changes.contains(&DiffChange {
+            op: DiffOp::Delete,
+            text: "brown".to_string()
+        }));
+    }
+
+    #[test]
+    fn test_sentence_diff_keeps_unchanged_sentences() {
+        let old = "First sentence. Second sentence. Third one.";
+        let new = "First sentence. A new second sentence. Third one.";
+        let report = diff_texts(old, new, DiffGranularity::Sentence);
+
+        assert_eq!(report.changes[0].op, DiffOp::Equal);
+        assert_eq!(report.changes[0].text, "First sentence. ");
+        assert_eq!(rebuild(&report, DiffOp::Delete), new);
+        assert_eq!(report.deletions, 2);
+    }
+}
@@ -0,0 +1,843 @@
+use std::sync::Arc;
+
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+use crate::db::{as_column, from_column, Database};
+use crate::diff::{diff_texts, DiffGranularity, DiffReport};
+use crate::error::AppError;
+use crate::models::Claims;
+use crate::state::AppState;
+
+const MAX_TITLE_CHARS: usize = 200;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DocumentSummary {
+    pub id: String,
+    pub title: String,
+    // Number of the latest version
+    pub version: i64,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredDocument {
+    #[serde(flatten)]
+    pub summary: DocumentSummary,
+    pub content: String,
+}
+
+// What produced a version
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VersionSource {
+    Created,
+    Saved,
+    // The output of an AI operation the author accepted
+    Operation,
+    Restored,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionSummary {
+    pub version: i64,
+    pub title: String,
+    pub author: String,
+    pub source: VersionSource,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operation: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restored_from: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DocumentVersion {
+    #[serde(flatten)]
+    pub summary: VersionSummary,
+    pub content: String,
+}
+
+// A change to a document, recorded as a new version
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DocumentUpdate {
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub content: Option<String>,
+    // Name of the AI operation whose output is being accepted
+    #[serde(default)]
+    pub operation: Option<String>,
+    // The version the change was made on, rejected with 409 when it is no
+    // longer the latest
+    #[serde(default)]
+    pub base_version: Option<i64>,
+}
+
+struct NewVersion {
+    title: String,
+    content: String,
+    source: VersionSource,
+    operation: Option<String>,
+    restored_from: Option<i64>,
+}
+
+const DOCUMENT_COLUMNS: &str = "id, title, version, created_at, updated_at";
+const VERSION_COLUMNS: &str =
+    "version, title, author, source, operation, restored_from, created_at";
+
+fn summary_from_row(row: &Row) -> rusqlite::Result<DocumentSummary> {
+    Ok(DocumentSummary {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        version: row.get(2)?,
+        created_at: row.get(3)?,
+        updated_at: row.get(4)?,
+    })
+}
+
+fn version_from_row(row: &Row) -> rusqlite::Result<VersionSummary> {
+    Ok(VersionSummary {
+        version: row.get(0)?,
+        title: row.get(1)?,
+        author: row.get(2)?,
+        source: from_column(row.get(3)?)?,
+        operation: row.get(4)?,
+        restored_from: row.get(5)?,
+        created_at: row.get(6)?,
+    })
+}
+
+fn check_title(title: &str) -> Result<String, AppError> {
+    let title = title.trim();
+    if title.is_empty() {
+        return Err(AppError::BadRequest("Title cannot be empty".to_string()));
+    }
+    if title.chars().count() > MAX_TITLE_CHARS {
+        return Err(AppError::BadRequest(format!(
+            "Titles are limited to {} characters",
+            MAX_TITLE_CHARS
+        )));
+    }
+    Ok(title.to_string())
+}
+
+// Users' documents and their immutable version history
+pub struct DocumentStore {
+    database: Arc<Database>,
+}
+
+impl DocumentStore {
+    pub fn new(database: Arc<Database>) -> Self {
+        DocumentStore { database }
+    }
+
+    pub fn create(
+        &self,
+        owner: &str,
+        title: &str,
+        content: &str,
+    ) -> Result<StoredDocument, AppError> {
+        let title = check_title(title)?;
+        let id = format!("{:032x}", rand::random::<u128>());
+        let now = Utc::now();
+
+        self.database.with(|connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "INSERT INTO documents (id, owner, title, content, version, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, 1, ?5, ?5)",
+                params![id, owner, title, content, now],
+            )?;
+            transaction.execute(
+                "INSERT INTO document_versions (document_id, version, title, content, author, source, created_at)
+                 VALUES (?1, 1, ?2, ?3, ?4, ?5, ?6)",
+                params![id, title, content, owner, as_column(VersionSource::Created), now],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })?;
+
+        Ok(StoredDocument {
+            summary: DocumentSummary {
+                id,
+                title,
+                version: 1,
+                created_at: now,
+                updated_at: now,
+            },
+            content: content.to_string(),
+        })
+    }
+
+    // The user's documents, most recently updated first
+    pub fn list(&self, owner: &str) -> Result<Vec<DocumentSummary>, AppError> {
+        self.database.with(|connection| {
+            let mut statement = connection.prepare(&format!(
+                "SELECT {} FROM documents WHERE owner = ?1 ORDER BY updated_at DESC",
+                DOCUMENT_COLUMNS
+            ))?;
+            let documents = statement
+                .query_map([owner], summary_from_row)?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(documents)
+        })
+    }
+
+    pub fn get(&self, owner: &str, id: &str) -> Result<StoredDocument, AppError> {
+        self.database.with(|connection| {
+            connection
+                .query_row(
+                    &format!(
+                        "SELECT {}, content FROM documents WHERE id = ?1 AND owner = ?2",
+                        DOCUMENT_COLUMNS
+                    ),
+                    [id, owner],
+                    |row| {
+                        Ok(StoredDocument {
+                            summary: summary_from_row(row)?,
+                            content: row.get(5)?,
+                        })
+                    },
+                )
+                .optional()?
+                .ok_or_else(|| AppError::NotFound(format!("Document {}", id)))
+        })
+    }
+
+    // Save a change as a new version. A save that changes nothing returns the
+    // document as it is, unless it accepts an operation's output.
+    pub fn update(
+        &self,
+        owner: &str,
+        id: &str,
+        author: &str,
+        update: DocumentUpdate,
+    ) -> Result<StoredDocument, AppError> {
+        let title = update.title.as_deref().map(check_title).transpose()?;
+        let source = match update.operation {
+            Some(_) => VersionSource::Operation,
+            None => VersionSource::Saved,
+        };
+        self.save(owner, id, author, update.base_version, |current| {
+            NewVersion {
+                title: title.unwrap_or_else(|| current.summary.title.clone()),
+                content: update.content.unwrap_or_else(|| current.content.clone()),
+                source,
+                operation: update.operation,
+                restored_from: None,
+            }
+        })
+    }
+
+    // Make the content and title of an earlier version the latest ones again
+    pub fn restore(
+        &self,
+        owner: &str,
+        id: &str,
+        author: &str,
+        version: i64,
+    ) -> Result<StoredDocument, AppError> {
+        let restored = self.version(owner, id, version)?;
+        self.save(owner, id, author, None, |_| NewVersion {
+            title: restored.summary.title,
+            content: restored.content,
+            source: VersionSource::Restored,
+            operation: None,
+            restored_from: Some(version),
+        })
+    }
+
+    // Record the version `change` makes from the current one
+    fn save(
+        &self,
+        owner: &str,
+        id: &str,
+        author: &str,
+        base_version: Option<i64>,
+        change: impl FnOnce(&StoredDocument) -> NewVersion,
+    ) -> Result<StoredDocument, AppError> {
+        let current = self.get(owner, id)?;
+        if let Some(base) = base_version.filter(|base| *base != current.summary.version) {
+            return Err(AppError::Conflict(format!(
+                "Document {} is at version {}, not {}",
+                id, current.summary.version, base
+            )));
+        }
+
+        let NewVersion {
+            title,
+            content,
+            source,
+            operation,
+            restored_from,
+        } = change(&current);
+        let unchanged = title == current.summary.title && content == current.content;
+        if unchanged && source == VersionSource::Saved {
+            return Ok(current);
+        }
+
+        let version = current.summary.version + 1;
+        let now = Utc::now();
+        self.database.with(|connection| {
+            let transaction = connection.transaction()?;
+            // Only moves forward if nobody saved in between
+            let updated = transaction.execute(
+                "UPDATE documents SET title = ?1, content = ?2, version = ?3, updated_at = ?4
+                 WHERE id = ?5 AND version = ?6",
+                params![title, content, version, now, id, current.summary.version],
+            )?;
+            if updated == 0 {
+                return Err(AppError::Conflict(format!(
+                    "Document {} was saved concurrently",
+                    id
+                )));
+            }
+            transaction.execute(
+                "INSERT INTO document_versions
+                 (document_id, version, title, content, author, source, operation, restored_from, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    id,
+                    version,
+                    title,
+                    content,
+                    author,
+                    as_column(source),
+                    operation,
+                    restored_from,
+                    now
+                ],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })?;
+
+        Ok(StoredDocument {
+            summary: DocumentSummary {
+                id: id.to_string(),
+                title,
+                version,
+                created_at: current.summary.created_at,
+                updated_at: now,
+            },
+            content,
+        })
+    }
+
+    // Delete a document along with its versions
+    pub fn remove(&self, owner: &str, id: &str) -> Result<(), AppError> {
+        let deleted = self.database.with(|connection| {
+            Ok(connection.execute(
+                "DELETE FROM documents WHERE id = ?1 AND owner = ?2",
+                [id, owner],
+            )?)
+        })?;
+        if deleted == 0 {
+            return Err(AppError::NotFound(format!("Document {}", id)));
+        }
+        Ok(())
+    }
+
+    // Every version of a document, newest first
+    pub fn versions(&self, owner: &str, id: &str) -> Result<Vec<VersionSummary>, AppError> {
+        // Checks the document exists and belongs to the user
+        self.get(owner, id)?;
+        self.database.with(|connection| {
+            let mut statement = connection.prepare(&format!(
+                "SELECT {} FROM document_versions WHERE document_id = ?1 ORDER BY version DESC",
+                VERSION_COLUMNS
+            ))?;
+            let versions = statement
+                .query_map([id], version_from_row)?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(versions)
+        })
+    }
+
+    pub fn version(
+        &self,
+        owner: &str,
+        id: &str,
+        version: i64,
+    ) -> Result<DocumentVersion, AppError> {
+        self.get(owner, id)?;
+        self.database.with(|connection| {
+            connection
+                .query_row(
+                    &format!(
+                        "SELECT {}, content FROM document_versions WHERE document_id = ?1 AND version = ?2",
+                        VERSION_COLUMNS
+                    ),
+                    params![id, version],
+                    |row| {
+                        Ok(DocumentVersion {
+                            summary: version_from_row(row)?,
+                            content: row.get(7)?,
+                        })
+                    },
+                )
+                .optional()?
+                .ok_or_else(|| AppError::NotFound(format!("Version {} of document {}", version, id)))
+        })
+    }
+
+    // Owner of a document the user owns or collaborates on
+    pub fn shared_owner(&self, user: &str, id: &str) -> Result<String, AppError> {
+        self.database.with(|connection| {
+            connection
+                .query_row(
+                    "SELECT owner FROM documents WHERE id = ?1 AND (owner = ?2 OR EXISTS (
+                         SELECT 1 FROM document_collaborators WHERE document_id = ?1 AND username = ?2
+                     ))",
+                    [id, user],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .ok_or_else(|| AppError::NotFound(format!("Document {}", id)))
+        })
+    }
+
+    pub fn collaborators(&self, owner: &str, id: &str) -> Result<Vec<Collaborator>, AppError> {
+        self.get(owner, id)?;
+        self.database.with(|connection| {
+            let mut statement = connection.prepare(
+                "SELECT username, added_at FROM document_collaborators
+                 WHERE document_id = ?1 ORDER BY added_at",
+            )?;
+            let collaborators = statement
+                .query_map([id], |row| {
+                    Ok(Collaborator {
+                        username: row.get(0)?,
+                        added_at: row.get(1)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(collaborators)
+        })
+    }
+
+    // Let another user edit the document live
+    pub fn add_collaborator(&self, owner: &str, id: &str, username: &str) -> Result<(), AppError> {
+        let username = username.trim();
+        if username.is_empty() || username == owner {
+            return Err(AppError::BadRequest(
+                "Collaborators must be other users".to_string(),
+            ));
+        }
+        self.get(owner, id)?;
+        self.database.with(|connection| {
+            connection.execute(
+                "INSERT OR IGNORE INTO document_collaborators (document_id, username, added_at)
+                 VALUES (?1, ?2, ?3)",
+                params![id, username, Utc::now()],
+            )?;
+            Ok(())
+        })
+    }
+
+    pub fn remove_collaborator(
+        &self,
+        owner: &str,
+        id: &str,
+        username: &str,
+    ) -> Result<(), AppError> {
+        self.get(owner, id)?;
+        let deleted = self.database.with(|connection| {
+            Ok(connection.execute(
+                "DELETE FROM document_collaborators WHERE document_id = ?1 AND username = ?2",
+                [id, username],
+            )?)
+        })?;
+        if deleted == 0 {
+            return Err(AppError::NotFound(format!(
+                "Collaborator {} of document {}",
+                username, id
+            )));
+        }
+        Ok(())
+    }
+
+    // The saved state of live editing, with the version it was saved at
+    pub fn collab_state(&self, id: &str) -> Result<Option<(i64, Vec<u8>)>, AppError> {
+        self.database.with(|connection| {
+            Ok(connection
+                .query_row(
+                    "SELECT version, state FROM document_collab_state WHERE document_id = ?1",
+                    [id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?)
+        })
+    }
+
+    pub fn store_collab_state(&self, id: &str, version: i64, state: &[u8]) -> Result<(), AppError> {
+        self.database.with(|connection| {
+            connection.execute(
+                "INSERT INTO document_collab_state (document_id, version, state) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (document_id) DO UPDATE SET version = excluded.version, state = excluded.state",
+                params![id, version, state],
+            )?;
+            Ok(())
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Collaborator {
+    pub username: String,
+    pub added_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewCollaborator {
+    pub username: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewDocument {
+    pub title: String,
+    #[serde(default)]
+    pub content: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DiffQuery {
+    pub from: i64,
+    // The latest version when not given
+    #[serde(default)]
+    pub to: Option<i64>,
+    #[serde(default)]
+    pub granularity: DiffGranularity,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionDiff {
+    pub from: i64,
+    pub to: i64,
+    #[serde(flatten)]
+    pub diff: DiffReport,
+}
+
+pub async fn list_documents(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<DocumentSummary>>, AppError> {
+    Ok(Json(state.documents.list(&claims.sub)?))
+}
+
+pub async fn create_document(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<NewDocument>,
+) -> Result<impl IntoResponse, AppError> {
+    let document = state
+        .documents
+        .create(&claims.sub, &request.title, &request.content)?;
+    Ok((StatusCode::CREATED, Json(document)))
+}
+
+pub async fn get_document(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+) -> Result<Json<StoredDocument>, AppError> {
+    Ok(Json(state.documents.get(&claims.sub, &id)?))
+}
+
+pub async fn update_document(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+    Json(update): Json<DocumentUpdate>,
+) -> Result<Json<StoredDocument>, AppError> {
+    let document = state
+        .documents
+        .update(&claims.sub, &id, &claims.sub, update)?;
+    // Editors connected live get the change instead of saving over it
+    state.collab.refresh(&state.documents, &id)?;
+    Ok(Json(document))
+}
+
+pub async fn delete_document(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    state.documents.remove(&claims.sub, &id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_versions(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<VersionSummary>>, AppError> {
+    Ok(Json(state.documents.versions(&claims.sub, &id)?))
+}
+
+pub async fn get_version(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path((id, version)): Path<(String, i64)>,
+) -> Result<Json<DocumentVersion>, AppError> {
+    Ok(Json(state.documents.version(&claims.sub, &id, version)?))
+}
+
+pub async fn restore_version(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path((id, version)): Path<(String, i64)>,
+) -> Result<Json<StoredDocument>, AppError> {
+    let document = state
+        .documents
+        .restore(&claims.sub, &id, &claims.sub, version)?;
+    state.collab.refresh(&state.documents, &id)?;
+    Ok(Json(document))
+}
+
+pub async fn list_collaborators(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<Collaborator>>, AppError> {
+    Ok(Json(state.documents.collaborators(&claims.sub, &id)?))
+}
+
+pub async fn add_collaborator(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+    Json(request): Json<NewCollaborator>,
+) -> Result<Json<Vec<Collaborator>>, AppError> {
+    state
+        .documents
+        .add_collaborator(&claims.sub, &id, &request.username)?;
+    Ok(Json(state.documents.collaborators(&claims.sub, &id)?))
+}
+
+pub async fn remove_collaborator(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path((id, username)): Path<(String, String)>,
+) -> Result<StatusCode, AppError> {
+    state
+        .documents
+        .remove_collaborator(&claims.sub, &id, &username)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Compare two versions of a document
+pub async fn diff_versions(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+    Query(query): Query<DiffQuery>,
+) -> Result<Json<VersionDiff>, AppError> {
+    let from = state.documents.version(&claims.sub, &id, query.from)?;
+    let (to, content) = match query.to {
+        Some(version) => (
+            version,
+            state.documents.version(&claims.sub, &id, version)?.content,
+        ),
+        None => {
+            let document = state.documents.get(&claims.sub, &id)?;
+            (document.summary.version, document.content)
+        }
+    };
+
+    Ok(Json(VersionDiff {
+        from: query.from,
+        to,
+        diff: diff_texts(&from.content, &content, query.granularity),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::create_router;
+    use crate::config::Config;
+    use crate::test_utils::{authed_request, json_body, test_state};
+    use axum::body::Body;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    fn store() -> DocumentStore {
+        let config = Config::default_test_config();
+        DocumentStore::new(Arc::new(Database::open(&config.database).unwrap()))
+    }
+
+    #[test]
+    fn test_saves_create_versions() {
+        let store = store();
+        let document = store.create("alice", "Draft", "First words.").unwrap();
+        let id = &document.summary.id;
+
+        let update = DocumentUpdate {
+            content: Some("Better words.".to_string()),
+            operation: Some("paraphrase".to_string()),
+            base_version: Some(1),
+            ..Default::default()
+        };
+        let saved = store.update("alice", id, "alice", update).unwrap();
+        assert_eq!(saved.summary.version, 2);
+
+        // Saving the same content again is not a new version
+        let update = DocumentUpdate {
+            content: Some("Better words.".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            store
+                .update("alice", id, "alice", update)
+                .unwrap()
+                .summary
+                .version,
+            2
+        );
+
+        // A change made on an older version is rejected
+        let stale = DocumentUpdate {
+            content: Some("Other words.".to_string()),
+            base_version: Some(1),
+            ..Default::default()
+        };
+        assert!(matches!(
+            store.update("alice", id, "alice", stale),
+            Err(AppError::Conflict(_))
+        ));
+
+        let restored = store.restore("alice", id, "alice", 1).unwrap();
+        assert_eq!(restored.content, "First words.");
+
+        let versions = store.versions("alice", id).unwrap();
+        let sources: Vec<_> = versions.iter().map(|v| (v.version, v.source)).collect();
+        assert_eq!(
+            sources,
+            vec![
+                (3, VersionSource::Restored),
+                (2, VersionSource::Operation),
+                (1, VersionSource::Created),
+            ]
+        );
+        assert_eq!(versions[0].restored_from, Some(1));
+        assert_eq!(versions[1].operation.as_deref(), Some("paraphrase"));
+
+        // Other users cannot see the document
+        assert!(matches!(store.get("bob", id), Err(AppError::NotFound(_))));
+        assert!(matches!(
+            store.version("bob", id, 1),
+            Err(AppError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_versions_are_immutable() {
+        let store = store();
+        let document = store.create("alice", "Draft", "Words.").unwrap();
+        let result = store.database.with(|connection| {
+            Ok(connection.execute(
+                "UPDATE document_versions SET content = 'Changed' WHERE document_id = ?1",
+                [&document.summary.id],
+            )?)
+        });
+        assert!(result.is_err());
+
+        store.remove("alice", &document.summary.id).unwrap();
+        let left: i64 = store
+            .database
+            .with(|c| Ok(c.query_row("SELECT COUNT(*) FROM document_versions", [], |r| r.get(0))?))
+            .unwrap();
+        assert_eq!(left, 0);
+    }
+
+    #[test]
+    fn test_unknown_version_sources_are_errors() {
+        let store = store();
+        let document = store.create("alice", "Draft", "Words.").unwrap();
+        store
+            .database
+            .with(|connection| {
+                Ok(connection.execute(
+                    "INSERT INTO document_versions (document_id, version, title, content, author, source, created_at)
+                     VALUES (?1, 2, 'Draft', 'Words.', 'alice', 'imported', ?2)",
+                    params![document.summary.id, Utc::now()],
+                )?)
+            })
+            .unwrap();
+
+        assert!(store.versions("alice", &document.summary.id).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_documents_api() {
+        let config = Config::default_test_config();
+        let state = test_state(config.clone());
+        let send = |method: &str, uri: &str, body: Option<Value>| {
+            let request = authed_request(&config, "alice")
+                .method(method)
+                .uri(uri)
+                .header("content-type", "application/json")
+                .body(body.map(json_body).unwrap_or_else(Body::empty))
+                .unwrap();
+            let app = create_router(state.clone());
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (
+                    status,
+                    serde_json::from_slice::<Value>(&body).unwrap_or(Value::Null),
+                )
+            }
+        };
+
+        let (status, document) = send(
+            "POST",
+            "/api/documents",
+            Some(json!({"title": "Notes", "content": "The cat sat."})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let id = document["id"].as_str().unwrap().to_string();
+
+        let (status, saved) = send(
+            "PUT",
+            &format!("/api/documents/{}", id),
+            Some(json!({"content": "The dog sat.", "base_version": 1})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(saved["version"], 2);
+
+        let (status, diff) = send("GET", &format!("/api/documents/{}/diff?from=1", id), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(diff["to"], 2);
+        assert_eq!(diff["insertions"], 1);
+        assert_eq!(diff["deletions"], 1);
+
+        let (status, _) = send(
+            "PUT",
+            &format!("/api/documents/{}", id),
+            Some(json!({"content": "Stale.", "base_version": 1})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (_, list) = send("GET", "/api/documents", None).await;
+        assert_eq!(list.as_array().unwrap().len(), 1);
+        assert!(list[0].get("content").is_none());
+
+        let (status, _) = send("DELETE", &format!("/api/documents/{}", id), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = send("GET", &format!("/api/documents/{}/versions", id), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,661 @@
+use std::io::{Cursor, Read};
+
+use axum::extract::multipart::MultipartError;
+use axum::extract::{Multipart, State};
+use axum::response::Response;
+use axum::{Extension, Json};
+use pulldown_cmark::{Event as MarkdownEvent, Parser, Tag};
+use quick_xml::events::{BytesStart, Event as XmlEvent};
+use quick_xml::Reader;
+use scraper::{Html, Node};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::error::AppError;
+use crate::models::Claims;
+use crate::openai::ResponseFormat;
+use crate::pipeline::{stream_pipeline, PipelineRequest, PipelineStep};
+use crate::ratelimit::ClientAddress;
+use crate::state::AppState;
+
+// Marks an explicit line break while whitespace is being collapsed
+const LINE_BREAK: char = '\u{2028}';
+// Largest uncompressed document.xml accepted from a DOCX, against zip bombs
+const MAX_DOCX_XML_BYTES: u64 = 64 * 1024 * 1024;
+pub const DOCX_CONTENT_TYPE: &str =
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DocumentFormat {
+    Text,
+    Markdown,
+    Html,
+    Docx,
+}
+
+impl DocumentFormat {
+    // Format of an upload from its file extension, or its content type
+    pub fn detect(filename: &str, content_type: Option<&str>) -> Result<Self, AppError> {
+        let extension = filename
+            .rsplit_once('.')
+            .map(|(_, extension)| extension.to_ascii_lowercase());
+        let format = match extension.as_deref() {
+            Some("txt") => Some(DocumentFormat::Text),
+            Some("md" | "markdown") => Some(DocumentFormat::Markdown),
+            Some("html" | "htm") => Some(DocumentFormat::Html),
+            Some("docx") => Some(DocumentFormat::Docx),
+            _ => match content_type.map(|c| c.split(';').next().unwrap_or_default().trim()) {
+                Some("text/plain") => Some(DocumentFormat::Text),
+                Some("text/markdown") => Some(DocumentFormat::Markdown),
+                Some("text/html") => Some(DocumentFormat::Html),
+                Some(DOCX_CONTENT_TYPE) => Some(DocumentFormat::Docx),
+                _ => None,
+            },
+        };
+
+        format.ok_or_else(|| {
+            AppError::UnsupportedMediaType(format!(
+                "{} is not a .txt, .md, .html or .docx file",
+                filename
+            ))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BlockKind {
+    Heading { level: u8 },
+    Paragraph,
+    ListItem,
+    Quote,
+    Code,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Block {
+    #[serde(flatten)]
+    pub kind: BlockKind,
+    pub text: String,
+}
+
+// Text of a document as a list of headings, paragraphs and other blocks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Document {
+    pub format: DocumentFormat,
+    pub blocks: Vec<Block>,
+}
+
+impl Document {
+    pub fn parse(format: DocumentFormat, bytes: &[u8]) -> Result<Self, AppError> {
+        let blocks = match format {
+            DocumentFormat::Docx => docx_blocks(bytes)?,
+            _ => {
+                let source = std::str::from_utf8(bytes).map_err(|_| {
+                    AppError::BadRequest("Text documents must be UTF-8".to_string())
+                })?;
+                let source = source.trim_start_matches('\u{feff}');
+                match format {
+                    DocumentFormat::Markdown => markdown_blocks(source),
+                    DocumentFormat::Html => html_blocks(source),
+                    _ => text_blocks(source),
+                }
+            }
+        };
+
+        Ok(Document { format, blocks })
+    }
+
+    // Plain text with a blank line between blocks, and list items on consecutive lines
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        let mut previous = None;
+        for block in &self.blocks {
+            if let Some(previous) = previous {
+                let list = previous == BlockKind::ListItem && block.kind == BlockKind::ListItem;
+                text.push_str(if list { "\n" } else { "\n\n" });
+            }
+            if block.kind == BlockKind::ListItem {
+                text.push_str("- ");
+            }
+            text.push_str(&block.text);
+            previous = Some(block.kind);
+        }
+        text
+    }
+}
+
+// Collects blocks from formats where blocks nest, such as a paragraph in a list item
+#[derive(Default)]
+struct BlockBuilder {
+    blocks: Vec<Block>,
+    kinds: Vec<BlockKind>,
+    buffer: String,
+}
+
+impl BlockBuilder {
+    fn kind(&self) -> BlockKind {
+        self.kinds.last().copied().unwrap_or(BlockKind::Paragraph)
+    }
+
+    // A paragraph inside a list item or quote is part of it
+    fn open(&mut self, kind: BlockKind) {
+        self.flush();
+        let kind = match (kind, self.kinds.last()) {
+            (BlockKind::Paragraph, Some(outer)) => *outer,
+            _ => kind,
+        };
+        self.kinds.push(kind);
+    }
+
+    fn close(&mut self) {
+        self.flush();
+        self.kinds.pop();
+    }
+
+    fn text(&mut self, text: &str) {
+        self.buffer.push_str(text);
+    }
+
+    fn line_break(&mut self) {
+        self.buffer.push(LINE_BREAK);
+    }
+
+    fn push(&mut self, kind: BlockKind, text: &str) {
+        let text = if kind == BlockKind::Code {
+            text.trim_end().to_string()
+        } else {
+            collapse_whitespace(text)
+        };
+        if !text.trim().is_empty() {
+            self.blocks.push(Block { kind, text });
+        }
+    }
+
+    fn flush(&mut self) {
+        let buffer = std::mem::take(&mut self.buffer);
+        self.push(self.kind(), &buffer);
+    }
+
+    fn finish(mut self) -> Vec<Block> {
+        self.flush();
+        self.blocks
+    }
+}
+
+// Collapse runs of whitespace, keeping explicit line breaks
+fn collapse_whitespace(text: &str) -> String {
+    text.split(LINE_BREAK)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Paragraphs are separated by blank lines, line breaks within them are kept
+fn text_blocks(source: &str) -> Vec<Block> {
+    let mut builder = BlockBuilder::default();
+    for line in source.lines() {
+        if line.trim().is_empty() {
+            builder.flush();
+        } else {
+            builder.text(line);
+            builder.line_break();
+        }
+    }
+    builder.finish()
+}
+
+fn markdown_blocks(source: &str) -> Vec<Block> {
+    let mut builder = BlockBuilder::default();
+    for event in Parser::new(source) {
+        match event {
+            MarkdownEvent::Start(tag) => match tag {
+                Tag::Heading(level, _, _) => {
+                    builder.open(BlockKind::Heading { level: level as u8 })
+                }
+                Tag::Paragraph => builder.open(BlockKind::Paragraph),
+                Tag::Item => builder.open(BlockKind::ListItem),
+                Tag::BlockQuote => builder.open(BlockKind::Quote),
+                Tag::CodeBlock(_) => builder.open(BlockKind::Code),
+                _ => {}
+            },
+            MarkdownEvent::End(tag) => {
+                if matches!(
+                    tag,
+                    Tag::Heading(..)
+                        | Tag::Paragraph
+                        | Tag::Item
+                        | Tag::BlockQuote
+                        | Tag::CodeBlock(_)
+                ) {
+                    builder.close();
+                }
+            }
+            MarkdownEvent::Text(text) | MarkdownEvent::Code(text) => builder.text(&text),
+            MarkdownEvent::SoftBreak => builder.text(" "),
+            MarkdownEvent::HardBreak => builder.line_break(),
+            _ => {}
+        }
+    }
+    builder.finish()
+}
+
+fn html_block_kind(name: &str) -> Option<BlockKind> {
+    Some(match name {
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => BlockKind::Heading {
+            level: name[1..].parse().unwrap_or(1),
+        },
+        "li" => BlockKind::ListItem,
+        "blockquote" => BlockKind::Quote,
+        "pre" => BlockKind::Code,
+        "p" | "div" | "section" | "article" | "header" | "footer" | "tr" | "dt" | "dd"
+        | "figcaption" => BlockKind::Paragraph,
+        _ => return None,
+    })
+}
+
+fn walk_html(node: ego_tree::NodeRef<Node>, builder: &mut BlockBuilder) {
+    for child in node.children() {
+        match child.value() {
+            Node::Text(text) => builder.text(text),
+            Node::Element(element) => match element.name() {
+                "script" | "style" | "head" | "template" | "noscript" => {}
+                "br" => builder.line_break(),
+                "td" | "th" => {
+                    builder.text(" ");
+                    walk_html(child, builder);
+                }
+                name => match html_block_kind(name) {
+                    Some(kind) => {
+                        builder.open(kind);
+                        walk_html(child, builder);
+                        builder.close();
+                    }
+                    None => walk_html(child, builder),
+                },
+            },
+            _ => {}
+        }
+    }
+}
+
+fn html_blocks(source: &str) -> Vec<Block> {
+    let html = Html::parse_document(source);
+    let mut builder = BlockBuilder::default();
+    walk_html(html.tree.root(), &mut builder);
+    builder.finish()
+}
+
+// Value of a `w:val` attribute
+fn docx_value(element: &BytesStart) -> Option<String> {
+    element
+        .attributes()
+        .flatten()
+        .find(|a| a.key.local_name().as_ref() == b"val")
+        .and_then(|a| a.unescape_value().ok().map(|v| v.into_owned()))
+}
+
+// Heading level of a paragraph style such as `Heading2` or `Title`
+fn docx_heading_level(style: &str) -> Option<u8> {
+    let style = style.to_ascii_lowercase();
+    if style == "title" {
+        return Some(1);
+    }
+    style
+        .strip_prefix("heading")
+        .and_then(|level| level.trim().parse::<u8>().ok())
+        .map(|level| level.clamp(1, 6))
+}
+
+// Update the kind of the current paragraph from its properties
+fn docx_paragraph_kind(element: &BytesStart, kind: &mut BlockKind) {
+    match element.local_name().as_ref() {
+        b"pStyle" => {
+            if let Some(level) = docx_value(element).and_then(|s| docx_heading_level(&s)) {
+                *kind = BlockKind::Heading { level };
+            }
+        }
+        b"numPr" => *kind = BlockKind::ListItem,
+        _ => {}
+    }
+}
+
+fn docx_blocks(bytes: &[u8]) -> Result<Vec<Block>, AppError> {
+    let invalid = |e: &dyn std::fmt::Display| AppError::BadRequest(format!("Invalid DOCX: {}", e));
+
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).map_err(|e| invalid(&e))?;
+    let entry = archive
+        .by_name("word/document.xml")
+        .map_err(|e| invalid(&e))?;
+    if entry.size() > MAX_DOCX_XML_BYTES {
+        return Err(AppError::PayloadTooLarge(
+            "The DOCX document is too large once uncompressed".to_string(),
+        ));
+    }
+    let mut xml = String::new();
+    entry
+        .take(MAX_DOCX_XML_BYTES)
+        .read_to_string(&mut xml)
+        .map_err(|e| invalid(&e))?;
+
+    let mut reader = Reader::from_str(&xml);
+    let mut builder = BlockBuilder::default();
+    let mut kind = BlockKind::Paragraph;
+    let mut paragraph = String::new();
+    let mut in_text = false;
+    loop {
+        match reader.read_event().map_err(|e| invalid(&e))? {
+            XmlEvent::Start(element) => match element.local_name().as_ref() {
+                b"p" => {
+                    kind = BlockKind::Paragraph;
+                    paragraph.clear();
+                }
+                b"t" => in_text = true,
+                _ => docx_paragraph_kind(&element, &mut kind),
+            },
+            XmlEvent::Empty(element) => match element.local_name().as_ref() {
+                b"tab" => paragraph.push(' '),
+                b"br" | b"cr" => paragraph.push(LINE_BREAK),
+                _ => docx_paragraph_kind(&element, &mut kind),
+            },
+            XmlEvent::End(element) => match element.local_name().as_ref() {
+                b"p" => builder.push(kind, &paragraph),
+                b"t" => in_text = false,
+                _ => {}
+            },
+            XmlEvent::Text(text) if in_text => {
+                paragraph.push_str(&text.unescape().map_err(|e| invalid(&e))?);
+            }
+            XmlEvent::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(builder.finish())
+}
+
+// An uploaded document and the other fields of its form
+struct Upload {
+    filename: String,
+    document: Document,
+    fields: Map<String, Value>,
+}
+
+fn multipart_error(e: MultipartError) -> AppError {
+    if e.status() == axum::http::StatusCode::PAYLOAD_TOO_LARGE {
+        AppError::PayloadTooLarge(e.body_text())
+    } else {
+        AppError::BadRequest(e.body_text())
+    }
+}
+
+async fn read_upload(mut multipart: Multipart, max_bytes: usize) -> Result<Upload, AppError> {
+    let mut file = None;
+    let mut fields = Map::new();
+    while let Some(field) = multipart.next_field().await.map_err(multipart_error)? {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "file" {
+            let filename = field.file_name().unwrap_or("upload").to_string();
+            let content_type = field.content_type().map(str::to_string);
+            let bytes = field.bytes().await.map_err(multipart_error)?;
+            file = Some((filename, content_type, bytes));
+        } else {
+            let value = field.text().await.map_err(multipart_error)?;
+            fields.insert(name, Value::String(value));
+        }
+    }
+
+    let (filename, content_type, bytes) =
+        file.ok_or_else(|| AppError::BadRequest("A `file` field is required".to_string()))?;
+    if bytes.len() > max_bytes {
+        return Err(AppError::PayloadTooLarge(format!(
+            "Uploads are limited to {} bytes",
+            max_bytes
+        )));
+    }
+
+    let format = DocumentFormat::detect(&filename, content_type.as_deref())?;
+    let document = Document::parse(format, &bytes)?;
+    Ok(Upload {
+        filename,
+        document,
+        fields,
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExtractedDocument {
+    pub filename: String,
+    #[serde(flatten)]
+    pub document: Document,
+    pub text: String,
+}
+
+// Extract the text of an uploaded document
+pub async fn extract_document(
+    State(state): State<AppState>,
+    multipart: Multipart,
+) -> Result<Json<ExtractedDocument>, AppError> {
+    let upload = read_upload(multipart, state.config.uploads.max_bytes).await?;
+    let text = upload.document.text();
+
+    Ok(Json(ExtractedDocument {
+        filename: upload.filename,
+        document: upload.document,
+        text,
+    }))
+}
+
+// Run an operation, given as `op` and its options, or a JSON list of pipeline
+// `steps`, on the text of an uploaded document
+pub async fn process_document(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    ClientAddress(address): ClientAddress,
+    format: ResponseFormat,
+    multipart: Multipart,
+) -> Result<Response, AppError> {
+    let Upload {
+        document,
+        mut fields,
+        ..
+    } = read_upload(multipart, state.config.uploads.max_bytes).await?;
+
+    let text = document.text();
+    if text.is_empty() {
+        return Err(AppError::BadRequest(
+            "No text was found in the document".to_string(),
+        ));
+    }
+
+    let steps = match fields.remove("steps") {
+        Some(Value::String(steps)) => serde_json::from_str(&steps)
+            .map_err(|e| AppError::BadRequest(format!("Invalid steps: {}", e)))?,
+        _ => match fields.remove("op") {
+            Some(Value::String(op)) => vec![PipelineStep {
+                op,
+                options: fields,
+            }],
+            _ => {
+                return Err(AppError::BadRequest(
+                    "An `op` or `steps` field is required".to_string(),
+                ))
+            }
+        },
+    };
+
+    let request = PipelineRequest { text, steps };
+    stream_pipeline(state, claims, address, format, request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::create_router;
+    use crate::test_utils::*;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use std::io::Write;
+    use tower::ServiceExt;
+
+    fn kinds(document: &Document) -> Vec<BlockKind> {
+        document.blocks.iter().map(|b| b.kind).collect()
+    }
+
+    #[test]
+    fn test_markdown_keeps_structure() {
+        let source = "# Title\n\nSome *emphasis*\nacross lines.\n\n- one\n- two\n\n> Quoted\n\n```\nlet x = 1;\n```\n";
+        let document = Document::parse(DocumentFormat::Markdown, source.as_bytes()).unwrap();
+
+        assert_eq!(
+            kinds(&document),
+            vec![
+                BlockKind::Heading { level: 1 },
+                BlockKind::Paragraph,
+                BlockKind::ListItem,
+                BlockKind::ListItem,
+                BlockKind::Quote,
+                BlockKind::Code,
+            ]
+        );
+        assert_eq!(
+            document.text(),
+            "Title\n\nSome emphasis across lines.\n\n- one\n- two\n\nQuoted\n\nlet x = 1;"
+        );
+    }
+
+    #[test]
+    fn test_html_skips_scripts_and_nests_paragraphs() {
+        let source = "<html><head><title>T</title><style>p{}</style></head><body>\
+            <h2>Intro</h2><p>First   line<br>second line</p>\
+            <ul><li><p>Item</p></li></ul><script>alert(1)</script></body></html>";
+        let document = Document::parse(DocumentFormat::Html, source.as_bytes()).unwrap();
+
+        assert_eq!(
+            kinds(&document),
+            vec![
+                BlockKind::Heading { level: 2 },
+                BlockKind::Paragraph,
+                BlockKind::ListItem,
+            ]
+        );
+        assert_eq!(document.blocks[1].text, "First line\nsecond line");
+        assert!(!document.text().contains("alert"));
+    }
+
+    fn docx(body: &str) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("word/document.xml", zip::write::FileOptions::default())
+            .unwrap();
+        write!(
+            zip,
+            r#"<?xml version="1.0"?><w:document xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main"><w:body>{}</w:body></w:document>"#,
+            body
+        )
+        .unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_docx_headings_and_lists() {
+        let bytes = docx(concat!(
+            r#"<w:p><w:pPr><w:pStyle w:val="Heading2"/></w:pPr><w:r><w:t>Plan</w:t></w:r></w:p>"#,
+            r#"<w:p><w:r><w:t xml:space="preserve">Fish </w:t></w:r><w:r><w:t>&amp; chips</w:t></w:r></w:p>"#,
+            r#"<w:p><w:pPr><w:numPr><w:ilvl w:val="0"/></w:numPr></w:pPr><w:r><w:t>Step</w:t></w:r></w:p>"#,
+            r#"<w:p></w:p>"#,
+        ));
+        let document = Document::parse(DocumentFormat::Docx, &bytes).unwrap();
+
+        assert_eq!(document.text(), "Plan\n\nFish & chips\n\n- Step");
+        assert_eq!(document.blocks[0].kind, BlockKind::Heading { level: 2 });
+        assert!(Document::parse(DocumentFormat::Docx, b"not a zip").is_err());
+    }
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(
+            DocumentFormat::detect("Notes.MD", None).unwrap(),
+            DocumentFormat::Markdown
+        );
+        assert_eq!(
+            DocumentFormat::detect("upload", Some("text/html; charset=utf-8")).unwrap(),
+            DocumentFormat::Html
+        );
+        assert!(matches!(
+            DocumentFormat::detect("slides.pdf", Some("application/pdf")),
+            Err(AppError::UnsupportedMediaType(_))
+        ));
+    }
+
+    const BOUNDARY: &str = "test-boundary";
+
+    fn multipart_body(filename: &str, contents: &str, fields: &[(&str, &str)]) -> Body {
+        let mut body = String::new();
+        for (name, value) in fields {
+            body.push_str(&format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                BOUNDARY, name, value
+            ));
+        }
+        body.push_str(&format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n{}\r\n--{}--\r\n",
+            BOUNDARY, filename, contents, BOUNDARY
+        ));
+        Body::from(body)
+    }
+
+    async fn upload(state: &AppState, uri: &str, body: Body) -> (StatusCode, Value) {
+        let request = authed_request(&state.config, "alice")
+            .method("POST")
+            .uri(uri)
+            .header(
+                "content-type",
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            )
+            .header("accept", "application/json")
+            .body(body)
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn test_extract_upload() {
+        let state = test_state(test_config("http://127.0.0.1:9"));
+
+        let body = multipart_body("notes.md", "# Notes\n\nHello.", &[]);
+        let (status, body) = upload(&state, "/api/documents/extract", body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["format"], "markdown");
+        assert_eq!(body["text"], "Notes\n\nHello.");
+        assert_eq!(body["blocks"][0]["type"], "heading");
+
+        let large = "a".repeat(state.config.uploads.max_bytes + 1);
+        let body = multipart_body("large.txt", &large, &[]);
+        let (status, _) = upload(&state, "/api/documents/extract", body).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_process_upload_runs_operation() {
+        let mock = spawn_mock_openai(|_| vec!["A summary.".to_string()]).await;
+        let state = test_state(test_config(&mock.base_url));
+
+        let body = multipart_body(
+            "page.html",
+            "<h1>Report</h1><p>Long findings.</p>",
+            &[("op", "summarize"), ("length", "short")],
+        );
+        let (status, body) = upload(&state, "/api/documents/process", body).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["result"], "A summary.");
+        let prompt = last_user_message(&mock.requests()[0]);
+        assert!(prompt.ends_with("Report\n\nLong findings."));
+        assert!(prompt.contains("brief"));
+    }
+}
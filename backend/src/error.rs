@@ -1,4 +1,4 @@
-use axum::http::StatusCode;
+use axum::http::{header, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::Json;
 use serde_json::json;
@@ -9,6 +9,9 @@ pub enum AppError {
     #[error("Authentication error: {0}")]
     Auth(String),
 
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
     // Mainly used in tests - for handling invalid client requests
     #[error("Invalid request: {0}")]
     #[allow(dead_code)]
@@ -19,6 +22,19 @@ pub enum AppError {
     #[allow(dead_code)]
     NotFound(String),
 
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+
+    #[error("Unsupported media type: {0}")]
+    UnsupportedMediaType(String),
+
+    // With the seconds to wait before trying again
+    #[error("Too many requests: {0}")]
+    RateLimited(String, u64),
+
     #[error("Internal server error: {0}")]
     Internal(String),
 
@@ -38,8 +54,13 @@ impl AppError {
     pub fn status_code(&self) -> StatusCode {
         match self {
             AppError::Auth(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
             AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
             AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::RateLimited(..) => StatusCode::TOO_MANY_REQUESTS,
             AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::OpenAI(_) => StatusCode::BAD_GATEWAY,
             AppError::Config(_) => StatusCode::INTERNAL_SERVER_ERROR,
@@ -58,7 +79,13 @@ impl IntoResponse for AppError {
             }
         }));
 
-        (status, body).into_response()
+        let mut response = (status, body).into_response();
+        if let AppError::RateLimited(_, retry_after) = self {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
     }
 }
 
@@ -82,6 +109,10 @@ mod tests {
             AppError::Auth("test".to_string()).status_code(),
             StatusCode::UNAUTHORIZED
         );
+        assert_eq!(
+            AppError::Forbidden("test".to_string()).status_code(),
+            StatusCode::FORBIDDEN
+        );
         assert_eq!(
             AppError::BadRequest("test".to_string()).status_code(),
             StatusCode::BAD_REQUEST
@@ -90,6 +121,22 @@ mod tests {
             AppError::NotFound("test".to_string()).status_code(),
             StatusCode::NOT_FOUND
         );
+        assert_eq!(
+            AppError::Conflict("test".to_string()).status_code(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            AppError::PayloadTooLarge("test".to_string()).status_code(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            AppError::UnsupportedMediaType("test".to_string()).status_code(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(
+            AppError::RateLimited("test".to_string(), 1).status_code(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
         assert_eq!(
             AppError::Internal("test".to_string()).status_code(),
             StatusCode::INTERNAL_SERVER_ERROR
@@ -110,7 +157,7 @@ mod tests {
 
     #[test]
     fn test_internal_error_conversion() {
-        let std_error = std::io::Error::new(std::io::ErrorKind::Other, "test error");
+        let std_error = std::io::Error::other("test error");
         let app_error = internal_error(std_error);
 
         match app_error {
@@ -0,0 +1,545 @@
+use std::io::{Cursor, Write};
+
+use axum::extract::State;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use chrono::{DateTime, Utc};
+use quick_xml::escape::escape;
+use serde::Deserialize;
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+use crate::documents::{Block, BlockKind, Document, DocumentFormat, DOCX_CONTENT_TYPE};
+use crate::error::AppError;
+use crate::jobs::ItemStatus;
+use crate::models::Claims;
+use crate::state::AppState;
+
+const HTML_STYLE: &str = "body { font-family: Georgia, serif; line-height: 1.5; max-width: 42em; margin: 2em auto; padding: 0 1em; color: #222; }
+pre { white-space: pre-wrap; font-family: Menlo, Consolas, monospace; background: #f5f5f5; padding: 0.75em; }
+blockquote { border-left: 3px solid #ccc; margin-left: 0; padding-left: 1em; color: #555; }
+.metadata { color: #666; font-size: 0.9em; border-bottom: 1px solid #ddd; padding-bottom: 1em; }
+.metadata dt { float: left; clear: left; font-weight: bold; margin-right: 0.5em; }
+.metadata dd { margin: 0; }
+@page { margin: 2cm; }
+@media print { body { margin: 0; max-width: none; } pre { background: none; border: 1px solid #ccc; } }";
+
+const DOCX_CONTENT_TYPES: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types"><Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/><Default Extension="xml" ContentType="application/xml"/><Override PartName="/word/document.xml" ContentType="application/vnd.openxmlformats-officedocument.wordprocessingml.document.main+xml"/><Override PartName="/word/styles.xml" ContentType="application/vnd.openxmlformats-officedocument.wordprocessingml.styles+xml"/><Override PartName="/word/numbering.xml" ContentType="application/vnd.openxmlformats-officedocument.wordprocessingml.numbering+xml"/></Types>"#;
+
+const DOCX_RELS: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="word/document.xml"/></Relationships>"#;
+
+const DOCX_DOCUMENT_RELS: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/styles" Target="styles.xml"/><Relationship Id="rId2" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/numbering" Target="numbering.xml"/></Relationships>"#;
+
+const DOCX_NUMBERING: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<w:numbering xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main"><w:abstractNum w:abstractNumId="0"><w:lvl w:ilvl="0"><w:start w:val="1"/><w:numFmt w:val="bullet"/><w:lvlText w:val="•"/><w:lvlJc w:val="left"/><w:pPr><w:ind w:left="720" w:hanging="360"/></w:pPr></w:lvl></w:abstractNum><w:num w:numId="1"><w:abstractNumId w:val="0"/></w:num></w:numbering>"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Docx,
+    Markdown,
+    Html,
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Docx => DOCX_CONTENT_TYPE,
+            ExportFormat::Markdown => "text/markdown; charset=utf-8",
+            ExportFormat::Html => "text/html; charset=utf-8",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Docx => "docx",
+            ExportFormat::Markdown => "md",
+            ExportFormat::Html => "html",
+        }
+    }
+}
+
+// A result to export, given as `text` or read from a stored job: one of its
+// texts when `index` is set, otherwise every completed one
+#[derive(Debug, Deserialize)]
+pub struct ExportRequest {
+    pub format: ExportFormat,
+    #[serde(default)]
+    pub text: Option<String>,
+    #[serde(default)]
+    pub job_id: Option<String>,
+    #[serde(default)]
+    pub index: Option<usize>,
+    #[serde(default)]
+    pub title: Option<String>,
+    // Add the operation, model and date at the top of the document
+    #[serde(default)]
+    pub include_metadata: bool,
+    #[serde(default)]
+    pub operation: Option<String>,
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportMetadata {
+    pub operation: Option<String>,
+    pub model: String,
+    pub date: DateTime<Utc>,
+}
+
+impl ExportMetadata {
+    // Labelled values in the order they are shown
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = Vec::new();
+        if let Some(operation) = &self.operation {
+            fields.push(("Operation", operation.clone()));
+        }
+        fields.push(("Model", self.model.clone()));
+        fields.push(("Date", self.date.format("%Y-%m-%d %H:%M UTC").to_string()));
+        fields
+    }
+}
+
+// What goes into an exported file
+#[derive(Debug, Clone)]
+pub struct Export {
+    pub title: Option<String>,
+    pub text: String,
+    pub metadata: Option<ExportMetadata>,
+}
+
+impl Export {
+    fn blocks(&self) -> Result<Vec<Block>, AppError> {
+        Ok(Document::parse(DocumentFormat::Markdown, self.text.as_bytes())?.blocks)
+    }
+
+    // The result as it is, below its title and with the metadata as front matter
+    pub fn markdown(&self) -> String {
+        let mut output = String::new();
+        if let Some(metadata) = &self.metadata {
+            let quote = |value: &str| serde_json::to_string(value).unwrap_or_default();
+            output.push_str("---\n");
+            if let Some(title) = &self.title {
+                output.push_str(&format!("title: {}\n", quote(title)));
+            }
+            if let Some(operation) = &metadata.operation {
+                output.push_str(&format!("operation: {}\n", quote(operation)));
+            }
+            output.push_str(&format!("model: {}\n", quote(&metadata.model)));
+            output.push_str(&format!("date: {}\n", metadata.date.to_rfc3339()));
+            output.push_str("---\n\n");
+        }
+        if let Some(title) = &self.title {
+            output.push_str(&format!("# {}\n\n", title));
+        }
+        output.push_str(self.text.trim_end());
+        output.push('\n');
+        output
+    }
+
+    // A standalone page, styled to print cleanly from a browser
+    pub fn html(&self) -> Result<String, AppError> {
+        let title = self.title.as_deref().unwrap_or("Export");
+        let mut body = String::new();
+        if let Some(title) = &self.title {
+            body.push_str(&format!("<h1>{}</h1>\n", escape(title.as_str())));
+        }
+        if let Some(metadata) = &self.metadata {
+            body.push_str("<dl class=\"metadata\">\n");
+            for (label, value) in metadata.fields() {
+                body.push_str(&format!(
+                    "<dt>{}</dt><dd>{}</dd>\n",
+                    label,
+                    escape(value.as_str())
+                ));
+            }
+            body.push_str("</dl>\n");
+        }
+
+        let mut in_list = false;
+        for block in self.blocks()? {
+            let is_item = block.kind == BlockKind::ListItem;
+            if is_item != in_list {
+                body.push_str(if is_item { "<ul>\n" } else { "</ul>\n" });
+                in_list = is_item;
+            }
+            let text = escape(block.text.as_str());
+            let html = match block.kind {
+                BlockKind::Heading { level } => {
+                    format!("<h{0}>{1}</h{0}>", level, text.replace('\n', "<br>"))
+                }
+                BlockKind::Paragraph => format!("<p>{}</p>", text.replace('\n', "<br>")),
+                BlockKind::ListItem => format!("<li>{}</li>", text.replace('\n', "<br>")),
+                BlockKind::Quote => {
+                    format!("<blockquote>{}</blockquote>", text.replace('\n', "<br>"))
+                }
+                BlockKind::Code => format!("<pre>{}</pre>", text),
+            };
+            body.push_str(&html);
+            body.push('\n');
+        }
+        if in_list {
+            body.push_str("</ul>\n");
+        }
+
+        Ok(format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}\n</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+            escape(title),
+            HTML_STYLE,
+            body
+        ))
+    }
+
+    // A Word document using its built-in heading, quote and list styles
+    pub fn docx(&self) -> Result<Vec<u8>, AppError> {
+        let mut paragraphs = String::new();
+        if let Some(title) = &self.title {
+            paragraphs.push_str(&docx_paragraph(Some("Title"), false, title));
+        }
+        if let Some(metadata) = &self.metadata {
+            for (label, value) in metadata.fields() {
+                let line = format!("{}: {}", label, value);
+                paragraphs.push_str(&docx_paragraph(Some("Subtitle"), false, &line));
+            }
+        }
+        for block in self.blocks()? {
+            let paragraph = match block.kind {
+                BlockKind::Heading { level } => {
+                    let style = format!("Heading{}", level);
+                    docx_paragraph(Some(&style), false, &block.text)
+                }
+                BlockKind::Paragraph => docx_paragraph(None, false, &block.text),
+                BlockKind::ListItem => docx_paragraph(Some("ListBullet"), true, &block.text),
+                BlockKind::Quote => docx_paragraph(Some("Quote"), false, &block.text),
+                BlockKind::Code => docx_paragraph(Some("Code"), false, &block.text),
+            };
+            paragraphs.push_str(&paragraph);
+        }
+
+        let document = format!(
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<w:document xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main"><w:body>{}<w:sectPr><w:pgSz w:w="11906" w:h="16838"/><w:pgMar w:top="1134" w:right="1134" w:bottom="1134" w:left="1134" w:header="708" w:footer="708" w:gutter="0"/></w:sectPr></w:body></w:document>"#,
+            paragraphs
+        );
+
+        let write_error = |e: &dyn std::fmt::Display| {
+            AppError::Internal(format!("Failed to write the DOCX document: {}", e))
+        };
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        for (name, contents) in [
+            ("[Content_Types].xml", DOCX_CONTENT_TYPES),
+            ("_rels/.rels", DOCX_RELS),
+            ("word/_rels/document.xml.rels", DOCX_DOCUMENT_RELS),
+            ("word/styles.xml", &docx_styles()),
+            ("word/numbering.xml", DOCX_NUMBERING),
+            ("word/document.xml", &document),
+        ] {
+            zip.start_file(name, options).map_err(|e| write_error(&e))?;
+            zip.write_all(contents.as_bytes())
+                .map_err(|e| write_error(&e))?;
+        }
+        let cursor = zip.finish().map_err(|e| write_error(&e))?;
+        Ok(cursor.into_inner())
+    }
+}
+
+// A paragraph whose line breaks become `w:br` elements
+fn docx_paragraph(style: Option<&str>, bullet: bool, text: &str) -> String {
+    let mut properties = String::new();
+    if let Some(style) = style {
+        properties.push_str(&format!("<w:pStyle w:val=\"{}\"/>", style));
+    }
+    if bullet {
+        properties.push_str("<w:numPr><w:ilvl w:val=\"0\"/><w:numId w:val=\"1\"/></w:numPr>");
+    }
+
+    let runs = text
+        .split('\n')
+        .map(|line| format!("<w:t xml:space=\"preserve\">{}</w:t>", escape(line)))
+        .collect::<Vec<_>>()
+        .join("<w:br/>");
+    format!(
+        "<w:p><w:pPr>{}</w:pPr><w:r>{}</w:r></w:p>",
+        properties, runs
+    )
+}
+
+fn docx_styles() -> String {
+    let style = |id: &str, name: &str, paragraph: &str, run: &str| {
+        format!(
+            "<w:style w:type=\"paragraph\" w:styleId=\"{0}\"><w:name w:val=\"{1}\"/><w:basedOn w:val=\"Normal\"/><w:next w:val=\"Normal\"/><w:qFormat/><w:pPr>{2}</w:pPr><w:rPr>{3}</w:rPr></w:style>",
+            id, name, paragraph, run
+        )
+    };
+
+    let mut styles = String::from(
+        "<w:style w:type=\"paragraph\" w:default=\"1\" w:styleId=\"Normal\"><w:name w:val=\"Normal\"/><w:qFormat/><w:pPr><w:spacing w:after=\"160\" w:line=\"276\" w:lineRule=\"auto\"/></w:pPr><w:rPr><w:sz w:val=\"22\"/></w:rPr></w:style>",
+    );
+    styles.push_str(&style(
+        "Title",
+        "Title",
+        "<w:spacing w:after=\"240\"/>",
+        "<w:b/><w:sz w:val=\"48\"/>",
+    ));
+    styles.push_str(&style(
+        "Subtitle",
+        "Subtitle",
+        "<w:spacing w:after=\"0\"/>",
+        "<w:color w:val=\"666666\"/><w:sz w:val=\"20\"/>",
+    ));
+    for (level, size) in (1..=6).zip([36, 30, 26, 24, 22, 22]) {
+        styles.push_str(&style(
+            &format!("Heading{}", level),
+            &format!("heading {}", level),
+            &format!("<w:keepNext/><w:spacing w:before=\"240\" w:after=\"120\"/><w:outlineLvl w:val=\"{}\"/>", level - 1),
+            &format!("<w:b/><w:sz w:val=\"{}\"/>", size),
+        ));
+    }
+    styles.push_str(&style(
+        "Quote",
+        "Quote",
+        "<w:ind w:left=\"720\"/>",
+        "<w:i/><w:color w:val=\"555555\"/>",
+    ));
+    styles.push_str(&style(
+        "Code",
+        "Code",
+        "<w:spacing w:after=\"0\" w:line=\"240\" w:lineRule=\"auto\"/>",
+        "<w:rFonts w:ascii=\"Consolas\" w:hAnsi=\"Consolas\"/><w:sz w:val=\"20\"/>",
+    ));
+    styles.push_str(&style(
+        "ListBullet",
+        "List Bullet",
+        "<w:spacing w:after=\"60\"/>",
+        "",
+    ));
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<w:styles xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main">{}</w:styles>"#,
+        styles
+    )
+}
+
+// File name of an export, from its title when there is one
+fn export_filename(title: Option<&str>, format: ExportFormat) -> String {
+    let stem = title
+        .map(|title| {
+            title
+                .split(|c: char| !c.is_alphanumeric())
+                .filter(|word| !word.is_empty())
+                .collect::<Vec<_>>()
+                .join("-")
+                .to_lowercase()
+        })
+        .filter(|stem| !stem.is_empty())
+        .unwrap_or_else(|| "export".to_string());
+    format!("{}.{}", stem, format.extension())
+}
+
+// Gather the text and metadata of an export from the request or a stored job
+fn build_export(
+    state: &AppState,
+    claims: &Claims,
+    request: ExportRequest,
+) -> Result<Export, AppError> {
+    let (text, operation, date) = match (request.text, &request.job_id) {
+        (Some(text), None) => (text, None, Utc::now()),
+        (None, Some(job_id)) => {
+            let (job, items) = state.jobs.results(&claims.sub, job_id)?;
+            let outputs = match request.index {
+                Some(index) => {
+                    let item = items
+                        .into_iter()
+                        .find(|item| item.index == index)
+                        .ok_or_else(|| {
+                            AppError::NotFound(format!("Text {} of job {}", index, job_id))
+                        })?;
+                    match item.output {
+                        Some(output) if item.status == ItemStatus::Completed => vec![output],
+                        _ => {
+                            return Err(AppError::BadRequest(format!(
+                                "Text {} of job {} has no output",
+                                index, job_id
+                            )))
+                        }
+                    }
+                }
+                None => items
+                    .into_iter()
+                    .filter(|item| item.status == ItemStatus::Completed)
+                    .filter_map(|item| item.output)
+                    .collect(),
+            };
+            if outputs.is_empty() {
+                return Err(AppError::BadRequest(format!(
+                    "Job {} has no completed texts",
+                    job_id
+                )));
+            }
+            (
+                outputs.join("\n\n"),
+                Some(job.op),
+                job.finished_at.unwrap_or_else(Utc::now),
+            )
+        }
+        _ => {
+            return Err(AppError::BadRequest(
+                "Either `text` or `job_id` is required".to_string(),
+            ))
+        }
+    };
+
+    let metadata = request.include_metadata.then(|| ExportMetadata {
+        operation: request.operation.or(operation),
+        model: request
+            .model
+            .unwrap_or_else(|| state.config.openai.model.clone()),
+        date,
+    });
+
+    Ok(Export {
+        title: request.title.filter(|title| !title.trim().is_empty()),
+        text,
+        metadata,
+    })
+}
+
+// Download a result as a DOCX, Markdown or standalone HTML file
+pub async fn export_result(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<ExportRequest>,
+) -> Result<Response, AppError> {
+    let format = request.format;
+    let export = build_export(&state, &claims, request)?;
+    let body = match format {
+        ExportFormat::Docx => export.docx()?,
+        ExportFormat::Markdown => export.markdown().into_bytes(),
+        ExportFormat::Html => export.html()?.into_bytes(),
+    };
+
+    let filename = export_filename(export.title.as_deref(), format);
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::create_router;
+    use crate::config::Config;
+    use crate::test_utils::{authed_request, json_body, test_state};
+    use axum::http::StatusCode;
+    use chrono::TimeZone;
+    use tower::ServiceExt;
+
+    fn sample() -> Export {
+        Export {
+            title: Some("Release <notes>".to_string()),
+            text: "## Changes\n\nFaster *exports*.\n\n- One\n- Two\n\n```\nlet a = 1;\nlet b = 2;\n```".to_string(),
+            metadata: Some(ExportMetadata {
+                operation: Some("paraphrase".to_string()),
+                model: "gpt-4o".to_string(),
+                date: Utc.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap(),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_docx_keeps_structure() {
+        let bytes = sample().docx().unwrap();
+        let document = Document::parse(DocumentFormat::Docx, &bytes).unwrap();
+        let kinds: Vec<(BlockKind, &str)> = document
+            .blocks
+            .iter()
+            .map(|block| (block.kind, block.text.as_str()))
+            .collect();
+
+        assert_eq!(
+            kinds,
+            vec![
+                (BlockKind::Heading { level: 1 }, "Release <notes>"),
+                (BlockKind::Paragraph, "Operation: paraphrase"),
+                (BlockKind::Paragraph, "Model: gpt-4o"),
+                (BlockKind::Paragraph, "Date: 2024-05-01 12:30 UTC"),
+                (BlockKind::Heading { level: 2 }, "Changes"),
+                (BlockKind::Paragraph, "Faster exports."),
+                (BlockKind::ListItem, "One"),
+                (BlockKind::ListItem, "Two"),
+                // Reading a DOCX only tells headings and lists apart
+                (BlockKind::Paragraph, "let a = 1;\nlet b = 2;"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_html_and_markdown_exports() {
+        let html = sample().html().unwrap();
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<title>Release &lt;notes&gt;</title>"));
+        assert!(html.contains("<dt>Model</dt><dd>gpt-4o</dd>"));
+        assert!(html.contains("<ul>\n<li>One</li>\n<li>Two</li>\n</ul>"));
+        assert!(html.contains("@media print"));
+
+        let markdown = sample().markdown();
+        assert!(
+            markdown.starts_with("---\ntitle: \"Release <notes>\"\noperation: \"paraphrase\"\n")
+        );
+        assert!(markdown
+            .contains("date: 2024-05-01T12:30:00+00:00\n---\n\n# Release <notes>\n\n## Changes"));
+        assert!(markdown.ends_with("```\n"));
+    }
+
+    #[tokio::test]
+    async fn test_export_endpoint() {
+        let config = Config::default_test_config();
+        let state = test_state(config.clone());
+
+        let request = authed_request(&config, "alice")
+            .method("POST")
+            .uri("/api/export")
+            .header("content-type", "application/json")
+            .body(json_body(serde_json::json!({
+                "format": "markdown",
+                "text": "Hello.",
+                "title": "My draft",
+            })))
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_DISPOSITION],
+            "attachment; filename=\"my-draft.md\""
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"# My draft\n\nHello.\n");
+
+        // Unknown jobs are not found
+        let request = authed_request(&config, "alice")
+            .method("POST")
+            .uri("/api/export")
+            .header("content-type", "application/json")
+            .body(json_body(
+                serde_json::json!({"format": "docx", "job_id": "missing"}),
+            ))
+            .unwrap();
+        let response = create_router(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
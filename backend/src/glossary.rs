@@ -0,0 +1,570 @@
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use rusqlite::{params, Connection, Row};
+use serde::{Deserialize, Serialize};
+
+use crate::db::{from_column, Database};
+use crate::error::AppError;
+use crate::languages::LanguageTag;
+use crate::models::Claims;
+use crate::state::AppState;
+
+// Glossary entries and do-not-translate terms a user may keep
+const MAX_ENTRIES: usize = 1000;
+const MAX_DO_NOT_TRANSLATE: usize = 200;
+
+// Preferred translation of a term for a language pair
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct GlossaryEntry {
+    pub id: u64,
+    pub source_term: String,
+    pub target_term: String,
+    pub source_language: LanguageTag,
+    pub target_language: LanguageTag,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewGlossaryEntry {
+    pub source_term: String,
+    pub target_term: String,
+    pub source_language: LanguageTag,
+    pub target_language: LanguageTag,
+}
+
+// Term that must appear verbatim in every translation, e.g. a product name
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DoNotTranslateTerm {
+    pub id: u64,
+    pub term: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewDoNotTranslateTerm {
+    pub term: String,
+}
+
+// Glossary entries and do-not-translate terms that apply to one translation
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GlossaryConstraints {
+    pub entries: Vec<GlossaryEntry>,
+    pub do_not_translate: Vec<DoNotTranslateTerm>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum GlossaryViolation {
+    Glossary {
+        source_term: String,
+        expected: String,
+    },
+    DoNotTranslate {
+        term: String,
+    },
+}
+
+// Outcome of checking a translation against its constraints
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct GlossaryReport {
+    pub applied_entries: usize,
+    pub applied_do_not_translate: usize,
+    pub violations: Vec<GlossaryViolation>,
+}
+
+fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(&needle.to_lowercase())
+}
+
+// Refuse to add to a user's `table` once it holds `limit` rows
+fn count_below(
+    connection: &Connection,
+    table: &str,
+    owner: &str,
+    limit: usize,
+    what: &str,
+) -> Result<(), AppError> {
+    let count: i64 = connection.query_row(
+        &format!("SELECT COUNT(*) FROM {} WHERE owner = ?1", table),
+        [owner],
+        |row| row.get(0),
+    )?;
+    if count as usize >= limit {
+        return Err(AppError::BadRequest(format!(
+            "At most {} {} can be added",
+            limit, what
+        )));
+    }
+    Ok(())
+}
+
+fn require_term(term: &str, field: &str) -> Result<String, AppError> {
+    let term = term.trim();
+    if term.is_empty() {
+        return Err(AppError::BadRequest(format!("{} must not be empty", field)));
+    }
+    Ok(term.to_string())
+}
+
+impl GlossaryConstraints {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.do_not_translate.is_empty()
+    }
+
+    // Extra prompt instructions, empty when nothing applies
+    pub fn instructions(&self) -> String {
+        let mut instructions = String::new();
+
+        if !self.entries.is_empty() {
+            instructions.push_str("\n\nAlways translate these terms exactly as given:");
+            for entry in &self.entries {
+                instructions.push_str(&format!(
+                    "\n- \"{}\" -> \"{}\"",
+                    entry.source_term, entry.target_term
+                ));
+            }
+        }
+
+        if !self.do_not_translate.is_empty() {
+            instructions
+                .push_str("\n\nKeep these terms exactly as written, do not translate them:");
+            for term in &self.do_not_translate {
+                instructions.push_str(&format!("\n- \"{}\"", term.term));
+            }
+        }
+
+        instructions
+    }
+
+    // Check a finished translation against the constraints
+    pub fn check(&self, output: &str) -> GlossaryReport {
+        let glossary = self
+            .entries
+            .iter()
+            .filter(|entry| !contains_ignore_case(output, &entry.target_term))
+            .map(|entry| GlossaryViolation::Glossary {
+                source_term: entry.source_term.clone(),
+                expected: entry.target_term.clone(),
+            });
+
+        let do_not_translate = self
+            .do_not_translate
+            .iter()
+            .filter(|term| !output.contains(&term.term))
+            .map(|term| GlossaryViolation::DoNotTranslate {
+                term: term.term.clone(),
+            });
+
+        GlossaryReport {
+            applied_entries: self.entries.len(),
+            applied_do_not_translate: self.do_not_translate.len(),
+            violations: glossary.chain(do_not_translate).collect(),
+        }
+    }
+}
+
+const ENTRY_COLUMNS: &str = "id, source_term, target_term, source_language, target_language";
+
+fn entry_from_row(row: &Row) -> rusqlite::Result<GlossaryEntry> {
+    Ok(GlossaryEntry {
+        id: row.get::<_, i64>(0)? as u64,
+        source_term: row.get(1)?,
+        target_term: row.get(2)?,
+        source_language: from_column(row.get(3)?)?,
+        target_language: from_column(row.get(4)?)?,
+    })
+}
+
+fn term_from_row(row: &Row) -> rusqlite::Result<DoNotTranslateTerm> {
+    Ok(DoNotTranslateTerm {
+        id: row.get::<_, i64>(0)? as u64,
+        term: row.get(1)?,
+    })
+}
+
+// Glossaries of every user, kept in the database so they outlive a restart
+pub struct GlossaryStore {
+    database: Arc<Database>,
+}
+
+impl GlossaryStore {
+    pub fn new(database: Arc<Database>) -> Self {
+        GlossaryStore { database }
+    }
+
+    pub fn entries(&self, username: &str) -> Result<Vec<GlossaryEntry>, AppError> {
+        self.database.with(|connection| {
+            let mut statement = connection.prepare(&format!(
+                "SELECT {} FROM glossary_entries WHERE owner = ?1 ORDER BY id",
+                ENTRY_COLUMNS
+            ))?;
+            let entries = statement
+                .query_map([username], entry_from_row)?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(entries)
+        })
+    }
+
+    pub fn do_not_translate(&self, username: &str) -> Result<Vec<DoNotTranslateTerm>, AppError> {
+        self.database.with(|connection| {
+            let mut statement = connection.prepare(
+                "SELECT id, term FROM do_not_translate_terms WHERE owner = ?1 ORDER BY id",
+            )?;
+            let terms = statement
+                .query_map([username], term_from_row)?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(terms)
+        })
+    }
+
+    pub fn add_entry(
+        &self,
+        username: &str,
+        entry: NewGlossaryEntry,
+    ) -> Result<GlossaryEntry, AppError> {
+        let source_term = require_term(&entry.source_term, "source_term")?;
+        let target_term = require_term(&entry.target_term, "target_term")?;
+
+        let id = self.database.with(|connection| {
+            count_below(
+                connection,
+                "glossary_entries",
+                username,
+                MAX_ENTRIES,
+                "glossary entries",
+            )?;
+            connection.execute(
+                "INSERT INTO glossary_entries
+                 (owner, source_term, target_term, source_language, target_language)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    username,
+                    source_term,
+                    target_term,
+                    entry.source_language.as_str(),
+                    entry.target_language.as_str(),
+                ],
+            )?;
+            Ok(connection.last_insert_rowid() as u64)
+        })?;
+
+        Ok(GlossaryEntry {
+            id,
+            source_term,
+            target_term,
+            source_language: entry.source_language,
+            target_language: entry.target_language,
+        })
+    }
+
+    pub fn add_do_not_translate(
+        &self,
+        username: &str,
+        term: NewDoNotTranslateTerm,
+    ) -> Result<DoNotTranslateTerm, AppError> {
+        let term = require_term(&term.term, "term")?;
+
+        let id = self.database.with(|connection| {
+            count_below(
+                connection,
+                "do_not_translate_terms",
+                username,
+                MAX_DO_NOT_TRANSLATE,
+                "do-not-translate terms",
+            )?;
+            connection.execute(
+                "INSERT INTO do_not_translate_terms (owner, term) VALUES (?1, ?2)",
+                params![username, term],
+            )?;
+            Ok(connection.last_insert_rowid() as u64)
+        })?;
+
+        Ok(DoNotTranslateTerm { id, term })
+    }
+
+    pub fn remove_entry(&self, username: &str, id: u64) -> Result<(), AppError> {
+        let deleted = self.database.with(|connection| {
+            Ok(connection.execute(
+                "DELETE FROM glossary_entries WHERE id = ?1 AND owner = ?2",
+                params![id as i64, username],
+            )?)
+        })?;
+        if deleted == 0 {
+            return Err(AppError::NotFound(format!("Glossary entry {}", id)));
+        }
+        Ok(())
+    }
+
+    pub fn remove_do_not_translate(&self, username: &str, id: u64) -> Result<(), AppError> {
+        let deleted = self.database.with(|connection| {
+            Ok(connection.execute(
+                "DELETE FROM do_not_translate_terms WHERE id = ?1 AND owner = ?2",
+                params![id as i64, username],
+            )?)
+        })?;
+        if deleted == 0 {
+            return Err(AppError::NotFound(format!("Do-not-translate term {}", id)));
+        }
+        Ok(())
+    }
+
+    // Select the entries and terms relevant to translating `text` between the
+    // given languages. Entries match on the primary language subtags, and
+    // only apply when the source language is known.
+    pub fn constraints_for(
+        &self,
+        username: &str,
+        text: &str,
+        source_language: Option<&LanguageTag>,
+        target_language: &LanguageTag,
+    ) -> Result<GlossaryConstraints, AppError> {
+        let entries = self
+            .entries(username)?
+            .into_iter()
+            .filter(|e| e.target_language.primary() == target_language.primary())
+            .filter(|e| source_language.is_some_and(|s| e.source_language.primary() == s.primary()))
+            .filter(|e| contains_ignore_case(text, &e.source_term))
+            .collect();
+
+        let do_not_translate = self
+            .do_not_translate(username)?
+            .into_iter()
+            .filter(|t| text.contains(&t.term))
+            .collect();
+
+        Ok(GlossaryConstraints {
+            entries,
+            do_not_translate,
+        })
+    }
+}
+
+// List the current user's glossary entries
+pub async fn list_entries(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<GlossaryEntry>>, AppError> {
+    Ok(Json(state.glossary.entries(&claims.sub)?))
+}
+
+// Add a glossary entry
+pub async fn create_entry(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(entry): Json<NewGlossaryEntry>,
+) -> Result<(StatusCode, Json<GlossaryEntry>), AppError> {
+    let entry = state.glossary.add_entry(&claims.sub, entry)?;
+    Ok((StatusCode::CREATED, Json(entry)))
+}
+
+// Delete a glossary entry
+pub async fn delete_entry(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<u64>,
+) -> Result<StatusCode, AppError> {
+    state.glossary.remove_entry(&claims.sub, id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// List the current user's do-not-translate terms
+pub async fn list_do_not_translate(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<DoNotTranslateTerm>>, AppError> {
+    Ok(Json(state.glossary.do_not_translate(&claims.sub)?))
+}
+
+// Add a do-not-translate term
+pub async fn create_do_not_translate(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(term): Json<NewDoNotTranslateTerm>,
+) -> Result<(StatusCode, Json<DoNotTranslateTerm>), AppError> {
+    let term = state.glossary.add_do_not_translate(&claims.sub, term)?;
+    Ok((StatusCode::CREATED, Json(term)))
+}
+
+// Delete a do-not-translate term
+pub async fn delete_do_not_translate(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<u64>,
+) -> Result<StatusCode, AppError> {
+    state.glossary.remove_do_not_translate(&claims.sub, id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn tag(s: &str) -> LanguageTag {
+        LanguageTag::parse(s).unwrap()
+    }
+
+    fn store() -> GlossaryStore {
+        let config = Config::default_test_config();
+        GlossaryStore::new(Arc::new(Database::open(&config.database).unwrap()))
+    }
+
+    fn store_with_terms() -> GlossaryStore {
+        let store = store();
+        store
+            .add_entry(
+                "alice",
+                NewGlossaryEntry {
+                    source_term: "sign in".to_string(),
+                    target_term: "iniciar sesión".to_string(),
+                    source_language: tag("en"),
+                    target_language: tag("es"),
+                },
+            )
+            .unwrap();
+        store
+            .add_entry(
+                "alice",
+                NewGlossaryEntry {
+                    source_term: "dashboard".to_string(),
+                    target_term: "Armaturenbrett".to_string(),
+                    source_language: tag("en"),
+                    target_language: tag("de"),
+                },
+            )
+            .unwrap();
+        store
+            .add_do_not_translate(
+                "alice",
+                NewDoNotTranslateTerm {
+                    term: "Acme Cloud".to_string(),
+                },
+            )
+            .unwrap();
+        store
+    }
+
+    #[test]
+    fn test_constraints_match_language_pair_and_text() {
+        let store = store_with_terms();
+        let text = "Sign in to Acme Cloud to see the dashboard.";
+
+        let constraints = store
+            .constraints_for("alice", text, Some(&tag("en")), &tag("es-MX"))
+            .unwrap();
+        assert_eq!(constraints.entries.len(), 1);
+        assert_eq!(constraints.entries[0].target_term, "iniciar sesión");
+        assert_eq!(constraints.do_not_translate.len(), 1);
+
+        let constraints = store
+            .constraints_for("bob", text, None, &tag("es"))
+            .unwrap();
+        assert!(constraints.is_empty());
+
+        // Without a known source language only do-not-translate terms apply
+        let constraints = store
+            .constraints_for("alice", text, None, &tag("es"))
+            .unwrap();
+        assert!(constraints.entries.is_empty());
+        assert_eq!(constraints.do_not_translate.len(), 1);
+    }
+
+    #[test]
+    fn test_instructions_list_terms() {
+        let store = store_with_terms();
+        let constraints = store
+            .constraints_for(
+                "alice",
+                "Sign in to Acme Cloud",
+                Some(&tag("en")),
+                &tag("es"),
+            )
+            .unwrap();
+        let instructions = constraints.instructions();
+
+        assert!(instructions.contains("\"sign in\" -> \"iniciar sesión\""));
+        assert!(instructions.contains("\"Acme Cloud\""));
+        assert_eq!(GlossaryConstraints::default().instructions(), "");
+    }
+
+    #[test]
+    fn test_check_reports_violations() {
+        let store = store_with_terms();
+        let constraints = store
+            .constraints_for(
+                "alice",
+                "Sign in to Acme Cloud",
+                Some(&tag("en")),
+                &tag("es"),
+            )
+            .unwrap();
+
+        let report = constraints.check("Inicia sesión en Acme Nube");
+        assert_eq!(
+            report.violations,
+            vec![
+                GlossaryViolation::Glossary {
+                    source_term: "sign in".to_string(),
+                    expected: "iniciar sesión".to_string(),
+                },
+                GlossaryViolation::DoNotTranslate {
+                    term: "Acme Cloud".to_string(),
+                },
+            ]
+        );
+
+        let report = constraints.check("Iniciar sesión en Acme Cloud");
+        assert!(report.violations.is_empty());
+    }
+
+    #[test]
+    fn test_glossaries_outlive_the_store() {
+        let dir = std::env::temp_dir().join(format!("glossary-test-{}", rand::random::<u64>()));
+        let config = crate::config::DatabaseConfig {
+            url: format!("sqlite://{}", dir.join("app.db").display()),
+        };
+        let open = || GlossaryStore::new(Arc::new(Database::open(&config).unwrap()));
+
+        let entry = NewGlossaryEntry {
+            source_term: "sign in".to_string(),
+            target_term: "iniciar sesión".to_string(),
+            source_language: tag("en"),
+            target_language: tag("es"),
+        };
+        let added = open().add_entry("alice", entry).unwrap();
+        assert_eq!(open().entries("alice").unwrap(), vec![added]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_terms_per_user_are_limited() {
+        let store = store();
+        let term = || NewDoNotTranslateTerm {
+            term: "Acme".to_string(),
+        };
+        for _ in 0..MAX_DO_NOT_TRANSLATE {
+            store.add_do_not_translate("alice", term()).unwrap();
+        }
+
+        assert!(matches!(
+            store.add_do_not_translate("alice", term()),
+            Err(AppError::BadRequest(_))
+        ));
+        store.add_do_not_translate("bob", term()).unwrap();
+    }
+
+    #[test]
+    fn test_remove_entries() {
+        let store = store_with_terms();
+        let id = store.entries("alice").unwrap()[0].id;
+
+        store.remove_entry("alice", id).unwrap();
+        assert_eq!(store.entries("alice").unwrap().len(), 1);
+        assert!(matches!(
+            store.remove_entry("alice", id),
+            Err(AppError::NotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,597 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use chrono::{DateTime, Utc};
+use rusqlite::types::ToSql;
+use rusqlite::{params, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use crate::db::{as_column, from_column, Database};
+use crate::error::AppError;
+use crate::models::{Claims, TokenUsage};
+use crate::openai::{process_text_with_openai, ResponseFormat};
+use crate::pipeline::{PipelineStep, StepRequest};
+use crate::proofread::proofread_and_record;
+use crate::ratelimit::{ClientAddress, StreamPermit};
+use crate::state::AppState;
+
+const PREVIEW_CHARS: usize = 200;
+const DEFAULT_PER_PAGE: usize = 20;
+const MAX_PER_PAGE: usize = 100;
+
+// How an operation was started
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntrySource {
+    // A call to the operation's own endpoint
+    Request,
+    // A step of a pipeline
+    Pipeline,
+    // A past entry run again
+    Rerun,
+    // A text of a batch job
+    Job,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryStatus {
+    Completed,
+    Failed,
+}
+
+// The first characters of a text
+fn preview(text: &str) -> String {
+    match text.char_indices().nth(PREVIEW_CHARS) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryEntry {
+    pub id: String,
+    pub operation: String,
+    pub source: EntrySource,
+    pub parameters: Value,
+    pub input_hash: String,
+    pub input_preview: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_preview: Option<String>,
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TokenUsage>,
+    pub latency_ms: u64,
+    pub status: EntryStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+// An entry with its full input and output, to re-open a past result
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryDetail {
+    #[serde(flatten)]
+    pub entry: HistoryEntry,
+    pub input: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+}
+
+// An operation being run, recorded once it finishes
+pub struct PendingEntry {
+    owner: String,
+    operation: String,
+    source: EntrySource,
+    parameters: Value,
+    input: String,
+    model: Option<String>,
+    started: Instant,
+    created_at: DateTime<Utc>,
+    // Counts the operation against the user's open streams until it is recorded
+    permit: Option<StreamPermit>,
+}
+
+impl PendingEntry {
+    // Start recording a request, whose `text` is the input and whose other
+    // fields are the parameters
+    pub fn new(
+        claims: &Claims,
+        operation: &str,
+        source: EntrySource,
+        request: &impl Serialize,
+    ) -> Self {
+        let mut parameters = match serde_json::to_value(request) {
+            Ok(Value::Object(fields)) => fields,
+            _ => Map::new(),
+        };
+        let input = match parameters.remove("text") {
+            Some(Value::String(text)) => text,
+            _ => String::new(),
+        };
+        parameters.retain(|_, value| !value.is_null());
+
+        PendingEntry {
+            owner: claims.sub.clone(),
+            operation: operation.to_string(),
+            source,
+            parameters: Value::Object(parameters),
+            input,
+            model: None,
+            started: Instant::now(),
+            created_at: Utc::now(),
+            permit: None,
+        }
+    }
+
+    // Charge the operation to the user's and the client's rate limits, holding
+    // one of the user's open streams until the operation ends. Rejections are
+    // not recorded, so a client flooding the server costs no writes.
+    pub fn admit(mut self, state: &AppState, address: Option<IpAddr>) -> Result<Self, AppError> {
+        self.permit = Some(state.limits.acquire(&self.owner, address)?);
+        Ok(self)
+    }
+
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    // The model the operation was sent to, when it is known
+    pub fn with_model(mut self, model: Option<&str>) -> Self {
+        self.model = model.map(str::to_string);
+        self
+    }
+
+    // Record the outcome. Failing to record never fails the operation itself.
+    pub fn finish(self, state: &AppState, result: Result<(&str, TokenUsage), &AppError>) {
+        self.record(state, result);
+    }
+
+    // Record an operation rejected before it ran, handing the error back
+    pub fn fail(&self, state: &AppState, error: AppError) -> AppError {
+        self.record(state, Err(&error));
+        error
+    }
+
+    fn record(&self, state: &AppState, result: Result<(&str, TokenUsage), &AppError>) {
+        let (output, usage, status, error) = match result {
+            Ok((output, usage)) => (Some(output), Some(usage), EntryStatus::Completed, None),
+            Err(e) => (None, None, EntryStatus::Failed, Some(e.to_string())),
+        };
+
+        let entry = HistoryDetail {
+            entry: HistoryEntry {
+                id: format!("{:032x}", rand::random::<u128>()),
+                operation: self.operation.clone(),
+                source: self.source,
+                parameters: self.parameters.clone(),
+                input_hash: hex::encode(Sha256::digest(self.input.as_bytes())),
+                input_preview: preview(&self.input),
+                output_preview: output.map(preview),
+                model: self
+                    .model
+                    .clone()
+                    .unwrap_or_else(|| state.config.openai.model.clone()),
+                usage,
+                latency_ms: self.started.elapsed().as_millis() as u64,
+                status,
+                error,
+                created_at: self.created_at,
+            },
+            input: self.input.clone(),
+            output: output.map(str::to_string),
+        };
+
+        if let Err(e) = state.history.insert(&self.owner, &entry) {
+            error!("Failed to record operation {}: {}", entry.entry.id, e);
+        }
+    }
+}
+
+// Filters and page of a history listing
+#[derive(Debug, Default, Deserialize)]
+pub struct HistoryQuery {
+    #[serde(default)]
+    pub operation: Option<String>,
+    #[serde(default)]
+    pub status: Option<EntryStatus>,
+    #[serde(default)]
+    pub source: Option<EntrySource>,
+    #[serde(default)]
+    pub since: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub until: Option<DateTime<Utc>>,
+    // Text searched for in the input and output
+    #[serde(default)]
+    pub q: Option<String>,
+    #[serde(default)]
+    pub page: Option<usize>,
+    #[serde(default)]
+    pub per_page: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryPage {
+    pub entries: Vec<HistoryEntry>,
+    pub page: usize,
+    pub per_page: usize,
+    pub total: usize,
+}
+
+const ENTRY_COLUMNS: &str = "id, operation, source, parameters, input_hash, input_preview, output, model, prompt_tokens, completion_tokens, latency_ms, status, error, created_at";
+
+fn entry_from_row(row: &Row) -> rusqlite::Result<(HistoryEntry, Option<String>)> {
+    let output: Option<String> = row.get(6)?;
+    let usage = match (row.get::<_, Option<i64>>(8)?, row.get::<_, Option<i64>>(9)?) {
+        (Some(prompt), Some(completion)) => {
+            Some(TokenUsage::new(prompt as usize, completion as usize))
+        }
+        _ => None,
+    };
+    let parameters: String = row.get(3)?;
+
+    let entry = HistoryEntry {
+        id: row.get(0)?,
+        operation: row.get(1)?,
+        source: from_column(row.get(2)?)?,
+        parameters: serde_json::from_str(&parameters).unwrap_or(Value::Null),
+        input_hash: row.get(4)?,
+        input_preview: row.get(5)?,
+        output_preview: output.as_deref().map(preview),
+        model: row.get(7)?,
+        usage,
+        latency_ms: row.get::<_, i64>(10)? as u64,
+        status: from_column(row.get(11)?)?,
+        error: row.get(12)?,
+        created_at: row.get(13)?,
+    };
+    Ok((entry, output))
+}
+
+// Every text operation users ran, with its outcome
+pub struct HistoryStore {
+    database: Arc<Database>,
+}
+
+impl HistoryStore {
+    pub fn new(database: Arc<Database>) -> Self {
+        HistoryStore { database }
+    }
+
+    fn insert(&self, owner: &str, detail: &HistoryDetail) -> Result<(), AppError> {
+        let entry = &detail.entry;
+        self.database.with(|connection| {
+            connection.execute(
+                "INSERT INTO operation_history (id, owner, operation, source, parameters, input, input_hash, input_preview, output, model, prompt_tokens, completion_tokens, latency_ms, status, error, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+                params![
+                    entry.id,
+                    owner,
+                    entry.operation,
+                    as_column(entry.source),
+                    entry.parameters.to_string(),
+                    detail.input,
+                    entry.input_hash,
+                    entry.input_preview,
+                    detail.output,
+                    entry.model,
+                    entry.usage.map(|u| u.prompt_tokens as i64),
+                    entry.usage.map(|u| u.completion_tokens as i64),
+                    entry.latency_ms as i64,
+                    as_column(entry.status),
+                    entry.error,
+                    entry.created_at,
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    // A page of the user's history, newest first
+    pub fn list(&self, owner: &str, query: &HistoryQuery) -> Result<HistoryPage, AppError> {
+        let mut conditions = vec!["owner = ?".to_string()];
+        let mut values: Vec<Box<dyn ToSql>> = vec![Box::new(owner.to_string())];
+        if let Some(operation) = &query.operation {
+            conditions.push("operation = ?".to_string());
+            values.push(Box::new(operation.clone()));
+        }
+        if let Some(status) = query.status {
+            conditions.push("status = ?".to_string());
+            values.push(Box::new(as_column(status)));
+        }
+        if let Some(source) = query.source {
+            conditions.push("source = ?".to_string());
+            values.push(Box::new(as_column(source)));
+        }
+        if let Some(since) = query.since {
+            conditions.push("created_at >= ?".to_string());
+            values.push(Box::new(since));
+        }
+        if let Some(until) = query.until {
+            conditions.push("created_at < ?".to_string());
+            values.push(Box::new(until));
+        }
+        if let Some(q) = query.q.as_deref().filter(|q| !q.trim().is_empty()) {
+            conditions.push(
+                "(instr(lower(input), lower(?)) > 0 OR instr(lower(output), lower(?)) > 0)"
+                    .to_string(),
+            );
+            values.push(Box::new(q.to_string()));
+            values.push(Box::new(q.to_string()));
+        }
+        let filter = conditions.join(" AND ");
+
+        let page = query.page.unwrap_or(1).max(1);
+        let per_page = query
+            .per_page
+            .unwrap_or(DEFAULT_PER_PAGE)
+            .clamp(1, MAX_PER_PAGE);
+
+        self.database.with(|connection| {
+            let values: Vec<&dyn ToSql> = values.iter().map(|v| v.as_ref()).collect();
+            let total: i64 = connection.query_row(
+                &format!("SELECT COUNT(*) FROM operation_history WHERE {}", filter),
+                values.as_slice(),
+                |row| row.get(0),
+            )?;
+
+            let mut statement = connection.prepare(&format!(
+                "SELECT {} FROM operation_history WHERE {} ORDER BY created_at DESC LIMIT {} OFFSET {}",
+                ENTRY_COLUMNS,
+                filter,
+                per_page,
+                (page - 1) * per_page
+            ))?;
+            let entries = statement
+                .query_map(values.as_slice(), |row| entry_from_row(row).map(|(entry, _)| entry))?
+                .collect::<rusqlite::Result<_>>()?;
+
+            Ok(HistoryPage {
+                entries,
+                page,
+                per_page,
+                total: total as usize,
+            })
+        })
+    }
+
+    pub fn get(&self, owner: &str, id: &str) -> Result<HistoryDetail, AppError> {
+        self.database.with(|connection| {
+            connection
+                .query_row(
+                    &format!(
+                        "SELECT {}, input FROM operation_history WHERE id = ?1 AND owner = ?2",
+                        ENTRY_COLUMNS
+                    ),
+                    [id, owner],
+                    |row| {
+                        let (entry, output) = entry_from_row(row)?;
+                        Ok(HistoryDetail {
+                            entry,
+                            input: row.get(14)?,
+                            output,
+                        })
+                    },
+                )
+                .optional()?
+                .ok_or_else(|| AppError::NotFound(format!("History entry {}", id)))
+        })
+    }
+
+    pub fn remove(&self, owner: &str, id: &str) -> Result<(), AppError> {
+        let deleted = self.database.with(|connection| {
+            Ok(connection.execute(
+                "DELETE FROM operation_history WHERE id = ?1 AND owner = ?2",
+                [id, owner],
+            )?)
+        })?;
+        if deleted == 0 {
+            return Err(AppError::NotFound(format!("History entry {}", id)));
+        }
+        Ok(())
+    }
+
+    pub fn clear(&self, owner: &str) -> Result<(), AppError> {
+        self.database.with(|connection| {
+            connection.execute("DELETE FROM operation_history WHERE owner = ?1", [owner])?;
+            Ok(())
+        })
+    }
+}
+
+pub async fn list_history(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<HistoryPage>, AppError> {
+    Ok(Json(state.history.list(&claims.sub, &query)?))
+}
+
+pub async fn get_history_entry(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+) -> Result<Json<HistoryDetail>, AppError> {
+    Ok(Json(state.history.get(&claims.sub, &id)?))
+}
+
+pub async fn delete_history_entry(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    state.history.remove(&claims.sub, &id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn clear_history(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<StatusCode, AppError> {
+    state.history.clear(&claims.sub)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Run a past operation again with the same input and parameters, responding
+// as its own endpoint would
+pub async fn rerun_history_entry(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+    ClientAddress(address): ClientAddress,
+    format: ResponseFormat,
+) -> Result<Response, AppError> {
+    let detail = state.history.get(&claims.sub, &id)?;
+    let mut options = match detail.entry.parameters {
+        Value::Object(options) => options,
+        _ => Map::new(),
+    };
+    let step = PipelineStep {
+        op: detail.entry.operation,
+        options: options.clone(),
+    };
+    options.insert("text".to_string(), Value::String(detail.input.clone()));
+    let entry = PendingEntry::new(&claims, &step.op, EntrySource::Rerun, &options);
+    let request = StepRequest::parse(&step, &detail.input).map_err(|e| entry.fail(&state, e))?;
+    let entry = entry.admit(&state, address)?;
+    match request {
+        StepRequest::Proofread(request) => {
+            let response = proofread_and_record(&state, &claims, &request, entry).await?;
+            Ok(Json(response).into_response())
+        }
+        request => {
+            let generation = request
+                .generation(&state, &claims)
+                .map_err(|e| entry.fail(&state, e))?;
+            process_text_with_openai(state, generation, format, entry).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::create_router;
+    use crate::test_utils::*;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_preview_cuts_long_text() {
+        assert_eq!(preview("Short."), "Short.");
+        let long = "é".repeat(PREVIEW_CHARS + 10);
+        let cut = preview(&long);
+        assert_eq!(cut.chars().count(), PREVIEW_CHARS + 1);
+        assert!(cut.ends_with('…'));
+    }
+
+    async fn get_json(config: &crate::config::Config, state: &AppState, uri: &str) -> Value {
+        let request = authed_request(config, "alice")
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_operations_are_recorded_and_rerun() {
+        let mock = spawn_mock_openai(|_| vec!["A short summary.".to_string()]).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+
+        let request = authed_request(&config, "alice")
+            .uri("/api/text/summarize?text=A%20long%20text&tone=casual")
+            .header("accept", "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        let page = get_json(&config, &state, "/api/history?operation=summarize").await;
+        assert_eq!(page["total"], 1);
+        let entry = &page["entries"][0];
+        assert_eq!(entry["status"], "completed");
+        assert_eq!(entry["source"], "request");
+        assert_eq!(entry["parameters"]["tone"], "casual");
+        assert_eq!(entry["input_preview"], "A long text");
+        assert_eq!(entry["output_preview"], "A short summary.");
+        assert_eq!(entry["model"], config.openai.model);
+        assert!(entry["usage"]["completion_tokens"].as_u64().unwrap() > 0);
+        let id = entry["id"].as_str().unwrap();
+
+        let detail = get_json(&config, &state, &format!("/api/history/{}", id)).await;
+        assert_eq!(detail["output"], "A short summary.");
+
+        let request = authed_request(&config, "alice")
+            .method("POST")
+            .uri(format!("/api/history/{}/rerun", id))
+            .header("accept", "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["result"], "A short summary.");
+        assert!(last_user_message(&mock.requests()[1]).contains("Use a casual tone."));
+
+        let page = get_json(&config, &state, "/api/history?source=rerun").await;
+        assert_eq!(page["total"], 1);
+        let page = get_json(&config, &state, "/api/history?q=LONG&per_page=1&page=2").await;
+        assert_eq!(page["total"], 2);
+        assert_eq!(page["entries"].as_array().unwrap().len(), 1);
+        let page = get_json(&config, &state, "/api/history?status=failed").await;
+        assert_eq!(page["total"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_requests_are_recorded_as_failed() {
+        let mock = spawn_mock_openai(|_| vec!["Unused.".to_string()]).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+
+        let request = authed_request(&config, "alice")
+            .uri("/api/text/paraphrase?text=Hello&temperature=5")
+            .body(Body::empty())
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let page = get_json(&config, &state, "/api/history?status=failed").await;
+        assert_eq!(page["total"], 1);
+        assert_eq!(page["entries"][0]["operation"], "paraphrase");
+        assert!(page["entries"][0]["error"]
+            .as_str()
+            .unwrap()
+            .contains("temperature"));
+        assert!(mock.requests().is_empty());
+
+        // Requests turned away by the rate limits leave no trace
+        let mut config = test_config(&mock.base_url);
+        config.rate_limit.user_per_minute = 1;
+        config.rate_limit.user_burst = 1;
+        let state = test_state(config.clone());
+        for _ in 0..3 {
+            let request = authed_request(&config, "alice")
+                .uri("/api/text/paraphrase?text=Hello")
+                .body(Body::empty())
+                .unwrap();
+            let response = create_router(state.clone()).oneshot(request).await.unwrap();
+            sse_events(response).await;
+        }
+        let page = get_json(&config, &state, "/api/history").await;
+        assert_eq!(page["total"], 1);
+        assert_eq!(page["entries"][0]["status"], "completed");
+    }
+}
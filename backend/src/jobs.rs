@@ -0,0 +1,1171 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use async_openai::{config::OpenAIConfig as ClientConfig, Client};
+use axum::extract::{Path as UrlPath, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::AbortHandle;
+use tracing::{debug, error, info};
+
+use crate::config::JobsConfig;
+use crate::db::{as_column, from_column, Database};
+use crate::error::AppError;
+use crate::history::{EntrySource, PendingEntry};
+use crate::models::{Claims, TokenUsage};
+use crate::openai::create_client;
+use crate::pipeline::{run_step, PipelineStep, StepRequest};
+use crate::proofread::Edit;
+use crate::ratelimit::ClientAddress;
+use crate::state::AppState;
+use crate::webhooks::{notify, WebhookEvent};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    fn is_finished(self) -> bool {
+        matches!(
+            self,
+            JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobProgress {
+    pub total: usize,
+    pub completed: usize,
+    pub failed: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemStatus {
+    Pending,
+    Completed,
+    Failed,
+}
+
+// One text of a job and what became of it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobItem {
+    pub index: usize,
+    pub status: ItemStatus,
+    pub input: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edits: Option<Vec<Edit>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TokenUsage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<u16>,
+}
+
+// What clients see of a job when polling or listing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobSummary {
+    pub id: String,
+    pub op: String,
+    pub status: JobStatus,
+    pub progress: JobProgress,
+    pub usage: TokenUsage,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+type ItemResult = Result<(String, TokenUsage, Option<Vec<Edit>>), AppError>;
+
+// What a runner needs to process the remaining texts of a job
+type PendingWork = (Claims, PipelineStep, Vec<(usize, String)>);
+
+// Suggested wait when a user has too many texts queued, as their jobs take a
+// while to get through them
+const QUEUED_RETRY_SECS: u64 = 60;
+
+const SUMMARY_COLUMNS: &str = "id, op, status, total, completed, failed, prompt_tokens, \
+     completion_tokens, created_at, started_at, finished_at";
+
+fn summary_from_row(row: &Row) -> rusqlite::Result<JobSummary> {
+    Ok(JobSummary {
+        id: row.get(0)?,
+        op: row.get(1)?,
+        status: from_column(row.get(2)?)?,
+        progress: JobProgress {
+            total: row.get::<_, i64>(3)? as usize,
+            completed: row.get::<_, i64>(4)? as usize,
+            failed: row.get::<_, i64>(5)? as usize,
+        },
+        usage: TokenUsage::new(
+            row.get::<_, i64>(6)? as usize,
+            row.get::<_, i64>(7)? as usize,
+        ),
+        created_at: row.get(8)?,
+        started_at: row.get(9)?,
+        finished_at: row.get(10)?,
+    })
+}
+
+const ITEM_COLUMNS: &str =
+    "position, status, input, output, edits, prompt_tokens, completion_tokens, error, code";
+
+fn item_from_row(row: &Row) -> rusqlite::Result<JobItem> {
+    let edits: Option<String> = row.get(4)?;
+    let prompt_tokens: Option<i64> = row.get(5)?;
+    let completion_tokens: Option<i64> = row.get(6)?;
+    Ok(JobItem {
+        index: row.get::<_, i64>(0)? as usize,
+        status: from_column(row.get(1)?)?,
+        input: row.get(2)?,
+        output: row.get(3)?,
+        edits: edits.and_then(|edits| serde_json::from_str(&edits).ok()),
+        usage: prompt_tokens
+            .zip(completion_tokens)
+            .map(|(prompt, completion)| TokenUsage::new(prompt as usize, completion as usize)),
+        error: row.get(7)?,
+        code: row.get(8)?,
+    })
+}
+
+fn find_summary(connection: &Connection, id: &str) -> rusqlite::Result<Option<JobSummary>> {
+    connection
+        .query_row(
+            &format!("SELECT {} FROM jobs WHERE id = ?1", SUMMARY_COLUMNS),
+            [id],
+            summary_from_row,
+        )
+        .optional()
+}
+
+// A job of the given user
+fn find_owned(connection: &Connection, owner: &str, id: &str) -> Result<JobSummary, AppError> {
+    connection
+        .query_row(
+            &format!(
+                "SELECT {} FROM jobs WHERE id = ?1 AND owner = ?2",
+                SUMMARY_COLUMNS
+            ),
+            params![id, owner],
+            summary_from_row,
+        )
+        .optional()?
+        .ok_or_else(|| AppError::NotFound(format!("Job {}", id)))
+}
+
+// Jobs of every user, kept in the database along with the result of every
+// text as soon as it is known
+pub struct JobStore {
+    database: Arc<Database>,
+    // Tasks of the jobs being run, so they can be cancelled
+    runners: Mutex<HashMap<String, AbortHandle>>,
+    // Shared by all jobs to bound how many texts are processed at once
+    workers: Semaphore,
+    // Texts a user may have waiting across their unfinished jobs
+    max_queued: usize,
+}
+
+impl JobStore {
+    // Jobs that were running when the server stopped are queued again
+    pub fn open(config: &JobsConfig, database: Arc<Database>) -> Result<Self, AppError> {
+        let requeued = database.with(|connection| {
+            Ok(connection.execute(
+                "UPDATE jobs SET status = ?1 WHERE status = ?2",
+                params![as_column(JobStatus::Queued), as_column(JobStatus::Running)],
+            )?)
+        })?;
+        if requeued > 0 {
+            info!("Queued {} interrupted jobs again", requeued);
+        }
+
+        Ok(JobStore {
+            database,
+            runners: Mutex::new(HashMap::new()),
+            workers: Semaphore::new(config.workers),
+            max_queued: config.max_queued_texts,
+        })
+    }
+
+    // Queue the texts to be processed by the operation of `step`, unless the
+    // user already has too many waiting
+    pub fn submit(
+        &self,
+        claims: &Claims,
+        step: PipelineStep,
+        texts: Vec<String>,
+    ) -> Result<JobSummary, AppError> {
+        let summary = JobSummary {
+            id: format!("{:032x}", rand::random::<u128>()),
+            op: step.op.clone(),
+            status: JobStatus::Queued,
+            progress: JobProgress {
+                total: texts.len(),
+                ..JobProgress::default()
+            },
+            usage: TokenUsage::new(0, 0),
+            created_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+        };
+        let stored_step = serde_json::to_string(&step)
+            .map_err(|e| AppError::Internal(format!("Failed to serialize job: {}", e)))?;
+
+        self.database.with(|connection| {
+            let transaction = connection.transaction()?;
+            let waiting: i64 = transaction.query_row(
+                "SELECT COALESCE(SUM(total - completed - failed), 0) FROM jobs
+                 WHERE owner = ?1 AND status IN (?2, ?3)",
+                params![
+                    claims.sub,
+                    as_column(JobStatus::Queued),
+                    as_column(JobStatus::Running)
+                ],
+                |row| row.get(0),
+            )?;
+            if waiting as usize + texts.len() > self.max_queued {
+                return Err(AppError::RateLimited(
+                    format!(
+                        "At most {} texts may wait to be processed, {} already are",
+                        self.max_queued, waiting
+                    ),
+                    QUEUED_RETRY_SECS,
+                ));
+            }
+            transaction.execute(
+                "INSERT INTO jobs (id, owner, role, op, step, status, total, completed, failed,
+                 prompt_tokens, completion_tokens, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, 0, 0, 0, 0, ?8)",
+                params![
+                    summary.id,
+                    claims.sub,
+                    as_column(claims.role),
+                    summary.op,
+                    stored_step,
+                    as_column(summary.status),
+                    texts.len() as i64,
+                    summary.created_at,
+                ],
+            )?;
+            {
+                let mut insert = transaction.prepare(
+                    "INSERT INTO job_items (job_id, position, status, input) VALUES (?1, ?2, ?3, ?4)",
+                )?;
+                for (index, input) in texts.iter().enumerate() {
+                    insert.execute(params![
+                        summary.id,
+                        index as i64,
+                        as_column(ItemStatus::Pending),
+                        input,
+                    ])?;
+                }
+            }
+            transaction.commit()?;
+            Ok(())
+        })?;
+
+        Ok(summary)
+    }
+
+    pub fn get(&self, owner: &str, id: &str) -> Result<JobSummary, AppError> {
+        self.database
+            .with(|connection| find_owned(connection, owner, id))
+    }
+
+    pub fn results(&self, owner: &str, id: &str) -> Result<(JobSummary, Vec<JobItem>), AppError> {
+        let summary = self.get(owner, id)?;
+        let items = self.database.with(|connection| {
+            let mut statement = connection.prepare(&format!(
+                "SELECT {} FROM job_items WHERE job_id = ?1 ORDER BY position",
+                ITEM_COLUMNS
+            ))?;
+            let items = statement
+                .query_map([id], item_from_row)?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(items)
+        })?;
+        Ok((summary, items))
+    }
+
+    // The user's jobs, newest first
+    pub fn list(&self, owner: &str) -> Result<Vec<JobSummary>, AppError> {
+        self.database.with(|connection| {
+            let mut statement = connection.prepare(&format!(
+                "SELECT {} FROM jobs WHERE owner = ?1 ORDER BY created_at DESC",
+                SUMMARY_COLUMNS
+            ))?;
+            let summaries = statement
+                .query_map([owner], summary_from_row)?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(summaries)
+        })
+    }
+
+    // Ids of the jobs waiting to run, oldest first
+    pub fn queued(&self) -> Result<Vec<String>, AppError> {
+        self.database.with(|connection| {
+            let mut statement =
+                connection.prepare("SELECT id FROM jobs WHERE status = ?1 ORDER BY created_at")?;
+            let ids = statement
+                .query_map([as_column(JobStatus::Queued)], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(ids)
+        })
+    }
+
+    fn stop_runner(&self, id: &str) {
+        if let Some(runner) = self.runners.lock().ok().and_then(|mut r| r.remove(id)) {
+            runner.abort();
+        }
+    }
+
+    // Stop a job that has not finished. Texts already processed keep their results.
+    pub fn cancel(&self, owner: &str, id: &str) -> Result<JobSummary, AppError> {
+        let summary = self.database.with(|connection| {
+            // Checked in the same transaction as the change, so a job
+            // finishing meanwhile is never turned into a cancelled one
+            let transaction = connection.transaction()?;
+            let job = find_owned(&transaction, owner, id)?;
+            if job.status.is_finished() {
+                return Err(AppError::BadRequest(format!(
+                    "Job {} has already finished",
+                    id
+                )));
+            }
+            transaction.execute(
+                "UPDATE jobs SET status = ?1, finished_at = ?2 WHERE id = ?3",
+                params![as_column(JobStatus::Cancelled), Utc::now(), id],
+            )?;
+            let summary = find_summary(&transaction, id)?
+                .ok_or_else(|| AppError::NotFound(format!("Job {}", id)))?;
+            transaction.commit()?;
+            Ok(summary)
+        })?;
+
+        self.stop_runner(id);
+        Ok(summary)
+    }
+
+    // Delete a job and its results, cancelling it first if needed
+    pub fn remove(&self, owner: &str, id: &str) -> Result<(), AppError> {
+        let deleted = self.database.with(|connection| {
+            Ok(connection.execute(
+                "DELETE FROM jobs WHERE id = ?1 AND owner = ?2",
+                params![id, owner],
+            )?)
+        })?;
+        if deleted == 0 {
+            return Err(AppError::NotFound(format!("Job {}", id)));
+        }
+        // Results still coming in find no job to go to
+        self.stop_runner(id);
+        Ok(())
+    }
+
+    // Mark a queued job as running and hand out what is left to process
+    fn start(&self, id: &str) -> Result<Option<PendingWork>, AppError> {
+        self.database.with(|connection| {
+            let transaction = connection.transaction()?;
+            let started = transaction.execute(
+                "UPDATE jobs SET status = ?1, started_at = COALESCE(started_at, ?2)
+                 WHERE id = ?3 AND status = ?4",
+                params![
+                    as_column(JobStatus::Running),
+                    Utc::now(),
+                    id,
+                    as_column(JobStatus::Queued),
+                ],
+            )?;
+            if started == 0 {
+                return Ok(None);
+            }
+
+            let (owner, role, step): (String, String, String) = transaction.query_row(
+                "SELECT owner, role, step FROM jobs WHERE id = ?1",
+                [id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )?;
+            let claims = Claims {
+                sub: owner,
+                exp: 0,
+                iat: 0,
+                role: from_column(role)?,
+            };
+            let step = serde_json::from_str(&step)
+                .map_err(|e| AppError::Internal(format!("Invalid job {}: {}", id, e)))?;
+            let pending = {
+                let mut statement = transaction.prepare(
+                    "SELECT position, input FROM job_items
+                     WHERE job_id = ?1 AND status = ?2 ORDER BY position",
+                )?;
+                let pending = statement
+                    .query_map(params![id, as_column(ItemStatus::Pending)], |row| {
+                        Ok((row.get::<_, i64>(0)? as usize, row.get(1)?))
+                    })?
+                    .collect::<rusqlite::Result<_>>()?;
+                pending
+            };
+            transaction.commit()?;
+
+            Ok(Some((claims, step, pending)))
+        })
+    }
+
+    // Store the result of one text, unless the job was stopped meanwhile
+    fn record(&self, id: &str, index: usize, result: ItemResult) -> Result<(), AppError> {
+        let (status, output, edits, usage, error, code) = match result {
+            Ok((output, usage, edits)) => {
+                let edits = edits
+                    .map(|edits| serde_json::to_string(&edits))
+                    .transpose()
+                    .map_err(|e| AppError::Internal(format!("Failed to serialize edits: {}", e)))?;
+                (
+                    ItemStatus::Completed,
+                    Some(output),
+                    edits,
+                    Some(usage),
+                    None,
+                    None,
+                )
+            }
+            Err(e) => (
+                ItemStatus::Failed,
+                None,
+                None,
+                None,
+                Some(e.to_string()),
+                Some(e.status_code().as_u16()),
+            ),
+        };
+        let completed = (status == ItemStatus::Completed) as i64;
+        let spent = usage.unwrap_or(TokenUsage::new(0, 0));
+
+        self.database.with(|connection| {
+            let transaction = connection.transaction()?;
+            let running = transaction.execute(
+                "UPDATE jobs SET completed = completed + ?1, failed = failed + ?2,
+                 prompt_tokens = prompt_tokens + ?3, completion_tokens = completion_tokens + ?4
+                 WHERE id = ?5 AND status = ?6",
+                params![
+                    completed,
+                    1 - completed,
+                    spent.prompt_tokens as i64,
+                    spent.completion_tokens as i64,
+                    id,
+                    as_column(JobStatus::Running),
+                ],
+            )?;
+            // A cancelled or deleted job keeps the results it had then
+            if running == 0 {
+                return Ok(());
+            }
+
+            transaction.execute(
+                "UPDATE job_items SET status = ?1, output = ?2, edits = ?3, prompt_tokens = ?4,
+                 completion_tokens = ?5, error = ?6, code = ?7
+                 WHERE job_id = ?8 AND position = ?9",
+                params![
+                    as_column(status),
+                    output,
+                    edits,
+                    usage.map(|u| u.prompt_tokens as i64),
+                    usage.map(|u| u.completion_tokens as i64),
+                    error,
+                    code,
+                    id,
+                    index as i64,
+                ],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })
+    }
+
+    // Mark a running job as done, returning its owner and summary
+    fn finish(&self, id: &str) -> Result<Option<(String, JobSummary)>, AppError> {
+        if let Ok(mut runners) = self.runners.lock() {
+            runners.remove(id);
+        }
+
+        self.database.with(|connection| {
+            let transaction = connection.transaction()?;
+            let Some(job) = find_summary(&transaction, id)? else {
+                return Ok(None);
+            };
+            if job.status != JobStatus::Running {
+                return Ok(None);
+            }
+
+            let status = if job.progress.failed == job.progress.total {
+                JobStatus::Failed
+            } else {
+                JobStatus::Completed
+            };
+            transaction.execute(
+                "UPDATE jobs SET status = ?1, finished_at = ?2 WHERE id = ?3",
+                params![as_column(status), Utc::now(), id],
+            )?;
+            let owner: String =
+                transaction.query_row("SELECT owner FROM jobs WHERE id = ?1", [id], |row| {
+                    row.get(0)
+                })?;
+            let summary = find_summary(&transaction, id)?
+                .ok_or_else(|| AppError::NotFound(format!("Job {}", id)))?;
+            transaction.commit()?;
+            Ok(Some((owner, summary)))
+        })
+    }
+}
+
+// Run a job in the background
+pub fn start_job(state: &AppState, id: String) {
+    // Hold the lock while spawning so the runner cannot finish before it is registered
+    let Ok(mut runners) = state.jobs.runners.lock() else {
+        return;
+    };
+    let runner = tokio::spawn(run_job(state.clone(), id.clone()));
+    runners.insert(id, runner.abort_handle());
+}
+
+// Start the jobs left queued by a previous run of the server
+pub fn resume_jobs(state: &AppState) {
+    let queued = match state.jobs.queued() {
+        Ok(queued) => queued,
+        Err(e) => {
+            error!("Failed to list queued jobs: {}", e);
+            return;
+        }
+    };
+    if !queued.is_empty() {
+        info!("Resuming {} queued jobs", queued.len());
+    }
+    for id in queued {
+        start_job(state, id);
+    }
+}
+
+async fn run_job(state: AppState, id: String) {
+    let (claims, step, pending) = match state.jobs.start(&id) {
+        Ok(Some(work)) => work,
+        Ok(None) => return,
+        Err(e) => {
+            error!("Failed to start job {}: {}", id, e);
+            return;
+        }
+    };
+    debug!("Running job {} with {} texts", id, pending.len());
+
+    let client = create_client(&state.config);
+    let (state, claims, client, step, id) = (&state, &claims, &client, &step, &id);
+    futures::stream::iter(pending)
+        .for_each_concurrent(state.config.jobs.workers, |(index, text)| async move {
+            let Ok(_permit) = state.jobs.workers.acquire().await else {
+                return;
+            };
+            let result = run_item(state, claims, client, step, &text).await;
+            if let Err(e) = state.jobs.record(id, index, result) {
+                error!("Failed to record text {} of job {}: {}", index, id, e);
+            }
+        })
+        .await;
+
+    match state.jobs.finish(id) {
+        Ok(Some((owner, job))) => {
+            debug!("Job {} finished", id);
+            notify_finished(state, &owner, &job);
+        }
+        Ok(None) => {}
+        Err(e) => error!("Failed to finish job {}: {}", id, e),
+    }
+}
+
+// Let the owner's webhooks know that a job is over
+fn notify_finished(state: &AppState, owner: &str, job: &JobSummary) {
+    let event = match job.status {
+        JobStatus::Completed => WebhookEvent::JobCompleted,
+        JobStatus::Failed => WebhookEvent::JobFailed,
+        JobStatus::Cancelled => WebhookEvent::JobCancelled,
+        JobStatus::Queued | JobStatus::Running => return,
+    };
+    match serde_json::to_value(job) {
+        Ok(data) => notify(state, owner, event, data),
+        Err(e) => error!("Failed to serialize job {}: {}", job.id, e),
+    }
+}
+
+// Cancel a job and let the owner's webhooks know, as its runner never gets to
+fn cancel(state: &AppState, owner: &str, id: &str) -> Result<JobSummary, AppError> {
+    let job = state.jobs.cancel(owner, id)?;
+    notify_finished(state, owner, &job);
+    Ok(job)
+}
+
+async fn run_item(
+    state: &AppState,
+    claims: &Claims,
+    client: &Client<ClientConfig>,
+    step: &PipelineStep,
+    text: &str,
+) -> ItemResult {
+    let mut input = step.options.clone();
+    input.insert("text".to_string(), Value::String(text.to_string()));
+    let entry = PendingEntry::new(claims, &step.op, EntrySource::Job, &input);
+    // Texts were charged when the job was submitted and are bounded by the job
+    // workers, so a running job never holds the owner's interactive streams
+    // or takes more of their rate limits
+    let request = StepRequest::parse(step, text).map_err(|e| entry.fail(state, e))?;
+
+    // Nobody listens to the events of a job's texts
+    let (tx, mut rx) = mpsc::channel(100);
+    tokio::spawn(async move { while rx.recv().await.is_some() {} });
+
+    let result = run_step(state, claims, client, request, &tx).await;
+    entry.finish(
+        state,
+        result
+            .as_ref()
+            .map(|(output, usage, _)| (output.as_str(), *usage)),
+    );
+    result
+}
+
+// An operation, with the options its own endpoint accepts, and the texts to run it on
+#[derive(Debug, Deserialize)]
+pub struct JobRequest {
+    #[serde(default)]
+    pub text: Option<String>,
+    #[serde(default)]
+    pub texts: Vec<String>,
+    #[serde(flatten)]
+    pub step: PipelineStep,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResultsFormat {
+    #[default]
+    Json,
+    Jsonl,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResultsQuery {
+    #[serde(default)]
+    pub format: ResultsFormat,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobResults {
+    #[serde(flatten)]
+    pub job: JobSummary,
+    pub items: Vec<JobItem>,
+}
+
+// Submit texts to be processed in the background
+pub async fn create_job(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    ClientAddress(address): ClientAddress,
+    Json(request): Json<JobRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let texts: Vec<String> = request.text.into_iter().chain(request.texts).collect();
+    if texts.is_empty() {
+        return Err(AppError::BadRequest(
+            "At least one text is required".to_string(),
+        ));
+    }
+    let max_texts = state.config.jobs.max_texts;
+    if texts.len() > max_texts {
+        return Err(AppError::PayloadTooLarge(format!(
+            "A job accepts at most {} texts",
+            max_texts
+        )));
+    }
+
+    // Options are the same for every text, so checking them once is enough
+    StepRequest::parse(&request.step, &texts[0])?.validate(&state, &claims)?;
+
+    // Texts run on the job workers, so the owner's limits are charged for
+    // them up front, a token per text
+    let cost = u32::try_from(texts.len()).unwrap_or(u32::MAX);
+    state.limits.charge(&claims.sub, address, cost)?;
+
+    let job = state.jobs.submit(&claims, request.step, texts)?;
+    start_job(&state, job.id.clone());
+
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+// List the current user's jobs
+pub async fn list_jobs(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<JobSummary>>, AppError> {
+    Ok(Json(state.jobs.list(&claims.sub)?))
+}
+
+// Poll a job's status and progress
+pub async fn get_job(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    UrlPath(id): UrlPath<String>,
+) -> Result<Json<JobSummary>, AppError> {
+    Ok(Json(state.jobs.get(&claims.sub, &id)?))
+}
+
+// Download a job's results so far, as JSON or as one JSON line per text
+pub async fn job_results(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    UrlPath(id): UrlPath<String>,
+    Query(query): Query<ResultsQuery>,
+) -> Result<Response, AppError> {
+    let (job, items) = state.jobs.results(&claims.sub, &id)?;
+
+    if query.format == ResultsFormat::Json {
+        return Ok(Json(JobResults { job, items }).into_response());
+    }
+
+    let mut body = String::new();
+    for item in &items {
+        let line = serde_json::to_string(item)
+            .map_err(|e| AppError::Internal(format!("Failed to serialize result: {}", e)))?;
+        body.push_str(&line);
+        body.push('\n');
+    }
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"job-{}.jsonl\"", id),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+// Cancel a queued or running job
+pub async fn cancel_job(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    UrlPath(id): UrlPath<String>,
+) -> Result<Json<JobSummary>, AppError> {
+    Ok(Json(cancel(&state, &claims.sub, &id)?))
+}
+
+// Delete a job and its results
+pub async fn delete_job(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    UrlPath(id): UrlPath<String>,
+) -> Result<StatusCode, AppError> {
+    // A job deleted before it finished is cancelled first, which webhooks hear
+    // about. It may finish meanwhile, which they then heard about instead.
+    if !state.jobs.get(&claims.sub, &id)?.status.is_finished() {
+        let _ = cancel(&state, &claims.sub, &id);
+    }
+    state.jobs.remove(&claims.sub, &id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::create_router;
+    use crate::config::{Config, DatabaseConfig};
+    use crate::models::UserRole;
+    use crate::test_utils::*;
+    use serde_json::{json, Value};
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    fn claims(username: &str) -> Claims {
+        Claims {
+            sub: username.to_string(),
+            exp: 0,
+            iat: 0,
+            role: UserRole::User,
+        }
+    }
+
+    fn step(op: &str) -> PipelineStep {
+        serde_json::from_value(json!({ "op": op })).unwrap()
+    }
+
+    fn open(database: &DatabaseConfig) -> JobStore {
+        let config = Config::default_test_config();
+        JobStore::open(&config.jobs, Arc::new(Database::open(database).unwrap())).unwrap()
+    }
+
+    fn store() -> JobStore {
+        open(&Config::default_test_config().database)
+    }
+
+    async fn call(state: &AppState, username: &str, method: &str, uri: &str, body: Value) -> Value {
+        let request = authed_request(&state.config, username)
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(json_body(body))
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        let status = response.status().as_u16();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap_or(json!({ "status": status }))
+    }
+
+    #[tokio::test]
+    async fn test_job_processes_every_text() {
+        let mock = spawn_mock_openai(|request| {
+            vec![format!("Short: {}", last_user_message(request).len())]
+        })
+        .await;
+        let state = test_state(test_config(&mock.base_url));
+
+        let job = call(
+            &state,
+            "alice",
+            "POST",
+            "/api/jobs",
+            json!({"op": "summarize", "texts": ["One text.", "Another text.", "A third."]}),
+        )
+        .await;
+        let id = job["id"].as_str().unwrap().to_string();
+        assert_eq!(job["progress"]["total"], 3);
+
+        let mut summary = Value::Null;
+        for _ in 0..100 {
+            summary = call(
+                &state,
+                "alice",
+                "GET",
+                &format!("/api/jobs/{}", id),
+                Value::Null,
+            )
+            .await;
+            if summary["status"] == "completed" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(summary["status"], "completed");
+        assert_eq!(summary["progress"]["completed"], 3);
+
+        let results = call(
+            &state,
+            "alice",
+            "GET",
+            &format!("/api/jobs/{}/results", id),
+            Value::Null,
+        )
+        .await;
+        let items = results["items"].as_array().unwrap();
+        assert_eq!(items.len(), 3);
+        assert!(items
+            .iter()
+            .all(|item| item["output"].as_str().unwrap().starts_with("Short")));
+        assert_eq!(mock.requests().len(), 3);
+
+        // Every text is in the owner's history
+        let query = crate::history::HistoryQuery {
+            source: Some(EntrySource::Job),
+            ..Default::default()
+        };
+        let page = state.history.list("alice", &query).unwrap();
+        assert_eq!(page.total, 3);
+        assert!(page
+            .entries
+            .iter()
+            .all(|entry| entry.operation == "summarize"));
+
+        // Other users can't see the job
+        let other = call(
+            &state,
+            "bob",
+            "GET",
+            &format!("/api/jobs/{}", id),
+            Value::Null,
+        )
+        .await;
+        assert_eq!(other["error"]["code"], 404);
+        assert_eq!(
+            call(&state, "alice", "GET", "/api/jobs", Value::Null)
+                .await
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_jobs_are_rejected_up_front() {
+        let state = test_state(test_config("http://127.0.0.1:9"));
+
+        let unknown = call(
+            &state,
+            "alice",
+            "POST",
+            "/api/jobs",
+            json!({"op": "no-such-op", "text": "x"}),
+        )
+        .await;
+        assert_eq!(unknown["error"]["code"], 404);
+
+        let candidates = call(
+            &state,
+            "alice",
+            "POST",
+            "/api/jobs",
+            json!({"op": "paraphrase", "n": 2, "text": "x"}),
+        )
+        .await;
+        assert_eq!(candidates["error"]["code"], 400);
+
+        let empty = call(
+            &state,
+            "alice",
+            "POST",
+            "/api/jobs",
+            json!({"op": "summarize"}),
+        )
+        .await;
+        assert_eq!(empty["error"]["code"], 400);
+        assert!(state.jobs.list("alice").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_submitting_a_job_charges_a_token_per_text() {
+        let mut config = test_config("http://127.0.0.1:9");
+        config.rate_limit.user_per_minute = 1;
+        config.rate_limit.user_burst = 3;
+        let state = test_state(config);
+
+        call(
+            &state,
+            "alice",
+            "POST",
+            "/api/jobs",
+            json!({"op": "summarize", "texts": ["One.", "Two."]}),
+        )
+        .await;
+        assert_eq!(state.limits.status("alice").unwrap().remaining, 1);
+
+        let limited = call(
+            &state,
+            "alice",
+            "POST",
+            "/api/jobs",
+            json!({"op": "summarize", "texts": ["Three.", "Four."]}),
+        )
+        .await;
+        assert_eq!(limited["error"]["code"], 429);
+        assert_eq!(state.jobs.list("alice").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_queued_texts_are_capped_per_user() {
+        let store = store();
+        let texts = |count: usize| vec!["Text.".to_string(); count];
+
+        let first = store
+            .submit(&claims("alice"), step("summarize"), texts(150))
+            .unwrap();
+        assert!(matches!(
+            store.submit(&claims("alice"), step("summarize"), texts(60)),
+            Err(AppError::RateLimited(..))
+        ));
+        store
+            .submit(&claims("bob"), step("summarize"), texts(60))
+            .unwrap();
+
+        // Texts of stopped jobs no longer wait
+        store.cancel("alice", &first.id).unwrap();
+        store
+            .submit(&claims("alice"), step("summarize"), texts(60))
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_running_job_leaves_interactive_limits_alone() {
+        let mock = spawn_mock_openai(|request| {
+            // Keep the job's texts generating while the interactive request runs
+            if last_user_message(request).contains("Slow") {
+                std::thread::sleep(Duration::from_millis(300));
+            }
+            vec!["Done.".to_string()]
+        })
+        .await;
+        let mut config = test_config(&mock.base_url);
+        config.rate_limit.user_per_minute = 1;
+        // Submitting the job takes two
+        config.rate_limit.user_burst = 3;
+        config.rate_limit.max_concurrent_streams = 1;
+        let state = test_state(config.clone());
+
+        let job = call(
+            &state,
+            "alice",
+            "POST",
+            "/api/jobs",
+            json!({"op": "summarize", "texts": ["Slow one.", "Slow two."]}),
+        )
+        .await;
+        let id = job["id"].as_str().unwrap().to_string();
+        while mock.requests().is_empty() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let request = authed_request(&config, "alice")
+            .uri("/api/text/paraphrase?text=Hello")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(sse_content(&sse_events(response).await), "Done.");
+        assert_eq!(
+            state.jobs.get("alice", &id).unwrap().status,
+            JobStatus::Running
+        );
+    }
+
+    #[test]
+    fn test_cancel_keeps_job_and_refuses_finished_ones() {
+        let store = store();
+        let job = store
+            .submit(
+                &claims("alice"),
+                step("summarize"),
+                vec!["Text.".to_string()],
+            )
+            .unwrap();
+
+        assert!(matches!(
+            store.cancel("bob", &job.id),
+            Err(AppError::NotFound(_))
+        ));
+        let cancelled = store.cancel("alice", &job.id).unwrap();
+        assert_eq!(cancelled.status, JobStatus::Cancelled);
+        assert!(store.start(&job.id).unwrap().is_none());
+        assert!(matches!(
+            store.cancel("alice", &job.id),
+            Err(AppError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_cancel_leaves_completed_job_alone() {
+        let store = store();
+        let job = store
+            .submit(
+                &claims("alice"),
+                step("summarize"),
+                vec!["Text.".to_string()],
+            )
+            .unwrap();
+        store.start(&job.id).unwrap().unwrap();
+        store
+            .record(
+                &job.id,
+                0,
+                Ok(("Short.".to_string(), TokenUsage::new(2, 1), None)),
+            )
+            .unwrap();
+        store.finish(&job.id).unwrap().unwrap();
+
+        assert!(matches!(
+            store.cancel("alice", &job.id),
+            Err(AppError::BadRequest(_))
+        ));
+        let summary = store.get("alice", &job.id).unwrap();
+        assert_eq!(summary.status, JobStatus::Completed);
+    }
+
+    #[test]
+    fn test_jobs_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("jobs-test-{:x}", rand::random::<u64>()));
+        let database = DatabaseConfig {
+            url: format!("sqlite://{}", dir.join("app.db").display()),
+        };
+
+        let store = open(&database);
+        let texts = vec!["First.".to_string(), "Second.".to_string()];
+        let job = store
+            .submit(&claims("alice"), step("summarize"), texts)
+            .unwrap();
+        store.start(&job.id).unwrap().unwrap();
+        // Every result is saved as it comes, then the server stops
+        store
+            .record(
+                &job.id,
+                0,
+                Ok(("1st.".to_string(), TokenUsage::new(3, 1), None)),
+            )
+            .unwrap();
+        drop(store);
+
+        let store = open(&database);
+        assert_eq!(store.queued().unwrap(), vec![job.id.clone()]);
+        let (claims, _, pending) = store.start(&job.id).unwrap().unwrap();
+        assert_eq!(claims.sub, "alice");
+        assert_eq!(pending, vec![(1, "Second.".to_string())]);
+        let (summary, items) = store.results("alice", &job.id).unwrap();
+        assert_eq!(summary.progress.completed, 1);
+        assert_eq!(summary.usage.total_tokens, 4);
+        assert_eq!(items[0].output.as_deref(), Some("1st."));
+        assert_eq!(items[0].usage, Some(TokenUsage::new(3, 1)));
+
+        store.remove("alice", &job.id).unwrap();
+        assert!(store.results("alice", &job.id).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_results_of_stopped_jobs_are_dropped() {
+        let store = store();
+        let texts = vec!["First.".to_string(), "Second.".to_string()];
+        let job = store
+            .submit(&claims("alice"), step("summarize"), texts)
+            .unwrap();
+        store.start(&job.id).unwrap().unwrap();
+        store.cancel("alice", &job.id).unwrap();
+
+        // A text finishing after the job was cancelled changes nothing
+        let late = Ok(("Late.".to_string(), TokenUsage::new(3, 1), None));
+        store.record(&job.id, 0, late).unwrap();
+        let (summary, items) = store.results("alice", &job.id).unwrap();
+        assert_eq!(summary.status, JobStatus::Cancelled);
+        assert_eq!(summary.progress.completed, 0);
+        assert_eq!(items[0].status, ItemStatus::Pending);
+        assert!(store.finish(&job.id).unwrap().is_none());
+
+        // Nor does one finishing after it was deleted bring it back
+        store.remove("alice", &job.id).unwrap();
+        let late = Ok(("Late.".to_string(), TokenUsage::new(3, 1), None));
+        store.record(&job.id, 1, late).unwrap();
+        assert!(matches!(
+            store.get("alice", &job.id),
+            Err(AppError::NotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,367 @@
+use std::fmt;
+
+use axum::extract::State;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+
+use crate::error::AppError;
+use crate::state::AppState;
+
+// Known languages: BCP-47 primary subtag, ISO 639-3 code used by the
+// detector, and the English name used in prompts
+const LANGUAGES: &[(&str, &str, &str)] = &[
+    ("af", "afr", "Afrikaans"),
+    ("ak", "aka", "Akan"),
+    ("am", "amh", "Amharic"),
+    ("ar", "ara", "Arabic"),
+    ("az", "aze", "Azerbaijani"),
+    ("be", "bel", "Belarusian"),
+    ("bg", "bul", "Bulgarian"),
+    ("bn", "ben", "Bengali"),
+    ("ca", "cat", "Catalan"),
+    ("cs", "ces", "Czech"),
+    ("da", "dan", "Danish"),
+    ("de", "deu", "German"),
+    ("el", "ell", "Greek"),
+    ("en", "eng", "English"),
+    ("eo", "epo", "Esperanto"),
+    ("es", "spa", "Spanish"),
+    ("et", "est", "Estonian"),
+    ("fa", "pes", "Persian"),
+    ("fi", "fin", "Finnish"),
+    ("fr", "fra", "French"),
+    ("gu", "guj", "Gujarati"),
+    ("he", "heb", "Hebrew"),
+    ("hi", "hin", "Hindi"),
+    ("hr", "hrv", "Croatian"),
+    ("hu", "hun", "Hungarian"),
+    ("hy", "hye", "Armenian"),
+    ("id", "ind", "Indonesian"),
+    ("it", "ita", "Italian"),
+    ("ja", "jpn", "Japanese"),
+    ("jv", "jav", "Javanese"),
+    ("ka", "kat", "Georgian"),
+    ("km", "khm", "Khmer"),
+    ("kn", "kan", "Kannada"),
+    ("ko", "kor", "Korean"),
+    ("la", "lat", "Latin"),
+    ("lt", "lit", "Lithuanian"),
+    ("lv", "lav", "Latvian"),
+    ("mk", "mkd", "Macedonian"),
+    ("ml", "mal", "Malayalam"),
+    ("mr", "mar", "Marathi"),
+    ("my", "mya", "Burmese"),
+    ("nb", "nob", "Norwegian Bokmål"),
+    ("ne", "nep", "Nepali"),
+    ("nl", "nld", "Dutch"),
+    ("or", "ori", "Odia"),
+    ("pa", "pan", "Punjabi"),
+    ("pl", "pol", "Polish"),
+    ("pt", "por", "Portuguese"),
+    ("ro", "ron", "Romanian"),
+    ("ru", "rus", "Russian"),
+    ("si", "sin", "Sinhala"),
+    ("sk", "slk", "Slovak"),
+    ("sl", "slv", "Slovenian"),
+    ("sn", "sna", "Shona"),
+    ("sr", "srp", "Serbian"),
+    ("sv", "swe", "Swedish"),
+    ("ta", "tam", "Tamil"),
+    ("te", "tel", "Telugu"),
+    ("th", "tha", "Thai"),
+    ("tk", "tuk", "Turkmen"),
+    ("tl", "tgl", "Tagalog"),
+    ("tr", "tur", "Turkish"),
+    ("uk", "ukr", "Ukrainian"),
+    ("ur", "urd", "Urdu"),
+    ("uz", "uzb", "Uzbek"),
+    ("vi", "vie", "Vietnamese"),
+    ("yi", "yid", "Yiddish"),
+    ("zh", "cmn", "Chinese"),
+    ("zu", "zul", "Zulu"),
+];
+
+// Names accepted before language tags were introduced
+const LEGACY_NAMES: &[(&str, &str)] = &[("english", "en"), ("spanish", "es")];
+
+// A validated, canonically-cased BCP-47 language tag such as `pt-BR` or `zh-Hant`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct LanguageTag(String);
+
+fn is_alpha(s: &str, min: usize, max: usize) -> bool {
+    (min..=max).contains(&s.len()) && s.chars().all(|c| c.is_ascii_alphabetic())
+}
+
+fn is_alphanum(s: &str, min: usize, max: usize) -> bool {
+    (min..=max).contains(&s.len()) && s.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn title_case(s: &str) -> String {
+    let lower = s.to_ascii_lowercase();
+    let mut chars = lower.chars();
+    match chars.next() {
+        Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+        None => String::new(),
+    }
+}
+
+impl LanguageTag {
+    // Parse and canonicalize a tag of the form
+    // language[-script][-region][-variant]*[-extension]*[-x-private]
+    pub fn parse(input: &str) -> Result<Self, AppError> {
+        let input = input.trim();
+        let invalid = || AppError::BadRequest(format!("Invalid language tag: {:?}", input));
+
+        if let Some((_, tag)) = LEGACY_NAMES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(input))
+        {
+            return Ok(LanguageTag(tag.to_string()));
+        }
+
+        let mut subtags = input.split(['-', '_']);
+        let language = subtags.next().ok_or_else(invalid)?;
+        if !is_alpha(language, 2, 3) && !is_alpha(language, 5, 8) {
+            return Err(invalid());
+        }
+
+        let mut canonical = vec![language.to_ascii_lowercase()];
+        let mut seen_script = false;
+        let mut seen_region = false;
+        let mut in_extension = false;
+        // A singleton must be followed by at least one subtag
+        let mut after_singleton = false;
+
+        for subtag in subtags {
+            if subtag.len() == 1 && subtag.chars().all(|c| c.is_ascii_alphanumeric()) {
+                if after_singleton {
+                    return Err(invalid());
+                }
+                in_extension = true;
+                after_singleton = true;
+                canonical.push(subtag.to_ascii_lowercase());
+            } else if in_extension {
+                // Everything after a singleton is extension or private-use data
+                if !is_alphanum(subtag, 2, 8) {
+                    return Err(invalid());
+                }
+                after_singleton = false;
+                canonical.push(subtag.to_ascii_lowercase());
+            } else if !seen_script && !seen_region && is_alpha(subtag, 4, 4) {
+                seen_script = true;
+                canonical.push(title_case(subtag));
+            } else if !seen_region
+                && (is_alpha(subtag, 2, 2)
+                    || (subtag.len() == 3 && subtag.chars().all(|c| c.is_ascii_digit())))
+            {
+                seen_region = true;
+                canonical.push(subtag.to_ascii_uppercase());
+            } else if is_alphanum(subtag, 5, 8)
+                || (subtag.len() == 4 && subtag.starts_with(|c: char| c.is_ascii_digit()))
+            {
+                seen_script = true;
+                seen_region = true;
+                canonical.push(subtag.to_ascii_lowercase());
+            } else {
+                return Err(invalid());
+            }
+        }
+
+        if after_singleton {
+            return Err(invalid());
+        }
+
+        Ok(LanguageTag(canonical.join("-")))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // The primary language subtag, e.g. `pt` for `pt-BR`
+    pub fn primary(&self) -> &str {
+        self.0.split('-').next().unwrap_or(&self.0)
+    }
+
+    // Human readable name used in prompts, e.g. "Portuguese (pt-BR)"
+    pub fn display_name(&self) -> String {
+        match LANGUAGES
+            .iter()
+            .find(|(code, _, _)| *code == self.primary())
+        {
+            Some((_, _, name)) if self.0 == self.primary() => name.to_string(),
+            Some((_, _, name)) => format!("{} ({})", name, self.0),
+            None => self.0.clone(),
+        }
+    }
+
+    // Whether this tag is covered by a supported tag, either exactly or by
+    // its primary language (`pt` covers `pt-BR`)
+    pub fn is_covered_by(&self, supported: &[LanguageTag]) -> bool {
+        supported
+            .iter()
+            .any(|s| s == self || (s.0 == s.primary() && s.primary() == self.primary()))
+    }
+}
+
+impl TryFrom<String> for LanguageTag {
+    type Error = AppError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        LanguageTag::parse(&value)
+    }
+}
+
+impl From<LanguageTag> for String {
+    fn from(tag: LanguageTag) -> Self {
+        tag.0
+    }
+}
+
+impl fmt::Display for LanguageTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// Result of detecting the language of a text
+#[derive(Debug, Clone, Serialize)]
+pub struct DetectedLanguage {
+    pub language: LanguageTag,
+    pub confidence: f64,
+    pub reliable: bool,
+}
+
+// Detect the language of a text locally, without calling the model
+pub fn detect_language(text: &str) -> Option<DetectedLanguage> {
+    let info = whatlang::detect(text)?;
+    let code = info.lang().code();
+    let (tag, _, _) = LANGUAGES.iter().find(|(_, iso3, _)| *iso3 == code)?;
+
+    Some(DetectedLanguage {
+        language: LanguageTag(tag.to_string()),
+        confidence: info.confidence(),
+        reliable: info.is_reliable(),
+    })
+}
+
+// Check that a requested language is enabled on this server
+pub fn ensure_supported(tag: &LanguageTag, supported: &[LanguageTag]) -> Result<(), AppError> {
+    if tag.is_covered_by(supported) {
+        Ok(())
+    } else {
+        Err(AppError::BadRequest(format!(
+            "Language {} is not supported. Supported languages: {}",
+            tag,
+            supported
+                .iter()
+                .map(LanguageTag::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LanguageInfo {
+    pub tag: LanguageTag,
+    pub name: String,
+}
+
+// List the target languages enabled on this server
+pub async fn list_languages(State(state): State<AppState>) -> Json<Vec<LanguageInfo>> {
+    Json(
+        state
+            .config
+            .languages
+            .supported
+            .iter()
+            .map(|tag| LanguageInfo {
+                tag: tag.clone(),
+                name: tag.display_name(),
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_canonicalizes_case() {
+        assert_eq!(LanguageTag::parse("PT-br").unwrap().as_str(), "pt-BR");
+        assert_eq!(
+            LanguageTag::parse("zh-hant-tw").unwrap().as_str(),
+            "zh-Hant-TW"
+        );
+        assert_eq!(LanguageTag::parse("es_419").unwrap().as_str(), "es-419");
+        assert_eq!(
+            LanguageTag::parse("de-CH-1996").unwrap().as_str(),
+            "de-CH-1996"
+        );
+        assert_eq!(
+            LanguageTag::parse("en-US-x-twain").unwrap().as_str(),
+            "en-US-x-twain"
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_tags() {
+        for input in [
+            "",
+            "e",
+            "toolonglang-us",
+            "en-",
+            "en-US-US",
+            "12",
+            "en-x",
+            "ja-Latn-Hira",
+        ] {
+            assert!(
+                LanguageTag::parse(input).is_err(),
+                "{:?} should be invalid",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_legacy_names_are_accepted() {
+        let tag: LanguageTag = serde_json::from_str("\"spanish\"").unwrap();
+        assert_eq!(tag.as_str(), "es");
+        assert_eq!(serde_json::to_string(&tag).unwrap(), "\"es\"");
+    }
+
+    #[test]
+    fn test_display_name() {
+        assert_eq!(LanguageTag::parse("ja").unwrap().display_name(), "Japanese");
+        assert_eq!(
+            LanguageTag::parse("pt-BR").unwrap().display_name(),
+            "Portuguese (pt-BR)"
+        );
+        assert_eq!(LanguageTag::parse("tlh").unwrap().display_name(), "tlh");
+    }
+
+    #[test]
+    fn test_supported_languages_cover_regions() {
+        let supported = vec![
+            LanguageTag::parse("pt").unwrap(),
+            LanguageTag::parse("en-GB").unwrap(),
+        ];
+
+        assert!(ensure_supported(&LanguageTag::parse("pt-BR").unwrap(), &supported).is_ok());
+        assert!(ensure_supported(&LanguageTag::parse("en-GB").unwrap(), &supported).is_ok());
+        assert!(ensure_supported(&LanguageTag::parse("en-US").unwrap(), &supported).is_err());
+        assert!(ensure_supported(&LanguageTag::parse("ja").unwrap(), &supported).is_err());
+    }
+
+    #[test]
+    fn test_detect_language() {
+        let detected =
+            detect_language("Guten Morgen, wie geht es Ihnen heute? Das Wetter ist sehr schön.")
+                .unwrap();
+        assert_eq!(detected.language.as_str(), "de");
+    }
+}
@@ -6,13 +6,45 @@ use tracing::{error, info};
 use crate::api::create_router;
 use crate::config::Config;
 use crate::error::AppError;
+use crate::jobs::resume_jobs;
+use crate::state::AppState;
+use crate::webhooks::resume_deliveries;
 
+mod analysis;
 mod api;
 mod auth;
+mod cache;
+mod coalesce;
+mod collab;
 mod config;
+mod db;
+mod diff;
+mod document_store;
+mod documents;
 mod error;
+mod export;
+mod glossary;
+mod history;
+mod jobs;
+mod languages;
+mod markup;
 mod models;
 mod openai;
+mod operations;
+mod params;
+mod pipeline;
+mod proofread;
+mod ratelimit;
+mod socket;
+mod state;
+mod streams;
+mod templates;
+#[cfg(test)]
+mod test_utils;
+mod text;
+mod tokens;
+mod translation_memory;
+mod webhooks;
 
 #[tokio::main]
 async fn main() -> Result<(), AppError> {
@@ -31,16 +63,21 @@ async fn main() -> Result<(), AppError> {
         }
     };
 
+    // Build the shared application state
+    let state = AppState::new(config.clone())?;
+    resume_jobs(&state);
+    resume_deliveries(&state);
+
     // Create the application router
-    let app = create_router(config.clone());
+    let app = create_router(state);
 
     // Bind to the configured address
     let addr = SocketAddr::from(([0, 0, 0, 0], config.server.port));
     info!("Listening on {}", addr);
 
-    // Start the server
+    // Start the server, with client addresses for the rate limits
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .map_err(|e| AppError::Internal(format!("Server error: {}", e)))
 }
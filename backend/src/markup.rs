@@ -0,0 +1,512 @@
+use std::ops::Range;
+
+use pulldown_cmark::{Event, LinkType, Options, Parser, Tag};
+use serde::{Deserialize, Serialize};
+
+use crate::error::AppError;
+use crate::models::StreamEvent;
+use crate::openai::{Generation, GenerationPart};
+use crate::templates::RenderedPrompt;
+
+const PLACEHOLDER_OPEN: char = '⟦';
+const PLACEHOLDER_CLOSE: char = '⟧';
+// Added to the style of prompts whose segment holds placeholders
+const PLACEHOLDER_INSTRUCTIONS: &str =
+    " The text contains placeholders such as ⟦1⟧ standing for markup. Keep every placeholder exactly as written, in the matching position of the result.";
+
+// HTML elements that stay within a segment of prose
+const INLINE_ELEMENTS: &[&str] = &[
+    "a", "abbr", "b", "bdi", "bdo", "br", "cite", "data", "del", "dfn", "em", "font", "i", "img",
+    "ins", "mark", "q", "s", "small", "span", "strong", "sub", "sup", "time", "u", "wbr",
+];
+// Inline HTML elements whose content is never rewritten
+const VERBATIM_INLINE_ELEMENTS: &[&str] = &["code", "kbd", "samp", "var"];
+// HTML elements whose content is never rewritten and ends any segment
+const VERBATIM_BLOCK_ELEMENTS: &[&str] = &["script", "style", "pre", "textarea", "svg", "math"];
+
+// Markup of the input, whose structure is kept while only its prose is rewritten
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Markup {
+    Markdown,
+    Html,
+}
+
+// A run of prose to rewrite, where every piece of markup inside it has been
+// replaced by a numbered placeholder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProseSegment {
+    pub text: String,
+    pub protected: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Piece {
+    Verbatim(String),
+    Prose(ProseSegment),
+}
+
+// Reported once the output is complete
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MarkupReport {
+    pub markup: Markup,
+    // Prose segments sent to the model
+    pub segments: usize,
+    // Markup the model dropped from its output, which could not be restored
+    pub missing: Vec<String>,
+}
+
+// Replaces placeholders in streamed output as soon as they are complete
+pub struct PlaceholderRestorer<'a> {
+    protected: &'a [String],
+    pending: String,
+}
+
+impl<'a> PlaceholderRestorer<'a> {
+    pub fn new(protected: &'a [String]) -> Self {
+        PlaceholderRestorer {
+            protected,
+            pending: String::new(),
+        }
+    }
+
+    // Restore a chunk of output, holding back a placeholder cut in half
+    pub fn push(&mut self, chunk: &str) -> String {
+        self.pending.push_str(chunk);
+        let mut output = String::new();
+
+        while let Some(start) = self.pending.find(PLACEHOLDER_OPEN) {
+            output.push_str(&self.pending[..start]);
+            self.pending.drain(..start);
+
+            let inner_start = PLACEHOLDER_OPEN.len_utf8();
+            let rest = &self.pending[inner_start..];
+            match rest.find(PLACEHOLDER_CLOSE) {
+                Some(end) => {
+                    let restored = rest[..end]
+                        .trim()
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|n| n.checked_sub(1))
+                        .and_then(|index| self.protected.get(index));
+                    match restored {
+                        Some(original) => {
+                            output.push_str(original);
+                            self.pending
+                                .drain(..inner_start + end + PLACEHOLDER_CLOSE.len_utf8());
+                        }
+                        // Not one of ours, keep the bracket as text
+                        None => {
+                            output.push(PLACEHOLDER_OPEN);
+                            self.pending.drain(..inner_start);
+                        }
+                    }
+                }
+                None if rest.chars().all(|c| c.is_ascii_digit() || c == ' ') => {
+                    return output;
+                }
+                None => {
+                    output.push(PLACEHOLDER_OPEN);
+                    self.pending.drain(..inner_start);
+                }
+            }
+        }
+
+        output.push_str(&self.pending);
+        self.pending.clear();
+        output
+    }
+
+    // Whatever was held back when the output ends
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+// Split a text range around the bare URLs it contains, which stay verbatim
+fn split_urls(source: &str, range: Range<usize>) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut position = range.start;
+    let text = &source[range.clone()];
+    let mut search = 0;
+
+    while let Some(found) = ["https://", "http://"]
+        .iter()
+        .filter_map(|scheme| text[search..].find(scheme))
+        .min()
+    {
+        let start = search + found;
+        let length = text[start..]
+            .find(|c: char| c.is_whitespace() || matches!(c, '<' | '>' | '"' | '\''))
+            .unwrap_or(text.len() - start);
+        let url = text[start..start + length].trim_end_matches(['.', ',', ';', ':', '!', '?', ')']);
+        let end = start + url.len().max(1);
+
+        ranges.push(position..range.start + start);
+        position = range.start + end;
+        search = end;
+    }
+    ranges.push(position..range.end);
+    ranges.retain(|r| !r.is_empty());
+    ranges
+}
+
+// Collects the text ranges of each segment while a document is scanned
+#[derive(Default)]
+struct SegmentCollector {
+    segments: Vec<Vec<Range<usize>>>,
+    current: Vec<Range<usize>>,
+}
+
+impl SegmentCollector {
+    fn text(&mut self, source: &str, range: Range<usize>) {
+        self.current.extend(split_urls(source, range));
+    }
+
+    fn end(&mut self) {
+        let ranges = std::mem::take(&mut self.current);
+        if !ranges.is_empty() {
+            self.segments.push(ranges);
+        }
+    }
+
+    // Turn the segments into pieces covering the whole source
+    fn pieces(mut self, source: &str) -> Vec<Piece> {
+        self.end();
+        let mut pieces = Vec::new();
+        let mut position = 0;
+
+        for mut ranges in self.segments {
+            // Leading and trailing whitespace stays where it is
+            let blank = |r: &Range<usize>| source[r.clone()].trim().is_empty();
+            while ranges.first().is_some_and(blank) {
+                ranges.remove(0);
+            }
+            while ranges.last().is_some_and(blank) {
+                ranges.pop();
+            }
+            if let Some(first) = ranges.first_mut() {
+                let text = &source[first.clone()];
+                first.start += text.len() - text.trim_start().len();
+            }
+            if let Some(last) = ranges.last_mut() {
+                let text = &source[last.clone()];
+                last.end -= text.len() - text.trim_end().len();
+            }
+
+            // Segments without words are not worth a prompt
+            let has_words = ranges
+                .iter()
+                .any(|r| source[r.clone()].chars().any(char::is_alphabetic));
+            let (Some(first), Some(last), true) = (ranges.first(), ranges.last(), has_words) else {
+                continue;
+            };
+
+            let (start, end) = (first.start, last.end);
+            pieces.push(Piece::Verbatim(source[position..start].to_string()));
+
+            let mut segment = ProseSegment {
+                text: String::new(),
+                protected: Vec::new(),
+            };
+            let mut previous_end = start;
+            for range in &ranges {
+                let gap = &source[previous_end..range.start];
+                if !gap.is_empty() {
+                    segment.protected.push(gap.to_string());
+                    segment.text.push(PLACEHOLDER_OPEN);
+                    segment.text.push_str(&segment.protected.len().to_string());
+                    segment.text.push(PLACEHOLDER_CLOSE);
+                }
+                segment.text.push_str(&source[range.clone()]);
+                previous_end = range.end;
+            }
+            pieces.push(Piece::Prose(segment));
+            position = end;
+        }
+
+        pieces.push(Piece::Verbatim(source[position..].to_string()));
+        pieces.retain(|piece| !matches!(piece, Piece::Verbatim(text) if text.is_empty()));
+        pieces
+    }
+}
+
+fn markdown_segments(source: &str) -> Vec<Piece> {
+    let mut collector = SegmentCollector::default();
+    let mut in_code_block = false;
+    let mut autolinks = 0;
+
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+    for (event, range) in Parser::new_ext(source, options).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Link(LinkType::Autolink | LinkType::Email, _, _)) => autolinks += 1,
+            Event::End(Tag::Link(LinkType::Autolink | LinkType::Email, _, _)) => autolinks -= 1,
+            // Inline markup stays inside the segment, as placeholders
+            Event::Start(Tag::Emphasis | Tag::Strong | Tag::Strikethrough)
+            | Event::End(Tag::Emphasis | Tag::Strong | Tag::Strikethrough)
+            | Event::Start(Tag::Link(..) | Tag::Image(..))
+            | Event::End(Tag::Link(..) | Tag::Image(..)) => {}
+            Event::Start(tag) | Event::End(tag) => {
+                collector.end();
+                if matches!(tag, Tag::CodeBlock(_)) {
+                    in_code_block = !in_code_block;
+                }
+            }
+            Event::Text(_) | Event::SoftBreak if !in_code_block && autolinks == 0 => {
+                collector.text(source, range);
+            }
+            Event::Rule => collector.end(),
+            _ => {}
+        }
+    }
+
+    collector.pieces(source)
+}
+
+// End of the tag starting at `start`, skipping `>` inside quoted attributes
+fn html_tag_end(source: &str, start: usize) -> usize {
+    let mut quote = None;
+    for (offset, c) in source[start..].char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None, '>') => return start + offset + 1,
+            _ => {}
+        }
+    }
+    source.len()
+}
+
+// End of the element named `name` whose content starts at `start`
+fn html_element_end(source: &str, start: usize, name: &str) -> usize {
+    let closing = format!("</{}", name);
+    source[start..]
+        .to_ascii_lowercase()
+        .find(&closing)
+        .map(|offset| html_tag_end(source, start + offset))
+        .unwrap_or(source.len())
+}
+
+fn html_segments(source: &str) -> Vec<Piece> {
+    let mut collector = SegmentCollector::default();
+    let mut position = 0;
+
+    while position < source.len() {
+        let rest = &source[position..];
+        if rest.starts_with("<!--") {
+            position = rest
+                .find("-->")
+                .map(|end| position + end + 3)
+                .unwrap_or(source.len());
+            continue;
+        }
+
+        let is_tag = rest.starts_with('<')
+            && rest[1..]
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '/' || c == '!');
+        if !is_tag {
+            let end = rest[1..]
+                .find('<')
+                .map(|offset| position + 1 + offset)
+                .unwrap_or(source.len());
+            collector.text(source, position..end);
+            position = end;
+            continue;
+        }
+
+        let end = html_tag_end(source, position);
+        let name: String = rest[1..]
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_ascii_lowercase();
+        let opening = !rest.starts_with("</");
+
+        if VERBATIM_BLOCK_ELEMENTS.contains(&name.as_str()) {
+            collector.end();
+            position = if opening {
+                html_element_end(source, end, &name)
+            } else {
+                end
+            };
+        } else if VERBATIM_INLINE_ELEMENTS.contains(&name.as_str()) {
+            position = if opening {
+                html_element_end(source, end, &name)
+            } else {
+                end
+            };
+        } else {
+            if !INLINE_ELEMENTS.contains(&name.as_str()) {
+                collector.end();
+            }
+            position = end;
+        }
+    }
+
+    collector.pieces(source)
+}
+
+// Split a document into verbatim markup and prose segments
+pub fn segment(source: &str, markup: Markup) -> Vec<Piece> {
+    match markup {
+        Markup::Markdown => markdown_segments(source),
+        Markup::Html => html_segments(source),
+    }
+}
+
+// A generation rewriting only the prose of a document. `render` builds the
+// prompt for a segment, given extra instructions to append to the style.
+pub fn markup_generation<F>(source: &str, markup: Markup, render: F) -> Result<Generation, AppError>
+where
+    F: Fn(&str, &str) -> Result<RenderedPrompt, AppError>,
+{
+    let mut parts = Vec::new();
+    let mut checks = Vec::new();
+    for piece in segment(source, markup) {
+        match piece {
+            Piece::Verbatim(text) => parts.push(GenerationPart::Fixed(text)),
+            Piece::Prose(segment) => {
+                let instructions = if segment.protected.is_empty() {
+                    ""
+                } else {
+                    PLACEHOLDER_INSTRUCTIONS
+                };
+                let prompt = render(&segment.text, instructions)?;
+                checks.push((parts.len(), segment.protected.clone()));
+                parts.push(GenerationPart::Protected {
+                    prompt,
+                    protected: segment.protected,
+                });
+            }
+        }
+    }
+
+    let segments = checks.len();
+    Ok(Generation::from_parts(parts).with_finisher(move |output| {
+        let missing = checks
+            .into_iter()
+            .flat_map(|(part, protected)| {
+                let text = &output.parts[part];
+                protected
+                    .into_iter()
+                    .filter(|markup| !markup.trim().is_empty() && !text.contains(markup.as_str()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        vec![StreamEvent::Markup(MarkupReport {
+            markup,
+            segments,
+            missing,
+        })]
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rebuild a document from its pieces with every segment left unchanged
+    fn rebuild(pieces: &[Piece]) -> String {
+        pieces
+            .iter()
+            .map(|piece| match piece {
+                Piece::Verbatim(text) => text.clone(),
+                Piece::Prose(segment) => {
+                    let mut restorer = PlaceholderRestorer::new(&segment.protected);
+                    restorer.push(&segment.text) + &restorer.finish()
+                }
+            })
+            .collect()
+    }
+
+    fn prose(pieces: &[Piece]) -> Vec<&str> {
+        pieces
+            .iter()
+            .filter_map(|piece| match piece {
+                Piece::Prose(segment) => Some(segment.text.as_str()),
+                Piece::Verbatim(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_markdown_protects_code_and_links() {
+        let source = "# Getting started\n\nRun `cargo build` and read [the guide](https://example.com/guide).\n\n```rust\nfn main() {}\n```\n\n- See https://example.com for more.\n";
+        let pieces = segment(source, Markup::Markdown);
+
+        assert_eq!(rebuild(&pieces), source);
+        assert_eq!(
+            prose(&pieces),
+            vec![
+                "Getting started",
+                "Run ⟦1⟧ and read ⟦2⟧the guide⟦3⟧.",
+                "See ⟦1⟧ for more.",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_html_protects_tags_and_code() {
+        let source = "<html><head><style>p { color: red; }</style></head><body>\n<h1 class=\"title\">Hello <em>world</em></h1>\n<p>Use <code>ls -l</code> to <a href=\"/docs?a=1&b=2\">list files</a>.</p>\n<pre>keep   this</pre>\n</body></html>";
+        let pieces = segment(source, Markup::Html);
+
+        assert_eq!(rebuild(&pieces), source);
+        assert_eq!(
+            prose(&pieces),
+            vec!["Hello ⟦1⟧world", "Use ⟦1⟧ to ⟦2⟧list files⟦3⟧.",]
+        );
+    }
+
+    #[test]
+    fn test_restorer_handles_split_placeholders() {
+        let protected = vec!["`code`".to_string(), "**".to_string()];
+        let mut restorer = PlaceholderRestorer::new(&protected);
+
+        let mut output = restorer.push("Run ⟦");
+        assert_eq!(output, "Run ");
+        output.push_str(&restorer.push("1⟧ now ⟦2"));
+        output.push_str(&restorer.push("⟧ and ⟦9⟧ ⟦x"));
+        output.push_str(&restorer.finish());
+        assert_eq!(output, "Run `code` now ** and ⟦9⟧ ⟦x");
+    }
+
+    #[test]
+    fn test_generation_reports_dropped_markup() {
+        let render = |text: &str, style: &str| {
+            Ok(RenderedPrompt {
+                system: None,
+                user: format!("{}{}", text, style),
+                model: None,
+                temperature: None,
+            })
+        };
+        let generation = markup_generation(
+            "Read `this` now.\n\n```\ncode\n```\n",
+            Markup::Markdown,
+            render,
+        )
+        .unwrap();
+
+        assert_eq!(generation.parts.len(), 2);
+        let GenerationPart::Protected { prompt, protected } = &generation.parts[0] else {
+            panic!("Expected a prose segment first");
+        };
+        assert!(prompt.user.starts_with("Read ⟦1⟧ now."));
+        assert!(prompt.user.contains("placeholders"));
+        assert_eq!(protected, &vec!["`this`".to_string()]);
+
+        let output = crate::openai::GenerationOutput {
+            text: String::new(),
+            parts: vec!["Read now.".to_string(), String::new()],
+        };
+        let finisher = generation.finishers.into_iter().next().unwrap();
+        let events = finisher(&output);
+        let [StreamEvent::Markup(report)] = events.as_slice() else {
+            panic!("Expected a markup report");
+        };
+        assert_eq!(report.missing, vec!["`this`".to_string()]);
+    }
+}
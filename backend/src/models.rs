@@ -1,8 +1,17 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::diff::{DiffGranularity, DiffReport};
+use crate::glossary::GlossaryReport;
+use crate::languages::LanguageTag;
+use crate::markup::{Markup, MarkupReport};
+use crate::params::GenerationParams;
+use crate::pipeline::StepReport;
+use crate::translation_memory::SegmentReport;
+
 // Authentication models
 #[derive(Debug, Serialize, Deserialize)]
+#[allow(dead_code)]
 pub struct User {
     pub username: String,
     pub password_hash: String,
@@ -20,43 +29,135 @@ pub struct LoginResponse {
     pub expires_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UserRole {
+    #[default]
+    User,
+    Admin,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
     pub sub: String, // Subject (username)
     pub exp: i64,    // Expiration time (as UTC timestamp)
     pub iat: i64,    // Issued at (as UTC timestamp)
+    #[serde(default)]
+    pub role: UserRole,
+}
+
+impl Claims {
+    pub fn is_admin(&self) -> bool {
+        self.role == UserRole::Admin
+    }
 }
 
 // Text processing models
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TextRequest {
     pub text: String,
+    // Level of the diff between the input and the output, words by default
+    #[serde(default)]
+    pub diff: Option<DiffGranularity>,
+    // Rewrite only the prose of a Markdown or HTML document
+    #[serde(default)]
+    pub markup: Option<Markup>,
+    #[serde(flatten)]
+    pub params: GenerationParams,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TranslationRequest {
     pub text: String,
-    pub target_language: TargetLanguage,
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-#[serde(rename_all = "lowercase")]
-pub enum TargetLanguage {
-    English,
-    Spanish,
+    pub target_language: LanguageTag,
+    // Detected from the text when not provided
+    #[serde(default)]
+    pub source_language: Option<LanguageTag>,
+    // Reuse and extend the translation memory, on by default
+    #[serde(default)]
+    pub use_memory: Option<bool>,
+    // Translate only the prose of a Markdown or HTML document
+    #[serde(default)]
+    pub markup: Option<Markup>,
+    #[serde(flatten)]
+    pub params: GenerationParams,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// A whole response in JSON mode, with the data of the named SSE events
+#[derive(Debug, Default, Serialize)]
 pub struct TextResponse {
     pub result: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub candidates: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub glossary: Option<GlossaryReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub segments: Option<Vec<SegmentReport>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<DiffReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub markup: Option<MarkupReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub steps: Option<Vec<StepReport>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TokenUsage>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[allow(dead_code)]
 pub struct SSEEvent {
     pub event: String,
     pub data: String,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct TokenUsage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+impl TokenUsage {
+    pub fn new(prompt_tokens: usize, completion_tokens: usize) -> Self {
+        TokenUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+}
+
+impl std::ops::AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: TokenUsage) {
+        *self = TokenUsage::new(
+            self.prompt_tokens + other.prompt_tokens,
+            self.completion_tokens + other.completion_tokens,
+        );
+    }
+}
+
+// Events produced while generating a response, before they are encoded for
+// the transport
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StreamEvent {
+    Delta(String),
+    // Output of one candidate when several were requested
+    Candidate { index: usize, content: String },
+    Usage(TokenUsage),
+    Metadata(serde_json::Value),
+    Glossary(GlossaryReport),
+    Segments(Vec<SegmentReport>),
+    Diff(DiffReport),
+    // Markup kept from the input, and any the model dropped
+    Markup(MarkupReport),
+    // Boundary of a pipeline step
+    Step(StepReport),
+    Error(String),
+    Done,
+}
+
 // For testing purposes
 #[cfg(test)]
 mod tests {
@@ -91,17 +192,34 @@ mod tests {
     }
 
     #[test]
-    fn test_target_language_serialization() {
-        let lang = TargetLanguage::English;
+    fn test_token_usage_totals() {
+        let usage = TokenUsage::new(12, 30);
+        assert_eq!(usage.total_tokens, 42);
 
-        let serialized = serde_json::to_string(&lang).unwrap();
-        assert_eq!(serialized, "\"english\"");
+        let serialized = serde_json::to_value(usage).unwrap();
+        assert_eq!(serialized["prompt_tokens"], 12);
+        assert_eq!(serialized["completion_tokens"], 30);
+    }
 
-        let deserialized: TargetLanguage = serde_json::from_str(&serialized).unwrap();
+    #[test]
+    fn test_translation_request_deserialization() {
+        let request: TranslationRequest =
+            serde_json::from_str(r#"{"text": "Hello", "target_language": "ja"}"#).unwrap();
 
-        match deserialized {
-            TargetLanguage::English => {}
-            _ => panic!("Expected English variant"),
-        }
+        assert_eq!(request.target_language.as_str(), "ja");
+        assert!(request.source_language.is_none());
+
+        let request: TranslationRequest = serde_json::from_str(
+            r#"{"text": "Hello", "target_language": "pt-br", "source_language": "en"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(request.target_language.as_str(), "pt-BR");
+        assert_eq!(request.source_language.unwrap().as_str(), "en");
+
+        assert!(serde_json::from_str::<TranslationRequest>(
+            r#"{"text": "Hello", "target_language": "not a language"}"#
+        )
+        .is_err());
     }
 }
@@ -2,22 +2,46 @@ use std::convert::Infallible;
 use std::sync::Arc;
 
 use async_openai::types::{
-    ChatCompletionRequestMessageArgs, CreateChatCompletionRequestArgs, Role,
+    ChatCompletionRequestMessageArgs, CreateChatCompletionRequest, CreateChatCompletionRequestArgs,
+    Role,
 };
 use async_openai::{config::OpenAIConfig as ClientConfig, Client};
-use axum::extract::{Query, State};
+use axum::async_trait;
+use axum::extract::{FromRequestParts, Query, State};
+use axum::http::request::Parts;
+use axum::http::{header, StatusCode};
 use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
-use axum::Json;
+use axum::{Extension, Json};
+use chrono::Utc;
 use futures::Stream;
 use futures_util::StreamExt;
+use serde::de::DeserializeOwned;
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tracing::{debug, error};
 
+use crate::cache::CachedResponse;
+use crate::coalesce::Registration;
 use crate::config::Config;
+use crate::diff::{DiffGranularity, DiffRequest};
 use crate::error::AppError;
-use crate::models::{TargetLanguage, TextRequest, TranslationRequest};
+use crate::history::{EntrySource, PendingEntry};
+use crate::languages::{detect_language, ensure_supported, LanguageTag};
+use crate::markup::{markup_generation, PlaceholderRestorer};
+use crate::models::{
+    Claims, StreamEvent, TextRequest, TextResponse, TokenUsage, TranslationRequest,
+};
+use crate::params::Sampling;
+use crate::pipeline::StepStatus;
+use crate::ratelimit::ClientAddress;
+use crate::state::AppState;
+use crate::streams::StreamBuffer;
+use crate::templates::RenderedPrompt;
+use crate::tokens::Tokenizer;
+use crate::translation_memory::{hint_instructions, FuzzyMatch, PlannedRun};
 
 // Struct to wrap SSE response with no-cache headers
 struct SseWithNoCacheHeaders<S>(Sse<S>);
@@ -38,8 +62,35 @@ where
     }
 }
 
+// How the client wants the result: streamed as SSE, the default, or as a
+// single JSON document when it only accepts JSON
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Stream,
+    Json,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ResponseFormat {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let accept = parts
+            .headers
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        if accept.contains("application/json") && !accept.contains("text/event-stream") {
+            Ok(ResponseFormat::Json)
+        } else {
+            Ok(ResponseFormat::Stream)
+        }
+    }
+}
+
 // Function to create a client for the OpenAI API
-fn create_client(config: &Config) -> Client<ClientConfig> {
+pub fn create_client(config: &Config) -> Client<ClientConfig> {
     let openai_config = ClientConfig::new()
         .with_api_key(&config.openai.api_key)
         .with_api_base(&config.openai.base_url);
@@ -47,194 +98,1047 @@ fn create_client(config: &Config) -> Client<ClientConfig> {
     Client::with_config(openai_config)
 }
 
+// Build the generation of a single-template operation such as paraphrase
+pub fn text_generation(
+    state: &AppState,
+    claims: &Claims,
+    template: &str,
+    request: &TextRequest,
+) -> Result<Generation, AppError> {
+    let params = &request.params;
+    params.validate(state.config.generation.limits_for(claims.role))?;
+
+    let style = params.style_instructions();
+    let render = |text: &str, instructions: &str| {
+        state.templates.render(
+            template,
+            &[
+                ("text", text),
+                ("style", &format!("{}{}", style, instructions)),
+            ],
+        )
+    };
+
+    let generation = match request.markup {
+        Some(markup) => markup_generation(&request.text, markup, render)?,
+        None => Generation::new(render(&request.text, "")?),
+    };
+    Ok(generation
+        .with_sampling(params.sampling())
+        .with_diff(request.text.clone(), request.diff.unwrap_or_default()))
+}
+
 // Paraphrase text - support both GET and POST
 pub async fn paraphrase(
-    State(config): State<Arc<Config>>,
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    ClientAddress(address): ClientAddress,
+    format: ResponseFormat,
     text_param: Option<Query<TextRequest>>,
     text_json: Option<Json<TextRequest>>,
 ) -> Result<impl IntoResponse, AppError> {
-    // Extract text from either query parameters or JSON body
-    let text = if let Some(query) = text_param {
-        query.text.clone()
-    } else if let Some(json) = text_json {
-        json.text.clone()
+    // Extract the request from either query parameters or JSON body
+    let request = if let Some(Query(query)) = text_param {
+        query
+    } else if let Some(Json(json)) = text_json {
+        json
     } else {
         return Err(AppError::BadRequest("Text is required".to_string()));
     };
 
-    let prompt = format!(
-        "Paraphrase the following text while maintaining its original meaning:\n\n{}",
-        text
-    );
-
-    process_text_with_openai(config, prompt).await
+    let entry = PendingEntry::new(&claims, "paraphrase", EntrySource::Request, &request);
+    let generation = text_generation(&state, &claims, "paraphrase", &request)
+        .map_err(|e| entry.fail(&state, e))?;
+    let entry = entry.admit(&state, address)?;
+    process_text_with_openai(state, generation, format, entry).await
 }
 
 // Expand text - support both GET and POST
 pub async fn expand(
-    State(config): State<Arc<Config>>,
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    ClientAddress(address): ClientAddress,
+    format: ResponseFormat,
     text_param: Option<Query<TextRequest>>,
     text_json: Option<Json<TextRequest>>,
 ) -> Result<impl IntoResponse, AppError> {
-    // Extract text from either query parameters or JSON body
-    let text = if let Some(query) = text_param {
-        query.text.clone()
-    } else if let Some(json) = text_json {
-        json.text.clone()
+    // Extract the request from either query parameters or JSON body
+    let request = if let Some(Query(query)) = text_param {
+        query
+    } else if let Some(Json(json)) = text_json {
+        json
     } else {
         return Err(AppError::BadRequest("Text is required".to_string()));
     };
 
-    let prompt = format!(
-        "Expand the following text with more details and explanations:\n\n{}",
-        text
-    );
-
-    process_text_with_openai(config, prompt).await
+    let entry = PendingEntry::new(&claims, "expand", EntrySource::Request, &request);
+    let generation =
+        text_generation(&state, &claims, "expand", &request).map_err(|e| entry.fail(&state, e))?;
+    let entry = entry.admit(&state, address)?;
+    process_text_with_openai(state, generation, format, entry).await
 }
 
 // Summarize text - support both GET and POST
 pub async fn summarize(
-    State(config): State<Arc<Config>>,
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    ClientAddress(address): ClientAddress,
+    format: ResponseFormat,
     text_param: Option<Query<TextRequest>>,
     text_json: Option<Json<TextRequest>>,
 ) -> Result<impl IntoResponse, AppError> {
-    // Extract text from either query parameters or JSON body
-    let text = if let Some(query) = text_param {
-        query.text.clone()
-    } else if let Some(json) = text_json {
-        json.text.clone()
+    // Extract the request from either query parameters or JSON body
+    let request = if let Some(Query(query)) = text_param {
+        query
+    } else if let Some(Json(json)) = text_json {
+        json
     } else {
         return Err(AppError::BadRequest("Text is required".to_string()));
     };
 
-    let prompt = format!("Summarize the following text concisely:\n\n{}", text);
-
-    process_text_with_openai(config, prompt).await
+    let entry = PendingEntry::new(&claims, "summarize", EntrySource::Request, &request);
+    request
+        .params
+        .require_single_candidate()
+        .map_err(|e| entry.fail(&state, e))?;
+    let generation = text_generation(&state, &claims, "summarize", &request)
+        .map_err(|e| entry.fail(&state, e))?;
+    let entry = entry.admit(&state, address)?;
+    process_text_with_openai(state, generation, format, entry).await
 }
 
 // Translate text - support both GET and POST
 pub async fn translate(
-    State(config): State<Arc<Config>>,
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    ClientAddress(address): ClientAddress,
+    format: ResponseFormat,
     translation_param: Option<Query<TranslationRequest>>,
     translation_json: Option<Json<TranslationRequest>>,
 ) -> Result<impl IntoResponse, AppError> {
     // Extract translation request from either query parameters or JSON body
-    let translation_request = if let Some(query) = translation_param {
-        TranslationRequest {
-            text: query.text.clone(),
-            target_language: query.target_language.clone(),
-        }
-    } else if let Some(json) = translation_json {
-        TranslationRequest {
-            text: json.text.clone(),
-            target_language: json.target_language.clone(),
-        }
+    let translation_request = if let Some(Query(query)) = translation_param {
+        query
+    } else if let Some(Json(json)) = translation_json {
+        json
     } else {
         return Err(AppError::BadRequest(
             "Translation parameters are required".to_string(),
         ));
     };
 
-    let target_language_str = match translation_request.target_language {
-        TargetLanguage::English => "English",
-        TargetLanguage::Spanish => "Spanish",
+    let entry = PendingEntry::new(
+        &claims,
+        "translate",
+        EntrySource::Request,
+        &translation_request,
+    );
+    let generation = translation_generation(&state, &claims, translation_request)
+        .map_err(|e| entry.fail(&state, e))?;
+    let entry = entry.admit(&state, address)?;
+    process_text_with_openai(state, generation, format, entry).await
+}
+
+// Build the generation of a translation, reusing the translation memory and
+// applying the glossary
+pub fn translation_generation(
+    state: &AppState,
+    claims: &Claims,
+    translation_request: TranslationRequest,
+) -> Result<Generation, AppError> {
+    let params = &translation_request.params;
+    params.validate(state.config.generation.limits_for(claims.role))?;
+    params.require_single_candidate()?;
+
+    let supported = &state.config.languages.supported;
+    ensure_supported(&translation_request.target_language, supported)?;
+
+    // Detect the source language locally when the client did not provide one.
+    // A guess the detector does not trust, as for short or mixed texts, is
+    // left out: it would mislead the model and file memory segments and
+    // glossary terms under the wrong pair.
+    let (source_language, detection) = match translation_request.source_language {
+        Some(source) => (Some(source), None),
+        None => {
+            let detected = detect_language(&translation_request.text);
+            let source = detected
+                .as_ref()
+                .filter(|d| d.reliable)
+                .map(|d| d.language.clone());
+            (source, detected)
+        }
     };
 
-    let prompt = format!(
-        "Translate the following text to {}:\n\n{}",
-        target_language_str, translation_request.text
-    );
+    let source_language_name = source_language
+        .as_ref()
+        .map(LanguageTag::display_name)
+        .unwrap_or_else(|| "its original language".to_string());
+
+    // Constrain the prompt with the user's glossary and check the output against it
+    let constraints = state.glossary.constraints_for(
+        &claims.sub,
+        &translation_request.text,
+        source_language.as_ref(),
+        &translation_request.target_language,
+    )?;
+
+    let target_language_name = translation_request.target_language.display_name();
+    let glossary_instructions = constraints.instructions();
+    let style_instructions = params.style_instructions();
+    let render = |text: &str, hints: &[FuzzyMatch], instructions: &str| {
+        state.templates.render(
+            "translate",
+            &[
+                ("text", text),
+                ("source_language", &source_language_name),
+                ("target_language", &target_language_name),
+                ("glossary", &glossary_instructions),
+                ("memory", &hint_instructions(hints)),
+                ("style", &format!("{}{}", style_instructions, instructions)),
+            ],
+        )
+    };
 
-    process_text_with_openai(config, prompt).await
+    // The translation memory needs a known language pair to look segments up,
+    // and works on plain text only
+    let plan = match (&source_language, translation_request.use_memory) {
+        _ if translation_request.markup.is_some() => None,
+        (Some(source), None | Some(true)) => Some(state.translation_memory.plan(
+            &claims.sub,
+            &translation_request.text,
+            source,
+            &translation_request.target_language,
+        )?),
+        _ => None,
+    };
+
+    let mut metadata = json!({
+        "source_language": source_language,
+        "source_language_detected": detection.is_some() && source_language.is_some(),
+        "detection_confidence": detection.as_ref().map(|d| d.confidence),
+        "target_language": translation_request.target_language,
+    });
+
+    let mut generation = match plan {
+        Some(plan) => {
+            metadata["translation_memory"] = json!({
+                "segments": plan.segments.iter().filter(|s| !s.source.is_empty()).count(),
+                "memory_hits": plan.segments.iter().filter(|s| s.memory.is_some()).count(),
+            });
+
+            // Reuse stored segments as they are and generate the runs in between
+            let mut parts = Vec::new();
+            let mut run_parts = Vec::new();
+            if let Some(leading) = plan.segments.first().filter(|s| s.source.is_empty()) {
+                parts.push(GenerationPart::Fixed(leading.trailing.clone()));
+            }
+            for run in plan.runs() {
+                run_parts.push((run.clone(), parts.len()));
+                match &run {
+                    PlannedRun::Memory(index) => {
+                        let segment = &plan.segments[*index];
+                        let memory = segment.memory.clone().unwrap_or_default();
+                        parts.push(GenerationPart::Fixed(memory + &segment.trailing));
+                    }
+                    PlannedRun::Generate(range) => {
+                        let prompt = render(&plan.run_source(range), &plan.run_hints(range), "")?;
+                        parts.push(GenerationPart::Prompt(prompt));
+                        parts.push(GenerationPart::Fixed(plan.run_trailing(range)));
+                    }
+                }
+            }
+
+            let memory = state.translation_memory.clone();
+            Generation::from_parts(parts)
+                .with_finisher(move |output| {
+                    let run_outputs: Vec<(PlannedRun, String)> = run_parts
+                        .into_iter()
+                        .map(|(run, part)| (run, output.parts[part].clone()))
+                        .collect();
+                    vec![StreamEvent::Segments(plan.finish(&memory, &run_outputs))]
+                })
+                .owned_by(&claims.sub)
+        }
+        None => match translation_request.markup {
+            Some(markup) => {
+                markup_generation(&translation_request.text, markup, |text, instructions| {
+                    render(text, &[], instructions)
+                })?
+            }
+            None => Generation::new(render(&translation_request.text, &[], "")?),
+        },
+    };
+    generation = generation
+        .with_metadata(metadata)
+        .with_sampling(params.sampling());
+
+    if !constraints.is_empty() {
+        generation = generation
+            .with_finisher(move |output| {
+                vec![StreamEvent::Glossary(constraints.check(&output.text))]
+            })
+            .owned_by(&claims.sub);
+    }
+
+    Ok(generation)
 }
 
-// Common function to process text with OpenAI API and return streaming response
-async fn process_text_with_openai(
-    config: Arc<Config>,
-    prompt: String,
-) -> Result<impl IntoResponse, AppError> {
-    let client = create_client(&config);
+// One piece of a generation's output, either fixed text or a prompt to stream
+pub enum GenerationPart {
+    Fixed(String),
+    Prompt(RenderedPrompt),
+    // A prompt whose output holds placeholders for the protected markup
+    Protected {
+        prompt: RenderedPrompt,
+        protected: Vec<String>,
+    },
+}
+
+// The output of a finished generation, in full and per part. When several
+// candidates were requested, this is the first one.
+pub struct GenerationOutput {
+    pub text: String,
+    pub parts: Vec<String>,
+}
+
+// Runs once the full output is known, producing extra events sent before `usage`
+pub type Finisher = Box<dyn FnOnce(&GenerationOutput) -> Vec<StreamEvent> + Send>;
+
+// Everything needed to run one generation through the streaming pipeline
+pub struct Generation {
+    pub parts: Vec<GenerationPart>,
+    // Sent as a `metadata` event before any content
+    pub metadata: Option<serde_json::Value>,
+    // Applied to every prompt, over the template's own settings
+    pub sampling: Sampling,
+    pub finishers: Vec<Finisher>,
+    // Reported after the finishers' events for each request on its own, so it
+    // is left out of the cache key
+    pub diff: Option<DiffRequest>,
+    // The user whose data the finishers read or change. Such a generation is
+    // only shared with, or replayed to, that user.
+    pub owner: Option<String>,
+}
+
+impl Generation {
+    pub fn new(prompt: RenderedPrompt) -> Self {
+        Self::from_parts(vec![GenerationPart::Prompt(prompt)])
+    }
+
+    pub fn from_parts(parts: Vec<GenerationPart>) -> Self {
+        Generation {
+            parts,
+            metadata: None,
+            sampling: Sampling::default(),
+            finishers: Vec::new(),
+            diff: None,
+            owner: None,
+        }
+    }
+
+    pub fn owned_by(mut self, owner: &str) -> Self {
+        self.owner = Some(owner.to_string());
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: serde_json::Value) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    pub fn with_sampling(mut self, sampling: Sampling) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    // Report what changed between the input and the output once it is complete
+    pub fn with_diff(mut self, input: String, granularity: DiffGranularity) -> Self {
+        self.diff = Some(DiffRequest { input, granularity });
+        self
+    }
+
+    pub fn with_finisher<F>(mut self, finisher: F) -> Self
+    where
+        F: FnOnce(&GenerationOutput) -> Vec<StreamEvent> + Send + 'static,
+    {
+        self.finishers.push(Box::new(finisher));
+        self
+    }
+}
+
+// A generation part ready to run, with prompts already counted and built
+enum PreparedPart {
+    Fixed(String),
+    Request {
+        request: Box<CreateChatCompletionRequest>,
+        tokenizer: Arc<Tokenizer>,
+        protected: Vec<String>,
+    },
+}
+
+// Count and build the upstream request for a prompt
+fn prepare_prompt(
+    state: &AppState,
+    prompt: RenderedPrompt,
+    sampling: Sampling,
+) -> Result<(CreateChatCompletionRequest, Arc<Tokenizer>, usize), AppError> {
+    // Templates may pin their own model, otherwise use the configured one
+    let model = prompt
+        .model
+        .clone()
+        .unwrap_or_else(|| state.config.openai.model.clone());
+
+    // Reject prompts that cannot fit in the model's context before calling upstream
+    let tokenizer = state.tokenizer_for(&model)?;
+    let max_tokens = sampling.max_tokens.map(usize::from);
+    let prompt_tokens = tokenizer.check_prompt(&prompt.contents(), max_tokens)?;
+    let request = build_chat_request(&model, prompt, sampling)?;
+
+    Ok((request, tokenizer, prompt_tokens))
+}
+
+// Run a prompt without streaming and parse its output as JSON, for
+// operations whose result is structured data rather than prose
+pub async fn complete_structured<T: DeserializeOwned>(
+    state: &AppState,
+    prompt: RenderedPrompt,
+    sampling: Sampling,
+) -> Result<(T, TokenUsage), AppError> {
+    let (mut request, tokenizer, prompt_tokens) = prepare_prompt(state, prompt, sampling)?;
+    request.stream = None;
+
+    let response = create_client(&state.config)
+        .chat()
+        .create(request)
+        .await
+        .map_err(|e| AppError::OpenAI(format!("Failed to create completion: {}", e)))?;
+
+    let content = response
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .ok_or_else(|| AppError::OpenAI("Completion has no content".to_string()))?;
+    let output = parse_json_output(&content)?;
+
+    // Prefer the upstream's own count when it reports one
+    let usage = match response.usage {
+        Some(usage) => TokenUsage::new(
+            usage.prompt_tokens as usize,
+            usage.completion_tokens as usize,
+        ),
+        None => TokenUsage::new(prompt_tokens, tokenizer.count(&content)),
+    };
+
+    Ok((output, usage))
+}
+
+// Models sometimes wrap JSON in a code fence or a sentence, so parse the
+// outermost object of the output
+fn parse_json_output<T: DeserializeOwned>(content: &str) -> Result<T, AppError> {
+    let json = match (content.find('{'), content.rfind('}')) {
+        (Some(start), Some(end)) if start < end => &content[start..=end],
+        _ => content,
+    };
+
+    serde_json::from_str(json)
+        .map_err(|e| AppError::OpenAI(format!("Model returned invalid JSON: {}", e)))
+}
+
+// Name and payload of an event, shared by the SSE and WebSocket transports
+pub fn event_payload(event: StreamEvent) -> (&'static str, serde_json::Value) {
+    match event {
+        StreamEvent::Delta(content) => ("message", serde_json::Value::String(content)),
+        StreamEvent::Candidate { index, content } => {
+            ("candidate", json!({ "index": index, "content": content }))
+        }
+        StreamEvent::Metadata(metadata) => ("metadata", metadata),
+        StreamEvent::Glossary(report) => {
+            ("glossary", serde_json::to_value(report).unwrap_or_default())
+        }
+        StreamEvent::Segments(segments) => (
+            "segments",
+            serde_json::to_value(segments).unwrap_or_default(),
+        ),
+        StreamEvent::Diff(report) => ("diff", serde_json::to_value(report).unwrap_or_default()),
+        StreamEvent::Markup(report) => ("markup", serde_json::to_value(report).unwrap_or_default()),
+        StreamEvent::Step(report) => ("step", serde_json::to_value(report).unwrap_or_default()),
+        StreamEvent::Usage(usage) => ("usage", serde_json::to_value(usage).unwrap_or_default()),
+        StreamEvent::Error(message) => ("error", json!({ "error": message })),
+        StreamEvent::Done => ("done", serde_json::Value::Null),
+    }
+}
+
+// Encode a stream event for an SSE response
+fn to_sse_event(event: StreamEvent) -> Event {
+    match event_payload(event) {
+        ("message", serde_json::Value::String(content)) => Event::default().data(content),
+        // Errors go out as unnamed events, which every EventSource listens to
+        ("error", data) => Event::default().data(data.to_string()),
+        ("done", _) => Event::default().event("done").data(""),
+        (name, data) => Event::default().event(name).data(data.to_string()),
+    }
+}
+
+// Send text that is part of every candidate's output
+async fn send_text(tx: &mpsc::Sender<StreamEvent>, candidates: usize, text: &str) {
+    if candidates == 1 {
+        let _ = tx.send(StreamEvent::Delta(text.to_string())).await;
+        return;
+    }
+    for index in 0..candidates {
+        let content = text.to_string();
+        let _ = tx.send(StreamEvent::Candidate { index, content }).await;
+    }
+}
+
+// Stream a single chat completion into the channel and return the collected
+// output of each candidate, with any placeholders restored to their markup
+async fn stream_completion(
+    client: &Client<ClientConfig>,
+    request: CreateChatCompletionRequest,
+    candidates: usize,
+    protected: &[String],
+    tx: &mpsc::Sender<StreamEvent>,
+) -> Result<Vec<String>, AppError> {
+    let mut stream = client
+        .chat()
+        .create_stream(request)
+        .await
+        .map_err(|e| AppError::OpenAI(format!("Failed to create stream: {}", e)))?;
+
+    debug!("Stream created successfully");
+
+    let mut outputs = vec![String::new(); candidates];
+    let mut restorers: Vec<_> = (0..candidates)
+        .map(|_| PlaceholderRestorer::new(protected))
+        .collect();
+    while let Some(response) = stream.next().await {
+        let response = response.map_err(|e| AppError::OpenAI(e.to_string()))?;
+        for choice in response.choices {
+            let index = choice.index as usize;
+            let (Some(content), Some(restorer)) = (choice.delta.content, restorers.get_mut(index))
+            else {
+                continue;
+            };
+            let content = restorer.push(&content);
+            send_content(tx, candidates, &mut outputs, index, content).await?;
+        }
+    }
+    // A placeholder held back until the end was never completed
+    for (index, restorer) in restorers.iter_mut().enumerate() {
+        let content = restorer.finish();
+        send_content(tx, candidates, &mut outputs, index, content).await?;
+    }
+
+    Ok(outputs)
+}
+
+// Record and send the content of one candidate
+async fn send_content(
+    tx: &mpsc::Sender<StreamEvent>,
+    candidates: usize,
+    outputs: &mut [String],
+    index: usize,
+    content: String,
+) -> Result<(), AppError> {
+    if content.is_empty() {
+        return Ok(());
+    }
+
+    outputs[index].push_str(&content);
+    let event = if candidates == 1 {
+        StreamEvent::Delta(content)
+    } else {
+        StreamEvent::Candidate { index, content }
+    };
+    tx.send(event)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to send event: {}", e)))
+}
+
+// Build a streaming chat completion request from a rendered prompt
+fn build_chat_request(
+    model: &str,
+    prompt: RenderedPrompt,
+    sampling: Sampling,
+) -> Result<CreateChatCompletionRequest, AppError> {
+    let mut messages = Vec::new();
+
+    if let Some(system) = prompt.system {
+        messages.push(
+            ChatCompletionRequestMessageArgs::default()
+                .role(Role::System)
+                .content(system)
+                .build()
+                .map_err(|e| AppError::Internal(format!("Failed to build message: {}", e)))?,
+        );
+    }
 
     // Create a message for the chat completion
-    let message = ChatCompletionRequestMessageArgs::default()
-        .role(Role::User)
-        .content(prompt)
-        .build()
-        .map_err(|e| AppError::Internal(format!("Failed to build message: {}", e)))?;
+    messages.push(
+        ChatCompletionRequestMessageArgs::default()
+            .role(Role::User)
+            .content(prompt.user)
+            .build()
+            .map_err(|e| AppError::Internal(format!("Failed to build message: {}", e)))?,
+    );
 
     // Create a chat completion request
-    let request = CreateChatCompletionRequestArgs::default()
-        .model(&config.openai.model)
-        .messages(vec![message])
-        .stream(true)
+    let mut request = CreateChatCompletionRequestArgs::default();
+    request.model(model).messages(messages).stream(true);
+    if let Some(temperature) = sampling.temperature.or(prompt.temperature) {
+        request.temperature(temperature);
+    }
+    if let Some(top_p) = sampling.top_p {
+        request.top_p(top_p);
+    }
+    if let Some(max_tokens) = sampling.max_tokens {
+        request.max_tokens(max_tokens);
+    }
+    if sampling.candidates() > 1 {
+        request.n(sampling.candidates() as u8);
+    }
+
+    request
         .build()
-        .map_err(|e| AppError::Internal(format!("Failed to build request: {}", e)))?;
-
-    debug!("Sending request to OpenAI");
-
-    // Create a channel for the stream
-    let (tx, rx) = mpsc::channel(100);
-
-    // Spawn a task to handle the stream
-    tokio::spawn(async move {
-        // Create the stream
-        let mut stream = client
-            .chat()
-            .create_stream(request)
-            .await
-            .unwrap_or_else(|e| {
-                error!("Failed to create stream: {}", e);
-                panic!("Failed to create stream: {}", e);
-            });
+        .map_err(|e| AppError::Internal(format!("Failed to build request: {}", e)))
+}
 
-        debug!("Stream created successfully");
-
-        while let Some(response) = stream.next().await {
-            match response {
-                Ok(response) => {
-                    for choice in response.choices {
-                        if let Some(content) = choice.delta.content {
-                            if !content.is_empty() {
-                                if let Err(e) = tx.send(Event::default().data(content)).await {
-                                    error!("Failed to send event: {}", e);
-                                    break;
-                                }
+// Common function to process text with OpenAI API and return the response
+// in the format the client asked for
+pub async fn process_text_with_openai(
+    state: AppState,
+    generation: Generation,
+    format: ResponseFormat,
+    entry: PendingEntry,
+) -> Result<Response, AppError> {
+    let owner = entry.owner().to_string();
+    let spawned = spawn_generation(state.clone(), generation, entry)?;
+    let response = respond(&state, &owner, format, spawned.events).await;
+    let cache = if spawned.cached { "HIT" } else { "MISS" };
+    Ok((
+        [
+            ("X-Prompt-Tokens", spawned.prompt_tokens.to_string()),
+            ("X-Cache", cache.to_string()),
+        ],
+        response,
+    )
+        .into_response())
+}
+
+// A generation running in the background
+pub struct SpawnedGeneration {
+    pub prompt_tokens: usize,
+    pub events: mpsc::Receiver<StreamEvent>,
+    // May be aborted to cancel the generation
+    pub task: JoinHandle<()>,
+    // Whether the events are replayed from the response cache
+    pub cached: bool,
+}
+
+// Run a generation in the background, or replay it from the cache when the
+// same one ran before, recording it in the history once it ends. Identical
+// generations running at the same time share one upstream request.
+pub fn spawn_generation(
+    state: AppState,
+    generation: Generation,
+    entry: PendingEntry,
+) -> Result<SpawnedGeneration, AppError> {
+    let mut prepared =
+        prepare_generation(&state, generation).map_err(|e| entry.fail(&state, e))?;
+    let prompt_tokens = prepared.prompt_tokens;
+    let entry = entry.with_model(prepared.model());
+    // Compared with the output for this request alone, whoever generated it
+    let diff = prepared.diff.take();
+
+    let key = prepared.cache_key();
+    if let Some(cached) = state.cache.get(&key) {
+        debug!("Replaying cached response ({} events)", cached.events.len());
+        let (tx, rx) = mpsc::channel(100);
+        let task = tokio::spawn(async move {
+            for event in &cached.events {
+                let _ = tx.send(event.clone()).await;
+            }
+            if let Some(diff) = diff {
+                let _ = tx.send(StreamEvent::Diff(diff.report(&cached.output))).await;
+            }
+            // Nothing was sent upstream for it
+            let usage = TokenUsage::new(0, 0);
+            let _ = tx.send(StreamEvent::Usage(usage)).await;
+            entry.finish(&state, Ok((&cached.output, usage)));
+            let _ = tx.send(StreamEvent::Done).await;
+        });
+        return Ok(SpawnedGeneration {
+            prompt_tokens,
+            events: rx,
+            task,
+            cached: true,
+        });
+    }
+
+    let (shared, leader) = state.inflight.join_or_start(&key);
+    match leader {
+        Some(tx) => {
+            debug!(
+                "Sending request to OpenAI ({} prompt tokens)",
+                prompt_tokens
+            );
+            let registration = Registration {
+                state: state.clone(),
+                shared: shared.clone(),
+            };
+            let task = tokio::spawn(generate(registration, prepared, key, tx));
+            shared.set_task(task.abort_handle());
+        }
+        None => debug!("Joining an identical generation in flight"),
+    }
+
+    let (events, task) = shared.subscribe(state, entry, diff);
+    Ok(SpawnedGeneration {
+        prompt_tokens,
+        events,
+        task,
+        cached: false,
+    })
+}
+
+// Run a generation shared by every request attached to it, caching it when
+// it succeeds
+async fn generate(
+    registration: Registration,
+    prepared: PreparedGeneration,
+    key: String,
+    tx: mpsc::Sender<StreamEvent>,
+) {
+    let state = &registration.state;
+    let client = create_client(&state.config);
+
+    // Pass the events on, keeping a copy for the cache
+    let (generated, mut events) = mpsc::channel(100);
+    let generate = async move { run_generation(&client, prepared, &generated).await };
+    let forward = async {
+        let mut sent = Vec::new();
+        while let Some(event) = events.recv().await {
+            if state.cache.is_enabled() {
+                sent.push(event.clone());
+            }
+            let _ = tx.send(event).await;
+        }
+        sent
+    };
+    let (result, sent) = tokio::join!(generate, forward);
+
+    let final_event = match &result {
+        Ok((output, usage)) => {
+            let response = CachedResponse {
+                output: output.clone(),
+                usage: *usage,
+                events: sent,
+                created_at: Utc::now(),
+            };
+            state.cache.insert(&key, response);
+            StreamEvent::Usage(*usage)
+        }
+        Err(e) => {
+            error!("Error from OpenAI stream: {}", e);
+            StreamEvent::Error(e.to_string())
+        }
+    };
+    registration.shared.set_outcome(result.map_err(Arc::new));
+    // Requests arriving from now on find the cached response or start over
+    drop(registration);
+
+    let _ = tx.send(final_event).await;
+    // Send a completion event
+    let _ = tx.send(StreamEvent::Done).await;
+    debug!("Stream completed");
+}
+
+// Send the events of a generation to the client, streamed as SSE or gathered
+// into a single JSON document. Streams are buffered so that the client can
+// resume them.
+pub async fn respond(
+    state: &AppState,
+    owner: &str,
+    format: ResponseFormat,
+    rx: mpsc::Receiver<StreamEvent>,
+) -> Response {
+    if format == ResponseFormat::Json {
+        return collect_response(rx).await;
+    }
+
+    let (id, buffer) = state.streams.record(owner, rx);
+    replay(&id, buffer, 0)
+}
+
+// Stream a buffered response as SSE from its `received`th event on. Every
+// event's id is the stream's id and its number, for `Last-Event-ID`.
+pub fn replay(id: &str, buffer: Arc<StreamBuffer>, received: usize) -> Response {
+    let stream_id = id.to_string();
+    let stream = buffer.events_from(received).map(move |(index, event)| {
+        Ok(to_sse_event(event).id(format!("{}:{}", stream_id, index + 1)))
+    });
+
+    // Create the SSE response with a keep-alive and wrap it with no-cache headers
+    let sse =
+        Sse::new(stream).keep_alive(KeepAlive::new().interval(std::time::Duration::from_secs(15)));
+
+    // Return the wrapped SSE response with no-cache headers
+    (
+        [("X-Stream-Id", id.to_string())],
+        SseWithNoCacheHeaders(sse),
+    )
+        .into_response()
+}
+
+// A generation with every prompt counted and built, ready to run
+pub struct PreparedGeneration {
+    parts: Vec<PreparedPart>,
+    metadata: Option<serde_json::Value>,
+    finishers: Vec<Finisher>,
+    diff: Option<DiffRequest>,
+    candidates: usize,
+    owner: Option<String>,
+    pub prompt_tokens: usize,
+}
+
+impl PreparedGeneration {
+    // Model of the first request, when the generation sends any
+    pub fn model(&self) -> Option<&str> {
+        self.parts.iter().find_map(|part| match part {
+            PreparedPart::Request { request, .. } => Some(request.model.as_str()),
+            PreparedPart::Fixed(_) => None,
+        })
+    }
+
+    // Identifies what the generation would send upstream: the model, the
+    // rendered prompts, so the operation, its input and parameters and the
+    // template's text, and the sampling settings. Generations with an owner
+    // are kept apart per user.
+    pub fn cache_key(&self) -> String {
+        let parts: Vec<serde_json::Value> = self
+            .parts
+            .iter()
+            .map(|part| match part {
+                PreparedPart::Fixed(text) => json!({ "fixed": text }),
+                PreparedPart::Request {
+                    request, protected, ..
+                } => {
+                    let mut request = serde_json::to_value(request).unwrap_or_default();
+                    if let Some(messages) = request["messages"].as_array_mut() {
+                        for message in messages {
+                            if let Some(content) = message["content"].as_str() {
+                                message["content"] = cache_key_whitespace(content).into();
                             }
                         }
                     }
+                    json!({ "request": request, "protected": protected })
                 }
-                Err(e) => {
-                    error!("Error from OpenAI stream: {}", e);
-                    let _ = tx
-                        .send(Event::default().data(json!({"error": e.to_string()}).to_string()))
-                        .await;
-                    break;
+            })
+            .collect();
+        let key = json!({
+            "parts": parts,
+            "metadata": self.metadata,
+            "candidates": self.candidates,
+            "owner": self.owner,
+        });
+        hex::encode(Sha256::digest(key.to_string()))
+    }
+}
+
+// Text with the whitespace that cannot change the output evened out: line
+// endings, runs of spaces within lines and the ends of lines and of the text.
+// Indentation is kept, unlike `text::normalize_whitespace`.
+fn cache_key_whitespace(text: &str) -> String {
+    text.trim()
+        .lines()
+        .map(|line| {
+            let words = line.trim_start();
+            let indent = &line[..line.len() - words.len()];
+            indent.to_string() + &words.split_whitespace().collect::<Vec<_>>().join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Prepare every prompt up front so oversized input fails before streaming
+pub fn prepare_generation(
+    state: &AppState,
+    generation: Generation,
+) -> Result<PreparedGeneration, AppError> {
+    let Generation {
+        parts,
+        metadata,
+        sampling,
+        finishers,
+        diff,
+        owner,
+    } = generation;
+
+    let mut prompt_tokens = 0;
+    let mut prepared = Vec::with_capacity(parts.len());
+    for part in parts {
+        match part {
+            GenerationPart::Fixed(text) => prepared.push(PreparedPart::Fixed(text)),
+            GenerationPart::Prompt(prompt) => {
+                let (request, tokenizer, tokens) = prepare_prompt(state, prompt, sampling)?;
+                prompt_tokens += tokens;
+                prepared.push(PreparedPart::Request {
+                    request: Box::new(request),
+                    tokenizer,
+                    protected: Vec::new(),
+                });
+            }
+            GenerationPart::Protected { prompt, protected } => {
+                let (request, tokenizer, tokens) = prepare_prompt(state, prompt, sampling)?;
+                prompt_tokens += tokens;
+                prepared.push(PreparedPart::Request {
+                    request: Box::new(request),
+                    tokenizer,
+                    protected,
+                });
+            }
+        }
+    }
+
+    Ok(PreparedGeneration {
+        parts: prepared,
+        metadata,
+        finishers,
+        diff,
+        candidates: sampling.candidates(),
+        owner,
+        prompt_tokens,
+    })
+}
+
+// Run a prepared generation, sending its metadata, content and finisher events
+// to the channel. Returns the output of the first candidate and the token usage;
+// reporting the usage or a failure is left to the caller.
+pub async fn run_generation(
+    client: &Client<ClientConfig>,
+    generation: PreparedGeneration,
+    tx: &mpsc::Sender<StreamEvent>,
+) -> Result<(String, TokenUsage), AppError> {
+    let PreparedGeneration {
+        parts,
+        metadata,
+        finishers,
+        diff,
+        candidates,
+        prompt_tokens,
+        ..
+    } = generation;
+
+    if let Some(metadata) = metadata {
+        let _ = tx.send(StreamEvent::Metadata(metadata)).await;
+    }
+
+    // Output of every part, following the first candidate
+    let mut outputs = Vec::with_capacity(parts.len());
+    let mut completion_tokens = 0;
+    for part in parts {
+        match part {
+            PreparedPart::Fixed(text) => {
+                if !text.is_empty() {
+                    send_text(tx, candidates, &text).await;
                 }
+                outputs.push(text);
+            }
+            PreparedPart::Request {
+                request,
+                tokenizer,
+                protected,
+            } => {
+                let mut output =
+                    stream_completion(client, *request, candidates, &protected, tx).await?;
+                completion_tokens += output.iter().map(|o| tokenizer.count(o)).sum::<usize>();
+                outputs.push(output.swap_remove(0));
             }
         }
+    }
 
-        // Send a completion event
-        let _ = tx.send(Event::default().event("done").data("")).await;
-        debug!("Stream completed");
-    });
+    let output = GenerationOutput {
+        text: outputs.concat(),
+        parts: outputs,
+    };
+    for finisher in finishers {
+        for event in finisher(&output) {
+            let _ = tx.send(event).await;
+        }
+    }
+    if let Some(diff) = diff {
+        let _ = tx.send(StreamEvent::Diff(diff.report(&output.text))).await;
+    }
 
-    // Convert the receiver to a stream
-    let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
-    let stream = stream.map(Ok);
+    Ok((
+        output.text,
+        TokenUsage::new(prompt_tokens, completion_tokens),
+    ))
+}
 
-    // Create the SSE response with a keep-alive and wrap it with no-cache headers
-    let sse =
-        Sse::new(stream).keep_alive(KeepAlive::new().interval(std::time::Duration::from_secs(15)));
+// Gather the events of a generation into a single JSON response
+async fn collect_response(mut rx: mpsc::Receiver<StreamEvent>) -> Response {
+    let mut response = TextResponse::default();
+    while let Some(event) = rx.recv().await {
+        match event {
+            StreamEvent::Delta(content) => response.result.push_str(&content),
+            StreamEvent::Candidate { index, content } => {
+                let candidates = response.candidates.get_or_insert_with(Vec::new);
+                if candidates.len() <= index {
+                    candidates.resize(index + 1, String::new());
+                }
+                candidates[index].push_str(&content);
+            }
+            StreamEvent::Metadata(metadata) => response.metadata = Some(metadata),
+            StreamEvent::Glossary(report) => response.glossary = Some(report),
+            StreamEvent::Segments(segments) => response.segments = Some(segments),
+            StreamEvent::Diff(report) => response.diff = Some(report),
+            StreamEvent::Markup(report) => response.markup = Some(report),
+            StreamEvent::Step(report) => {
+                let steps = response.steps.get_or_insert_with(Vec::new);
+                match report.status {
+                    StepStatus::Started => response.result.clear(),
+                    // The result is the output of the last step that completed
+                    StepStatus::Completed | StepStatus::Failed => {
+                        response.result = steps
+                            .iter()
+                            .chain(std::iter::once(&report))
+                            .rev()
+                            .find_map(|step| step.output.clone())
+                            .unwrap_or_default();
+                        steps.push(report);
+                    }
+                }
+            }
+            StreamEvent::Usage(usage) => response.usage = Some(usage),
+            StreamEvent::Error(message) => {
+                // The message already carries the error's description
+                let status = StatusCode::BAD_GATEWAY;
+                let body = json!({ "error": { "message": message, "code": status.as_u16() } });
+                return (status, Json(body)).into_response();
+            }
+            StreamEvent::Done => break,
+        }
+    }
 
-    // Return the wrapped SSE response with no-cache headers
-    Ok(SseWithNoCacheHeaders(sse))
+    if let Some(first) = response.candidates.as_ref().and_then(|c| c.first()) {
+        response.result = first.clone();
+    }
+
+    Json(response).into_response()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::api::create_router;
     use crate::config::Config;
+    use crate::test_utils::*;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use tower::ServiceExt;
 
     #[test]
     fn test_create_client() {
@@ -247,6 +1151,265 @@ mod tests {
         let _ = client.chat();
     }
 
-    // TODO: Add more comprehensive tests with mocked OpenAI API responses
-    // This would require setting up a mock server to simulate OpenAI API responses
+    #[test]
+    fn test_to_sse_event_encodes_usage() {
+        let event = to_sse_event(StreamEvent::Usage(TokenUsage::new(3, 4)));
+        let encoded = format!("{:?}", event);
+        assert!(encoded.contains("usage"));
+        assert!(encoded.contains("total_tokens"));
+    }
+
+    #[test]
+    fn test_build_chat_request_applies_template_settings() {
+        let prompt = RenderedPrompt {
+            system: Some("You are an editor.".to_string()),
+            user: "Fix this".to_string(),
+            model: None,
+            temperature: Some(0.3),
+        };
+
+        let request = build_chat_request("gpt-4o", prompt.clone(), Sampling::default()).unwrap();
+
+        assert_eq!(request.model, "gpt-4o");
+        assert_eq!(request.messages.len(), 2);
+        assert_eq!(request.messages[0].role, Role::System);
+        assert_eq!(request.temperature, Some(0.3));
+        assert_eq!(request.stream, Some(true));
+
+        // Request parameters win over the template's settings
+        let sampling = Sampling {
+            temperature: Some(0.9),
+            top_p: None,
+            max_tokens: Some(200),
+            n: Some(3),
+        };
+        let request = build_chat_request("gpt-4o", prompt, sampling).unwrap();
+        assert_eq!(request.temperature, Some(0.9));
+        assert_eq!(request.max_tokens, Some(200));
+        assert_eq!(request.n, Some(3));
+    }
+
+    fn translate_to_spanish(request: &serde_json::Value) -> Vec<String> {
+        let message = last_user_message(request);
+        let text = message.rsplit("\n\n").next().unwrap_or_default();
+        let translated = text
+            .replace("Hello there.", "Hola.")
+            .replace("How are you?", "¿Cómo estás?")
+            .replace("Good day.", "Buen día.");
+        vec![translated]
+    }
+
+    async fn post_translate(
+        config: &Config,
+        state: &AppState,
+        text: &str,
+    ) -> Vec<(String, String)> {
+        let request = authed_request(config, "alice")
+            .method("POST")
+            .uri("/api/text/translate")
+            .header("content-type", "application/json")
+            .body(json_body(serde_json::json!({
+                "text": text,
+                "source_language": "en",
+                "target_language": "es",
+            })))
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        sse_events(response).await
+    }
+
+    #[tokio::test]
+    async fn test_translate_reuses_translation_memory() {
+        let mock = spawn_mock_openai(translate_to_spanish).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+
+        let events = post_translate(&config, &state, "Hello there. How are you?").await;
+        assert_eq!(sse_content(&events), "Hola. ¿Cómo estás?");
+
+        let events = post_translate(&config, &state, "Hello there. Good day.").await;
+        assert_eq!(sse_content(&events), "Hola. Buen día.");
+
+        // Only the new sentence went upstream the second time
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(last_user_message(&requests[1]).ends_with("\n\nGood day."));
+
+        let metadata = sse_json(&events, "metadata").unwrap();
+        assert_eq!(metadata["translation_memory"]["memory_hits"], 1);
+        let segments = sse_json(&events, "segments").unwrap();
+        assert_eq!(segments[0]["origin"], "memory");
+        assert_eq!(segments[1]["origin"], "generated");
+    }
+
+    #[tokio::test]
+    async fn test_translate_ignores_unreliable_detection() {
+        let mock = spawn_mock_openai(translate_to_spanish).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+        let text = "Hello there. Good day.";
+        assert!(!detect_language(text).unwrap().reliable);
+
+        let request = authed_request(&config, "alice")
+            .method("POST")
+            .uri("/api/text/translate")
+            .header("content-type", "application/json")
+            .body(json_body(serde_json::json!({
+                "text": text,
+                "target_language": "es",
+            })))
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        let events = sse_events(response).await;
+
+        let metadata = sse_json(&events, "metadata").unwrap();
+        assert_eq!(metadata["source_language"], serde_json::Value::Null);
+        assert_eq!(metadata["source_language_detected"], false);
+        assert!(metadata.get("translation_memory").is_none());
+        let requests = mock.requests();
+        assert!(last_user_message(&requests[0]).contains("from its original language"));
+        assert!(state.translation_memory.stats("alice").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_translate_markdown_keeps_markup() {
+        let mock = spawn_mock_openai(translate_to_spanish).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+
+        let text = "# Hello there.\n\nHello there. Run `make` from https://example.com/a.\n\n```\nHello there.\n```\n";
+        let request = authed_request(&config, "alice")
+            .method("POST")
+            .uri("/api/text/translate")
+            .header("content-type", "application/json")
+            .body(json_body(serde_json::json!({
+                "text": text,
+                "source_language": "en",
+                "target_language": "es",
+                "markup": "markdown",
+            })))
+            .unwrap();
+        let response = create_router(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let events = sse_events(response).await;
+
+        assert_eq!(
+            sse_content(&events),
+            "# Hola.\n\nHola. Run `make` from https://example.com/a.\n\n```\nHello there.\n```\n"
+        );
+        let report = sse_json(&events, "markup").unwrap();
+        assert_eq!(report["segments"], 2);
+        assert_eq!(report["missing"], serde_json::json!([]));
+
+        // Only the prose went upstream, with its markup as placeholders
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(last_user_message(&requests[1]).ends_with("\n\nHello there. Run ⟦1⟧ from ⟦2⟧."));
+    }
+
+    #[tokio::test]
+    async fn test_generation_params_reach_upstream() {
+        let mock = spawn_mock_openai(|_| vec!["Short.".to_string()]).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+
+        let request = authed_request(&config, "alice")
+            .uri("/api/text/summarize?text=Long%20text&max_tokens=50&temperature=0.2&tone=casual")
+            .body(Body::empty())
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let events = sse_events(response).await;
+        assert_eq!(sse_content(&events), "Short.");
+
+        // Nothing is shared between the input and the output
+        let diff = sse_json(&events, "diff").unwrap();
+        assert_eq!(diff["granularity"], "word");
+        assert_eq!(diff["changes"][0]["op"], "delete");
+        assert_eq!(diff["changes"][0]["text"], "Long text");
+
+        let upstream = &mock.requests()[0];
+        assert_eq!(upstream["max_tokens"], 50);
+        assert!((upstream["temperature"].as_f64().unwrap() - 0.2).abs() < 1e-6);
+        assert!(last_user_message(upstream).contains("Use a casual tone."));
+
+        // Only admins may set top_p
+        let request = authed_request(&config, "alice")
+            .uri("/api/text/summarize?text=Long%20text&top_p=0.5")
+            .body(Body::empty())
+            .unwrap();
+        let response = create_router(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    fn numbered_candidates(request: &serde_json::Value) -> Vec<String> {
+        let n = request["n"].as_u64().unwrap_or(1);
+        (1..=n).map(|i| format!("Option number {}", i)).collect()
+    }
+
+    fn paraphrase_request(config: &Config, accept: &str, body: serde_json::Value) -> Request<Body> {
+        authed_request(config, "alice")
+            .method("POST")
+            .uri("/api/text/paraphrase")
+            .header("content-type", "application/json")
+            .header("accept", accept)
+            .body(json_body(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_candidates_stream_on_indexed_events() {
+        let mock = spawn_mock_openai(numbered_candidates).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+
+        let request = paraphrase_request(
+            &config,
+            "text/event-stream",
+            serde_json::json!({"text": "Some text", "n": 2}),
+        );
+        let response = create_router(state).oneshot(request).await.unwrap();
+        let events = sse_events(response).await;
+
+        let mut candidates = vec![String::new(); 2];
+        for (_, data) in events.iter().filter(|(event, _)| event == "candidate") {
+            let candidate: serde_json::Value = serde_json::from_str(data).unwrap();
+            let index = candidate["index"].as_u64().unwrap() as usize;
+            candidates[index].push_str(candidate["content"].as_str().unwrap());
+        }
+        assert_eq!(candidates, vec!["Option number 1", "Option number 2"]);
+        assert_eq!(sse_content(&events), "");
+        assert_eq!(mock.requests()[0]["n"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_json_mode_returns_candidates() {
+        let mock = spawn_mock_openai(numbered_candidates).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+
+        let request = paraphrase_request(
+            &config,
+            "application/json",
+            serde_json::json!({"text": "Some text", "n": 3}),
+        );
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body["result"], "Option number 1");
+        assert_eq!(body["candidates"].as_array().unwrap().len(), 3);
+        assert_eq!(body["candidates"][2], "Option number 3");
+        assert!(body["usage"]["completion_tokens"].as_u64().unwrap() > 0);
+
+        // Summaries have a single answer
+        let request = authed_request(&config, "alice")
+            .uri("/api/text/summarize?text=Some%20text&n=2")
+            .body(Body::empty())
+            .unwrap();
+        let response = create_router(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
 }
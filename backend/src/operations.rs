@@ -0,0 +1,744 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::config::GenerationLimits;
+use crate::db::Database;
+use crate::error::AppError;
+use crate::history::{EntrySource, PendingEntry};
+use crate::models::Claims;
+use crate::openai::{process_text_with_openai, Generation, ResponseFormat};
+use crate::params::GenerationParams;
+use crate::ratelimit::ClientAddress;
+use crate::state::AppState;
+use crate::templates::PromptTemplate;
+
+// Owner key used for operations shared with every user
+const SHARED_OWNER: &str = "";
+// Operations a user may define, and admins may share
+const MAX_OPERATIONS: usize = 50;
+
+// Registry templates that may also be run as operations. Translation and
+// proofreading have their own endpoints, which check what they need.
+const TEMPLATE_OPERATIONS: &[&str] = &["paraphrase", "expand", "summarize"];
+
+// A named text operation defined at runtime by a user or an admin
+#[derive(Debug, Clone, Serialize)]
+pub struct CustomOperation {
+    pub name: String,
+    pub description: String,
+    pub parameters: Vec<String>,
+    pub owner: String,
+    pub shared: bool,
+    pub version: u32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    #[serde(skip)]
+    pub template: PromptTemplate,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OperationDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    // Extra variables beyond `text` that callers fill in when running the operation
+    #[serde(default)]
+    pub parameters: Vec<String>,
+    #[serde(default)]
+    pub system: Option<String>,
+    pub user: String,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    // Only admins may define operations visible to every user
+    #[serde(default)]
+    pub shared: bool,
+}
+
+// Input for running an operation: the text plus any declared parameters
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OperationRunRequest {
+    pub text: String,
+    #[serde(flatten)]
+    pub params: Map<String, Value>,
+}
+
+fn validate_name(name: &str) -> Result<(), AppError> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(AppError::BadRequest(
+            "Operation names must be 1-64 characters of a-z, 0-9, '-' or '_'".to_string(),
+        ))
+    }
+}
+
+impl OperationDefinition {
+    // A pinned model and temperature are held to the limits of the defining role
+    fn check_limits(&self, limits: &GenerationLimits, default_model: &str) -> Result<(), AppError> {
+        if let Some(model) = &self.model {
+            if model != default_model && !limits.allows_model(model) {
+                return Err(AppError::Forbidden(format!(
+                    "Your role cannot pin the model {}",
+                    model
+                )));
+            }
+        }
+
+        GenerationParams {
+            temperature: self.temperature,
+            ..Default::default()
+        }
+        .validate(limits)
+    }
+
+    fn to_template(&self, version: u32) -> Result<PromptTemplate, AppError> {
+        if self.parameters.iter().any(|p| p == "text") {
+            return Err(AppError::BadRequest(
+                "`text` is always provided and cannot be declared as a parameter".to_string(),
+            ));
+        }
+
+        let template = PromptTemplate {
+            name: self.name.clone(),
+            version,
+            description: self.description.clone(),
+            variables: std::iter::once("text".to_string())
+                .chain(self.parameters.iter().cloned())
+                .collect(),
+            model: self.model.clone(),
+            temperature: self.temperature,
+            system: self.system.clone(),
+            user: self.user.clone(),
+        };
+        template.validate()?;
+
+        Ok(template)
+    }
+}
+
+const OPERATION_COLUMNS: &str = "owner, author, definition, version, created_at, updated_at";
+
+fn operation_from_row(row: &Row) -> rusqlite::Result<CustomOperation> {
+    let definition: String = row.get(2)?;
+    let definition: OperationDefinition = serde_json::from_str(&definition).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, Box::new(e))
+    })?;
+    let version: u32 = row.get(3)?;
+    let template = definition.to_template(version).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, e.into())
+    })?;
+
+    Ok(CustomOperation {
+        name: definition.name,
+        description: definition.description,
+        parameters: definition.parameters,
+        owner: row.get(1)?,
+        shared: row.get::<_, String>(0)? == SHARED_OWNER,
+        version,
+        created_at: row.get(4)?,
+        updated_at: row.get(5)?,
+        template,
+    })
+}
+
+// Custom operations of every user, kept in the database so they outlive a
+// restart. Shared operations are stored under `SHARED_OWNER`.
+pub struct OperationStore {
+    database: Arc<Database>,
+}
+
+impl OperationStore {
+    pub fn new(database: Arc<Database>) -> Self {
+        OperationStore { database }
+    }
+
+    // Create or replace an operation, bumping its version on replace
+    pub fn upsert(
+        &self,
+        claims: &Claims,
+        definition: OperationDefinition,
+    ) -> Result<CustomOperation, AppError> {
+        validate_name(&definition.name)?;
+
+        if definition.shared && !claims.is_admin() {
+            return Err(AppError::Forbidden(
+                "Only admins can define shared operations".to_string(),
+            ));
+        }
+
+        let owner = if definition.shared {
+            SHARED_OWNER
+        } else {
+            claims.sub.as_str()
+        };
+        let stored = serde_json::to_string(&definition)
+            .map_err(|e| AppError::Internal(format!("Failed to serialize operation: {}", e)))?;
+
+        self.database.with(|connection| {
+            let transaction = connection.transaction()?;
+            let existing: Option<(u32, DateTime<Utc>)> = transaction
+                .query_row(
+                    "SELECT version, created_at FROM custom_operations WHERE owner = ?1 AND name = ?2",
+                    params![owner, definition.name],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?;
+
+            let now = Utc::now();
+            let (version, created_at) = match existing {
+                Some((version, created_at)) => (version + 1, created_at),
+                None => {
+                    let defined: i64 = transaction.query_row(
+                        "SELECT COUNT(*) FROM custom_operations WHERE owner = ?1",
+                        [owner],
+                        |row| row.get(0),
+                    )?;
+                    if defined as usize >= MAX_OPERATIONS {
+                        return Err(AppError::BadRequest(format!(
+                            "At most {} operations can be defined",
+                            MAX_OPERATIONS
+                        )));
+                    }
+                    (1, now)
+                }
+            };
+
+            let operation = CustomOperation {
+                name: definition.name.clone(),
+                description: definition.description.clone(),
+                parameters: definition.parameters.clone(),
+                owner: claims.sub.clone(),
+                shared: definition.shared,
+                version,
+                created_at,
+                updated_at: now,
+                template: definition.to_template(version)?,
+            };
+            transaction.execute(
+                "INSERT OR REPLACE INTO custom_operations
+                 (owner, name, author, definition, version, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    owner,
+                    operation.name,
+                    operation.owner,
+                    stored,
+                    version,
+                    created_at,
+                    now,
+                ],
+            )?;
+            transaction.commit()?;
+
+            Ok(operation)
+        })
+    }
+
+    // Find an operation visible to the user, preferring their own over shared ones
+    pub fn find(&self, username: &str, name: &str) -> Result<Option<CustomOperation>, AppError> {
+        self.database.with(|connection| {
+            Ok(connection
+                .query_row(
+                    &format!(
+                        "SELECT {} FROM custom_operations WHERE owner IN (?1, ?2) AND name = ?3
+                         ORDER BY owner = ?2 LIMIT 1",
+                        OPERATION_COLUMNS
+                    ),
+                    params![username, SHARED_OWNER, name],
+                    operation_from_row,
+                )
+                .optional()?)
+        })
+    }
+
+    // Every operation visible to the user, their own hiding shared ones of the same name
+    pub fn list(&self, username: &str) -> Result<Vec<CustomOperation>, AppError> {
+        let operations: Vec<CustomOperation> = self.database.with(|connection| {
+            let mut statement = connection.prepare(&format!(
+                "SELECT {} FROM custom_operations WHERE owner IN (?1, ?2)
+                 ORDER BY name, owner = ?1",
+                OPERATION_COLUMNS
+            ))?;
+            let operations = statement
+                .query_map(params![SHARED_OWNER, username], operation_from_row)?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(operations)
+        })?;
+
+        let mut visible: BTreeMap<String, CustomOperation> = BTreeMap::new();
+        for operation in operations {
+            visible.insert(operation.name.clone(), operation);
+        }
+        Ok(visible.into_values().collect())
+    }
+
+    // Delete the user's own operation, or a shared one if the user is an admin
+    pub fn remove(&self, claims: &Claims, name: &str) -> Result<(), AppError> {
+        self.database.with(|connection| {
+            let delete = |owner: &str| {
+                connection.execute(
+                    "DELETE FROM custom_operations WHERE owner = ?1 AND name = ?2",
+                    params![owner, name],
+                )
+            };
+            if delete(&claims.sub)? > 0 {
+                return Ok(());
+            }
+
+            let shared = connection
+                .query_row(
+                    "SELECT 1 FROM custom_operations WHERE owner = ?1 AND name = ?2",
+                    params![SHARED_OWNER, name],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+            if !shared {
+                return Err(AppError::NotFound(format!("Operation {}", name)));
+            }
+            if !claims.is_admin() {
+                return Err(AppError::Forbidden(
+                    "Only admins can delete shared operations".to_string(),
+                ));
+            }
+            delete(SHARED_OWNER)?;
+            Ok(())
+        })
+    }
+}
+
+// List the custom operations available to the current user
+pub async fn list_operations(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<CustomOperation>>, AppError> {
+    Ok(Json(state.operations.list(&claims.sub)?))
+}
+
+// Create or replace a custom operation
+pub async fn upsert_operation(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(definition): Json<OperationDefinition>,
+) -> Result<impl IntoResponse, AppError> {
+    definition.check_limits(
+        state.config.generation.limits_for(claims.role),
+        &state.config.openai.model,
+    )?;
+    let operation = state.operations.upsert(&claims, definition)?;
+    let status = if operation.version == 1 {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+
+    Ok((status, Json(operation)))
+}
+
+// Delete a custom operation
+pub async fn delete_operation(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AppError> {
+    state.operations.remove(&claims, &name)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Run a custom operation - support both GET and POST
+pub async fn run_operation(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(name): Path<String>,
+    ClientAddress(address): ClientAddress,
+    format: ResponseFormat,
+    run_param: Option<Query<OperationRunRequest>>,
+    run_json: Option<Json<OperationRunRequest>>,
+) -> Result<impl IntoResponse, AppError> {
+    // Extract the input from either query parameters or JSON body
+    let request = if let Some(Query(query)) = run_param {
+        query
+    } else if let Some(Json(json)) = run_json {
+        json
+    } else {
+        return Err(AppError::BadRequest("Text is required".to_string()));
+    };
+
+    let entry = PendingEntry::new(&claims, &name, EntrySource::Request, &request);
+    let generation =
+        operation_generation(&state, &claims, &name, request).map_err(|e| entry.fail(&state, e))?;
+    let entry = entry.admit(&state, address)?;
+    process_text_with_openai(state, generation, format, entry).await
+}
+
+// Build the generation of a custom operation or registry template
+pub fn operation_generation(
+    state: &AppState,
+    claims: &Claims,
+    name: &str,
+    request: OperationRunRequest,
+) -> Result<Generation, AppError> {
+    // User-defined operations first, then the free-text templates of the registry
+    let template = match state.operations.find(&claims.sub, name)? {
+        Some(operation) => operation.template,
+        None if TEMPLATE_OPERATIONS.contains(&name) => state.templates.get(name)?.clone(),
+        None => return Err(AppError::NotFound(format!("Operation {}", name))),
+    };
+
+    // Declared variables fill the template, anything else is a generation parameter
+    let (variables, extra): (Map<_, _>, Map<_, _>) = request
+        .params
+        .into_iter()
+        .partition(|(key, _)| template.variables.contains(key));
+    let template_params = variables
+        .into_iter()
+        .map(|(key, value)| match value {
+            Value::String(value) => Ok((key, value)),
+            Value::Number(_) | Value::Bool(_) => Ok((key, value.to_string())),
+            _ => Err(AppError::BadRequest(format!(
+                "Parameter {} must be a string, number or boolean",
+                key
+            ))),
+        })
+        .collect::<Result<HashMap<_, _>, _>>()?;
+    // Parameters the template has no use for are refused rather than dropped
+    if let Some(key) = extra
+        .keys()
+        .find(|key| !GenerationParams::NAMES.contains(&key.as_str()))
+    {
+        return Err(AppError::BadRequest(format!(
+            "Operation {} has no parameter {}",
+            name, key
+        )));
+    }
+    let params: GenerationParams = serde_json::from_value(Value::Object(extra))
+        .map_err(|e| AppError::BadRequest(format!("Invalid generation parameters: {}", e)))?;
+    params.validate(state.config.generation.limits_for(claims.role))?;
+    if !template.variables.iter().any(|v| v == "style") {
+        if let Some(key) = params
+            .overridden()
+            .into_iter()
+            .find(|key| GenerationParams::STYLE_NAMES.contains(key))
+        {
+            return Err(AppError::BadRequest(format!(
+                "Operation {} does not take {}",
+                name, key
+            )));
+        }
+    }
+
+    let style = params.style_instructions();
+    let vars: Vec<(&str, &str)> = [("text", request.text.as_str()), ("style", style.as_str())]
+        .into_iter()
+        .chain(
+            template_params
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        )
+        .collect();
+    let prompt = template.render(&vars)?;
+
+    Ok(Generation::new(prompt).with_sampling(params.sampling()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::create_router;
+    use crate::config::Config;
+    use crate::models::UserRole;
+    use crate::test_utils::*;
+    use tower::ServiceExt;
+
+    fn claims(username: &str, role: UserRole) -> Claims {
+        Claims {
+            sub: username.to_string(),
+            exp: 0,
+            iat: 0,
+            role,
+        }
+    }
+
+    fn store() -> OperationStore {
+        let config = Config::default_test_config();
+        OperationStore::new(Arc::new(Database::open(&config.database).unwrap()))
+    }
+
+    fn definition(name: &str, shared: bool) -> OperationDefinition {
+        OperationDefinition {
+            name: name.to_string(),
+            description: "Make it formal".to_string(),
+            parameters: vec!["audience".to_string()],
+            system: None,
+            user: "Rewrite formally for {{audience}}:\n\n{{text}}".to_string(),
+            model: None,
+            temperature: None,
+            shared,
+        }
+    }
+
+    #[test]
+    fn test_upsert_bumps_version() {
+        let store = store();
+        let user = claims("alice", UserRole::User);
+
+        let first = store.upsert(&user, definition("formal", false)).unwrap();
+        let second = store.upsert(&user, definition("formal", false)).unwrap();
+
+        assert_eq!(first.version, 1);
+        assert_eq!(second.version, 2);
+        assert_eq!(second.created_at, first.created_at);
+    }
+
+    #[test]
+    fn test_shared_operations_require_admin() {
+        let store = store();
+
+        let result = store.upsert(&claims("alice", UserRole::User), definition("formal", true));
+        assert!(matches!(result, Err(AppError::Forbidden(_))));
+
+        store
+            .upsert(&claims("root", UserRole::Admin), definition("formal", true))
+            .unwrap();
+        assert!(store.find("bob", "formal").unwrap().is_some());
+        assert!(matches!(
+            store.remove(&claims("bob", UserRole::User), "formal"),
+            Err(AppError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn test_private_operations_are_per_user() {
+        let store = store();
+        store
+            .upsert(
+                &claims("alice", UserRole::User),
+                definition("bullets", false),
+            )
+            .unwrap();
+
+        assert_eq!(store.list("alice").unwrap().len(), 1);
+        assert!(store.list("bob").unwrap().is_empty());
+        assert!(store.find("bob", "bullets").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_operations_outlive_the_store() {
+        let dir = std::env::temp_dir().join(format!("operations-test-{}", rand::random::<u64>()));
+        let config = crate::config::DatabaseConfig {
+            url: format!("sqlite://{}", dir.join("app.db").display()),
+        };
+        let open = || OperationStore::new(Arc::new(Database::open(&config).unwrap()));
+
+        open()
+            .upsert(
+                &claims("alice", UserRole::User),
+                definition("formal", false),
+            )
+            .unwrap();
+        let operation = open().find("alice", "formal").unwrap().unwrap();
+        assert_eq!(operation.owner, "alice");
+        assert_eq!(operation.template.variables, ["text", "audience"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_operations_per_user_are_limited() {
+        let store = store();
+        let user = claims("alice", UserRole::User);
+        for index in 0..MAX_OPERATIONS {
+            store
+                .upsert(&user, definition(&format!("op-{}", index), false))
+                .unwrap();
+        }
+
+        let result = store.upsert(&user, definition("one-more", false));
+        assert!(matches!(result, Err(AppError::BadRequest(_))));
+        // Replacing an existing operation is still allowed
+        store.upsert(&user, definition("op-0", false)).unwrap();
+        store
+            .upsert(
+                &claims("bob", UserRole::User),
+                definition("one-more", false),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_invalid_definitions_are_rejected() {
+        let store = store();
+        let user = claims("alice", UserRole::User);
+
+        assert!(store.upsert(&user, definition("Not Valid", false)).is_err());
+
+        let mut undeclared = definition("formal", false);
+        undeclared.parameters.clear();
+        assert!(store.upsert(&user, undeclared).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pinned_model_and_temperature_follow_role_limits() {
+        let config = test_config("http://127.0.0.1:9");
+        let state = test_state(config.clone());
+        let define = |user: &str, model: &str, temperature: f32| {
+            let request = authed_request(&config, user)
+                .method("POST")
+                .uri("/api/text/ops")
+                .header("content-type", "application/json")
+                .body(json_body(serde_json::json!({
+                    "name": "formal",
+                    "user": "Rewrite formally:\n\n{{text}}",
+                    "model": model,
+                    "temperature": temperature,
+                })))
+                .unwrap();
+            create_router(state.clone()).oneshot(request)
+        };
+
+        let response = define("alice", "gpt-4", 0.5).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = define("alice", &config.openai.model, 1.5).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(state.operations.find("alice", "formal").unwrap().is_none());
+
+        let response = define("alice", &config.openai.model, 0.5).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = define("admin", "gpt-4", 1.5).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_run_accepts_numeric_parameters() {
+        let mock = spawn_mock_openai(|_| vec!["First.".to_string(), "Second.".to_string()]).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+        state
+            .operations
+            .upsert(
+                &claims("alice", UserRole::User),
+                definition("formal", false),
+            )
+            .unwrap();
+
+        let request = authed_request(&config, "alice")
+            .method("POST")
+            .uri("/api/text/ops/formal")
+            .header("content-type", "application/json")
+            .body(json_body(serde_json::json!({
+                "text": "Hello there",
+                "audience": "lawyers",
+                "temperature": 0.3,
+                "n": 2,
+                "max_tokens": 50,
+            })))
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        sse_events(response).await;
+
+        let upstream = &mock.requests()[0];
+        assert_eq!(upstream["temperature"].as_f64().unwrap() as f32, 0.3);
+        assert_eq!(upstream["n"], 2);
+        assert_eq!(upstream["max_tokens"], 50);
+        assert!(last_user_message(upstream).contains("lawyers"));
+    }
+
+    #[tokio::test]
+    async fn test_run_refuses_what_the_template_cannot_use() {
+        let mock = spawn_mock_openai(|_| vec!["Done.".to_string()]).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+        state
+            .operations
+            .upsert(
+                &claims("alice", UserRole::User),
+                definition("formal", false),
+            )
+            .unwrap();
+        let run = |name: &str, body: Value| {
+            let request = authed_request(&config, "alice")
+                .method("POST")
+                .uri(format!("/api/text/ops/{}", name))
+                .header("content-type", "application/json")
+                .body(json_body(body))
+                .unwrap();
+            create_router(state.clone()).oneshot(request)
+        };
+
+        // Only the free-text registry templates run as operations
+        let response = run("translate", serde_json::json!({"text": "Hello"}))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = run("proofread", serde_json::json!({"text": "Hello"}))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Unknown parameters and style without a `style` variable are refused
+        let response = run(
+            "formal",
+            serde_json::json!({"text": "Hello", "audience": "lawyers", "mood": "sunny"}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = run(
+            "formal",
+            serde_json::json!({"text": "Hello", "audience": "lawyers", "tone": "formal"}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = run(
+            "summarize",
+            serde_json::json!({"text": "Hello", "target_language": "es"}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(mock.requests().is_empty());
+
+        let response = run(
+            "summarize",
+            serde_json::json!({"text": "Hello", "tone": "formal"}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        sse_events(response).await;
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_run_request_collects_parameters() {
+        let uri: axum::http::Uri = "/api/text/ops/formal?text=hello&audience=lawyers"
+            .parse()
+            .unwrap();
+        let Query(request) = Query::<OperationRunRequest>::try_from_uri(&uri).unwrap();
+
+        assert_eq!(request.text, "hello");
+        assert_eq!(
+            request.params.get("audience").and_then(Value::as_str),
+            Some("lawyers")
+        );
+    }
+}
@@ -0,0 +1,334 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+
+use crate::config::GenerationLimits;
+use crate::error::AppError;
+
+// Longest audience description accepted, in characters
+const MAX_AUDIENCE_LENGTH: usize = 100;
+
+// Most candidate outputs a single request may ask for
+pub const MAX_CANDIDATES: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Length {
+    Short,
+    Medium,
+    Long,
+}
+
+impl Length {
+    fn instruction(self) -> &'static str {
+        match self {
+            Length::Short => "Keep the result brief.",
+            Length::Medium => "Keep the result to a moderate length.",
+            Length::Long => "Make the result detailed and thorough.",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tone {
+    Neutral,
+    Formal,
+    Casual,
+    Friendly,
+    Professional,
+    Confident,
+}
+
+impl Tone {
+    fn name(self) -> &'static str {
+        match self {
+            Tone::Neutral => "neutral",
+            Tone::Formal => "formal",
+            Tone::Casual => "casual",
+            Tone::Friendly => "friendly",
+            Tone::Professional => "professional",
+            Tone::Confident => "confident",
+        }
+    }
+}
+
+// Accept numbers as JSON numbers or as strings, since query parameters and
+// flattened fields only ever carry strings
+fn optional_number<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr + Deserialize<'de>,
+    T::Err: Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString<T> {
+        Number(T),
+        String(String),
+    }
+
+    match Option::<NumberOrString<T>>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(NumberOrString::Number(number)) => Ok(Some(number)),
+        Some(NumberOrString::String(value)) => {
+            value.trim().parse().map(Some).map_err(de::Error::custom)
+        }
+    }
+}
+
+// Optional generation settings a client may put on a text request
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GenerationParams {
+    #[serde(
+        default,
+        deserialize_with = "optional_number",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub temperature: Option<f32>,
+    #[serde(
+        default,
+        deserialize_with = "optional_number",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub top_p: Option<f32>,
+    #[serde(
+        default,
+        deserialize_with = "optional_number",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_tokens: Option<u16>,
+    // Number of candidate outputs to generate
+    #[serde(
+        default,
+        deserialize_with = "optional_number",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub n: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<Length>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tone: Option<Tone>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+}
+
+// Sampling settings sent with the upstream request
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sampling {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u16>,
+    pub n: Option<u8>,
+}
+
+impl Sampling {
+    pub fn candidates(&self) -> usize {
+        self.n.unwrap_or(1).max(1) as usize
+    }
+}
+
+impl GenerationParams {
+    // Every parameter a request may set, in the order of the fields
+    pub const NAMES: [&'static str; 7] = [
+        "temperature",
+        "top_p",
+        "max_tokens",
+        "n",
+        "length",
+        "tone",
+        "audience",
+    ];
+    // Those carried into the prompt by the `style` template variable
+    pub const STYLE_NAMES: [&'static str; 3] = ["length", "tone", "audience"];
+
+    // Names of the parameters the request sets
+    pub fn overridden(&self) -> Vec<&'static str> {
+        let set = [
+            self.temperature.is_some(),
+            self.top_p.is_some(),
+            self.max_tokens.is_some(),
+            self.n.is_some(),
+            self.length.is_some(),
+            self.tone.is_some(),
+            self.audience.is_some(),
+        ];
+        Self::NAMES
+            .into_iter()
+            .zip(set)
+            .filter(|(_, set)| *set)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    // Check the parameters against the limits of the caller's role
+    pub fn validate(&self, limits: &GenerationLimits) -> Result<(), AppError> {
+        if let Some(name) = self
+            .overridden()
+            .into_iter()
+            .find(|name| !limits.overridable.iter().any(|o| o == name))
+        {
+            return Err(AppError::Forbidden(format!(
+                "Your role cannot set {}",
+                name
+            )));
+        }
+
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=limits.max_temperature).contains(&temperature) {
+                return Err(AppError::BadRequest(format!(
+                    "temperature must be between 0 and {}",
+                    limits.max_temperature
+                )));
+            }
+        }
+
+        if let Some(top_p) = self.top_p {
+            if !(0.0..=1.0).contains(&top_p) {
+                return Err(AppError::BadRequest(
+                    "top_p must be between 0 and 1".to_string(),
+                ));
+            }
+        }
+
+        if let Some(max_tokens) = self.max_tokens {
+            if max_tokens == 0 || max_tokens > limits.max_tokens {
+                return Err(AppError::BadRequest(format!(
+                    "max_tokens must be between 1 and {}",
+                    limits.max_tokens
+                )));
+            }
+        }
+
+        if let Some(n) = self.n {
+            if n == 0 || n > MAX_CANDIDATES {
+                return Err(AppError::BadRequest(format!(
+                    "n must be between 1 and {}",
+                    MAX_CANDIDATES
+                )));
+            }
+        }
+
+        if let Some(audience) = &self.audience {
+            let audience = audience.trim();
+            if audience.is_empty()
+                || audience.chars().count() > MAX_AUDIENCE_LENGTH
+                || audience.chars().any(char::is_control)
+            {
+                return Err(AppError::BadRequest(format!(
+                    "audience must be a single line of at most {} characters",
+                    MAX_AUDIENCE_LENGTH
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    // For operations that can only produce a single output
+    pub fn require_single_candidate(&self) -> Result<(), AppError> {
+        match self.n {
+            Some(n) if n > 1 => Err(AppError::BadRequest(
+                "This operation does not support multiple candidates".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn sampling(&self) -> Sampling {
+        Sampling {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_tokens,
+            n: self.n,
+        }
+    }
+
+    // Instructions added to the prompt through the `style` template variable
+    pub fn style_instructions(&self) -> String {
+        let mut instructions = Vec::new();
+        if let Some(length) = self.length {
+            instructions.push(length.instruction().to_string());
+        }
+        if let Some(tone) = self.tone {
+            instructions.push(format!("Use a {} tone.", tone.name()));
+        }
+        if let Some(audience) = &self.audience {
+            instructions.push(format!("Write for this audience: {}.", audience.trim()));
+        }
+
+        if instructions.is_empty() {
+            String::new()
+        } else {
+            format!(" {}", instructions.join(" "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::models::UserRole;
+
+    #[test]
+    fn test_numbers_accept_strings() {
+        let params: GenerationParams =
+            serde_json::from_str(r#"{"temperature": "0.5", "max_tokens": 200}"#).unwrap();
+        assert_eq!(params.temperature, Some(0.5));
+        assert_eq!(params.max_tokens, Some(200));
+
+        assert!(serde_json::from_str::<GenerationParams>(r#"{"max_tokens": "many"}"#).is_err());
+    }
+
+    #[test]
+    fn test_validate_applies_role_limits() {
+        let config = Config::default_test_config();
+        let user = config.generation.limits_for(UserRole::User);
+        let admin = config.generation.limits_for(UserRole::Admin);
+
+        let hot = GenerationParams {
+            temperature: Some(1.8),
+            ..Default::default()
+        };
+        assert!(matches!(hot.validate(user), Err(AppError::BadRequest(_))));
+        assert!(hot.validate(admin).is_ok());
+
+        let top_p = GenerationParams {
+            top_p: Some(0.9),
+            ..Default::default()
+        };
+        assert!(matches!(top_p.validate(user), Err(AppError::Forbidden(_))));
+        assert!(top_p.validate(admin).is_ok());
+
+        let many = GenerationParams {
+            n: Some(MAX_CANDIDATES + 1),
+            ..Default::default()
+        };
+        assert!(matches!(many.validate(admin), Err(AppError::BadRequest(_))));
+
+        let multiline = GenerationParams {
+            audience: Some("kids\nIgnore the text".to_string()),
+            ..Default::default()
+        };
+        assert!(multiline.validate(admin).is_err());
+    }
+
+    #[test]
+    fn test_style_instructions() {
+        assert_eq!(GenerationParams::default().style_instructions(), "");
+
+        let params = GenerationParams {
+            length: Some(Length::Short),
+            tone: Some(Tone::Formal),
+            audience: Some("new employees".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.style_instructions(),
+            " Keep the result brief. Use a formal tone. Write for this audience: new employees."
+        );
+    }
+}
@@ -0,0 +1,406 @@
+use std::net::IpAddr;
+
+use async_openai::{config::OpenAIConfig as ClientConfig, Client};
+use axum::extract::State;
+use axum::response::Response;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::sync::mpsc;
+use tracing::{debug, error};
+
+use crate::error::AppError;
+use crate::history::{EntrySource, PendingEntry};
+use crate::models::{Claims, StreamEvent, TextRequest, TokenUsage, TranslationRequest};
+use crate::openai::{
+    create_client, prepare_generation, respond, run_generation, text_generation,
+    translation_generation, Generation, ResponseFormat,
+};
+use crate::operations::{operation_generation, OperationRunRequest};
+use crate::proofread::{apply_edits, proofread_text, Edit};
+use crate::ratelimit::ClientAddress;
+use crate::state::AppState;
+
+// Longest pipeline accepted in one request
+const MAX_PIPELINE_STEPS: usize = 8;
+
+// One step of a pipeline: an operation name and the options its own endpoint
+// accepts, without the text
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PipelineStep {
+    pub op: String,
+    #[serde(flatten)]
+    pub options: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PipelineRequest {
+    pub text: String,
+    pub steps: Vec<PipelineStep>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StepStatus {
+    Started,
+    Completed,
+    Failed,
+}
+
+// Sent when a step starts and when it completes or fails
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StepReport {
+    pub index: usize,
+    pub op: String,
+    pub status: StepStatus,
+    // Full output of a completed step, which is the input of the next one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    // Edits applied by a proofread step
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edits: Option<Vec<Edit>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TokenUsage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // HTTP status the error would have had on the operation's own endpoint
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<u16>,
+}
+
+impl StepReport {
+    fn new(index: usize, op: &str, status: StepStatus) -> Self {
+        StepReport {
+            index,
+            op: op.to_string(),
+            status,
+            output: None,
+            edits: None,
+            usage: None,
+            error: None,
+            code: None,
+        }
+    }
+}
+
+// A step parsed into the request of its operation
+pub enum StepRequest {
+    Rewrite(String, TextRequest),
+    Translate(TranslationRequest),
+    Proofread(TextRequest),
+    Custom(String, OperationRunRequest),
+}
+
+impl StepRequest {
+    // Parse a step's options for the given input text
+    pub fn parse(step: &PipelineStep, text: &str) -> Result<Self, AppError> {
+        let mut options = step.options.clone();
+        options.insert("text".to_string(), Value::String(text.to_string()));
+        let options = Value::Object(options);
+
+        let invalid = |e: serde_json::Error| AppError::BadRequest(e.to_string());
+        Ok(match step.op.as_str() {
+            "paraphrase" | "expand" | "summarize" => StepRequest::Rewrite(
+                step.op.clone(),
+                serde_json::from_value(options).map_err(invalid)?,
+            ),
+            "translate" => {
+                StepRequest::Translate(serde_json::from_value(options).map_err(invalid)?)
+            }
+            "proofread" => {
+                StepRequest::Proofread(serde_json::from_value(options).map_err(invalid)?)
+            }
+            name => StepRequest::Custom(
+                name.to_string(),
+                serde_json::from_value(options).map_err(invalid)?,
+            ),
+        })
+    }
+}
+
+impl StepRequest {
+    // Build the generation of a step other than proofreading
+    pub fn generation(&self, state: &AppState, claims: &Claims) -> Result<Generation, AppError> {
+        let generation = match self {
+            StepRequest::Rewrite(template, request) => {
+                text_generation(state, claims, template, request)?
+            }
+            StepRequest::Translate(request) => {
+                translation_generation(state, claims, request.clone())?
+            }
+            StepRequest::Custom(name, request) => {
+                operation_generation(state, claims, name, request.clone())?
+            }
+            StepRequest::Proofread(_) => {
+                return Err(AppError::Internal(
+                    "Proofreading is not a streamed generation".to_string(),
+                ))
+            }
+        };
+
+        if generation.sampling.candidates() > 1 {
+            return Err(AppError::BadRequest(
+                "Pipeline steps cannot generate multiple candidates".to_string(),
+            ));
+        }
+        Ok(generation)
+    }
+
+    // Check that the step would run, without calling the model
+    pub fn validate(&self, state: &AppState, claims: &Claims) -> Result<(), AppError> {
+        match self {
+            StepRequest::Proofread(request) => {
+                let params = &request.params;
+                params.validate(state.config.generation.limits_for(claims.role))?;
+                params.require_single_candidate()
+            }
+            _ => self.generation(state, claims).map(|_| ()),
+        }
+    }
+}
+
+// Run one step, streaming its events, and return its output, usage and any edits
+pub async fn run_step(
+    state: &AppState,
+    claims: &Claims,
+    client: &Client<ClientConfig>,
+    request: StepRequest,
+    tx: &mpsc::Sender<StreamEvent>,
+) -> Result<(String, TokenUsage, Option<Vec<Edit>>), AppError> {
+    if let StepRequest::Proofread(request) = &request {
+        // Proofreading returns edits, so the next step gets the corrected text
+        let response = proofread_text(state, claims, request).await?;
+        let corrected = apply_edits(&request.text, &response.edits);
+        let _ = tx.send(StreamEvent::Delta(corrected.clone())).await;
+        return Ok((corrected, response.usage, Some(response.edits)));
+    }
+
+    let generation = request.generation(state, claims)?;
+    let prepared = prepare_generation(state, generation)?;
+    let (output, usage) = run_generation(client, prepared, tx).await?;
+    Ok((output, usage, None))
+}
+
+// Run operations one after another, each on the previous one's output
+pub async fn run_pipeline(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    ClientAddress(address): ClientAddress,
+    format: ResponseFormat,
+    Json(request): Json<PipelineRequest>,
+) -> Result<Response, AppError> {
+    stream_pipeline(state, claims, address, format, request).await
+}
+
+// Validate a pipeline, then run it in the background and respond with its
+// events. Every step is charged to the rate limits, and the whole pipeline
+// holds one stream.
+pub async fn stream_pipeline(
+    state: AppState,
+    claims: Claims,
+    address: Option<IpAddr>,
+    format: ResponseFormat,
+    request: PipelineRequest,
+) -> Result<Response, AppError> {
+    if request.steps.is_empty() || request.steps.len() > MAX_PIPELINE_STEPS {
+        return Err(AppError::BadRequest(format!(
+            "A pipeline needs between 1 and {} steps",
+            MAX_PIPELINE_STEPS
+        )));
+    }
+
+    // Reject malformed steps before anything runs
+    for (index, step) in request.steps.iter().enumerate() {
+        StepRequest::parse(step, &request.text)
+            .map_err(|e| AppError::BadRequest(format!("Step {} ({}): {}", index, step.op, e)))?;
+    }
+
+    let permit = state
+        .limits
+        .acquire_many(&claims.sub, address, request.steps.len() as u32)?;
+    let client = create_client(&state.config);
+    let (tx, rx) = mpsc::channel(100);
+    let owner = claims.sub.clone();
+    let response_state = state.clone();
+
+    tokio::spawn(async move {
+        // Held until the last step ends, even if the client has gone
+        let _permit = permit;
+        let mut text = request.text;
+        let mut total = TokenUsage::new(0, 0);
+        let mut failed = false;
+
+        for (index, step) in request.steps.iter().enumerate() {
+            let started = StepReport::new(index, &step.op, StepStatus::Started);
+            let _ = tx.send(StreamEvent::Step(started)).await;
+
+            let result = match StepRequest::parse(step, &text) {
+                Ok(step_request) => {
+                    let mut input = step.options.clone();
+                    input.insert("text".to_string(), Value::String(text.clone()));
+                    let entry = PendingEntry::new(&claims, &step.op, EntrySource::Pipeline, &input);
+                    let result = run_step(&state, &claims, &client, step_request, &tx).await;
+                    entry.finish(
+                        &state,
+                        result
+                            .as_ref()
+                            .map(|(output, usage, _)| (output.as_str(), *usage)),
+                    );
+                    result
+                }
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok((output, usage, edits)) => {
+                    total += usage;
+                    let mut report = StepReport::new(index, &step.op, StepStatus::Completed);
+                    report.output = Some(output.clone());
+                    report.edits = edits;
+                    report.usage = Some(usage);
+                    let _ = tx.send(StreamEvent::Step(report)).await;
+                    text = output;
+                }
+                Err(e) => {
+                    error!("Pipeline step {} ({}) failed: {}", index, step.op, e);
+                    let mut report = StepReport::new(index, &step.op, StepStatus::Failed);
+                    report.code = Some(e.status_code().as_u16());
+                    report.error = Some(e.to_string());
+                    let _ = tx.send(StreamEvent::Step(report)).await;
+                    failed = true;
+                    break;
+                }
+            }
+        }
+
+        if !failed {
+            let _ = tx.send(StreamEvent::Usage(total)).await;
+        }
+        let _ = tx.send(StreamEvent::Done).await;
+        debug!("Pipeline completed");
+    });
+
+    Ok(respond(&response_state, &owner, format, rx).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::create_router;
+    use crate::test_utils::*;
+    use axum::http::StatusCode;
+    use tower::ServiceExt;
+
+    // Answer each operation's prompt with a recognizable output
+    fn respond_to_step(request: &Value) -> Vec<String> {
+        let message = last_user_message(request);
+        let output = if message.starts_with("Summarize") {
+            "Teh summary."
+        } else if message.starts_with("Proofread") {
+            r#"{"edits": [{"start": 0, "end": 3, "original": "Teh", "replacement": "El"}]}"#
+        } else if message.starts_with("Translate") {
+            "Teh resumen."
+        } else {
+            "Unexpected prompt"
+        };
+        vec![output.to_string()]
+    }
+
+    async fn post_pipeline(config: &crate::config::Config, state: &AppState, body: Value) -> Value {
+        let request = authed_request(config, "alice")
+            .method("POST")
+            .uri("/api/text/pipeline")
+            .header("content-type", "application/json")
+            .header("accept", "application/json")
+            .body(json_body(body))
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_feeds_each_step_the_previous_output() {
+        let mock = spawn_mock_openai(respond_to_step).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+
+        let body = post_pipeline(
+            &config,
+            &state,
+            serde_json::json!({
+                "text": "A long text about many things.",
+                "steps": [
+                    {"op": "summarize"},
+                    {"op": "translate", "target_language": "es", "source_language": "en"},
+                    {"op": "proofread"},
+                ],
+            }),
+        )
+        .await;
+
+        assert_eq!(body["result"], "El resumen.");
+        let steps = body["steps"].as_array().unwrap();
+        assert_eq!(steps.len(), 3);
+        assert_eq!(steps[0]["output"], "Teh summary.");
+        assert_eq!(steps[1]["output"], "Teh resumen.");
+        assert_eq!(steps[2]["edits"][0]["replacement"], "El");
+
+        // The translation was asked for the summary, not the original text
+        let requests = mock.requests();
+        assert!(last_user_message(&requests[1]).ends_with("Teh summary."));
+        assert!(body["usage"]["total_tokens"].as_u64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_reports_the_failed_step() {
+        let mock = spawn_mock_openai(respond_to_step).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+
+        let body = post_pipeline(
+            &config,
+            &state,
+            serde_json::json!({
+                "text": "A long text.",
+                "steps": [{"op": "summarize"}, {"op": "no-such-op"}, {"op": "proofread"}],
+            }),
+        )
+        .await;
+
+        let steps = body["steps"].as_array().unwrap();
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[1]["status"], "failed");
+        assert_eq!(steps[1]["code"], 404);
+        // The last successful output is kept
+        assert_eq!(body["result"], "Teh summary.");
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_is_charged_per_step() {
+        let mock = spawn_mock_openai(respond_to_step).await;
+        let mut config = test_config(&mock.base_url);
+        config.rate_limit.user_per_minute = 1;
+        config.rate_limit.user_burst = 3;
+        let state = test_state(config.clone());
+        let body = serde_json::json!({
+            "text": "A long text.",
+            "steps": [{"op": "summarize"}, {"op": "summarize"}],
+        });
+
+        post_pipeline(&config, &state, body.clone()).await;
+        assert_eq!(state.limits.status("alice").unwrap().remaining, 1);
+
+        let request = authed_request(&config, "alice")
+            .method("POST")
+            .uri("/api/text/pipeline")
+            .header("content-type", "application/json")
+            .body(json_body(body))
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(mock.requests().len(), 2);
+    }
+}
@@ -0,0 +1,349 @@
+use axum::extract::{Query, State};
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::error::AppError;
+use crate::history::{EntrySource, PendingEntry};
+use crate::models::{Claims, TextRequest, TokenUsage};
+use crate::openai::complete_structured;
+use crate::ratelimit::ClientAddress;
+use crate::state::AppState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EditCategory {
+    Grammar,
+    Spelling,
+    Punctuation,
+    Style,
+    Clarity,
+    #[serde(other)]
+    Other,
+}
+
+// An edit as suggested by the model, before it is checked against the input
+#[derive(Debug, Clone, Deserialize)]
+pub struct SuggestedEdit {
+    #[serde(default)]
+    pub start: Option<usize>,
+    #[serde(default)]
+    pub end: Option<usize>,
+    pub original: String,
+    pub replacement: String,
+    #[serde(default = "default_category")]
+    pub category: EditCategory,
+    #[serde(default)]
+    pub explanation: String,
+}
+
+fn default_category() -> EditCategory {
+    EditCategory::Other
+}
+
+#[derive(Debug, Deserialize)]
+struct ProofreadOutput {
+    #[serde(default)]
+    edits: Vec<SuggestedEdit>,
+}
+
+// An edit whose span is known to hold `original` in the input. Offsets count
+// characters, not bytes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Edit {
+    pub start: usize,
+    pub end: usize,
+    pub original: String,
+    pub replacement: String,
+    pub category: EditCategory,
+    pub explanation: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProofreadResponse {
+    pub edits: Vec<Edit>,
+    // Suggestions dropped because they did not match the input or overlapped another edit
+    pub discarded: usize,
+    pub usage: TokenUsage,
+}
+
+// Character offsets of every occurrence of `needle` in `text`
+fn occurrences(text: &str, needle: &str) -> Vec<usize> {
+    text.match_indices(needle)
+        .map(|(byte, _)| text[..byte].chars().count())
+        .collect()
+}
+
+// Locate a suggested edit in the input. Models are unreliable at counting, so
+// when the span does not hold the original text the nearest occurrence of it
+// is used instead.
+fn locate(text: &str, chars: &[char], edit: &SuggestedEdit) -> Option<(usize, usize)> {
+    let length = edit.original.chars().count();
+    if length == 0 || edit.original == edit.replacement {
+        return None;
+    }
+
+    if let (Some(start), Some(end)) = (edit.start, edit.end) {
+        if end == start + length && end <= chars.len() {
+            let span: String = chars[start..end].iter().collect();
+            if span == edit.original {
+                return Some((start, end));
+            }
+        }
+    }
+
+    let hint = edit.start.unwrap_or(0);
+    occurrences(text, &edit.original)
+        .into_iter()
+        .min_by_key(|start| start.abs_diff(hint))
+        .map(|start| (start, start + length))
+}
+
+// Keep the suggestions that match the input, in order and without overlaps.
+// Returns the edits and the number of suggestions dropped.
+pub fn check_edits(text: &str, suggestions: Vec<SuggestedEdit>) -> (Vec<Edit>, usize) {
+    let chars: Vec<char> = text.chars().collect();
+    let total = suggestions.len();
+
+    let mut edits: Vec<Edit> = suggestions
+        .into_iter()
+        .filter_map(|suggestion| {
+            let (start, end) = locate(text, &chars, &suggestion)?;
+            Some(Edit {
+                start,
+                end,
+                original: suggestion.original,
+                replacement: suggestion.replacement,
+                category: suggestion.category,
+                explanation: suggestion.explanation,
+            })
+        })
+        .collect();
+
+    edits.sort_by_key(|edit| (edit.start, edit.end));
+    let mut kept: Vec<Edit> = Vec::with_capacity(edits.len());
+    for edit in edits {
+        if kept.last().is_none_or(|last| last.end <= edit.start) {
+            kept.push(edit);
+        }
+    }
+
+    let discarded = total - kept.len();
+    (kept, discarded)
+}
+
+// Apply checked edits to the text they were made for
+pub fn apply_edits(text: &str, edits: &[Edit]) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut output = String::with_capacity(text.len());
+    let mut position = 0;
+    for edit in edits {
+        output.extend(&chars[position..edit.start]);
+        output.push_str(&edit.replacement);
+        position = edit.end;
+    }
+    output.extend(&chars[position..]);
+    output
+}
+
+// Proofread text - support both GET and POST
+pub async fn proofread(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    ClientAddress(address): ClientAddress,
+    text_param: Option<Query<TextRequest>>,
+    text_json: Option<Json<TextRequest>>,
+) -> Result<impl IntoResponse, AppError> {
+    // Extract the request from either query parameters or JSON body
+    let request = if let Some(Query(query)) = text_param {
+        query
+    } else if let Some(Json(json)) = text_json {
+        json
+    } else {
+        return Err(AppError::BadRequest("Text is required".to_string()));
+    };
+
+    let entry = PendingEntry::new(&claims, "proofread", EntrySource::Request, &request)
+        .admit(&state, address)?;
+    Ok(Json(
+        proofread_and_record(&state, &claims, &request, entry).await?,
+    ))
+}
+
+// Proofread and record the outcome in the user's history, with the corrected
+// text as the output
+pub async fn proofread_and_record(
+    state: &AppState,
+    claims: &Claims,
+    request: &TextRequest,
+    entry: PendingEntry,
+) -> Result<ProofreadResponse, AppError> {
+    let result = proofread_text(state, claims, request).await;
+    match &result {
+        Ok(response) => {
+            let corrected = apply_edits(&request.text, &response.edits);
+            entry.finish(state, Ok((&corrected, response.usage)));
+        }
+        Err(e) => entry.finish(state, Err(e)),
+    }
+    result
+}
+
+// Ask the model for edits to the request's text and keep the ones that match it
+pub async fn proofread_text(
+    state: &AppState,
+    claims: &Claims,
+    request: &TextRequest,
+) -> Result<ProofreadResponse, AppError> {
+    let params = &request.params;
+    params.validate(state.config.generation.limits_for(claims.role))?;
+    params.require_single_candidate()?;
+
+    let prompt = state.templates.render(
+        "proofread",
+        &[
+            ("text", &request.text),
+            ("style", &params.style_instructions()),
+        ],
+    )?;
+
+    let (output, usage): (ProofreadOutput, _) =
+        complete_structured(state, prompt, params.sampling()).await?;
+    let (edits, discarded) = check_edits(&request.text, output.edits);
+    debug!(
+        "Proofread kept {} edits, discarded {}",
+        edits.len(),
+        discarded
+    );
+
+    Ok(ProofreadResponse {
+        edits,
+        discarded,
+        usage,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::create_router;
+    use crate::test_utils::*;
+    use axum::http::StatusCode;
+    use tower::ServiceExt;
+
+    fn suggestion(start: usize, end: usize, original: &str, replacement: &str) -> SuggestedEdit {
+        SuggestedEdit {
+            start: Some(start),
+            end: Some(end),
+            original: original.to_string(),
+            replacement: replacement.to_string(),
+            category: EditCategory::Spelling,
+            explanation: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_check_edits_keeps_matching_spans() {
+        let text = "I has teh cat.";
+        let (edits, discarded) = check_edits(
+            text,
+            vec![
+                suggestion(6, 9, "teh", "the"),
+                suggestion(2, 5, "has", "have"),
+            ],
+        );
+
+        assert_eq!(discarded, 0);
+        assert_eq!(edits.len(), 2);
+        assert_eq!((edits[0].start, edits[0].end), (2, 5));
+        assert_eq!(edits[1].original, "teh");
+    }
+
+    #[test]
+    fn test_check_edits_relocates_wrong_offsets() {
+        // Offsets count characters, so the accented letter is one position
+        let text = "Café teh menu, teh end";
+        let (edits, discarded) = check_edits(text, vec![suggestion(14, 17, "teh", "the")]);
+
+        assert_eq!(discarded, 0);
+        assert_eq!((edits[0].start, edits[0].end), (15, 18));
+    }
+
+    #[test]
+    fn test_check_edits_drops_invalid_and_overlapping() {
+        let text = "Their going home.";
+        let (edits, discarded) = check_edits(
+            text,
+            vec![
+                suggestion(0, 5, "Their", "They're"),
+                suggestion(0, 11, "Their going", "They are going"),
+                suggestion(0, 4, "Ther", "Ther"),
+                suggestion(3, 8, "missing", "gone"),
+            ],
+        );
+
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].replacement, "They're");
+        assert_eq!(discarded, 3);
+    }
+
+    #[test]
+    fn test_apply_edits() {
+        let text = "I has teh cat.";
+        let (edits, _) = check_edits(
+            text,
+            vec![
+                suggestion(2, 5, "has", "have"),
+                suggestion(6, 9, "teh", "the"),
+            ],
+        );
+
+        assert_eq!(apply_edits(text, &edits), "I have the cat.");
+    }
+
+    #[test]
+    fn test_suggestions_tolerate_missing_fields() {
+        let output: ProofreadOutput = serde_json::from_str(
+            r#"{"edits": [{"original": "teh", "replacement": "the", "category": "typo"}]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(output.edits[0].category, EditCategory::Other);
+        assert_eq!(output.edits[0].start, None);
+    }
+
+    #[tokio::test]
+    async fn test_proofread_returns_checked_edits() {
+        let mock = spawn_mock_openai(|_| {
+            vec![concat!(
+                "```json\n",
+                r#"{"edits": [{"start": 5, "end": 8, "original": "teh", "replacement": "the", "#,
+                r#""category": "spelling", "explanation": "Typo."}, "#,
+                r#"{"start": 0, "end": 3, "original": "xyz", "replacement": "abc"}]}"#,
+                "\n```"
+            )
+            .to_string()]
+        })
+        .await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+
+        let request = authed_request(&config, "alice")
+            .method("POST")
+            .uri("/api/text/proofread")
+            .header("content-type", "application/json")
+            .body(json_body(serde_json::json!({"text": "Fix teh typo."})))
+            .unwrap();
+        let response = create_router(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["edits"].as_array().unwrap().len(), 1);
+        assert_eq!(body["edits"][0]["start"], 4);
+        assert_eq!(body["edits"][0]["category"], "spelling");
+        assert_eq!(body["discarded"], 1);
+        assert_eq!(mock.requests()[0]["stream"], serde_json::Value::Null);
+    }
+}
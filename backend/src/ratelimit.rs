@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts, State};
+use axum::http::request::Parts;
+use axum::http::{HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+
+use crate::config::RateLimitConfig;
+use crate::error::AppError;
+use crate::models::Claims;
+use crate::state::AppState;
+
+// Suggested wait when a user has too many streams open, as there is no telling
+// when one ends
+const STREAM_RETRY_SECS: u64 = 5;
+
+// Buckets kept before full ones, which hold nothing worth remembering, are dropped
+const PRUNE_ABOVE: usize = 4096;
+
+// Tokens added to a bucket per second, up to `burst`
+#[derive(Debug, Clone, Copy)]
+struct Rate {
+    per_sec: f64,
+    burst: f64,
+}
+
+impl Rate {
+    fn new(per_minute: u32, burst: u32) -> Option<Self> {
+        (per_minute > 0).then(|| Rate {
+            per_sec: per_minute as f64 / 60.0,
+            burst: burst.max(1) as f64,
+        })
+    }
+
+    // Tokens in a bucket at `now`, a new bucket being full
+    fn tokens(&self, bucket: Option<&Bucket>, now: Instant) -> f64 {
+        bucket.map_or(self.burst, |bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            (bucket.tokens + elapsed * self.per_sec).min(self.burst)
+        })
+    }
+
+    // Seconds until a bucket holding `tokens` has `wanted`
+    fn wait(&self, tokens: f64, wanted: f64) -> u64 {
+        ((wanted - tokens).max(0.0) / self.per_sec).ceil() as u64
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct Limits {
+    users: HashMap<String, Bucket>,
+    addresses: HashMap<IpAddr, Bucket>,
+    streams: HashMap<String, usize>,
+}
+
+// What the user has left, sent as `RateLimit-*` headers
+pub struct RateLimitStatus {
+    pub limit: u32,
+    pub remaining: u32,
+    // Seconds until the bucket is full again
+    pub reset: u64,
+}
+
+// Token buckets per user and per client address, and the streams each user
+// has open
+pub struct RateLimiter {
+    user: Option<Rate>,
+    address: Option<Rate>,
+    max_streams: usize,
+    limits: Mutex<Limits>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        RateLimiter {
+            user: Rate::new(config.user_per_minute, config.user_burst),
+            address: Rate::new(config.ip_per_minute, config.ip_burst),
+            max_streams: config.max_concurrent_streams,
+            limits: Mutex::new(Limits::default()),
+        }
+    }
+
+    // Take a token from the user's and the address's buckets and count a
+    // stream, which lasts as long as the permit
+    pub fn acquire(
+        self: &Arc<Self>,
+        user: &str,
+        address: Option<IpAddr>,
+    ) -> Result<StreamPermit, AppError> {
+        self.acquire_many(user, address, 1)
+    }
+
+    // Same for a request that generates `cost` times, such as a pipeline
+    pub fn acquire_many(
+        self: &Arc<Self>,
+        user: &str,
+        address: Option<IpAddr>,
+        cost: u32,
+    ) -> Result<StreamPermit, AppError> {
+        let mut limits = self.lock()?;
+
+        let open = limits.streams.get(user).copied().unwrap_or(0);
+        if self.max_streams > 0 && open >= self.max_streams {
+            return Err(AppError::RateLimited(
+                format!("At most {} streams may be open at once", self.max_streams),
+                STREAM_RETRY_SECS,
+            ));
+        }
+
+        self.take(&mut limits, user, address, cost)?;
+        *limits.streams.entry(user.to_string()).or_default() += 1;
+
+        Ok(StreamPermit {
+            limiter: self.clone(),
+            user: user.to_string(),
+        })
+    }
+
+    // Take `cost` tokens for work that holds no stream, such as submitting a job
+    pub fn charge(&self, user: &str, address: Option<IpAddr>, cost: u32) -> Result<(), AppError> {
+        let mut limits = self.lock()?;
+        self.take(&mut limits, user, address, cost)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Limits>, AppError> {
+        self.limits
+            .lock()
+            .map_err(|_| AppError::Internal("Rate limits poisoned".to_string()))
+    }
+
+    // Take `cost` tokens from the user's and the address's buckets, or none if
+    // either falls short. A bucket can never hold more than its burst, so a
+    // larger cost takes the whole bucket once it is full.
+    fn take(
+        &self,
+        limits: &mut Limits,
+        user: &str,
+        address: Option<IpAddr>,
+        cost: u32,
+    ) -> Result<(), AppError> {
+        let now = Instant::now();
+        let cost = |rate: Rate| (cost as f64).min(rate.burst);
+
+        // Both are checked before either is taken from
+        let user_tokens = self
+            .user
+            .map(|rate| (rate, rate.tokens(limits.users.get(user), now)));
+        let address_tokens = self.address.zip(address).map(|(rate, address)| {
+            (
+                rate,
+                address,
+                rate.tokens(limits.addresses.get(&address), now),
+            )
+        });
+        let waits = [
+            user_tokens.map(|(rate, tokens)| rate.wait(tokens, cost(rate))),
+            address_tokens.map(|(rate, _, tokens)| rate.wait(tokens, cost(rate))),
+        ];
+        if let Some(wait) = waits.into_iter().flatten().max().filter(|wait| *wait > 0) {
+            return Err(AppError::RateLimited(
+                format!("Rate limit exceeded, retry in {}s", wait),
+                wait,
+            ));
+        }
+
+        if let Some((rate, tokens)) = user_tokens {
+            let bucket = Bucket {
+                tokens: tokens - cost(rate),
+                updated: now,
+            };
+            limits.users.insert(user.to_string(), bucket);
+        }
+        if let Some((rate, address, tokens)) = address_tokens {
+            let bucket = Bucket {
+                tokens: tokens - cost(rate),
+                updated: now,
+            };
+            limits.addresses.insert(address, bucket);
+        }
+        self.prune(limits, now);
+        Ok(())
+    }
+
+    fn prune(&self, limits: &mut Limits, now: Instant) {
+        if let Some(rate) = self.user.filter(|_| limits.users.len() > PRUNE_ABOVE) {
+            limits
+                .users
+                .retain(|_, bucket| rate.tokens(Some(bucket), now) < rate.burst);
+        }
+        if let Some(rate) = self
+            .address
+            .filter(|_| limits.addresses.len() > PRUNE_ABOVE)
+        {
+            limits
+                .addresses
+                .retain(|_, bucket| rate.tokens(Some(bucket), now) < rate.burst);
+        }
+    }
+
+    // The user's bucket, when users are limited
+    pub fn status(&self, user: &str) -> Option<RateLimitStatus> {
+        let rate = self.user?;
+        let limits = self.limits.lock().ok()?;
+        let tokens = rate.tokens(limits.users.get(user), Instant::now());
+        Some(RateLimitStatus {
+            limit: rate.burst as u32,
+            remaining: tokens.floor() as u32,
+            reset: rate.wait(tokens, rate.burst),
+        })
+    }
+}
+
+// One of a user's open streams
+pub struct StreamPermit {
+    limiter: Arc<RateLimiter>,
+    user: String,
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        let Ok(mut limits) = self.limiter.limits.lock() else {
+            return;
+        };
+        if let Some(open) = limits.streams.get_mut(&self.user) {
+            *open -= 1;
+            if *open == 0 {
+                limits.streams.remove(&self.user);
+            }
+        }
+    }
+}
+
+// The client's address, when the server was started with it
+pub struct ClientAddress(pub Option<IpAddr>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientAddress {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let address = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|info| info.0.ip());
+        Ok(ClientAddress(address))
+    }
+}
+
+// Tell users what is left of their rate limit on every response
+pub async fn rate_limit_headers<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let user = request
+        .extensions()
+        .get::<Claims>()
+        .map(|claims| claims.sub.clone());
+    let mut response = next.run(request).await;
+    if let Some(status) = user.and_then(|user| state.limits.status(&user)) {
+        let headers = response.headers_mut();
+        headers.insert("ratelimit-limit", HeaderValue::from(status.limit));
+        headers.insert("ratelimit-remaining", HeaderValue::from(status.remaining));
+        headers.insert("ratelimit-reset", HeaderValue::from(status.reset));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::create_router;
+    use crate::test_utils::*;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_limits_users_addresses_and_open_streams() {
+        let limiter = Arc::new(RateLimiter::new(&RateLimitConfig {
+            user_per_minute: 60,
+            user_burst: 2,
+            ip_per_minute: 30,
+            ip_burst: 3,
+            max_concurrent_streams: 0,
+        }));
+        let address: IpAddr = "10.0.0.1".parse().unwrap();
+
+        assert!(limiter.acquire("alice", Some(address)).is_ok());
+        assert!(limiter.acquire("alice", Some(address)).is_ok());
+        match limiter.acquire("alice", Some(address)) {
+            Err(AppError::RateLimited(_, wait)) => assert_eq!(wait, 1),
+            _ => panic!("Expected the user to be limited"),
+        }
+        // The address has one token left, and a rejected request took none
+        assert!(limiter.acquire("bob", Some(address)).is_ok());
+        match limiter.acquire("carol", Some(address)) {
+            Err(AppError::RateLimited(_, wait)) => assert_eq!(wait, 2),
+            _ => panic!("Expected the address to be limited"),
+        }
+        assert_eq!(limiter.status("bob").unwrap().remaining, 1);
+
+        let limiter = Arc::new(RateLimiter::new(&RateLimitConfig {
+            user_per_minute: 0,
+            user_burst: 1,
+            ip_per_minute: 0,
+            ip_burst: 1,
+            max_concurrent_streams: 1,
+        }));
+        let permit = limiter.acquire("alice", None).unwrap();
+        assert!(limiter.acquire("alice", None).is_err());
+        assert!(limiter.acquire("bob", None).is_ok());
+        drop(permit);
+        assert!(limiter.acquire("alice", None).is_ok());
+    }
+
+    #[test]
+    fn test_costs_take_several_tokens() {
+        let limiter = Arc::new(RateLimiter::new(&RateLimitConfig {
+            user_per_minute: 60,
+            user_burst: 3,
+            ip_per_minute: 0,
+            ip_burst: 1,
+            max_concurrent_streams: 0,
+        }));
+
+        // More than the burst takes the whole bucket
+        assert!(limiter.acquire_many("alice", None, 8).is_ok());
+        match limiter.charge("alice", None, 1) {
+            Err(AppError::RateLimited(_, wait)) => assert_eq!(wait, 1),
+            _ => panic!("Expected the user to be limited"),
+        }
+
+        assert!(limiter.charge("bob", None, 2).is_ok());
+        assert_eq!(limiter.status("bob").unwrap().remaining, 1);
+        assert!(limiter.charge("bob", None, 2).is_err());
+        assert_eq!(limiter.status("bob").unwrap().remaining, 1);
+    }
+
+    #[tokio::test]
+    async fn test_generating_requests_are_rate_limited() {
+        let mock = spawn_mock_openai(|_| vec!["Limited.".to_string()]).await;
+        let mut config = test_config(&mock.base_url);
+        config.rate_limit.user_per_minute = 1;
+        config.rate_limit.user_burst = 1;
+        let state = test_state(config.clone());
+        let send = |method: &str, uri: &str, content_type: &str, body: &str| {
+            let request = authed_request(&config, "alice")
+                .method(method)
+                .uri(uri)
+                .header("content-type", content_type)
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            create_router(state.clone()).oneshot(request)
+        };
+
+        let response = send("GET", "/api/text/paraphrase?text=Hello", "text/plain", "")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["ratelimit-remaining"], "0");
+        assert_eq!(sse_content(&sse_events(response).await), "Limited.");
+
+        let response = send("GET", "/api/text/paraphrase?text=Again", "text/plain", "")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["retry-after"], "60");
+        assert_eq!(response.headers()["ratelimit-limit"], "1");
+
+        // Everything that generates is limited, whatever its route
+        let id = state
+            .history
+            .list("alice", &Default::default())
+            .unwrap()
+            .entries[0]
+            .id
+            .clone();
+        let rerun = format!("/api/history/{}/rerun", id);
+        let response = send("POST", &rerun, "text/plain", "").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let upload = "--limit\r\nContent-Disposition: form-data; name=\"op\"\r\n\r\nparaphrase\r\n\
+            --limit\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\nHello\r\n--limit--\r\n";
+        let content_type = "multipart/form-data; boundary=limit";
+        let response = send("POST", "/api/documents/process", content_type, upload)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(mock.requests().len(), 1);
+
+        // Local work is not charged
+        let response = send("GET", "/api/text/analyze?text=Hello", "text/plain", "")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send("GET", "/api/text/ops", "text/plain", "")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_open_stream_lasts_until_generation_ends() {
+        let mock = spawn_mock_openai(|_| vec!["Slow.".to_string()]).await;
+        let mut config = test_config(&mock.base_url);
+        config.rate_limit.max_concurrent_streams = 1;
+        let state = test_state(config.clone());
+        let send = |text: &str| {
+            let request = authed_request(&config, "alice")
+                .uri(format!("/api/text/paraphrase?text={}", text))
+                .body(Body::empty())
+                .unwrap();
+            create_router(state.clone()).oneshot(request)
+        };
+
+        // Dropping the response leaves the generation running
+        drop(send("First").await.unwrap());
+        let response = send("Second").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        while state
+            .history
+            .list("alice", &Default::default())
+            .unwrap()
+            .total
+            == 0
+        {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let response = send("Third").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, State};
+use axum::response::Response;
+use axum::Extension;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+use crate::error::AppError;
+use crate::history::{EntrySource, PendingEntry};
+use crate::models::Claims;
+use crate::openai::{event_payload, spawn_generation};
+use crate::pipeline::{PipelineStep, StepRequest};
+use crate::proofread::proofread_and_record;
+use crate::state::AppState;
+
+// Operations a single connection may run at once
+const MAX_RUNNING: usize = 8;
+
+// A message from the client
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ClientMessage {
+    // Start an operation: `op` and its options, as in a pipeline step, plus the `text`
+    Run {
+        id: String,
+        #[serde(flatten)]
+        step: PipelineStep,
+    },
+    Cancel {
+        id: String,
+    },
+    // Hold back an operation's events. The generation carries on, as other
+    // identical requests may be following it.
+    Pause {
+        id: String,
+    },
+    Resume {
+        id: String,
+    },
+}
+
+// A message to the client: an event of one of its operations, named as in SSE
+// responses, or `error` when there is no operation to attach it to
+#[derive(Debug, Serialize)]
+struct Frame<'a> {
+    id: Option<&'a str>,
+    event: &'a str,
+    data: Value,
+}
+
+impl Frame<'_> {
+    fn text(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+fn error_data(error: &AppError) -> Value {
+    json!({ "error": error.to_string(), "code": error.status_code().as_u16() })
+}
+
+fn error_frame(id: Option<&str>, error: &AppError) -> String {
+    Frame {
+        id,
+        event: "error",
+        data: error_data(error),
+    }
+    .text()
+}
+
+// The task running an operation, cancelled when dropped
+struct Running {
+    task: JoinHandle<()>,
+    paused: watch::Sender<bool>,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+// Aborts a generation when the operation forwarding its events is cancelled
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// Run text operations over a WebSocket, several at a time, each identified by
+// the id the client gave it
+pub async fn operation_socket(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let address = connect_info.map(|info| info.0.ip());
+    upgrade.on_upgrade(move |socket| serve(state, claims, address, socket))
+}
+
+async fn serve(state: AppState, claims: Claims, address: Option<IpAddr>, socket: WebSocket) {
+    let (mut sink, mut stream) = socket.split();
+    let (frames, mut outgoing) = mpsc::channel::<String>(100);
+    let mut running: HashMap<String, Running> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            incoming = stream.next() => match incoming {
+                Some(Ok(WsMessage::Text(text))) => {
+                    running.retain(|_, operation| !operation.task.is_finished());
+                    let reply = match serde_json::from_str::<ClientMessage>(&text) {
+                        Ok(message) => handle(&state, &claims, address, message, &mut running, &frames),
+                        Err(e) => Some(error_frame(
+                            None,
+                            &AppError::BadRequest(format!("Invalid message: {}", e)),
+                        )),
+                    };
+                    if let Some(reply) = reply {
+                        if sink.send(WsMessage::Text(reply)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            Some(frame) = outgoing.recv() => {
+                if sink.send(WsMessage::Text(frame)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    // Dropping the running operations cancels them
+    debug!(
+        "Operation socket of {} closed with {} running",
+        claims.sub,
+        running.len()
+    );
+}
+
+// Act on a client message, returning the immediate reply
+fn handle(
+    state: &AppState,
+    claims: &Claims,
+    address: Option<IpAddr>,
+    message: ClientMessage,
+    running: &mut HashMap<String, Running>,
+    frames: &mpsc::Sender<String>,
+) -> Option<String> {
+    let not_running = |id: &str| {
+        Some(error_frame(
+            Some(id),
+            &AppError::NotFound(format!("Operation {} is not running", id)),
+        ))
+    };
+    let acknowledge = |id: &str, event: &str| {
+        Some(
+            Frame {
+                id: Some(id),
+                event,
+                data: Value::Null,
+            }
+            .text(),
+        )
+    };
+
+    match message {
+        ClientMessage::Run { id, step } => {
+            if running.contains_key(&id) {
+                let error = AppError::Conflict(format!("Operation {} is already running", id));
+                return Some(error_frame(Some(&id), &error));
+            }
+            if running.len() >= MAX_RUNNING {
+                let error = AppError::BadRequest(format!(
+                    "At most {} operations may run at once",
+                    MAX_RUNNING
+                ));
+                return Some(error_frame(Some(&id), &error));
+            }
+            match start(state, claims, address, &id, step, frames) {
+                Ok(operation) => {
+                    running.insert(id, operation);
+                    None
+                }
+                Err(e) => Some(error_frame(Some(&id), &e)),
+            }
+        }
+        ClientMessage::Cancel { id } => match running.remove(&id) {
+            Some(_) => acknowledge(&id, "cancelled"),
+            None => not_running(&id),
+        },
+        ClientMessage::Pause { id } | ClientMessage::Resume { id }
+            if !running.contains_key(&id) =>
+        {
+            not_running(&id)
+        }
+        ClientMessage::Pause { id } => {
+            running[&id].paused.send_replace(true);
+            acknowledge(&id, "paused")
+        }
+        ClientMessage::Resume { id } => {
+            running[&id].paused.send_replace(false);
+            acknowledge(&id, "resumed")
+        }
+    }
+}
+
+// Check an operation and start running it, within the user's rate limits
+fn start(
+    state: &AppState,
+    claims: &Claims,
+    address: Option<IpAddr>,
+    id: &str,
+    mut step: PipelineStep,
+    frames: &mpsc::Sender<String>,
+) -> Result<Running, AppError> {
+    let input = step.options.clone();
+    let text = match step.options.remove("text") {
+        Some(Value::String(text)) => text,
+        _ => return Err(AppError::BadRequest("Text is required".to_string())),
+    };
+    let entry = PendingEntry::new(claims, &step.op, EntrySource::Request, &input);
+    let request = StepRequest::parse(&step, &text).map_err(|e| entry.fail(state, e))?;
+    let entry = entry.admit(state, address)?;
+
+    let (paused, mut pause) = watch::channel(false);
+    let state = state.clone();
+    let claims = claims.clone();
+    let id = id.to_string();
+    let frames = frames.clone();
+    let send = move |event: &str, data: Value| {
+        let frame = Frame {
+            id: Some(&id),
+            event,
+            data,
+        }
+        .text();
+        let frames = frames.clone();
+        async move { frames.send(frame).await.is_ok() }
+    };
+
+    let task = tokio::spawn(async move {
+        if let StepRequest::Proofread(request) = &request {
+            // Proofreading is not streamed, so its whole response is one event
+            match proofread_and_record(&state, &claims, request, entry).await {
+                Ok(response) => {
+                    send(
+                        "proofread",
+                        serde_json::to_value(response).unwrap_or_default(),
+                    )
+                    .await
+                }
+                Err(e) => send("error", error_data(&e)).await,
+            };
+            send("done", Value::Null).await;
+            return;
+        }
+
+        let (mut events, _generation) = match request
+            .generation(&state, &claims)
+            .map_err(|e| entry.fail(&state, e))
+            .and_then(|generation| spawn_generation(state.clone(), generation, entry))
+        {
+            Ok(spawned) => (spawned.events, AbortOnDrop(spawned.task)),
+            Err(e) => {
+                send("error", error_data(&e)).await;
+                send("done", Value::Null).await;
+                return;
+            }
+        };
+
+        while let Some(event) = events.recv().await {
+            // Waiting here leaves the events buffered on the server
+            while *pause.borrow_and_update() {
+                if pause.changed().await.is_err() {
+                    return;
+                }
+            }
+            let (event, data) = event_payload(event);
+            if !send(event, data).await {
+                return;
+            }
+        }
+    });
+
+    Ok(Running { task, paused })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use tokio_tungstenite::tungstenite;
+
+    #[tokio::test]
+    async fn test_runs_operations_side_by_side() {
+        let mock = spawn_mock_openai(|request| {
+            let text = last_user_message(request);
+            let output = if text.contains("first") {
+                "One."
+            } else {
+                "Two."
+            };
+            vec![output.to_string()]
+        })
+        .await;
+        let config = test_config(&mock.base_url);
+        let address = spawn_app(test_state(config.clone())).await;
+        let (mut socket, _) = connect_websocket(&config, address, "/api/text/ws", "alice")
+            .await
+            .unwrap();
+
+        for (id, text) in [("a", "The first text."), ("b", "The second text.")] {
+            let message = json!({ "type": "run", "id": id, "op": "paraphrase", "text": text });
+            socket
+                .send(tungstenite::Message::Text(message.to_string()))
+                .await
+                .unwrap();
+        }
+        let message = json!({ "type": "cancel", "id": "missing" });
+        socket
+            .send(tungstenite::Message::Text(message.to_string()))
+            .await
+            .unwrap();
+
+        let mut outputs: HashMap<String, String> = HashMap::new();
+        let mut done = 0;
+        let mut errors = Vec::new();
+        while done < 2 {
+            let frame = socket.next().await.unwrap().unwrap().into_text().unwrap();
+            let frame: Value = serde_json::from_str(&frame).unwrap();
+            let id = frame["id"].as_str().unwrap_or_default().to_string();
+            match frame["event"].as_str().unwrap() {
+                "message" => outputs
+                    .entry(id)
+                    .or_default()
+                    .push_str(frame["data"].as_str().unwrap()),
+                "error" => errors.push((id, frame["data"]["code"].clone())),
+                "done" => done += 1,
+                _ => {}
+            }
+        }
+
+        assert_eq!(outputs["a"], "One.");
+        assert_eq!(outputs["b"], "Two.");
+        assert_eq!(errors, vec![("missing".to_string(), json!(404))]);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_invalid_operations() {
+        let config = test_config("http://127.0.0.1:9");
+        let address = spawn_app(test_state(config.clone())).await;
+        let (mut socket, _) = connect_websocket(&config, address, "/api/text/ws", "alice")
+            .await
+            .unwrap();
+
+        let message = json!({ "type": "run", "id": "a", "op": "paraphrase" });
+        socket
+            .send(tungstenite::Message::Text(message.to_string()))
+            .await
+            .unwrap();
+        let frame = socket.next().await.unwrap().unwrap().into_text().unwrap();
+        let frame: Value = serde_json::from_str(&frame).unwrap();
+        assert_eq!(frame["id"], "a");
+        assert_eq!(frame["event"], "error");
+        assert_eq!(frame["data"]["code"], 400);
+
+        socket
+            .send(tungstenite::Message::Text("{".to_string()))
+            .await
+            .unwrap();
+        let frame = socket.next().await.unwrap().unwrap().into_text().unwrap();
+        let frame: Value = serde_json::from_str(&frame).unwrap();
+        assert!(frame["id"].is_null());
+    }
+}
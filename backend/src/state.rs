@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::FromRef;
+
+use crate::cache::ResponseCache;
+use crate::coalesce::InFlight;
+use crate::collab::CollabRooms;
+use crate::config::Config;
+use crate::db::Database;
+use crate::document_store::DocumentStore;
+use crate::error::AppError;
+use crate::glossary::GlossaryStore;
+use crate::history::HistoryStore;
+use crate::jobs::JobStore;
+use crate::operations::OperationStore;
+use crate::ratelimit::RateLimiter;
+use crate::streams::StreamBuffers;
+use crate::templates::TemplateRegistry;
+use crate::tokens::Tokenizer;
+use crate::translation_memory::TranslationMemory;
+use crate::webhooks::WebhookStore;
+
+// Shared application state handed to every handler
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Arc<Config>,
+    pub tokenizer: Arc<Tokenizer>,
+    pub templates: Arc<TemplateRegistry>,
+    pub operations: Arc<OperationStore>,
+    pub glossary: Arc<GlossaryStore>,
+    pub translation_memory: Arc<TranslationMemory>,
+    pub jobs: Arc<JobStore>,
+    pub webhooks: Arc<WebhookStore>,
+    pub documents: Arc<DocumentStore>,
+    pub collab: Arc<CollabRooms>,
+    pub streams: Arc<StreamBuffers>,
+    pub cache: Arc<ResponseCache>,
+    pub inflight: Arc<InFlight>,
+    pub limits: Arc<RateLimiter>,
+    pub history: Arc<HistoryStore>,
+    // Tokenizers for models other than the default, loaded on first use
+    model_tokenizers: Arc<Mutex<HashMap<String, Arc<Tokenizer>>>>,
+}
+
+impl AppState {
+    pub fn new(config: Arc<Config>) -> Result<Self, AppError> {
+        let tokenizer = Arc::new(Tokenizer::for_config(&config.openai)?);
+        let templates = Arc::new(TemplateRegistry::load(Path::new(&config.templates.dir))?);
+        let database = Arc::new(Database::open(&config.database)?);
+        let jobs = Arc::new(JobStore::open(&config.jobs, database.clone())?);
+        let webhooks = Arc::new(WebhookStore::new(&config.webhooks, database.clone())?);
+        let cache = Arc::new(ResponseCache::new(&config.cache)?);
+        let limits = Arc::new(RateLimiter::new(&config.rate_limit));
+
+        Ok(AppState {
+            config,
+            tokenizer,
+            templates,
+            operations: Arc::new(OperationStore::new(database.clone())),
+            glossary: Arc::new(GlossaryStore::new(database.clone())),
+            translation_memory: Arc::new(TranslationMemory::new(database.clone())),
+            jobs,
+            webhooks,
+            documents: Arc::new(DocumentStore::new(database.clone())),
+            history: Arc::new(HistoryStore::new(database)),
+            collab: Arc::new(CollabRooms::default()),
+            streams: Arc::new(StreamBuffers::default()),
+            cache,
+            inflight: Arc::new(InFlight::default()),
+            limits,
+            model_tokenizers: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    // Tokenizer matching the model a request will actually be sent to
+    pub fn tokenizer_for(&self, model: &str) -> Result<Arc<Tokenizer>, AppError> {
+        if model == self.config.openai.model {
+            return Ok(self.tokenizer.clone());
+        }
+
+        let mut tokenizers = self
+            .model_tokenizers
+            .lock()
+            .map_err(|_| AppError::Internal("Tokenizer cache poisoned".to_string()))?;
+        if let Some(tokenizer) = tokenizers.get(model) {
+            return Ok(tokenizer.clone());
+        }
+
+        let tokenizer = Arc::new(Tokenizer::for_model(&self.config.openai, model)?);
+        tokenizers.insert(model.to_string(), tokenizer.clone());
+        Ok(tokenizer)
+    }
+}
+
+// Allow handlers and middleware that only need the configuration to keep
+// extracting `State<Arc<Config>>`
+impl FromRef<AppState> for Arc<Config> {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.clone()
+    }
+}
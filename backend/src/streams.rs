@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, Method, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Extension;
+use futures::Stream;
+use serde::Deserialize;
+use tokio::sync::{mpsc, watch};
+
+use crate::error::AppError;
+use crate::models::{Claims, StreamEvent};
+use crate::openai::replay;
+use crate::state::AppState;
+
+// How long a finished stream can still be resumed
+const RETENTION: Duration = Duration::from_secs(300);
+
+// The events of a streamed response, kept so a client that lost its
+// connection can pick up where it left off
+pub struct StreamBuffer {
+    owner: String,
+    events: Mutex<Vec<StreamEvent>>,
+    // Bumped on every new event, and once more when the stream finishes
+    changes: watch::Sender<()>,
+    finished: Mutex<Option<Instant>>,
+}
+
+impl StreamBuffer {
+    // A buffer filled with the events of `rx` in the background
+    pub fn follow(owner: &str, mut rx: mpsc::Receiver<StreamEvent>) -> Arc<Self> {
+        let (changes, _) = watch::channel(());
+        let buffer = Arc::new(StreamBuffer {
+            owner: owner.to_string(),
+            events: Mutex::new(Vec::new()),
+            changes,
+            finished: Mutex::new(None),
+        });
+
+        let recorded = buffer.clone();
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                if let Ok(mut events) = recorded.events.lock() {
+                    events.push(event);
+                }
+                recorded.changes.send_replace(());
+            }
+            if let Ok(mut finished) = recorded.finished.lock() {
+                *finished = Some(Instant::now());
+            }
+            recorded.changes.send_replace(());
+        });
+
+        buffer
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished.lock().map(|f| f.is_some()).unwrap_or(true)
+    }
+
+    fn expired(&self) -> bool {
+        self.finished
+            .lock()
+            .map(|f| f.is_some_and(|at| at.elapsed() > RETENTION))
+            .unwrap_or(true)
+    }
+
+    // The events from the `next`th on, numbered, then the ones still to come
+    pub fn events_from(
+        self: Arc<Self>,
+        next: usize,
+    ) -> impl Stream<Item = (usize, StreamEvent)> + Send + 'static {
+        let changes = self.changes.subscribe();
+        futures::stream::unfold(
+            (self, next, changes),
+            |(buffer, next, mut changes)| async move {
+                loop {
+                    // Seen before reading, so an event pushed in between wakes us
+                    changes.borrow_and_update();
+                    let event = buffer.events.lock().ok()?.get(next).cloned();
+                    if let Some(event) = event {
+                        return Some(((next, event), (buffer, next + 1, changes)));
+                    }
+                    if buffer.is_finished() {
+                        return None;
+                    }
+                    changes.changed().await.ok()?;
+                }
+            },
+        )
+    }
+}
+
+// Streams being sent or recently finished, by id
+#[derive(Default)]
+pub struct StreamBuffers {
+    streams: Mutex<HashMap<String, Arc<StreamBuffer>>>,
+}
+
+impl StreamBuffers {
+    // Keep every event of a stream, reading them in the background so the
+    // generation runs to its end even if the client goes away
+    pub fn record(
+        &self,
+        owner: &str,
+        rx: mpsc::Receiver<StreamEvent>,
+    ) -> (String, Arc<StreamBuffer>) {
+        let id = format!("{:032x}", rand::random::<u128>());
+        let buffer = StreamBuffer::follow(owner, rx);
+
+        if let Ok(mut streams) = self.streams.lock() {
+            streams.retain(|_, stream| !stream.expired());
+            streams.insert(id.clone(), buffer.clone());
+        }
+
+        (id, buffer)
+    }
+
+    // A stream of the user's that can still be resumed
+    pub fn find(&self, owner: &str, id: &str) -> Option<Arc<StreamBuffer>> {
+        let streams = self.streams.lock().ok()?;
+        streams
+            .get(id)
+            .filter(|stream| stream.owner == owner && !stream.expired())
+            .cloned()
+    }
+}
+
+// Stream id and number of events received, from a `Last-Event-ID` header
+fn last_event_id(headers: &HeaderMap) -> Option<(String, usize)> {
+    let value = headers.get("last-event-id")?.to_str().ok()?;
+    let (id, received) = value.rsplit_once(':')?;
+    Some((id.to_string(), received.parse().ok()?))
+}
+
+// Whether a request reads an event stream, as `EventSource` reconnects do.
+// Anything else carrying a `Last-Event-ID` still goes to its handler.
+fn reads_event_stream<B>(request: &Request<B>) -> bool {
+    let accepts_events = request
+        .headers()
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains("text/event-stream"));
+    accepts_events && matches!(*request.method(), Method::GET | Method::POST)
+}
+
+// Answer a reconnect carrying the `Last-Event-ID` of a known stream with the
+// events it missed, instead of starting the operation over
+pub async fn resume_middleware<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !reads_event_stream(&request) {
+        return next.run(request).await;
+    }
+    let resumed = last_event_id(request.headers()).and_then(|(id, received)| {
+        let claims = request.extensions().get::<Claims>()?;
+        let buffer = state.streams.find(&claims.sub, &id)?;
+        Some(replay(&id, buffer, received))
+    });
+    match resumed {
+        Some(response) => response,
+        None => next.run(request).await,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResumeQuery {
+    // Number of events already received, when there is no `Last-Event-ID`
+    #[serde(default)]
+    pub after: Option<usize>,
+}
+
+// Resume a stream by its id, from the start unless told otherwise
+pub async fn resume_stream(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+    Query(query): Query<ResumeQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let buffer = state
+        .streams
+        .find(&claims.sub, &id)
+        .ok_or_else(|| AppError::NotFound(format!("Stream {}", id)))?;
+    let received = last_event_id(&headers)
+        .filter(|(last, _)| *last == id)
+        .map(|(_, received)| received)
+        .or(query.after)
+        .unwrap_or(0);
+    Ok(replay(&id, buffer, received))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::create_router;
+    use crate::test_utils::*;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use tower::ServiceExt;
+
+    // (id, data) of every event in an SSE body
+    async fn events_with_ids(response: Response) -> Vec<(String, String)> {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        body.split("\n\n")
+            .filter_map(|block| {
+                let field = |name: &str| {
+                    block
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name))
+                        .map(|value| value.trim_start().to_string())
+                };
+                Some((field("id:")?, field("data:").unwrap_or_default()))
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_replays_missed_events() {
+        let mock = spawn_mock_openai(|_| vec!["Resumed output.".to_string()]).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+        let uri = "/api/text/paraphrase?text=Hello";
+
+        let request = authed_request(&config, "alice")
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        let stream_id = response.headers()["x-stream-id"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let events = events_with_ids(response).await;
+        assert!(events.len() > 2);
+        assert_eq!(events[0].0, format!("{}:1", stream_id));
+
+        // An EventSource reconnecting to the same URL
+        let request = authed_request(&config, "alice")
+            .uri(uri)
+            .header("accept", "text/event-stream")
+            .header("last-event-id", &events[1].0)
+            .body(Body::empty())
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(events_with_ids(response).await, events[2..]);
+        assert_eq!(mock.requests().len(), 1);
+
+        // Another user cannot resume it
+        let request = authed_request(&config, "bob")
+            .uri(format!("/api/streams/{}", stream_id))
+            .body(Body::empty())
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let request = authed_request(&config, "alice")
+            .uri(format!("/api/streams/{}?after=0", stream_id))
+            .body(Body::empty())
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(events_with_ids(response).await, events);
+    }
+
+    #[tokio::test]
+    async fn test_writes_with_last_event_id_reach_their_handler() {
+        let mock = spawn_mock_openai(|_| vec!["Output.".to_string()]).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+
+        let request = authed_request(&config, "alice")
+            .uri("/api/text/paraphrase?text=Hello")
+            .body(Body::empty())
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        let last_event_id = format!("{}:1", response.headers()["x-stream-id"].to_str().unwrap());
+        sse_events(response).await;
+
+        let id = state
+            .documents
+            .create("alice", "Draft", "Text")
+            .unwrap()
+            .summary
+            .id;
+
+        let request = authed_request(&config, "alice")
+            .method("DELETE")
+            .uri(format!("/api/documents/{}", id))
+            .header("accept", "text/event-stream")
+            .header("last-event-id", &last_event_id)
+            .body(Body::empty())
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        assert!(response.status().is_success());
+        assert!(state.documents.get("alice", &id).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_stream_starts_over() {
+        let mock = spawn_mock_openai(|_| vec!["Fresh.".to_string()]).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+
+        let request = authed_request(&config, "alice")
+            .uri("/api/text/paraphrase?text=Hello")
+            .header("accept", "text/event-stream")
+            .header("last-event-id", "expired:3")
+            .body(Body::empty())
+            .unwrap();
+        let response = create_router(state).oneshot(request).await.unwrap();
+        let events = sse_events(response).await;
+        assert_eq!(sse_content(&events), "Fresh.");
+        assert_eq!(mock.requests().len(), 1);
+    }
+}
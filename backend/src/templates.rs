@@ -0,0 +1,413 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use axum::extract::State;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::error::AppError;
+use crate::state::AppState;
+
+// Templates shipped with the binary, used when no file overrides them
+const BUILTIN_TEMPLATES: &[(&str, &str)] = &[
+    (
+        "paraphrase.toml",
+        include_str!("../templates/paraphrase.toml"),
+    ),
+    ("expand.toml", include_str!("../templates/expand.toml")),
+    (
+        "proofread.toml",
+        include_str!("../templates/proofread.toml"),
+    ),
+    (
+        "summarize.toml",
+        include_str!("../templates/summarize.toml"),
+    ),
+    (
+        "translate.toml",
+        include_str!("../templates/translate.toml"),
+    ),
+];
+
+// A prompt template as stored on disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptTemplate {
+    pub name: String,
+    pub version: u32,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub variables: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    pub user: String,
+}
+
+// A template with its variables filled in, ready to be sent upstream
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderedPrompt {
+    pub system: Option<String>,
+    pub user: String,
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+}
+
+impl RenderedPrompt {
+    // Message contents in the order they are sent, used for token counting
+    pub fn contents(&self) -> Vec<&str> {
+        self.system
+            .iter()
+            .map(String::as_str)
+            .chain(std::iter::once(self.user.as_str()))
+            .collect()
+    }
+}
+
+// Summary of a template returned by the listing endpoint
+#[derive(Debug, Serialize)]
+pub struct TemplateInfo {
+    pub name: String,
+    pub version: u32,
+    pub description: String,
+    pub variables: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+}
+
+impl From<&PromptTemplate> for TemplateInfo {
+    fn from(template: &PromptTemplate) -> Self {
+        TemplateInfo {
+            name: template.name.clone(),
+            version: template.version,
+            description: template.description.clone(),
+            variables: template.variables.clone(),
+            model: template.model.clone(),
+            temperature: template.temperature,
+        }
+    }
+}
+
+// Find every `{{variable}}` placeholder in a template string
+fn placeholders(source: &str) -> Vec<&str> {
+    let mut found = Vec::new();
+    let mut rest = source;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        match after.find("}}") {
+            Some(end) => {
+                found.push(after[..end].trim());
+                rest = &after[end + 2..];
+            }
+            None => break,
+        }
+    }
+    found
+}
+
+// Replace every `{{variable}}` placeholder with its value
+fn substitute(source: &str, vars: &HashMap<&str, &str>) -> String {
+    let mut output = String::with_capacity(source.len());
+    let mut rest = source;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find("}}") {
+            Some(end) => {
+                let name = after[..end].trim();
+                output.push_str(vars.get(name).copied().unwrap_or_default());
+                rest = &after[end + 2..];
+            }
+            None => {
+                output.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+impl PromptTemplate {
+    pub fn parse(source: &str) -> Result<Self, AppError> {
+        let template: PromptTemplate = toml::from_str(source)
+            .map_err(|e| AppError::BadRequest(format!("Invalid prompt template: {}", e)))?;
+        template.validate()?;
+        Ok(template)
+    }
+
+    // Make sure the template only references variables it declares
+    pub fn validate(&self) -> Result<(), AppError> {
+        if self.name.trim().is_empty() {
+            return Err(AppError::BadRequest(
+                "Prompt template name is required".to_string(),
+            ));
+        }
+
+        let sources = self.system.iter().chain(std::iter::once(&self.user));
+        for placeholder in sources.flat_map(|s| placeholders(s)) {
+            if !self.variables.iter().any(|v| v == placeholder) {
+                return Err(AppError::BadRequest(format!(
+                    "Template {} uses undeclared variable {}",
+                    self.name, placeholder
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn render(&self, vars: &[(&str, &str)]) -> Result<RenderedPrompt, AppError> {
+        let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+
+        for variable in &self.variables {
+            if !vars.contains_key(variable.as_str()) {
+                return Err(AppError::BadRequest(format!(
+                    "Missing variable {} for template {}",
+                    variable, self.name
+                )));
+            }
+        }
+
+        Ok(RenderedPrompt {
+            system: self.system.as_deref().map(|s| substitute(s, &vars)),
+            user: substitute(&self.user, &vars),
+            model: self.model.clone(),
+            temperature: self.temperature,
+        })
+    }
+}
+
+// Registry of the prompt templates available to the text operations
+#[derive(Debug, Default)]
+pub struct TemplateRegistry {
+    templates: BTreeMap<String, PromptTemplate>,
+}
+
+impl TemplateRegistry {
+    // Load the built-in templates, then let files in `dir` override or extend
+    // them, whatever their version
+    pub fn load(dir: &Path) -> Result<Self, AppError> {
+        let mut registry = TemplateRegistry::default();
+
+        for (file, source) in BUILTIN_TEMPLATES {
+            let template = PromptTemplate::parse(source).map_err(|e| {
+                AppError::Internal(format!("Invalid built-in template {}: {}", file, e))
+            })?;
+            registry.insert(template);
+        }
+
+        if !dir.is_dir() {
+            info!(
+                "Prompt template directory {} not found, using built-in templates",
+                dir.display()
+            );
+            return Ok(registry);
+        }
+
+        let entries = fs::read_dir(dir)
+            .map_err(|e| AppError::Internal(format!("Failed to read {}: {}", dir.display(), e)))?;
+        let mut from_files: HashMap<String, (u32, PathBuf)> = HashMap::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+                continue;
+            }
+
+            let source = fs::read_to_string(&path).map_err(|e| {
+                AppError::Internal(format!("Failed to read {}: {}", path.display(), e))
+            })?;
+            let template = PromptTemplate::parse(&source).map_err(|e| {
+                AppError::Internal(format!("Invalid template {}: {}", path.display(), e))
+            })?;
+
+            // Files always replace the built-in template. Between files, the
+            // highest version wins, and the same version twice is ambiguous.
+            if let Some((version, other)) = from_files.get(&template.name) {
+                if *version == template.version {
+                    return Err(AppError::Internal(format!(
+                        "Templates {} and {} both define {} v{}",
+                        other.display(),
+                        path.display(),
+                        template.name,
+                        template.version
+                    )));
+                }
+                if *version > template.version {
+                    warn!(
+                        "Ignoring {} v{} from {}, v{} is loaded from {}",
+                        template.name,
+                        template.version,
+                        path.display(),
+                        version,
+                        other.display()
+                    );
+                    continue;
+                }
+            }
+            from_files.insert(template.name.clone(), (template.version, path.clone()));
+
+            info!(
+                "Loaded prompt template {} v{} from {}",
+                template.name,
+                template.version,
+                path.display()
+            );
+            registry.insert(template);
+        }
+
+        Ok(registry)
+    }
+
+    pub fn insert(&mut self, template: PromptTemplate) {
+        self.templates.insert(template.name.clone(), template);
+    }
+
+    pub fn get(&self, name: &str) -> Result<&PromptTemplate, AppError> {
+        self.templates
+            .get(name)
+            .ok_or_else(|| AppError::NotFound(format!("Prompt template {}", name)))
+    }
+
+    pub fn render(&self, name: &str, vars: &[(&str, &str)]) -> Result<RenderedPrompt, AppError> {
+        self.get(name)?.render(vars)
+    }
+
+    pub fn list(&self) -> Vec<TemplateInfo> {
+        self.templates.values().map(TemplateInfo::from).collect()
+    }
+}
+
+// List the available prompt templates
+pub async fn list_templates(State(state): State<AppState>) -> Json<Vec<TemplateInfo>> {
+    Json(state.templates.list())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_templates_load() {
+        let registry = TemplateRegistry::load(Path::new("does-not-exist")).unwrap();
+        let names: Vec<String> = registry.list().into_iter().map(|t| t.name).collect();
+
+        assert_eq!(
+            names,
+            vec![
+                "expand",
+                "paraphrase",
+                "proofread",
+                "summarize",
+                "translate"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_files_override_builtin_templates_whatever_their_version() {
+        let dir = std::env::temp_dir().join(format!("templates-{:x}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let paraphrase = |version: u32, wording: &str| {
+            format!(
+                "name = \"paraphrase\"\nversion = {}\nvariables = [\"text\", \"style\"]\nuser = \"{}{{{{style}}}}: {{{{text}}}}\"\n",
+                version, wording
+            )
+        };
+
+        // The built-in paraphrase template is at v2
+        fs::write(dir.join("paraphrase.toml"), paraphrase(1, "Reword")).unwrap();
+        fs::write(dir.join("older.toml"), paraphrase(0, "Restate")).unwrap();
+        let registry = TemplateRegistry::load(&dir).unwrap();
+        assert_eq!(registry.get("paraphrase").unwrap().version, 1);
+        let prompt = registry
+            .render("paraphrase", &[("text", "Hi"), ("style", "")])
+            .unwrap();
+        assert_eq!(prompt.user, "Reword: Hi");
+
+        // Two files with the same version are ambiguous
+        fs::write(dir.join("again.toml"), paraphrase(1, "Rephrase")).unwrap();
+        match TemplateRegistry::load(&dir) {
+            Err(AppError::Internal(message)) => assert!(message.contains("again.toml")),
+            other => panic!("Expected a conflict, got {:?}", other.map(|_| ())),
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_render_substitutes_variables() {
+        let registry = TemplateRegistry::load(Path::new("does-not-exist")).unwrap();
+        let prompt = registry
+            .render(
+                "translate",
+                &[
+                    ("text", "Hola"),
+                    ("source_language", "Spanish"),
+                    ("target_language", "English"),
+                    ("glossary", ""),
+                    ("memory", ""),
+                    ("style", " Use a formal tone."),
+                ],
+            )
+            .unwrap();
+
+        assert_eq!(
+            prompt.user,
+            "Translate the following text from Spanish to English. Use a formal tone.\n\nHola"
+        );
+        assert_eq!(prompt.system, None);
+    }
+
+    #[test]
+    fn test_render_requires_declared_variables() {
+        let registry = TemplateRegistry::load(Path::new("does-not-exist")).unwrap();
+
+        assert!(matches!(
+            registry.render("translate", &[("text", "Hola")]),
+            Err(AppError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_rejects_undeclared_variables() {
+        let source = r#"
+            name = "broken"
+            version = 1
+            variables = ["text"]
+            system = "You are {{persona}}"
+            user = "{{text}}"
+        "#;
+
+        assert!(PromptTemplate::parse(source).is_err());
+    }
+
+    #[test]
+    fn test_parse_reads_model_settings() {
+        let source = r#"
+            name = "formal"
+            version = 3
+            variables = ["text"]
+            model = "gpt-4o"
+            temperature = 0.2
+            system = "You are an editor."
+            user = "Make this formal: {{ text }}"
+        "#;
+
+        let template = PromptTemplate::parse(source).unwrap();
+        let prompt = template.render(&[("text", "hey")]).unwrap();
+
+        assert_eq!(prompt.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(prompt.temperature, Some(0.2));
+        assert_eq!(
+            prompt.contents(),
+            vec!["You are an editor.", "Make this formal: hey"]
+        );
+    }
+}
@@ -0,0 +1,222 @@
+// Helpers shared by the tests: a mock of the OpenAI chat completions API and
+// utilities to drive the router end to end
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{header, Request, Response};
+use axum::response::IntoResponse;
+use axum::routing::post;
+use axum::{Json, Router};
+use serde_json::{json, Value};
+
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
+
+use crate::api::create_router;
+use crate::auth::generate_token;
+use crate::config::Config;
+use crate::state::AppState;
+
+type Responder = Arc<dyn Fn(&Value) -> Vec<String> + Send + Sync>;
+
+#[derive(Clone)]
+struct MockState {
+    respond: Responder,
+    requests: Arc<Mutex<Vec<Value>>>,
+}
+
+// A running mock of the chat completions endpoint
+pub struct MockOpenAI {
+    pub base_url: String,
+    requests: Arc<Mutex<Vec<Value>>>,
+}
+
+impl MockOpenAI {
+    // Request bodies received so far
+    pub fn requests(&self) -> Vec<Value> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+// Text of the last user message of a chat completion request
+pub fn last_user_message(request: &Value) -> String {
+    request["messages"]
+        .as_array()
+        .and_then(|m| m.iter().rev().find(|m| m["role"] == "user"))
+        .and_then(|m| m["content"].as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+async fn chat_completions(
+    State(mock): State<MockState>,
+    Json(request): Json<Value>,
+) -> Response<axum::body::BoxBody> {
+    mock.requests.lock().unwrap().push(request.clone());
+    let choices = (mock.respond)(&request);
+
+    if request["stream"] != true {
+        return Json(json!({
+            "id": "chatcmpl-mock",
+            "object": "chat.completion",
+            "created": 0,
+            "model": request["model"],
+            "choices": choices.iter().enumerate().map(|(index, content)| json!({
+                "index": index,
+                "message": {"role": "assistant", "content": content},
+                "finish_reason": "stop",
+            })).collect::<Vec<_>>(),
+        }))
+        .into_response();
+    }
+
+    // Stream every choice word by word, interleaving the choices
+    let mut body = String::new();
+    let chunks: Vec<Vec<String>> = choices
+        .iter()
+        .map(|c| c.split_inclusive(' ').map(str::to_string).collect())
+        .collect();
+    let longest = chunks.iter().map(Vec::len).max().unwrap_or(0);
+    for position in 0..longest {
+        for (index, words) in chunks.iter().enumerate() {
+            if let Some(word) = words.get(position) {
+                let chunk = json!({
+                    "id": "chatcmpl-mock",
+                    "object": "chat.completion.chunk",
+                    "created": 0,
+                    "model": request["model"],
+                    "choices": [{"index": index, "delta": {"content": word}, "finish_reason": null}],
+                });
+                body.push_str(&format!("data: {}\n\n", chunk));
+            }
+        }
+    }
+    body.push_str("data: [DONE]\n\n");
+
+    ([(header::CONTENT_TYPE, "text/event-stream")], body).into_response()
+}
+
+// Start a mock upstream that answers every request with the choices returned by `respond`
+pub async fn spawn_mock_openai<F>(respond: F) -> MockOpenAI
+where
+    F: Fn(&Value) -> Vec<String> + Send + Sync + 'static,
+{
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let state = MockState {
+        respond: Arc::new(respond),
+        requests: requests.clone(),
+    };
+    let app = Router::new()
+        .route("/chat/completions", post(chat_completions))
+        .with_state(state);
+
+    let server =
+        axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(app.into_make_service());
+    let base_url = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+
+    MockOpenAI { base_url, requests }
+}
+
+// Test configuration pointing at a mock upstream
+pub fn test_config(base_url: &str) -> Config {
+    let mut config = Config::default_test_config();
+    config.openai.base_url = base_url.to_string();
+    config
+}
+
+pub fn test_state(config: Config) -> AppState {
+    AppState::new(Arc::new(config)).unwrap()
+}
+
+// Serve the whole application on a local port, for tests that need a real
+// connection such as WebSockets
+pub async fn spawn_app(state: AppState) -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let app = create_router(state);
+    tokio::spawn(async move {
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .unwrap();
+    });
+    address
+}
+
+// Open a WebSocket to the application authenticated as `username`
+pub async fn connect_websocket(
+    config: &Config,
+    address: SocketAddr,
+    path: &str,
+    username: &str,
+) -> Result<
+    (
+        WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
+        tungstenite::handshake::client::Response,
+    ),
+    tungstenite::Error,
+> {
+    let (token, _) = generate_token(username, config).unwrap();
+    let mut request = format!("ws://{}{}", address, path)
+        .into_client_request()
+        .unwrap();
+    request.headers_mut().insert(
+        header::AUTHORIZATION,
+        format!("Bearer {}", token).parse().unwrap(),
+    );
+    tokio_tungstenite::connect_async(request).await
+}
+
+// Build a request authenticated as `username`
+pub fn authed_request(config: &Config, username: &str) -> axum::http::request::Builder {
+    let (token, _) = generate_token(username, config).unwrap();
+    Request::builder().header(header::AUTHORIZATION, format!("Bearer {}", token))
+}
+
+pub fn json_body(value: Value) -> Body {
+    Body::from(value.to_string())
+}
+
+// Parse an SSE body into (event name, data) pairs, `message` for unnamed events
+pub async fn sse_events(response: Response<axum::body::BoxBody>) -> Vec<(String, String)> {
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+
+    body.split("\n\n")
+        .filter(|block| !block.trim().is_empty())
+        .filter_map(|block| {
+            let mut event = "message".to_string();
+            let mut data = Vec::new();
+            for line in block.lines() {
+                if let Some(name) = line.strip_prefix("event:") {
+                    event = name.trim().to_string();
+                } else if let Some(value) = line.strip_prefix("data:") {
+                    data.push(value.strip_prefix(' ').unwrap_or(value).to_string());
+                }
+            }
+            (!data.is_empty() || event != "message").then(|| (event, data.join("\n")))
+        })
+        .collect()
+}
+
+// Concatenated content of the unnamed events of an SSE body
+pub fn sse_content(events: &[(String, String)]) -> String {
+    events
+        .iter()
+        .filter(|(event, _)| event == "message")
+        .map(|(_, data)| data.as_str())
+        .collect()
+}
+
+// Data of the first event with the given name, parsed as JSON
+pub fn sse_json(events: &[(String, String)], name: &str) -> Option<Value> {
+    events
+        .iter()
+        .find(|(event, _)| event == name)
+        .and_then(|(_, data)| serde_json::from_str(data).ok())
+}
@@ -0,0 +1,172 @@
+// Text helpers shared by the operations
+
+// Abbreviations that end with a period without ending the sentence
+const ABBREVIATIONS: &[&str] = &[
+    "mr", "mrs", "ms", "dr", "prof", "sr", "jr", "st", "vs", "e.g", "i.e", "cf", "fig",
+];
+
+// A sentence and the whitespace that followed it in the source text.
+// Concatenating `text` and `trailing` of every sentence gives back the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sentence<'a> {
+    pub text: &'a str,
+    pub trailing: &'a str,
+}
+
+fn is_terminator(c: char) -> bool {
+    matches!(c, '.' | '!' | '?' | '…')
+}
+
+// Full-width terminators end a sentence even without following whitespace
+fn is_cjk_terminator(c: char) -> bool {
+    matches!(c, '。' | '！' | '？')
+}
+
+fn is_closer(c: char) -> bool {
+    matches!(c, '"' | '\'' | '”' | '’' | ')' | ']' | '»' | '」' | '』')
+}
+
+fn ends_with_abbreviation(text: &str) -> bool {
+    let word = text
+        .trim_end_matches('.')
+        .rsplit(|c: char| c.is_whitespace() || c == '(')
+        .next()
+        .unwrap_or("")
+        .to_lowercase();
+    ABBREVIATIONS.contains(&word.as_str())
+}
+
+// Length in bytes of the whitespace at the start of `text`
+fn whitespace_len(text: &str) -> usize {
+    text.len() - text.trim_start().len()
+}
+
+// Split text into sentences. Line breaks always end a sentence, so
+// paragraphs and list items are kept apart.
+pub fn split_sentences(text: &str) -> Vec<Sentence<'_>> {
+    let mut sentences = Vec::new();
+
+    let leading = whitespace_len(text);
+    if leading > 0 {
+        sentences.push(Sentence {
+            text: "",
+            trailing: &text[..leading],
+        });
+    }
+
+    let mut start = leading;
+    let mut pos = leading;
+    while pos < text.len() {
+        let c = text[pos..].chars().next().unwrap_or_default();
+        let mut content_end = None;
+
+        if c == '\n' {
+            content_end = Some(pos);
+        } else if is_terminator(c) || is_cjk_terminator(c) {
+            // Swallow repeated terminators and closing quotes or brackets
+            let mut end = pos + c.len_utf8();
+            for next in text[end..].chars() {
+                if is_terminator(next) || is_cjk_terminator(next) || is_closer(next) {
+                    end += next.len_utf8();
+                } else {
+                    break;
+                }
+            }
+
+            let followed_by_space = text[end..].chars().next().is_none_or(char::is_whitespace);
+            let is_abbreviation = c == '.' && ends_with_abbreviation(&text[start..end]);
+
+            if is_cjk_terminator(c) || (followed_by_space && !is_abbreviation) {
+                content_end = Some(end);
+            } else {
+                pos = end;
+                continue;
+            }
+        }
+
+        match content_end {
+            Some(end) => {
+                // Whitespace before a line break belongs to the separator, and
+                // blank lines after it are swallowed with the rest
+                let content = text[start..end].trim_end();
+                let trailing_end = end + whitespace_len(&text[end..]);
+                sentences.push(Sentence {
+                    text: content,
+                    trailing: &text[start + content.len()..trailing_end],
+                });
+                start = trailing_end;
+                pos = trailing_end;
+            }
+            None => pos += c.len_utf8(),
+        }
+    }
+
+    if start < text.len() {
+        let content = text[start..].trim_end();
+        sentences.push(Sentence {
+            text: content,
+            trailing: &text[start + content.len()..],
+        });
+    }
+
+    sentences
+}
+
+// Collapse runs of whitespace so equivalent sentences compare equal
+pub fn normalize_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(text: &str) -> Vec<&str> {
+        split_sentences(text).into_iter().map(|s| s.text).collect()
+    }
+
+    #[test]
+    fn test_split_sentences_round_trips() {
+        let text = "  Hello there. How are you?\n\nI'm fine!  Thanks";
+        let sentences = split_sentences(text);
+
+        let rebuilt: String = sentences
+            .iter()
+            .map(|s| format!("{}{}", s.text, s.trailing))
+            .collect();
+        assert_eq!(rebuilt, text);
+        assert_eq!(
+            texts(text),
+            vec!["", "Hello there.", "How are you?", "I'm fine!", "Thanks"]
+        );
+        assert_eq!(sentences[2].trailing, "\n\n");
+    }
+
+    #[test]
+    fn test_split_sentences_keeps_abbreviations_and_decimals() {
+        assert_eq!(
+            texts("Dr. Smith paid 3.50 dollars, e.g. for coffee. Then he left."),
+            vec![
+                "Dr. Smith paid 3.50 dollars, e.g. for coffee.",
+                "Then he left."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_sentences_handles_quotes_and_cjk() {
+        assert_eq!(
+            texts("He said \"Stop!\" Then silence."),
+            vec!["He said \"Stop!\"", "Then silence."]
+        );
+        assert_eq!(
+            texts("今日は晴れです。明日は雨です。"),
+            vec!["今日は晴れです。", "明日は雨です。"]
+        );
+    }
+
+    #[test]
+    fn test_normalize_whitespace() {
+        assert_eq!(normalize_whitespace("  a \n b\tc "), "a b c");
+    }
+}
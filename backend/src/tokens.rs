@@ -0,0 +1,165 @@
+use tiktoken_rs::CoreBPE;
+use tracing::warn;
+
+use crate::config::OpenAIConfig;
+use crate::error::AppError;
+
+// Fixed overhead the chat format adds per message and for priming the reply
+const TOKENS_PER_MESSAGE: usize = 3;
+const TOKENS_PER_REPLY: usize = 3;
+
+// Tokenizer matching the configured model, used to validate prompts before
+// they are sent upstream
+pub struct Tokenizer {
+    bpe: CoreBPE,
+    model: String,
+    context_limit: usize,
+    completion_reserve: usize,
+}
+
+impl Tokenizer {
+    pub fn for_config(config: &OpenAIConfig) -> Result<Self, AppError> {
+        Self::for_model(config, &config.model)
+    }
+
+    // Tokenizer for a model other than the configured default, e.g. one set by
+    // a prompt template. The context limit override only applies to the default.
+    pub fn for_model(config: &OpenAIConfig, model: &str) -> Result<Self, AppError> {
+        // Models served through compatible APIs are often unknown to tiktoken,
+        // cl100k_base is the closest general-purpose encoding in that case
+        let bpe = match tiktoken_rs::get_bpe_from_model(model) {
+            Ok(bpe) => bpe,
+            Err(_) => {
+                warn!(
+                    "No tokenizer known for model {}, falling back to cl100k_base",
+                    model
+                );
+                tiktoken_rs::cl100k_base()
+                    .map_err(|e| AppError::Internal(format!("Failed to load tokenizer: {}", e)))?
+            }
+        };
+
+        let context_limit = config
+            .context_limit
+            .filter(|_| model == config.model)
+            .unwrap_or_else(|| tiktoken_rs::model::get_context_size(model));
+
+        Ok(Tokenizer {
+            bpe,
+            model: model.to_string(),
+            context_limit,
+            completion_reserve: config.completion_reserve,
+        })
+    }
+
+    // Count the tokens of a plain piece of text
+    pub fn count(&self, text: &str) -> usize {
+        self.bpe.encode_with_special_tokens(text).len()
+    }
+
+    // Count the tokens of a chat prompt, including the per-message overhead
+    pub fn count_messages<S: AsRef<str>>(&self, messages: &[S]) -> usize {
+        messages
+            .iter()
+            .map(|m| self.count(m.as_ref()) + TOKENS_PER_MESSAGE)
+            .sum::<usize>()
+            + TOKENS_PER_REPLY
+    }
+
+    // Maximum number of prompt tokens, leaving room for a completion of up to
+    // `max_tokens`, or the configured reserve when that is larger
+    pub fn prompt_limit(&self, max_tokens: Option<usize>) -> usize {
+        let reserve = max_tokens.unwrap_or(0).max(self.completion_reserve);
+        self.context_limit.saturating_sub(reserve)
+    }
+
+    // Count the prompt and reject it if it would not fit in the model's context
+    // along with the completion
+    pub fn check_prompt<S: AsRef<str>>(
+        &self,
+        messages: &[S],
+        max_tokens: Option<usize>,
+    ) -> Result<usize, AppError> {
+        let prompt_tokens = self.count_messages(messages);
+        let limit = self.prompt_limit(max_tokens);
+
+        if prompt_tokens > limit {
+            return Err(AppError::PayloadTooLarge(format!(
+                "Prompt is {} tokens, which is {} tokens over the {}-token limit for {}",
+                prompt_tokens,
+                prompt_tokens - limit,
+                limit,
+                self.model
+            )));
+        }
+
+        Ok(prompt_tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_count_messages_includes_overhead() {
+        let config = Config::default_test_config();
+        let tokenizer = Tokenizer::for_config(&config.openai).unwrap();
+
+        let text_tokens = tokenizer.count("Hello world");
+        assert!(text_tokens > 0);
+        assert_eq!(
+            tokenizer.count_messages(&["Hello world"]),
+            text_tokens + TOKENS_PER_MESSAGE + TOKENS_PER_REPLY
+        );
+    }
+
+    #[test]
+    fn test_check_prompt_rejects_oversized_input() {
+        let mut config = Config::default_test_config();
+        config.openai.context_limit = Some(20);
+        config.openai.completion_reserve = 10;
+        let tokenizer = Tokenizer::for_config(&config.openai).unwrap();
+
+        assert!(tokenizer.check_prompt(&["short"], None).is_ok());
+
+        let long_text = "word ".repeat(50);
+        match tokenizer.check_prompt(&[long_text], None) {
+            Err(AppError::PayloadTooLarge(msg)) => assert!(msg.contains("over the 10-token limit")),
+            other => panic!(
+                "Expected PayloadTooLarge error, got {:?}",
+                other.map(|_| ())
+            ),
+        }
+    }
+
+    #[test]
+    fn test_check_prompt_reserves_max_tokens() {
+        let mut config = Config::default_test_config();
+        config.openai.context_limit = Some(40);
+        config.openai.completion_reserve = 10;
+        let tokenizer = Tokenizer::for_config(&config.openai).unwrap();
+
+        let text = "word ".repeat(20);
+        assert!(tokenizer.check_prompt(&[&text], None).is_ok());
+        // A smaller max_tokens still leaves the reserve
+        assert!(tokenizer.check_prompt(&[&text], Some(5)).is_ok());
+        match tokenizer.check_prompt(&[&text], Some(30)) {
+            Err(AppError::PayloadTooLarge(msg)) => assert!(msg.contains("over the 10-token limit")),
+            other => panic!(
+                "Expected PayloadTooLarge error, got {:?}",
+                other.map(|_| ())
+            ),
+        }
+    }
+
+    #[test]
+    fn test_unknown_model_falls_back() {
+        let mut config = Config::default_test_config();
+        config.openai.model = "some-local-model".to_string();
+        let tokenizer = Tokenizer::for_config(&config.openai).unwrap();
+
+        assert!(tokenizer.count("Hello world") > 0);
+    }
+}
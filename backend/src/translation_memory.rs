@@ -0,0 +1,571 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use chrono::{DateTime, Utc};
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::db::{from_column, Database};
+use crate::error::AppError;
+use crate::languages::LanguageTag;
+use crate::models::Claims;
+use crate::state::AppState;
+use crate::text::{normalize_whitespace, split_sentences};
+
+// Minimum similarity for a stored segment to be offered as a hint
+const FUZZY_THRESHOLD: f64 = 0.75;
+// Maximum number of hints offered per segment
+const FUZZY_LIMIT: usize = 2;
+// Segments kept per user and language pair, the least recently stored going first
+const MAX_SEGMENTS: usize = 10_000;
+
+// Memories are kept apart per user and language pair
+type MemoryKey = (String, LanguageTag, LanguageTag);
+
+#[derive(Debug, Clone)]
+struct MemoryEntry {
+    source: String,
+    translation: String,
+}
+
+// A stored segment similar to the one being translated
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FuzzyMatch {
+    pub source: String,
+    pub translation: String,
+    pub similarity: f64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SegmentOrigin {
+    Memory,
+    Generated,
+}
+
+// Per-segment outcome reported at the end of a translation
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SegmentReport {
+    pub index: usize,
+    pub source: String,
+    // Missing when the generated text could not be aligned to its segments
+    pub translation: Option<String>,
+    pub origin: SegmentOrigin,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fuzzy_matches: Vec<FuzzyMatch>,
+}
+
+// A sentence of the input, with its stored translation if one exists
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlannedSegment {
+    pub source: String,
+    pub trailing: String,
+    pub memory: Option<String>,
+    pub fuzzy_matches: Vec<FuzzyMatch>,
+}
+
+// A stretch of consecutive segments that is either reused or generated together
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlannedRun {
+    Memory(usize),
+    Generate(Range<usize>),
+}
+
+// How a text will be translated, segment by segment
+#[derive(Debug, Clone)]
+pub struct TranslationPlan {
+    key: MemoryKey,
+    pub segments: Vec<PlannedSegment>,
+}
+
+impl TranslationPlan {
+    pub fn has_memory_hits(&self) -> bool {
+        self.segments.iter().any(|s| s.memory.is_some())
+    }
+
+    pub fn runs(&self) -> Vec<PlannedRun> {
+        let mut runs = Vec::new();
+        let mut pending: Option<usize> = None;
+
+        for (index, segment) in self.segments.iter().enumerate() {
+            // Whitespace-only segments travel with whatever is around them
+            if segment.source.is_empty() {
+                continue;
+            }
+
+            if segment.memory.is_some() {
+                if let Some(start) = pending.take() {
+                    runs.push(PlannedRun::Generate(start..index));
+                }
+                runs.push(PlannedRun::Memory(index));
+            } else if pending.is_none() {
+                pending = Some(index);
+            }
+        }
+
+        if let Some(start) = pending {
+            runs.push(PlannedRun::Generate(start..self.segments.len()));
+        }
+
+        runs
+    }
+
+    // Source text of a generated run, keeping the original separators between segments
+    pub fn run_source(&self, range: &Range<usize>) -> String {
+        let segments = &self.segments[range.clone()];
+        let mut source = String::new();
+        for (i, segment) in segments.iter().enumerate() {
+            source.push_str(&segment.source);
+            if i + 1 < segments.len() {
+                source.push_str(&segment.trailing);
+            }
+        }
+        source
+    }
+
+    // Separator that followed the last segment of a run in the input
+    pub fn run_trailing(&self, range: &Range<usize>) -> String {
+        self.segments[range.clone()]
+            .last()
+            .map(|s| s.trailing.clone())
+            .unwrap_or_default()
+    }
+
+    // Fuzzy matches for every segment of a run, used as hints for the model
+    pub fn run_hints(&self, range: &Range<usize>) -> Vec<FuzzyMatch> {
+        self.segments[range.clone()]
+            .iter()
+            .flat_map(|s| s.fuzzy_matches.iter().cloned())
+            .collect()
+    }
+
+    // Match generated run outputs back to their segments, store them, and
+    // build the per-segment report
+    pub fn finish(
+        &self,
+        memory: &TranslationMemory,
+        run_outputs: &[(PlannedRun, String)],
+    ) -> Vec<SegmentReport> {
+        let mut reports = Vec::new();
+        let mut learned = Vec::new();
+
+        for (run, output) in run_outputs {
+            match run {
+                PlannedRun::Memory(index) => {
+                    let segment = &self.segments[*index];
+                    reports.push(SegmentReport {
+                        index: *index,
+                        source: segment.source.clone(),
+                        translation: segment.memory.clone(),
+                        origin: SegmentOrigin::Memory,
+                        fuzzy_matches: Vec::new(),
+                    });
+                }
+                PlannedRun::Generate(range) => {
+                    let segments: Vec<(usize, &PlannedSegment)> = range
+                        .clone()
+                        .map(|i| (i, &self.segments[i]))
+                        .filter(|(_, s)| !s.source.is_empty())
+                        .collect();
+
+                    let translations: Vec<String> = if segments.len() == 1 {
+                        vec![output.trim().to_string()]
+                    } else {
+                        split_sentences(output)
+                            .into_iter()
+                            .filter(|s| !s.text.is_empty())
+                            .map(|s| s.text.to_string())
+                            .collect()
+                    };
+                    let aligned = translations.len() == segments.len();
+
+                    for (position, (index, segment)) in segments.into_iter().enumerate() {
+                        let translation = aligned.then(|| translations[position].clone());
+                        if let Some(translation) = &translation {
+                            learned.push((segment.source.clone(), translation.clone()));
+                        }
+                        reports.push(SegmentReport {
+                            index,
+                            source: segment.source.clone(),
+                            translation,
+                            origin: SegmentOrigin::Generated,
+                            fuzzy_matches: segment.fuzzy_matches.clone(),
+                        });
+                    }
+                }
+            }
+        }
+
+        if let Err(e) = memory.store(&self.key, learned) {
+            error!("Failed to store translated segments: {}", e);
+        }
+        reports
+    }
+}
+
+// Stored translations, segmented by sentence, kept in the database so they
+// outlive a restart
+pub struct TranslationMemory {
+    database: Arc<Database>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MemoryStats {
+    pub source_language: LanguageTag,
+    pub target_language: LanguageTag,
+    pub segments: usize,
+    pub last_updated: Option<DateTime<Utc>>,
+}
+
+impl TranslationMemory {
+    pub fn new(database: Arc<Database>) -> Self {
+        TranslationMemory { database }
+    }
+
+    // Look up every sentence of `text` and decide what can be reused
+    pub fn plan(
+        &self,
+        username: &str,
+        text: &str,
+        source_language: &LanguageTag,
+        target_language: &LanguageTag,
+    ) -> Result<TranslationPlan, AppError> {
+        let key = (
+            username.to_string(),
+            source_language.clone(),
+            target_language.clone(),
+        );
+        let entries = self.entries(&key)?;
+
+        let segments = split_sentences(text)
+            .into_iter()
+            .map(|sentence| {
+                let normalized = normalize_whitespace(sentence.text);
+                let memory = entries.get(&normalized).map(|e| e.translation.clone());
+                let fuzzy_matches = if memory.is_none() && !normalized.is_empty() {
+                    fuzzy_matches(&entries, &normalized)
+                } else {
+                    Vec::new()
+                };
+
+                PlannedSegment {
+                    source: sentence.text.to_string(),
+                    trailing: sentence.trailing.to_string(),
+                    memory,
+                    fuzzy_matches,
+                }
+            })
+            .collect();
+
+        Ok(TranslationPlan { key, segments })
+    }
+
+    fn entries(&self, key: &MemoryKey) -> Result<HashMap<String, MemoryEntry>, AppError> {
+        let (owner, source_language, target_language) = key;
+        self.database.with(|connection| {
+            let mut statement = connection.prepare(
+                "SELECT source, translation FROM translation_memory
+                 WHERE owner = ?1 AND source_language = ?2 AND target_language = ?3",
+            )?;
+            let entries = statement
+                .query_map(
+                    params![owner, source_language.as_str(), target_language.as_str()],
+                    |row| {
+                        Ok(MemoryEntry {
+                            source: row.get(0)?,
+                            translation: row.get(1)?,
+                        })
+                    },
+                )?
+                .map(|entry| entry.map(|e| (e.source.clone(), e)))
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(entries)
+        })
+    }
+
+    fn store(&self, key: &MemoryKey, pairs: Vec<(String, String)>) -> Result<(), AppError> {
+        if pairs.is_empty() {
+            return Ok(());
+        }
+
+        let (owner, source_language, target_language) = key;
+        let now = Utc::now();
+        self.database.with(|connection| {
+            let transaction = connection.transaction()?;
+            for (source, translation) in pairs {
+                transaction.execute(
+                    "INSERT OR REPLACE INTO translation_memory
+                     (owner, source_language, target_language, source, translation, updated_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![
+                        owner,
+                        source_language.as_str(),
+                        target_language.as_str(),
+                        normalize_whitespace(&source),
+                        translation,
+                        now,
+                    ],
+                )?;
+            }
+            transaction.execute(
+                "DELETE FROM translation_memory
+                 WHERE owner = ?1 AND source_language = ?2 AND target_language = ?3
+                 AND rowid NOT IN (SELECT rowid FROM translation_memory
+                     WHERE owner = ?1 AND source_language = ?2 AND target_language = ?3
+                     ORDER BY updated_at DESC LIMIT ?4)",
+                params![
+                    owner,
+                    source_language.as_str(),
+                    target_language.as_str(),
+                    MAX_SEGMENTS as i64,
+                ],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })
+    }
+
+    pub fn stats(&self, username: &str) -> Result<Vec<MemoryStats>, AppError> {
+        self.database.with(|connection| {
+            let mut statement = connection.prepare(
+                "SELECT source_language, target_language, COUNT(*), MAX(updated_at)
+                 FROM translation_memory WHERE owner = ?1
+                 GROUP BY source_language, target_language
+                 ORDER BY source_language, target_language",
+            )?;
+            let stats = statement
+                .query_map([username], |row| {
+                    Ok(MemoryStats {
+                        source_language: from_column(row.get(0)?)?,
+                        target_language: from_column(row.get(1)?)?,
+                        segments: row.get::<_, i64>(2)? as usize,
+                        last_updated: row.get(3)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(stats)
+        })
+    }
+
+    pub fn clear(&self, username: &str) -> Result<(), AppError> {
+        self.database.with(|connection| {
+            connection.execute(
+                "DELETE FROM translation_memory WHERE owner = ?1",
+                [username],
+            )?;
+            Ok(())
+        })
+    }
+}
+
+fn fuzzy_matches(entries: &HashMap<String, MemoryEntry>, normalized: &str) -> Vec<FuzzyMatch> {
+    let length = normalized.chars().count() as f64;
+
+    let mut matches: Vec<FuzzyMatch> = entries
+        .values()
+        // Skip candidates whose length alone rules out a close match
+        .filter(|e| {
+            let other = e.source.chars().count() as f64;
+            other.min(length) / other.max(length) >= FUZZY_THRESHOLD
+        })
+        .filter_map(|e| {
+            let similarity = strsim::normalized_levenshtein(normalized, &e.source);
+            (similarity >= FUZZY_THRESHOLD).then(|| FuzzyMatch {
+                source: e.source.clone(),
+                translation: e.translation.clone(),
+                similarity,
+            })
+        })
+        .collect();
+
+    matches.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    matches.truncate(FUZZY_LIMIT);
+    matches
+}
+
+// Prompt instructions listing fuzzy matches, empty when there are none
+pub fn hint_instructions(hints: &[FuzzyMatch]) -> String {
+    if hints.is_empty() {
+        return String::new();
+    }
+
+    let mut instructions =
+        String::from("\n\nFor consistency, these similar sentences were translated before:");
+    for hint in hints {
+        instructions.push_str(&format!(
+            "\n- \"{}\" -> \"{}\"",
+            hint.source, hint.translation
+        ));
+    }
+    instructions
+}
+
+// Show how many segments are stored per language pair for the current user
+pub async fn memory_stats(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<MemoryStats>>, AppError> {
+    Ok(Json(state.translation_memory.stats(&claims.sub)?))
+}
+
+// Forget every stored translation of the current user
+pub async fn clear_memory(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<StatusCode, AppError> {
+    state.translation_memory.clear(&claims.sub)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn memory() -> TranslationMemory {
+        let config = Config::default_test_config();
+        TranslationMemory::new(Arc::new(Database::open(&config.database).unwrap()))
+    }
+
+    fn tag(s: &str) -> LanguageTag {
+        LanguageTag::parse(s).unwrap()
+    }
+
+    fn learn(memory: &TranslationMemory, text: &str, translation: &str) {
+        let plan = memory.plan("alice", text, &tag("en"), &tag("es")).unwrap();
+        let runs = plan.runs();
+        let outputs: Vec<(PlannedRun, String)> = runs
+            .into_iter()
+            .map(|run| (run, translation.to_string()))
+            .collect();
+        plan.finish(memory, &outputs);
+    }
+
+    #[test]
+    fn test_plan_reuses_exact_matches() {
+        let memory = memory();
+        learn(&memory, "Hello there. How are you?", "Hola. ¿Cómo estás?");
+
+        let plan = memory
+            .plan(
+                "alice",
+                "Hello there.  The weather is nice. How are you?",
+                &tag("en"),
+                &tag("es"),
+            )
+            .unwrap();
+
+        assert_eq!(plan.segments[0].memory.as_deref(), Some("Hola."));
+        assert_eq!(plan.segments[1].memory, None);
+        assert_eq!(plan.segments[2].memory.as_deref(), Some("¿Cómo estás?"));
+        assert_eq!(
+            plan.runs(),
+            vec![
+                PlannedRun::Memory(0),
+                PlannedRun::Generate(1..2),
+                PlannedRun::Memory(2)
+            ]
+        );
+        assert_eq!(plan.run_trailing(&(1..2)), " ");
+    }
+
+    #[test]
+    fn test_memories_are_per_user_and_language_pair() {
+        let memory = memory();
+        learn(&memory, "Hello there.", "Hola.");
+
+        assert!(memory
+            .plan("alice", "Hello there.", &tag("en"), &tag("es"))
+            .unwrap()
+            .has_memory_hits());
+        assert!(!memory
+            .plan("bob", "Hello there.", &tag("en"), &tag("es"))
+            .unwrap()
+            .has_memory_hits());
+        assert!(!memory
+            .plan("alice", "Hello there.", &tag("en"), &tag("de"))
+            .unwrap()
+            .has_memory_hits());
+    }
+
+    #[test]
+    fn test_memories_outlive_the_store() {
+        let dir = std::env::temp_dir().join(format!("memory-test-{}", rand::random::<u64>()));
+        let config = crate::config::DatabaseConfig {
+            url: format!("sqlite://{}", dir.join("app.db").display()),
+        };
+        let open = || TranslationMemory::new(Arc::new(Database::open(&config).unwrap()));
+
+        learn(&open(), "Hello there.", "Hola.");
+        let plan = open()
+            .plan("alice", "Hello there.", &tag("en"), &tag("es"))
+            .unwrap();
+        assert_eq!(plan.segments[0].memory.as_deref(), Some("Hola."));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_segments_per_language_pair_are_limited() {
+        let memory = memory();
+        let key = ("alice".to_string(), tag("en"), tag("es"));
+        let pairs = (0..MAX_SEGMENTS)
+            .map(|i| (format!("Sentence {}.", i), format!("Frase {}.", i)))
+            .collect();
+        memory.store(&key, pairs).unwrap();
+        learn(&memory, "Hello there.", "Hola.");
+
+        assert_eq!(memory.stats("alice").unwrap()[0].segments, MAX_SEGMENTS);
+        assert!(memory
+            .plan("alice", "Hello there.", &tag("en"), &tag("es"))
+            .unwrap()
+            .has_memory_hits());
+    }
+
+    #[test]
+    fn test_fuzzy_matches_are_offered_as_hints() {
+        let memory = memory();
+        learn(
+            &memory,
+            "Click the blue button to continue.",
+            "Haz clic en el botón azul para continuar.",
+        );
+
+        let plan = memory
+            .plan(
+                "alice",
+                "Click the green button to continue.",
+                &tag("en"),
+                &tag("es"),
+            )
+            .unwrap();
+
+        assert_eq!(plan.segments[0].memory, None);
+        let hints = plan.run_hints(&(0..1));
+        assert_eq!(hints.len(), 1);
+        assert!(hints[0].similarity > 0.8);
+        assert!(hint_instructions(&hints).contains("botón azul"));
+    }
+
+    #[test]
+    fn test_finish_reports_unaligned_runs() {
+        let memory = memory();
+        let plan = memory
+            .plan("alice", "One. Two.", &tag("en"), &tag("es"))
+            .unwrap();
+
+        let reports = plan.finish(
+            &memory,
+            &[(PlannedRun::Generate(0..2), "Uno dos.".to_string())],
+        );
+
+        assert_eq!(reports.len(), 2);
+        assert!(reports.iter().all(|r| r.translation.is_none()));
+        assert!(memory.stats("alice").unwrap().is_empty());
+    }
+}
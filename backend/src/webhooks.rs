@@ -0,0 +1,897 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::extract::{Path, State};
+use axum::http::{header, StatusCode};
+use axum::{Extension, Json};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use rusqlite::{params, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tokio::net::lookup_host;
+use tracing::{debug, error, info, warn};
+
+use crate::config::WebhooksConfig;
+use crate::db::{as_column, from_column, Database};
+use crate::error::AppError;
+use crate::models::Claims;
+use crate::state::AppState;
+
+// Webhooks a user may register
+const MAX_WEBHOOKS: usize = 10;
+// Deliveries kept per webhook for inspection
+const MAX_DELIVERIES: usize = 50;
+
+// Named after their payload, so events of other resources can be added
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(clippy::enum_variant_names)]
+pub enum WebhookEvent {
+    #[serde(rename = "job.completed")]
+    JobCompleted,
+    #[serde(rename = "job.failed")]
+    JobFailed,
+    #[serde(rename = "job.cancelled")]
+    JobCancelled,
+}
+
+impl WebhookEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WebhookEvent::JobCompleted => "job.completed",
+            WebhookEvent::JobFailed => "job.failed",
+            WebhookEvent::JobCancelled => "job.cancelled",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Webhook {
+    pub id: u64,
+    pub url: String,
+    // Events sent to the URL, every event when empty
+    pub events: Vec<WebhookEvent>,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip)]
+    secret: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewWebhook {
+    pub url: String,
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+}
+
+// The signing secret is only shown when the webhook is created
+#[derive(Debug, Serialize)]
+pub struct CreatedWebhook {
+    #[serde(flatten)]
+    pub webhook: Webhook,
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryAttempt {
+    pub attempt: u32,
+    pub at: DateTime<Utc>,
+    // HTTP status answered by the receiver, missing when it could not be reached
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Delivery {
+    pub id: u64,
+    pub webhook_id: u64,
+    pub event: WebhookEvent,
+    pub status: DeliveryStatus,
+    pub payload: Value,
+    pub attempts: Vec<DeliveryAttempt>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Hex HMAC-SHA256 of `{timestamp}.{body}`, sent in X-Webhook-Signature
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// Wait before retrying after the given attempt, doubling each time
+fn retry_delay(config: &WebhooksConfig, attempt: u32) -> Duration {
+    let factor = 1u64 << (attempt - 1).min(16);
+    Duration::from_millis(config.retry_delay_ms.saturating_mul(factor))
+}
+
+// Whether an address is reachable from the internet at large, as opposed to
+// loopback, private, link-local, shared or otherwise reserved ranges
+fn is_public(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_multicast()
+                || v4.is_documentation()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b))
+                || a >= 240)
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(v4));
+            }
+            let first = v6.segments()[0];
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+// Resolves webhook hosts, refusing names that point at a non-public address
+// so a receiver cannot be turned into a way into the local network
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = lookup_host((name.as_str(), 0)).await?.collect();
+            if addresses.iter().any(|address| !is_public(address.ip())) {
+                return Err(format!("{} resolves to a non-public address", name.as_str()).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+const WEBHOOK_COLUMNS: &str = "id, url, events, secret, created_at";
+
+fn webhook_from_row(row: &Row) -> rusqlite::Result<Webhook> {
+    let events: String = row.get(2)?;
+    Ok(Webhook {
+        id: row.get::<_, i64>(0)? as u64,
+        url: row.get(1)?,
+        events: serde_json::from_str(&events).unwrap_or_default(),
+        secret: row.get(3)?,
+        created_at: row.get(4)?,
+    })
+}
+
+const DELIVERY_COLUMNS: &str = "id, webhook_id, event, status, payload, attempts, created_at";
+
+fn delivery_from_row(row: &Row) -> rusqlite::Result<Delivery> {
+    let payload: String = row.get(4)?;
+    let attempts: String = row.get(5)?;
+    Ok(Delivery {
+        id: row.get::<_, i64>(0)? as u64,
+        webhook_id: row.get::<_, i64>(1)? as u64,
+        event: from_column(row.get(2)?)?,
+        status: from_column(row.get(3)?)?,
+        payload: serde_json::from_str(&payload).unwrap_or(Value::Null),
+        attempts: serde_json::from_str(&attempts).unwrap_or_default(),
+        created_at: row.get(6)?,
+    })
+}
+
+// Webhooks of every user and their recent deliveries, kept in the database so
+// both outlive a restart
+pub struct WebhookStore {
+    database: Arc<Database>,
+    client: reqwest::Client,
+    config: WebhooksConfig,
+}
+
+impl WebhookStore {
+    pub fn new(config: &WebhooksConfig, database: Arc<Database>) -> Result<Self, AppError> {
+        let mut client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            // A redirect could lead anywhere, past the checks on the URL
+            .redirect(Policy::none());
+        if !config.allow_private_hosts {
+            client = client.dns_resolver(Arc::new(PublicResolver));
+        }
+        let client = client
+            .build()
+            .map_err(|e| AppError::Internal(format!("Failed to build webhook client: {}", e)))?;
+
+        Ok(WebhookStore {
+            database,
+            client,
+            config: config.clone(),
+        })
+    }
+
+    pub fn add(&self, owner: &str, webhook: NewWebhook) -> Result<CreatedWebhook, AppError> {
+        let url = Url::parse(&webhook.url)
+            .map_err(|e| AppError::BadRequest(format!("Invalid webhook URL: {}", e)))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(AppError::BadRequest(
+                "Webhook URLs must use http or https".to_string(),
+            ));
+        }
+        // Names are checked again when they are resolved for each delivery
+        let host = url.host_str().unwrap_or_default();
+        let private = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            Ok(address) => !is_public(address),
+            Err(_) => {
+                let domain = host.trim_end_matches('.').to_ascii_lowercase();
+                domain.is_empty() || domain == "localhost" || domain.ends_with(".localhost")
+            }
+        };
+        if private && !self.config.allow_private_hosts {
+            return Err(AppError::BadRequest(
+                "Webhook URLs must point at a public address".to_string(),
+            ));
+        }
+
+        let secret = format!("whsec_{:032x}", rand::random::<u128>());
+        let created_at = Utc::now();
+        let events = serde_json::to_string(&webhook.events)
+            .map_err(|e| AppError::Internal(format!("Failed to serialize events: {}", e)))?;
+
+        let id = self.database.with(|connection| {
+            let registered: i64 = connection.query_row(
+                "SELECT COUNT(*) FROM webhooks WHERE owner = ?1",
+                [owner],
+                |row| row.get(0),
+            )?;
+            if registered as usize >= MAX_WEBHOOKS {
+                return Err(AppError::BadRequest(format!(
+                    "At most {} webhooks can be registered",
+                    MAX_WEBHOOKS
+                )));
+            }
+
+            connection.execute(
+                "INSERT INTO webhooks (owner, url, events, secret, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![owner, url.as_str(), events, secret, created_at],
+            )?;
+            Ok(connection.last_insert_rowid() as u64)
+        })?;
+
+        let webhook = Webhook {
+            id,
+            url: url.to_string(),
+            events: webhook.events,
+            created_at,
+            secret: secret.clone(),
+        };
+        Ok(CreatedWebhook { webhook, secret })
+    }
+
+    pub fn list(&self, owner: &str) -> Result<Vec<Webhook>, AppError> {
+        self.database.with(|connection| {
+            let mut statement = connection.prepare(&format!(
+                "SELECT {} FROM webhooks WHERE owner = ?1 ORDER BY id",
+                WEBHOOK_COLUMNS
+            ))?;
+            let webhooks = statement
+                .query_map([owner], webhook_from_row)?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(webhooks)
+        })
+    }
+
+    // Delete a webhook, and its deliveries with it
+    pub fn remove(&self, owner: &str, id: u64) -> Result<(), AppError> {
+        let deleted = self.database.with(|connection| {
+            Ok(connection.execute(
+                "DELETE FROM webhooks WHERE id = ?1 AND owner = ?2",
+                params![id as i64, owner],
+            )?)
+        })?;
+        if deleted == 0 {
+            return Err(AppError::NotFound(format!("Webhook {}", id)));
+        }
+        Ok(())
+    }
+
+    // Recent deliveries to one of the user's webhooks, newest first
+    pub fn deliveries(&self, owner: &str, id: u64) -> Result<Vec<Delivery>, AppError> {
+        self.database.with(|connection| {
+            connection
+                .query_row(
+                    "SELECT id FROM webhooks WHERE id = ?1 AND owner = ?2",
+                    params![id as i64, owner],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .ok_or_else(|| AppError::NotFound(format!("Webhook {}", id)))?;
+
+            let mut statement = connection.prepare(&format!(
+                "SELECT {} FROM webhook_deliveries WHERE webhook_id = ?1 ORDER BY id DESC",
+                DELIVERY_COLUMNS
+            ))?;
+            let deliveries = statement
+                .query_map([id as i64], delivery_from_row)?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(deliveries)
+        })
+    }
+
+    fn subscribers(&self, owner: &str, event: WebhookEvent) -> Result<Vec<Webhook>, AppError> {
+        Ok(self
+            .list(owner)?
+            .into_iter()
+            .filter(|w| w.events.is_empty() || w.events.contains(&event))
+            .collect())
+    }
+
+    // Record a pending delivery of `data`, dropping the oldest beyond the ones
+    // kept, and return the payload to send
+    fn start_delivery(
+        &self,
+        webhook_id: u64,
+        event: WebhookEvent,
+        data: &Value,
+    ) -> Result<(u64, Value), AppError> {
+        let created_at = Utc::now();
+        self.database.with(|connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "INSERT INTO webhook_deliveries (webhook_id, event, status, payload, attempts, created_at)
+                 VALUES (?1, ?2, ?3, '', '[]', ?4)",
+                params![
+                    webhook_id as i64,
+                    as_column(event),
+                    as_column(DeliveryStatus::Pending),
+                    created_at,
+                ],
+            )?;
+            // The payload carries the id the row was given
+            let id = transaction.last_insert_rowid() as u64;
+            let payload = json!({
+                "id": id,
+                "event": event,
+                "created_at": created_at,
+                "data": data,
+            });
+            transaction.execute(
+                "UPDATE webhook_deliveries SET payload = ?1 WHERE id = ?2",
+                params![payload.to_string(), id as i64],
+            )?;
+            transaction.execute(
+                "DELETE FROM webhook_deliveries WHERE webhook_id = ?1 AND id NOT IN
+                 (SELECT id FROM webhook_deliveries WHERE webhook_id = ?1 ORDER BY id DESC LIMIT ?2)",
+                params![webhook_id as i64, MAX_DELIVERIES as i64],
+            )?;
+            transaction.commit()?;
+            Ok((id, payload))
+        })
+    }
+
+    fn record_attempt(&self, delivery_id: u64, attempt: DeliveryAttempt, status: DeliveryStatus) {
+        let recorded = self.database.with(|connection| {
+            let transaction = connection.transaction()?;
+            let attempts: Option<String> = transaction
+                .query_row(
+                    "SELECT attempts FROM webhook_deliveries WHERE id = ?1",
+                    [delivery_id as i64],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            // Dropped meanwhile, along with its webhook or as one of the oldest
+            let Some(attempts) = attempts else {
+                return Ok(());
+            };
+
+            let mut attempts: Vec<DeliveryAttempt> =
+                serde_json::from_str(&attempts).unwrap_or_default();
+            attempts.push(attempt);
+            let attempts = serde_json::to_string(&attempts)
+                .map_err(|e| AppError::Internal(format!("Failed to serialize attempts: {}", e)))?;
+            transaction.execute(
+                "UPDATE webhook_deliveries SET attempts = ?1, status = ?2 WHERE id = ?3",
+                params![attempts, as_column(status), delivery_id as i64],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        });
+        if let Err(e) = recorded {
+            error!("Failed to record delivery {}: {}", delivery_id, e);
+        }
+    }
+
+    // Deliveries cut short by a restart, with their webhook. Those that had
+    // used up their attempts are marked failed instead.
+    fn interrupted(&self) -> Result<Vec<(Webhook, Delivery)>, AppError> {
+        self.database.with(|connection| {
+            connection.execute(
+                "UPDATE webhook_deliveries SET status = ?1 WHERE status = ?2 AND json_array_length(attempts) >= ?3",
+                params![
+                    as_column(DeliveryStatus::Failed),
+                    as_column(DeliveryStatus::Pending),
+                    self.config.max_attempts,
+                ],
+            )?;
+
+            let mut statement = connection.prepare(&format!(
+                "SELECT {} FROM webhook_deliveries WHERE status = ?1 ORDER BY id",
+                DELIVERY_COLUMNS
+            ))?;
+            let deliveries: Vec<Delivery> = statement
+                .query_map([as_column(DeliveryStatus::Pending)], delivery_from_row)?
+                .collect::<rusqlite::Result<_>>()?;
+
+            let mut interrupted = Vec::new();
+            for delivery in deliveries {
+                let webhook = connection.query_row(
+                    &format!("SELECT {} FROM webhooks WHERE id = ?1", WEBHOOK_COLUMNS),
+                    [delivery.webhook_id as i64],
+                    webhook_from_row,
+                )?;
+                interrupted.push((webhook, delivery));
+            }
+            Ok(interrupted)
+        })
+    }
+}
+
+// Send an event to every webhook of the user that subscribed to it, in the background
+pub fn notify(state: &AppState, owner: &str, event: WebhookEvent, data: Value) {
+    let store = &state.webhooks;
+    let webhooks = match store.subscribers(owner, event) {
+        Ok(webhooks) => webhooks,
+        Err(e) => {
+            error!("Failed to read the webhooks of {}: {}", owner, e);
+            return;
+        }
+    };
+
+    for webhook in webhooks {
+        match store.start_delivery(webhook.id, event, &data) {
+            Ok((id, payload)) => {
+                tokio::spawn(deliver(
+                    store.clone(),
+                    webhook,
+                    id,
+                    event,
+                    payload.to_string(),
+                    1,
+                ));
+            }
+            Err(e) => error!("Failed to record delivery to webhook {}: {}", webhook.id, e),
+        }
+    }
+}
+
+// Carry on with the deliveries left pending by a previous run of the server
+pub fn resume_deliveries(state: &AppState) {
+    let store = &state.webhooks;
+    let interrupted = match store.interrupted() {
+        Ok(interrupted) => interrupted,
+        Err(e) => {
+            error!("Failed to read interrupted deliveries: {}", e);
+            return;
+        }
+    };
+    if !interrupted.is_empty() {
+        info!("Resuming {} webhook deliveries", interrupted.len());
+    }
+
+    for (webhook, delivery) in interrupted {
+        let next_attempt = delivery.attempts.len() as u32 + 1;
+        tokio::spawn(deliver(
+            store.clone(),
+            webhook,
+            delivery.id,
+            delivery.event,
+            delivery.payload.to_string(),
+            next_attempt,
+        ));
+    }
+}
+
+// POST the payload until the receiver answers with a 2xx or the attempts run out
+async fn deliver(
+    store: Arc<WebhookStore>,
+    webhook: Webhook,
+    delivery_id: u64,
+    event: WebhookEvent,
+    body: String,
+    first_attempt: u32,
+) {
+    let max_attempts = store.config.max_attempts;
+    for attempt in first_attempt..=max_attempts {
+        // Signed again on every attempt so receivers can reject stale timestamps
+        let timestamp = Utc::now().timestamp();
+        let started = Instant::now();
+        let result = store
+            .client
+            .post(&webhook.url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("X-Webhook-Id", delivery_id.to_string())
+            .header("X-Webhook-Event", event.as_str())
+            .header("X-Webhook-Timestamp", timestamp.to_string())
+            .header(
+                "X-Webhook-Signature",
+                format!("sha256={}", sign(&webhook.secret, timestamp, &body)),
+            )
+            .body(body.clone())
+            .send()
+            .await;
+
+        let (status, error) = match result {
+            Ok(response) if response.status().is_success() => {
+                (Some(response.status().as_u16()), None)
+            }
+            Ok(response) => (
+                Some(response.status().as_u16()),
+                Some(format!("Receiver answered {}", response.status())),
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        };
+
+        let delivered = error.is_none();
+        let outcome = if delivered {
+            DeliveryStatus::Delivered
+        } else if attempt == max_attempts {
+            DeliveryStatus::Failed
+        } else {
+            DeliveryStatus::Pending
+        };
+        store.record_attempt(
+            delivery_id,
+            DeliveryAttempt {
+                attempt,
+                at: Utc::now(),
+                status,
+                error,
+                duration_ms: started.elapsed().as_millis() as u64,
+            },
+            outcome,
+        );
+
+        if delivered {
+            debug!("Delivered {} to webhook {}", event.as_str(), webhook.id);
+            return;
+        }
+        if attempt < max_attempts {
+            tokio::time::sleep(retry_delay(&store.config, attempt)).await;
+        }
+    }
+
+    warn!(
+        "Giving up on delivery {} to webhook {} after {} attempts",
+        delivery_id, webhook.id, max_attempts
+    );
+}
+
+// List the current user's webhooks
+pub async fn list_webhooks(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<Webhook>>, AppError> {
+    Ok(Json(state.webhooks.list(&claims.sub)?))
+}
+
+// Register a webhook, returning its signing secret
+pub async fn create_webhook(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(webhook): Json<NewWebhook>,
+) -> Result<(StatusCode, Json<CreatedWebhook>), AppError> {
+    let webhook = state.webhooks.add(&claims.sub, webhook)?;
+    Ok((StatusCode::CREATED, Json(webhook)))
+}
+
+// Delete a webhook and its delivery history
+pub async fn delete_webhook(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<u64>,
+) -> Result<StatusCode, AppError> {
+    state.webhooks.remove(&claims.sub, id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Inspect recent deliveries to a webhook and their attempts
+pub async fn list_deliveries(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<Delivery>>, AppError> {
+    Ok(Json(state.webhooks.deliveries(&claims.sub, id)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::create_router;
+    use crate::test_utils::*;
+    use axum::body::{Body, Bytes};
+    use axum::http::HeaderMap;
+    use axum::routing::post;
+    use axum::Router;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use tower::ServiceExt;
+
+    // A local receiver failing the first `failures` deliveries
+    struct Receiver {
+        url: String,
+        received: Arc<Mutex<Vec<(HeaderMap, String)>>>,
+    }
+
+    async fn spawn_receiver(failures: usize) -> Receiver {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let log = received.clone();
+        let app = Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: Bytes| async move {
+                log.lock()
+                    .unwrap()
+                    .push((headers, String::from_utf8(body.to_vec()).unwrap()));
+                if calls.fetch_add(1, Ordering::SeqCst) < failures {
+                    StatusCode::SERVICE_UNAVAILABLE
+                } else {
+                    StatusCode::NO_CONTENT
+                }
+            }),
+        );
+
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let url = format!("http://{}/hook", server.local_addr());
+        tokio::spawn(server);
+        Receiver { url, received }
+    }
+
+    async fn settled(state: &AppState, owner: &str, id: u64) -> Delivery {
+        for _ in 0..200 {
+            let deliveries = state.webhooks.deliveries(owner, id).unwrap();
+            if let Some(delivery) = deliveries.first() {
+                if delivery.status != DeliveryStatus::Pending {
+                    return delivery.clone();
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Delivery did not settle");
+    }
+
+    fn register(state: &AppState, url: &str, events: Vec<WebhookEvent>) -> CreatedWebhook {
+        state
+            .webhooks
+            .add(
+                "alice",
+                NewWebhook {
+                    url: url.to_string(),
+                    events,
+                },
+            )
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_delivery_is_signed_and_retried() {
+        let receiver = spawn_receiver(1).await;
+        let state = test_state(test_config("http://127.0.0.1:9"));
+        let created = register(&state, &receiver.url, vec![]);
+
+        notify(
+            &state,
+            "alice",
+            WebhookEvent::JobCompleted,
+            json!({"id": "job"}),
+        );
+        let delivery = settled(&state, "alice", created.webhook.id).await;
+
+        assert_eq!(delivery.status, DeliveryStatus::Delivered);
+        assert_eq!(delivery.attempts.len(), 2);
+        assert_eq!(delivery.attempts[0].status, Some(503));
+
+        let received = receiver.received.lock().unwrap();
+        let (headers, body) = &received[1];
+        let timestamp = headers["x-webhook-timestamp"].to_str().unwrap();
+        let signature = headers["x-webhook-signature"].to_str().unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(created.secret.as_bytes()).unwrap();
+        mac.update(format!("{}.{}", timestamp, body).as_bytes());
+        let expected = hex::decode(signature.strip_prefix("sha256=").unwrap()).unwrap();
+        assert!(mac.verify_slice(&expected).is_ok());
+        assert_eq!(headers["x-webhook-event"], "job.completed");
+    }
+
+    #[tokio::test]
+    async fn test_delivery_gives_up_after_max_attempts() {
+        let receiver = spawn_receiver(usize::MAX).await;
+        let state = test_state(test_config("http://127.0.0.1:9"));
+        let created = register(&state, &receiver.url, vec![]);
+
+        notify(&state, "alice", WebhookEvent::JobFailed, json!({}));
+        let delivery = settled(&state, "alice", created.webhook.id).await;
+
+        assert_eq!(delivery.status, DeliveryStatus::Failed);
+        assert_eq!(delivery.attempts.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_only_subscribed_events_are_sent() {
+        let state = test_state(test_config("http://127.0.0.1:9"));
+        let created = register(
+            &state,
+            "http://127.0.0.1:9/hook",
+            vec![WebhookEvent::JobCancelled],
+        );
+
+        notify(&state, "alice", WebhookEvent::JobCompleted, json!({}));
+        notify(&state, "bob", WebhookEvent::JobCancelled, json!({}));
+        assert!(state
+            .webhooks
+            .deliveries("alice", created.webhook.id)
+            .unwrap()
+            .is_empty());
+
+        let invalid = state.webhooks.add(
+            "alice",
+            NewWebhook {
+                url: "ftp://example.com".to_string(),
+                events: vec![],
+            },
+        );
+        assert!(matches!(invalid, Err(AppError::BadRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn test_webhooks_and_deliveries_survive_a_restart() {
+        let receiver = spawn_receiver(0).await;
+        let dir = std::env::temp_dir().join(format!("webhooks-test-{:x}", rand::random::<u64>()));
+        let mut config = test_config("http://127.0.0.1:9");
+        config.database.url = format!("sqlite://{}", dir.join("app.db").display());
+
+        let state = test_state(config.clone());
+        let created = register(&state, &receiver.url, vec![]);
+        // A delivery recorded but not sent when the server stopped
+        let (id, _) = state
+            .webhooks
+            .start_delivery(created.webhook.id, WebhookEvent::JobCompleted, &json!({}))
+            .unwrap();
+        drop(state);
+
+        let state = test_state(config);
+        let webhooks = state.webhooks.list("alice").unwrap();
+        assert_eq!(webhooks.len(), 1);
+        assert_eq!(webhooks[0].url, receiver.url);
+        resume_deliveries(&state);
+        let delivery = settled(&state, "alice", created.webhook.id).await;
+        assert_eq!(delivery.id, id);
+        assert_eq!(delivery.status, DeliveryStatus::Delivered);
+
+        assert_eq!(
+            receiver.received.lock().unwrap()[0].0["x-webhook-id"],
+            id.to_string()
+        );
+
+        drop(state);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_private_hosts_are_refused() {
+        let mut config = test_config("http://127.0.0.1:9");
+        config.webhooks.allow_private_hosts = false;
+        let state = test_state(config);
+        let add = |url: &str| {
+            state.webhooks.add(
+                "alice",
+                NewWebhook {
+                    url: url.to_string(),
+                    events: vec![],
+                },
+            )
+        };
+
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://10.1.2.3/hook",
+            "http://192.168.0.10/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/hook",
+            "http://[::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[::ffff:127.0.0.1]/hook",
+            "http://localhost:3000/hook",
+        ] {
+            assert!(
+                matches!(add(url), Err(AppError::BadRequest(_))),
+                "{} was accepted",
+                url
+            );
+        }
+        assert!(add("https://hooks.example.com/jobs").is_ok());
+        assert!(add("http://93.184.215.14/hook").is_ok());
+
+        // Names are checked once resolved, whatever they look like
+        let name = "localhost".parse::<Name>().unwrap();
+        assert!(PublicResolver.resolve(name).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_finished_job_notifies_webhook() {
+        let mock = spawn_mock_openai(|_| vec!["Done.".to_string()]).await;
+        let receiver = spawn_receiver(0).await;
+        let config = test_config(&mock.base_url);
+        let state = test_state(config.clone());
+        let created = register(&state, &receiver.url, vec![WebhookEvent::JobCompleted]);
+
+        let request = authed_request(&config, "alice")
+            .method("POST")
+            .uri("/api/jobs")
+            .header("content-type", "application/json")
+            .body(json_body(json!({"op": "summarize", "text": "Some text."})))
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let delivery = settled(&state, "alice", created.webhook.id).await;
+        assert_eq!(delivery.status, DeliveryStatus::Delivered);
+        assert_eq!(delivery.payload["event"], "job.completed");
+        assert_eq!(delivery.payload["data"]["status"], "completed");
+        assert_eq!(delivery.payload["data"]["progress"]["completed"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_job_notifies_webhook() {
+        let receiver = spawn_receiver(0).await;
+        let config = test_config("http://127.0.0.1:9");
+        let state = test_state(config.clone());
+        let created = register(&state, &receiver.url, vec![WebhookEvent::JobCancelled]);
+        let claims = Claims {
+            sub: "alice".to_string(),
+            exp: 0,
+            iat: 0,
+            role: crate::models::UserRole::User,
+        };
+        let step = serde_json::from_value(json!({"op": "summarize"})).unwrap();
+        // Left queued, so only cancelling can end it
+        let job = state
+            .jobs
+            .submit(&claims, step, vec!["Some text.".to_string()])
+            .unwrap();
+
+        let request = authed_request(&config, "alice")
+            .method("POST")
+            .uri(format!("/api/jobs/{}/cancel", job.id))
+            .body(Body::empty())
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let delivery = settled(&state, "alice", created.webhook.id).await;
+        assert_eq!(delivery.status, DeliveryStatus::Delivered);
+        assert_eq!(delivery.payload["event"], "job.cancelled");
+        assert_eq!(delivery.payload["data"]["id"], job.id);
+        assert_eq!(delivery.payload["data"]["status"], "cancelled");
+        let received = receiver.received.lock().unwrap();
+        assert_eq!(received[0].0["x-webhook-event"], "job.cancelled");
+    }
+}